@@ -12,7 +12,12 @@ use pnet::{
 };
 use tracing::error;
 
-use crate::{constants::PSN_MASK, device_protocol::FrameTx, qp::QueuePairAttrTable, utils::Psn};
+use crate::{
+    constants::{NAK_PSN_SEQUENCE_ERROR, NAK_REMOTE_ACCESS_ERROR, PSN_MASK},
+    device_protocol::FrameTx,
+    qp::QueuePairAttrTable,
+    utils::Psn,
+};
 
 #[derive(Debug)]
 pub(crate) enum AckResponse {
@@ -26,12 +31,19 @@ pub(crate) enum AckResponse {
         base_psn: Psn,
         ack_req_packet_psn: Psn,
     },
+    /// NAK for a request that violates the access rights of the QP
+    AccessNak {
+        qpn: u32,
+        psn: Psn,
+    },
 }
 
 impl AckResponse {
     fn qpn(&self) -> u32 {
         match *self {
-            AckResponse::Ack { qpn, .. } | AckResponse::Nak { qpn, .. } => qpn,
+            AckResponse::Ack { qpn, .. }
+            | AckResponse::Nak { qpn, .. }
+            | AckResponse::AccessNak { qpn, .. } => qpn,
         }
     }
 }
@@ -70,9 +82,16 @@ impl AckResponder {
                 continue;
             };
             let frame = match x {
-                AckResponse::Ack { qpn, msn, last_psn } => {
-                    AckFrameBuilder::build_ack(last_psn, u128::MAX, 0.into(), 0, dqpn, false, false)
-                }
+                AckResponse::Ack { qpn, msn, last_psn } => AckFrameBuilder::build_ack(
+                    last_psn,
+                    u128::MAX,
+                    0.into(),
+                    0,
+                    dqpn,
+                    false,
+                    false,
+                    0,
+                ),
                 AckResponse::Nak {
                     qpn,
                     base_psn,
@@ -85,6 +104,17 @@ impl AckResponder {
                     dqpn,
                     true,
                     true,
+                    NAK_PSN_SEQUENCE_ERROR,
+                ),
+                AckResponse::AccessNak { qpn, psn } => AckFrameBuilder::build_ack(
+                    psn,
+                    0,
+                    psn,
+                    0,
+                    dqpn,
+                    true,
+                    true,
+                    NAK_REMOTE_ACCESS_ERROR,
                 ),
             };
             if let Err(e) = self.raw_frame_tx.send(&frame) {
@@ -101,7 +131,8 @@ struct AckFrameBuilder;
     clippy::arithmetic_side_effects,
     clippy::as_conversions,
     clippy::cast_possible_truncation,
-    clippy::big_endian_bytes
+    clippy::big_endian_bytes,
+    clippy::too_many_arguments
 )]
 impl AckFrameBuilder {
     fn build_ack(
@@ -112,6 +143,7 @@ impl AckFrameBuilder {
         dqpn: u32,
         is_packet_loss: bool,
         is_window_slided: bool,
+        syndrome: u16,
     ) -> Vec<u8> {
         const TRANS_TYPE_RC: u8 = 0x00;
         const OPCODE_ACKNOWLEDGE: u8 = 0x11;
@@ -124,6 +156,7 @@ impl AckFrameBuilder {
         bth.set_psn(u24::from_u32(now_psn.into_inner()));
         bth.set_dqpn(u24::from_u32(dqpn));
         bth.set_trans_type(u3::from_u8(TRANS_TYPE_RC));
+        bth.set_msn(syndrome);
         payload[..12].copy_from_slice(&bth.value.to_be_bytes());
 
         let mut aeth_seg0 = AethSeg0::default();
@@ -0,0 +1,20 @@
+use ibverbs_sys::{ibv_event_type, IBV_EVENT_CQ_ERR, IBV_EVENT_SRQ_LIMIT_REACHED};
+
+/// Asynchronous events reported through `ibv_get_async_event`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AsyncEvent {
+    /// A CQ overflowed, `cq_context` is the address of the `ibv_cq`
+    CqError { cq_context: u64 },
+    /// The number of outstanding WRs of an SRQ dropped below the armed limit, `srq_context`
+    /// is the address of the `ibv_srq`
+    SrqLimitReached { srq_context: u64 },
+}
+
+impl AsyncEvent {
+    pub(crate) fn event_type(self) -> ibv_event_type {
+        match self {
+            AsyncEvent::CqError { .. } => IBV_EVENT_CQ_ERR,
+            AsyncEvent::SrqLimitReached { .. } => IBV_EVENT_SRQ_LIMIT_REACHED,
+        }
+    }
+}
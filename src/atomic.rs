@@ -0,0 +1,675 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io::{self, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use ibverbs_sys::ibv_access_flags;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::{
+    completion::{Completion, CompletionStatus, CompletionTask},
+    mr::MrTable,
+    qp::{QpState, QueuePairAttrTable},
+    rnr_retry::RnrRetryHandle,
+    send::{SendWr, SendWrAtomic},
+};
+
+/// Default port of the atomic connections
+const DEFAULT_ATOMIC_PORT: u16 = 62000;
+
+/// Configuration of the atomic connections
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct AtomicConfig {
+    /// Port the device listens on
+    port: u16,
+    /// Port the peers listen on
+    peer_port: u16,
+}
+
+impl Default for AtomicConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_ATOMIC_PORT,
+            peer_port: DEFAULT_ATOMIC_PORT,
+        }
+    }
+}
+
+impl AtomicConfig {
+    pub(crate) fn new(port: u16, peer_port: u16) -> Self {
+        Self { port, peer_port }
+    }
+
+    pub(crate) fn port(self) -> u16 {
+        self.port
+    }
+
+    pub(crate) fn peer_port(self) -> u16 {
+        self.peer_port
+    }
+}
+
+/// Time to wait for the responder before the request is resent
+const ATOMIC_RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Number of times a request is resent before the WR fails with `RetryExceeded`
+const ATOMIC_RETRY_COUNT: usize = 3;
+
+/// Interval at which an idle responder connection checks whether the QP was destroyed
+const RESPONDER_CLOSE_POLL: Duration = Duration::from_secs(1);
+
+/// Size of the operand in bytes
+const ATOMIC_SIZE: u64 = 8;
+
+/// An 8-byte atomic operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AtomicOp {
+    CompareSwap { compare: u64, swap: u64 },
+    FetchAdd { add: u64 },
+}
+
+impl AtomicOp {
+    /// Applies the operation to `target`, returns the original value
+    fn apply(self, target: &AtomicU64) -> u64 {
+        match self {
+            AtomicOp::CompareSwap { compare, swap } => target
+                .compare_exchange(compare, swap, Ordering::AcqRel, Ordering::Acquire)
+                .unwrap_or_else(|orig| orig),
+            AtomicOp::FetchAdd { add } => target.fetch_add(add, Ordering::AcqRel),
+        }
+    }
+
+    /// Returns the `ibv_wc_opcode` of the operation
+    pub(crate) fn wc_opcode(self) -> u32 {
+        match self {
+            AtomicOp::CompareSwap { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_COMP_SWAP,
+            AtomicOp::FetchAdd { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_FETCH_ADD,
+        }
+    }
+}
+
+/// Result of an atomic request reported by the responder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AtomicStatus {
+    Success,
+    /// The QP or the MR does not grant remote atomic access, or the target is out of range
+    AccessError,
+    /// The request is malformed or the QP cannot accept it
+    InvalidRequest,
+}
+
+impl AtomicStatus {
+    fn to_u32(self) -> u32 {
+        match self {
+            AtomicStatus::Success => 0,
+            AtomicStatus::AccessError => 1,
+            AtomicStatus::InvalidRequest => 2,
+        }
+    }
+
+    fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(AtomicStatus::Success),
+            1 => Some(AtomicStatus::AccessError),
+            2 => Some(AtomicStatus::InvalidRequest),
+            _ => None,
+        }
+    }
+
+    fn to_completion_status(self) -> CompletionStatus {
+        match self {
+            AtomicStatus::Success => CompletionStatus::Success,
+            AtomicStatus::AccessError => CompletionStatus::RemoteAccessError,
+            AtomicStatus::InvalidRequest => CompletionStatus::RemoteInvalidRequest,
+        }
+    }
+}
+
+const REQUEST_SIZE: usize = 48;
+const RESPONSE_SIZE: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AtomicRequest {
+    /// QPN of the requester
+    src_qpn: u32,
+    /// QPN of the responder
+    dest_qpn: u32,
+    /// Identifies the request among the requests of the same requester, a resent
+    /// request keeps its id
+    id: u64,
+    raddr: u64,
+    rkey: u32,
+    op: AtomicOp,
+}
+
+impl AtomicRequest {
+    fn to_bytes(self) -> [u8; REQUEST_SIZE] {
+        let (kind, operand0, operand1) = match self.op {
+            AtomicOp::CompareSwap { compare, swap } => (0u32, compare, swap),
+            AtomicOp::FetchAdd { add } => (1u32, add, 0),
+        };
+        let mut bytes = [0u8; REQUEST_SIZE];
+        bytes[0..8].copy_from_slice(&self.raddr.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.rkey.to_be_bytes());
+        bytes[12..16].copy_from_slice(&kind.to_be_bytes());
+        bytes[16..24].copy_from_slice(&operand0.to_be_bytes());
+        bytes[24..32].copy_from_slice(&operand1.to_be_bytes());
+        bytes[32..36].copy_from_slice(&self.src_qpn.to_be_bytes());
+        bytes[36..44].copy_from_slice(&self.id.to_be_bytes());
+        bytes[44..48].copy_from_slice(&self.dest_qpn.to_be_bytes());
+        bytes
+    }
+
+    #[allow(clippy::unwrap_used)]
+    fn from_bytes(bytes: &[u8; REQUEST_SIZE]) -> Option<Self> {
+        let operand0 = u64::from_be_bytes(bytes[16..24].try_into().unwrap());
+        let operand1 = u64::from_be_bytes(bytes[24..32].try_into().unwrap());
+        let op = match u32::from_be_bytes(bytes[12..16].try_into().unwrap()) {
+            0 => AtomicOp::CompareSwap {
+                compare: operand0,
+                swap: operand1,
+            },
+            1 => AtomicOp::FetchAdd { add: operand0 },
+            _ => return None,
+        };
+        Some(Self {
+            src_qpn: u32::from_be_bytes(bytes[32..36].try_into().unwrap()),
+            dest_qpn: u32::from_be_bytes(bytes[44..48].try_into().unwrap()),
+            id: u64::from_be_bytes(bytes[36..44].try_into().unwrap()),
+            raddr: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            rkey: u32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+            op,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AtomicResponse {
+    status: AtomicStatus,
+    /// Value of the target before the operation
+    orig: u64,
+}
+
+impl AtomicResponse {
+    fn error(status: AtomicStatus) -> Self {
+        Self { status, orig: 0 }
+    }
+
+    fn to_bytes(self) -> [u8; RESPONSE_SIZE] {
+        let mut bytes = [0u8; RESPONSE_SIZE];
+        bytes[0..4].copy_from_slice(&self.status.to_u32().to_be_bytes());
+        bytes[4..12].copy_from_slice(&self.orig.to_be_bytes());
+        bytes
+    }
+
+    #[allow(clippy::unwrap_used)]
+    fn from_bytes(bytes: &[u8; RESPONSE_SIZE]) -> Option<Self> {
+        Some(Self {
+            status: AtomicStatus::from_u32(u32::from_be_bytes(bytes[0..4].try_into().unwrap()))?,
+            orig: u64::from_be_bytes(bytes[4..12].try_into().unwrap()),
+        })
+    }
+}
+
+/// Executes the atomic requests issued by the peers of the QPs of the device
+///
+/// The hardware does not carry atomic packets, so requests are exchanged over TCP,
+/// like the receive WRs, and executed on the CPU against the target MR. The device
+/// listens on a single port, each request names the QP it targets. The peer reconnects
+/// after a failed request, so connections are accepted until the responder is closed.
+pub(crate) struct AtomicResponder {
+    listener: TcpListener,
+    state: Arc<ResponderState>,
+}
+
+struct ResponderState {
+    qp_table: QueuePairAttrTable,
+    mr_table: MrTable,
+    closed: AtomicBool,
+    /// Last executed request of each QP as `(src_qpn, id, response)`, replayed to a
+    /// resent request instead of executing it twice
+    ///
+    /// The requester issues one atomic at a time, so the last one is the only one
+    /// that can be resent.
+    last: Mutex<HashMap<u32, (u32, u64, AtomicResponse)>>,
+}
+
+/// Closes the responder of the device
+#[derive(Debug)]
+pub(crate) struct AtomicResponderHandle {
+    local_addr: SocketAddr,
+    state: Arc<ResponderState>,
+}
+
+impl std::fmt::Debug for ResponderState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ResponderState").finish_non_exhaustive()
+    }
+}
+
+impl AtomicResponderHandle {
+    /// Forgets the last request executed on the QP, used on QP teardown so that a QP
+    /// reusing the QPN does not replay it
+    pub(crate) fn forget(&self, qpn: u32) {
+        let _prev = self.state.last.lock().remove(&qpn);
+    }
+
+    /// Stops accepting connections, open connections are closed once idle
+    pub(crate) fn close(self) {
+        self.state.closed.store(true, Ordering::Relaxed);
+        // wakes the blocking accept
+        let _ignore = TcpStream::connect(self.local_addr);
+    }
+}
+
+impl AtomicResponder {
+    /// Listens for peer connections on `addr`
+    pub(crate) fn bind(
+        addr: Ipv4Addr,
+        config: AtomicConfig,
+        qp_table: QueuePairAttrTable,
+        mr_table: MrTable,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind((addr, config.port()))?;
+        Ok(Self {
+            listener,
+            state: Arc::new(ResponderState {
+                qp_table,
+                mr_table,
+                closed: AtomicBool::new(false),
+                last: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    pub(crate) fn spawn(self) -> io::Result<AtomicResponderHandle> {
+        let handle = AtomicResponderHandle {
+            local_addr: self.listener.local_addr()?,
+            state: Arc::clone(&self.state),
+        };
+        let _handle = thread::Builder::new()
+            .name("atomic-responder".into())
+            .spawn(move || self.run())
+            .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
+        Ok(handle)
+    }
+
+    fn run(self) {
+        for stream in self.listener.incoming() {
+            if self.state.closed.load(Ordering::Relaxed) {
+                return;
+            }
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("failed to accept atomic connection: {err}");
+                    continue;
+                }
+            };
+            let Ok(SocketAddr::V4(peer)) = stream.peer_addr() else {
+                continue;
+            };
+            if let Err(err) = stream.set_read_timeout(Some(RESPONDER_CLOSE_POLL)) {
+                warn!("failed to set read timeout: {err}");
+                continue;
+            }
+            let state = Arc::clone(&self.state);
+            if let Err(err) = thread::Builder::new()
+                .name("atomic-responder-conn".into())
+                .spawn(move || state.serve(stream, *peer.ip()))
+            {
+                error!("failed to spawn atomic responder thread: {err}");
+            }
+        }
+    }
+}
+
+impl ResponderState {
+    /// Executes the requests of a peer device until the connection is closed
+    fn serve(&self, mut stream: TcpStream, peer_addr: Ipv4Addr) {
+        let mut buf = [0; REQUEST_SIZE];
+        while !self.closed.load(Ordering::Relaxed) {
+            match stream.read_exact(&mut buf) {
+                Ok(()) => {}
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(_err) => return,
+            }
+            let resp = AtomicRequest::from_bytes(&buf)
+                .map_or(AtomicResponse::error(AtomicStatus::InvalidRequest), |req| {
+                    self.execute_once(req, peer_addr)
+                });
+            if stream.write_all(&resp.to_bytes()).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Executes the request unless it is a resend of the last one, whose response is
+    /// replayed instead
+    fn execute_once(&self, req: AtomicRequest, peer_addr: Ipv4Addr) -> AtomicResponse {
+        let mut last = self.last.lock();
+        if let Some(&(src_qpn, id, resp)) = last.get(&req.dest_qpn) {
+            if src_qpn == req.src_qpn && id == req.id {
+                return resp;
+            }
+        }
+        let resp = self.execute(req, peer_addr);
+        let _prev = last.insert(req.dest_qpn, (req.src_qpn, req.id, resp));
+        resp
+    }
+
+    fn execute(&self, req: AtomicRequest, peer_addr: Ipv4Addr) -> AtomicResponse {
+        // only the peer of the QP may target it
+        let Some(qp) = self
+            .qp_table
+            .get(req.dest_qpn)
+            .filter(|qp| qp.dqp_ip == peer_addr.to_bits() && qp.dqpn == req.src_qpn)
+        else {
+            return AtomicResponse::error(AtomicStatus::InvalidRequest);
+        };
+        if !matches!(
+            qp.state,
+            QpState::Rtr | QpState::Rts | QpState::Sqd | QpState::Sqe
+        ) {
+            return AtomicResponse::error(AtomicStatus::InvalidRequest);
+        }
+        let qp_allowed =
+            u32::from(qp.access_flags) & ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC.0 != 0;
+        let mr_allowed = self.mr_table.get(req.rkey).is_some_and(|mr| {
+            mr.pd_handle == qp.pd_handle
+                && mr.allows(ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC)
+                && mr.contains(req.raddr, ATOMIC_SIZE)
+        });
+        if !qp_allowed || !mr_allowed {
+            return AtomicResponse::error(AtomicStatus::AccessError);
+        }
+        if !req.raddr.is_multiple_of(ATOMIC_SIZE) {
+            return AtomicResponse::error(AtomicStatus::InvalidRequest);
+        }
+        #[allow(unsafe_code, clippy::as_conversions)]
+        // SAFETY: the target is 8-byte aligned and lies within a registered MR
+        let target = unsafe { AtomicU64::from_ptr(req.raddr as *mut u64) };
+        AtomicResponse {
+            status: AtomicStatus::Success,
+            orig: req.op.apply(target),
+        }
+    }
+}
+
+/// An atomic WR ready to be issued, all earlier WRs of the QP have been acknowledged
+#[derive(Debug)]
+pub(crate) struct AtomicTask {
+    pub(crate) qpn: u32,
+    pub(crate) wr: SendWrAtomic,
+}
+
+/// Issues atomic WRs to the peer and completes them
+///
+/// The original value returned by the responder is written to the local buffer of
+/// the WR. A request without response is resent with the same id, the responder
+/// replays the response, so the operation runs at most once. After the WR completes,
+/// the RNR retry worker is notified to release the WRs queued behind it.
+pub(crate) struct AtomicWorker {
+    atomic_rx: flume::Receiver<AtomicTask>,
+    /// Port the peers listen on
+    peer_port: u16,
+    /// Connections to the responders of the peer devices, keyed by peer IP
+    connections: HashMap<u32, TcpStream>,
+    /// Id of the next request of each QP
+    next_ids: HashMap<u32, u64>,
+    qp_table: QueuePairAttrTable,
+    mr_table: MrTable,
+    completion_tx: flume::Sender<CompletionTask>,
+    rnr: RnrRetryHandle,
+}
+
+impl AtomicWorker {
+    pub(crate) fn new(
+        atomic_rx: flume::Receiver<AtomicTask>,
+        config: AtomicConfig,
+        qp_table: QueuePairAttrTable,
+        mr_table: MrTable,
+        completion_tx: flume::Sender<CompletionTask>,
+        rnr: RnrRetryHandle,
+    ) -> Self {
+        Self {
+            atomic_rx,
+            peer_port: config.peer_port(),
+            connections: HashMap::new(),
+            next_ids: HashMap::new(),
+            qp_table,
+            mr_table,
+            completion_tx,
+            rnr,
+        }
+    }
+
+    pub(crate) fn spawn(self) {
+        let _handle = thread::Builder::new()
+            .name("atomic-worker".into())
+            .spawn(move || self.run())
+            .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
+    }
+
+    fn run(mut self) {
+        while let Ok(AtomicTask { qpn, wr }) = self.atomic_rx.recv() {
+            let status = self.execute(qpn, wr).unwrap_or_else(|err| {
+                error!("atomic request of qp {qpn} failed: {err}");
+                CompletionStatus::RetryExceeded
+            });
+            self.complete(qpn, wr, status);
+            self.rnr.atomic_done(qpn);
+        }
+    }
+
+    fn execute(&mut self, qpn: u32, wr: SendWrAtomic) -> io::Result<CompletionStatus> {
+        let qp = self
+            .qp_table
+            .get(qpn)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        if qp.state == QpState::Err {
+            return Ok(CompletionStatus::WrFlushError);
+        }
+        let sge = wr.sge();
+        let local_allowed = self.mr_table.get(sge.lkey).is_some_and(|mr| {
+            mr.pd_handle == qp.pd_handle
+                && mr.allows(ibv_access_flags::IBV_ACCESS_LOCAL_WRITE)
+                && mr.contains(sge.addr, ATOMIC_SIZE)
+        });
+        if !local_allowed {
+            return Ok(CompletionStatus::LocalProtectionError);
+        }
+        let next_id = self.next_ids.entry(qpn).or_default();
+        let req = AtomicRequest {
+            src_qpn: qpn,
+            dest_qpn: qp.dqpn,
+            id: *next_id,
+            raddr: wr.raddr,
+            rkey: wr.rkey,
+            op: wr.op,
+        };
+        *next_id = next_id.wrapping_add(1);
+        let mut attempt = 0;
+        let resp = loop {
+            match self.request(qp.dqp_ip, req) {
+                Ok(resp) => break resp,
+                Err(err) if attempt < ATOMIC_RETRY_COUNT => {
+                    warn!("atomic request of qp {qpn} failed, resending: {err}");
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        if resp.status != AtomicStatus::Success {
+            return Ok(resp.status.to_completion_status());
+        }
+        #[allow(unsafe_code, clippy::as_conversions)]
+        // SAFETY: the buffer lies within a registered MR granting local write access
+        unsafe {
+            (sge.addr as *mut u64).write_unaligned(resp.orig);
+        }
+
+        Ok(CompletionStatus::Success)
+    }
+
+    /// Sends the request and waits for the response, the connection is dropped on failure
+    fn request(&mut self, dqp_ip: u32, req: AtomicRequest) -> io::Result<AtomicResponse> {
+        let result = self.connect(dqp_ip).and_then(|stream| {
+            stream.write_all(&req.to_bytes())?;
+            let mut buf = [0; RESPONSE_SIZE];
+            stream.read_exact(&mut buf)?;
+            AtomicResponse::from_bytes(&buf).ok_or(io::Error::from(io::ErrorKind::InvalidData))
+        });
+        if result.is_err() {
+            let _ignore = self.connections.remove(&dqp_ip);
+        }
+        result
+    }
+
+    /// Returns the connection to the peer device, shared by all QPs connected to it
+    fn connect(&mut self, dqp_ip: u32) -> io::Result<&mut TcpStream> {
+        match self.connections.entry(dqp_ip) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let stream = TcpStream::connect((Ipv4Addr::from_bits(dqp_ip), self.peer_port))?;
+                stream.set_read_timeout(Some(ATOMIC_RESPONSE_TIMEOUT))?;
+                Ok(entry.insert(stream))
+            }
+        }
+    }
+
+    fn complete(&self, qpn: u32, wr: SendWrAtomic, status: CompletionStatus) {
+        let signaled = wr.send_flags() & ibverbs_sys::ibv_send_flags::IBV_SEND_SIGNALED.0 != 0;
+        let completion = if status == CompletionStatus::Success {
+            if !signaled {
+                return;
+            }
+            match wr.op {
+                AtomicOp::CompareSwap { .. } => Completion::CompareSwap { wr_id: wr.wr_id() },
+                AtomicOp::FetchAdd { .. } => Completion::FetchAdd { wr_id: wr.wr_id() },
+            }
+        } else {
+            // an unsuccessful WR always generates a completion
+            self.qp_table.set_state(qpn, QpState::Err);
+            let Some(completion) = Completion::send_error(&SendWr::Atomic(wr), status) else {
+                return;
+            };
+            completion
+        };
+        let _ignore = self
+            .completion_tx
+            .send(CompletionTask::Complete { qpn, completion });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn atomic_op_returns_original_value() {
+        let target = AtomicU64::new(5);
+        let op = AtomicOp::CompareSwap {
+            compare: 4,
+            swap: 9,
+        };
+        assert_eq!(op.apply(&target), 5);
+        assert_eq!(target.load(Ordering::Relaxed), 5);
+        let op = AtomicOp::CompareSwap {
+            compare: 5,
+            swap: 9,
+        };
+        assert_eq!(op.apply(&target), 5);
+        assert_eq!(target.load(Ordering::Relaxed), 9);
+        assert_eq!(AtomicOp::FetchAdd { add: 3 }.apply(&target), 9);
+        assert_eq!(target.load(Ordering::Relaxed), 12);
+    }
+
+    #[test]
+    fn atomic_request_encoding() {
+        let req = AtomicRequest {
+            src_qpn: 3,
+            dest_qpn: 4,
+            id: 5,
+            raddr: 0x1000,
+            rkey: 7,
+            op: AtomicOp::CompareSwap {
+                compare: 1,
+                swap: 2,
+            },
+        };
+        assert_eq!(AtomicRequest::from_bytes(&req.to_bytes()), Some(req));
+        let resp = AtomicResponse {
+            status: AtomicStatus::AccessError,
+            orig: 3,
+        };
+        assert_eq!(AtomicResponse::from_bytes(&resp.to_bytes()), Some(resp));
+    }
+
+    #[test]
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)] // flags fit in u8
+    fn resent_request_is_replayed() {
+        const QPN: u32 = 1 << 8;
+        let target = Box::new(AtomicU64::new(1));
+        let raddr = std::ptr::from_ref(target.as_ref()) as u64;
+        let qp_table = QueuePairAttrTable::new();
+        let _ignore = qp_table.map_qp_mut(QPN, |qp| {
+            qp.state = QpState::Rts;
+            qp.pd_handle = 1;
+            qp.access_flags = ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC.0 as u8;
+            qp.dqp_ip = Ipv4Addr::LOCALHOST.to_bits();
+            qp.dqpn = 3;
+        });
+        let mr_table = MrTable::new();
+        let access = ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC.0 as u8;
+        mr_table.insert(
+            7,
+            crate::mr::MemoryRegion::new(raddr, ATOMIC_SIZE, 1, access),
+            crate::mtt::PgtEntry { index: 0, count: 1 },
+        );
+        let state = ResponderState {
+            qp_table,
+            mr_table,
+            closed: AtomicBool::new(false),
+            last: Mutex::new(HashMap::new()),
+        };
+        let peer = Ipv4Addr::LOCALHOST;
+        let req = AtomicRequest {
+            src_qpn: 3,
+            dest_qpn: QPN,
+            id: 0,
+            raddr,
+            rkey: 7,
+            op: AtomicOp::FetchAdd { add: 2 },
+        };
+        assert_eq!(state.execute_once(req, peer).orig, 1);
+        assert_eq!(state.execute_once(req, peer).orig, 1);
+        assert_eq!(target.load(Ordering::Relaxed), 3);
+        let next = AtomicRequest { id: 1, ..req };
+        assert_eq!(state.execute_once(next, peer).orig, 3);
+        assert_eq!(target.load(Ordering::Relaxed), 5);
+        // only the peer of the QP may target it
+        let other = AtomicRequest { src_qpn: 4, ..next };
+        let resp = state.execute_once(other, peer);
+        assert_eq!(resp.status, AtomicStatus::InvalidRequest);
+        let resp = state.execute_once(AtomicRequest { id: 2, ..req }, Ipv4Addr::UNSPECIFIED);
+        assert_eq!(resp.status, AtomicStatus::InvalidRequest);
+        assert_eq!(target.load(Ordering::Relaxed), 5);
+    }
+}
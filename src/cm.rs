@@ -0,0 +1,620 @@
+use std::{
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use ibverbs_sys::{
+    ibv_ah_attr, ibv_gid, ibv_global_route, ibv_mtu, ibv_qp_attr, ibv_qp_attr_mask, ibv_qp_state,
+};
+use rand::Rng;
+use thiserror::Error;
+
+use crate::{
+    constants::PSN_MASK,
+    net::config::NetworkConfig,
+    protocol_impl::device::ops_impl::{
+        qp_attr::{IbvQpAttr, IbvQpInitAttr},
+        DeviceOps,
+    },
+};
+
+/// Maximum length of the private data carried by a handshake message
+pub(crate) const MAX_PRIVATE_DATA: usize = 56;
+
+/// Size of the fixed part of a handshake message
+const HEADER_SIZE: usize = 14;
+
+/// Local ACK timeout exponent used for connected QPs
+const DEFAULT_TIMEOUT: u8 = 14;
+/// Retry count used for connected QPs
+const DEFAULT_RETRY_CNT: u8 = 7;
+/// RNR retry count used for connected QPs, 7 retries infinitely
+const DEFAULT_RNR_RETRY: u8 = 7;
+/// Minimal RNR NAK timer used for connected QPs
+const DEFAULT_MIN_RNR_TIMER: u8 = 12;
+
+/// Time the listener waits for each handshake message of the requester
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection manager errors
+#[derive(Error, Debug)]
+pub(crate) enum CmError {
+    /// The peer rejected the connection, with its private data
+    #[error("connection rejected by peer")]
+    Rejected(Vec<u8>),
+    /// The peer sent a message not expected at this point of the handshake
+    #[error("unexpected handshake message: {0:?}")]
+    Protocol(MsgKind),
+    /// The private data exceeds `MAX_PRIVATE_DATA`
+    #[error("private data too long: {0} bytes")]
+    PrivateDataTooLong(usize),
+    /// I/O error on the handshake connection or on the device
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Device operations driven by the connection manager
+pub(crate) trait CmDevice {
+    fn create_qp(&mut self, pd_handle: u32, attr: IbvQpInitAttr) -> io::Result<u32>;
+    fn update_qp(&mut self, qpn: u32, attr: IbvQpAttr) -> io::Result<()>;
+    fn destroy_qp(&mut self, qpn: u32);
+    /// Returns the network configuration of the card, its address is advertised to peers
+    fn network_config(&self) -> NetworkConfig;
+}
+
+impl<D: DeviceOps + ?Sized> CmDevice for D {
+    fn create_qp(&mut self, pd_handle: u32, attr: IbvQpInitAttr) -> io::Result<u32> {
+        DeviceOps::create_qp(self, pd_handle, attr)
+    }
+
+    fn update_qp(&mut self, qpn: u32, attr: IbvQpAttr) -> io::Result<()> {
+        DeviceOps::update_qp(self, qpn, attr)
+    }
+
+    fn destroy_qp(&mut self, qpn: u32) {
+        DeviceOps::destroy_qp(self, qpn);
+    }
+
+    fn network_config(&self) -> NetworkConfig {
+        DeviceOps::network_config(self)
+    }
+}
+
+/// Parameters of the RC QP created for a connection
+pub(crate) struct QpParams {
+    pub(crate) pd_handle: u32,
+    pub(crate) init_attr: IbvQpInitAttr,
+    /// Remote access granted to the peer, `ibv_access_flags`
+    pub(crate) access_flags: u32,
+    pub(crate) path_mtu: ibv_mtu,
+}
+
+/// Listens for connection requests
+pub(crate) struct CmListener {
+    inner: TcpListener,
+}
+
+impl CmListener {
+    pub(crate) fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Ok(Self {
+            inner: TcpListener::bind(addr)?,
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Waits for the next connection request
+    ///
+    /// A requester that does not send its request within `HANDSHAKE_TIMEOUT` fails the
+    /// call, the next call accepts the next requester.
+    pub(crate) fn get_request(&self) -> Result<ConnRequest, CmError> {
+        let (mut stream, _addr) = self.inner.accept()?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let msg = CmMessage::read_from(&mut stream)?;
+        if msg.kind != MsgKind::Request {
+            return Err(CmError::Protocol(msg.kind));
+        }
+        Ok(ConnRequest {
+            stream,
+            remote: msg,
+        })
+    }
+}
+
+/// A connection request received by a `CmListener`
+pub(crate) struct ConnRequest {
+    stream: TcpStream,
+    remote: CmMessage,
+}
+
+impl ConnRequest {
+    /// Returns the private data sent by the requester
+    pub(crate) fn private_data(&self) -> &[u8] {
+        &self.remote.private_data
+    }
+
+    /// Accepts the request, the QP is created and moved to RTS once the requester is ready
+    pub(crate) fn accept<D: CmDevice + ?Sized>(
+        self,
+        dev: &mut D,
+        params: QpParams,
+        private_data: &[u8],
+    ) -> Result<Connection, CmError> {
+        check_private_data(private_data)?;
+        let qpn = dev.create_qp(params.pd_handle, params.init_attr)?;
+        self.accept_qp(dev, qpn, params.access_flags, params.path_mtu, private_data)
+            .inspect_err(|_err| dev.destroy_qp(qpn))
+    }
+
+    /// Accepts the request on an existing QP in the RESET state, the QP is moved to
+    /// RTS once the requester is ready
+    ///
+    /// The QP is left in its last state on failure.
+    pub(crate) fn accept_qp<D: CmDevice + ?Sized>(
+        mut self,
+        dev: &mut D,
+        qpn: u32,
+        access_flags: u32,
+        path_mtu: ibv_mtu,
+        private_data: &[u8],
+    ) -> Result<Connection, CmError> {
+        check_private_data(private_data)?;
+        let local_ip = dev.network_config().ip.ip();
+        let psn = random_psn();
+        dev.update_qp(qpn, init_attr(access_flags))?;
+        dev.update_qp(qpn, rtr_attr(&self.remote, path_mtu))?;
+        CmMessage::new(MsgKind::Reply, qpn, local_ip, psn, private_data)
+            .write_to(&mut self.stream)?;
+        let msg = CmMessage::read_from(&mut self.stream)?;
+        if msg.kind != MsgKind::Ready {
+            return Err(CmError::Protocol(msg.kind));
+        }
+        // the established connection waits for the disconnect without a deadline
+        self.stream.set_read_timeout(None)?;
+        dev.update_qp(qpn, rts_attr(psn))?;
+
+        Ok(Connection::new(self.stream, qpn, self.remote))
+    }
+
+    /// Rejects the request
+    pub(crate) fn reject(mut self, private_data: &[u8]) -> Result<(), CmError> {
+        check_private_data(private_data)?;
+        CmMessage::new(MsgKind::Reject, 0, Ipv4Addr::UNSPECIFIED, 0, private_data)
+            .write_to(&mut self.stream)?;
+        self.stream.shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+}
+
+/// Connects to a `CmListener`, the QP is created and moved to RTS on success
+pub(crate) fn connect<D: CmDevice + ?Sized, A: ToSocketAddrs>(
+    dev: &mut D,
+    addr: A,
+    params: QpParams,
+    private_data: &[u8],
+) -> Result<Connection, CmError> {
+    check_private_data(private_data)?;
+    let qpn = dev.create_qp(params.pd_handle, params.init_attr)?;
+    connect_qp(
+        dev,
+        addr,
+        qpn,
+        params.access_flags,
+        params.path_mtu,
+        private_data,
+    )
+    .inspect_err(|_err| dev.destroy_qp(qpn))
+}
+
+/// Connects an existing QP in the RESET state to a `CmListener`, the QP is moved to
+/// RTS on success
+///
+/// The QP is left in its last state on failure.
+pub(crate) fn connect_qp<D: CmDevice + ?Sized, A: ToSocketAddrs>(
+    dev: &mut D,
+    addr: A,
+    qpn: u32,
+    access_flags: u32,
+    path_mtu: ibv_mtu,
+    private_data: &[u8],
+) -> Result<Connection, CmError> {
+    check_private_data(private_data)?;
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_nodelay(true)?;
+    let local_ip = dev.network_config().ip.ip();
+    let psn = random_psn();
+    dev.update_qp(qpn, init_attr(access_flags))?;
+    CmMessage::new(MsgKind::Request, qpn, local_ip, psn, private_data).write_to(&mut stream)?;
+    let msg = CmMessage::read_from(&mut stream)?;
+    match msg.kind {
+        MsgKind::Reply => {}
+        MsgKind::Reject => return Err(CmError::Rejected(msg.private_data)),
+        MsgKind::Request | MsgKind::Ready | MsgKind::Disconnect => {
+            return Err(CmError::Protocol(msg.kind))
+        }
+    }
+    dev.update_qp(qpn, rtr_attr(&msg, path_mtu))?;
+    dev.update_qp(qpn, rts_attr(psn))?;
+    CmMessage::new(MsgKind::Ready, qpn, local_ip, psn, &[]).write_to(&mut stream)?;
+
+    Ok(Connection::new(stream, qpn, msg))
+}
+
+/// An established connection
+///
+/// The QP is owned by the caller and must be destroyed with `CmDevice::destroy_qp`
+/// after the connection is disconnected.
+pub(crate) struct Connection {
+    stream: TcpStream,
+    qpn: u32,
+    remote: CmMessage,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, qpn: u32, remote: CmMessage) -> Self {
+        Self {
+            stream,
+            qpn,
+            remote,
+        }
+    }
+
+    pub(crate) fn qpn(&self) -> u32 {
+        self.qpn
+    }
+
+    pub(crate) fn remote_qpn(&self) -> u32 {
+        self.remote.qpn
+    }
+
+    pub(crate) fn remote_ip(&self) -> Ipv4Addr {
+        self.remote.ip
+    }
+
+    /// Returns the private data sent by the peer during the handshake
+    pub(crate) fn remote_private_data(&self) -> &[u8] {
+        &self.remote.private_data
+    }
+
+    /// Notifies the peer and moves the QP to the error state, flushing outstanding WRs
+    pub(crate) fn disconnect<D: CmDevice + ?Sized>(mut self, dev: &mut D) -> Result<(), CmError> {
+        // the peer may already be gone
+        let _ignore = CmMessage::new(MsgKind::Disconnect, self.qpn, Ipv4Addr::UNSPECIFIED, 0, &[])
+            .write_to(&mut self.stream);
+        let _ignore = self.stream.shutdown(Shutdown::Both);
+        dev.update_qp(self.qpn, error_attr())?;
+        Ok(())
+    }
+
+    /// Waits until the peer disconnects, then moves the QP to the error state
+    pub(crate) fn wait_disconnect<D: CmDevice + ?Sized>(
+        mut self,
+        dev: &mut D,
+    ) -> Result<(), CmError> {
+        match CmMessage::read_from(&mut self.stream) {
+            Ok(msg) if msg.kind == MsgKind::Disconnect => {}
+            Ok(msg) => return Err(CmError::Protocol(msg.kind)),
+            // a closed connection is treated as a disconnect
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {}
+            Err(err) => return Err(err.into()),
+        }
+        dev.update_qp(self.qpn, error_attr())?;
+        Ok(())
+    }
+}
+
+/// Kind of a handshake message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum MsgKind {
+    Request = 1,
+    Reply = 2,
+    Reject = 3,
+    /// The requester reached RTS
+    Ready = 4,
+    Disconnect = 5,
+}
+
+impl MsgKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Request),
+            2 => Some(Self::Reply),
+            3 => Some(Self::Reject),
+            4 => Some(Self::Ready),
+            5 => Some(Self::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+/// Handshake message, carrying the parameters of the sender's QP
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CmMessage {
+    kind: MsgKind,
+    qpn: u32,
+    ip: Ipv4Addr,
+    psn: u32,
+    private_data: Vec<u8>,
+}
+
+impl CmMessage {
+    fn new(kind: MsgKind, qpn: u32, ip: Ipv4Addr, psn: u32, private_data: &[u8]) -> Self {
+        Self {
+            kind,
+            qpn,
+            ip,
+            psn,
+            private_data: private_data.to_vec(),
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        bytes[0] = self.kind as u8;
+        bytes[1..5].copy_from_slice(&self.qpn.to_be_bytes());
+        bytes[5..9].copy_from_slice(&self.ip.octets());
+        bytes[9..13].copy_from_slice(&self.psn.to_be_bytes());
+        bytes[13] = u8::try_from(self.private_data.len())
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mut buf = Vec::with_capacity(HEADER_SIZE + self.private_data.len());
+        buf.extend_from_slice(&bytes);
+        buf.extend_from_slice(&self.private_data);
+        writer.write_all(&buf)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        reader.read_exact(&mut bytes)?;
+        let kind = MsgKind::from_u8(bytes[0]).ok_or(io::Error::from(io::ErrorKind::InvalidData))?;
+        let len = usize::from(bytes[13]);
+        if len > MAX_PRIVATE_DATA {
+            return Err(io::Error::from(io::ErrorKind::InvalidData));
+        }
+        let mut private_data = vec![0; len];
+        reader.read_exact(&mut private_data)?;
+        Ok(Self {
+            kind,
+            qpn: u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+            ip: Ipv4Addr::new(bytes[5], bytes[6], bytes[7], bytes[8]),
+            psn: u32::from_be_bytes([bytes[9], bytes[10], bytes[11], bytes[12]]),
+            private_data,
+        })
+    }
+}
+
+fn check_private_data(private_data: &[u8]) -> Result<(), CmError> {
+    if private_data.len() > MAX_PRIVATE_DATA {
+        return Err(CmError::PrivateDataTooLong(private_data.len()));
+    }
+    Ok(())
+}
+
+fn random_psn() -> u32 {
+    rand::thread_rng().gen::<u32>() & PSN_MASK
+}
+
+fn init_attr(access_flags: u32) -> IbvQpAttr {
+    let attr = ibv_qp_attr {
+        qp_state: ibv_qp_state::IBV_QPS_INIT,
+        pkey_index: 0,
+        port_num: 1,
+        qp_access_flags: access_flags,
+        ..Default::default()
+    };
+    let mask = ibv_qp_attr_mask::IBV_QP_STATE
+        | ibv_qp_attr_mask::IBV_QP_PKEY_INDEX
+        | ibv_qp_attr_mask::IBV_QP_PORT
+        | ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS;
+    IbvQpAttr::new(attr, mask.0)
+}
+
+fn rtr_attr(remote: &CmMessage, path_mtu: ibv_mtu) -> IbvQpAttr {
+    let attr = ibv_qp_attr {
+        qp_state: ibv_qp_state::IBV_QPS_RTR,
+        path_mtu,
+        dest_qp_num: remote.qpn,
+        rq_psn: remote.psn,
+        max_dest_rd_atomic: 1,
+        min_rnr_timer: DEFAULT_MIN_RNR_TIMER,
+        ah_attr: ibv_ah_attr {
+            grh: ibv_global_route {
+                dgid: ibv_gid {
+                    raw: remote.ip.to_ipv6_mapped().octets(),
+                },
+                ..Default::default()
+            },
+            is_global: 1,
+            port_num: 1,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mask = ibv_qp_attr_mask::IBV_QP_STATE
+        | ibv_qp_attr_mask::IBV_QP_AV
+        | ibv_qp_attr_mask::IBV_QP_PATH_MTU
+        | ibv_qp_attr_mask::IBV_QP_DEST_QPN
+        | ibv_qp_attr_mask::IBV_QP_RQ_PSN
+        | ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC
+        | ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER;
+    IbvQpAttr::new(attr, mask.0)
+}
+
+fn rts_attr(psn: u32) -> IbvQpAttr {
+    let attr = ibv_qp_attr {
+        qp_state: ibv_qp_state::IBV_QPS_RTS,
+        sq_psn: psn,
+        timeout: DEFAULT_TIMEOUT,
+        retry_cnt: DEFAULT_RETRY_CNT,
+        rnr_retry: DEFAULT_RNR_RETRY,
+        max_rd_atomic: 1,
+        ..Default::default()
+    };
+    let mask = ibv_qp_attr_mask::IBV_QP_STATE
+        | ibv_qp_attr_mask::IBV_QP_SQ_PSN
+        | ibv_qp_attr_mask::IBV_QP_TIMEOUT
+        | ibv_qp_attr_mask::IBV_QP_RETRY_CNT
+        | ibv_qp_attr_mask::IBV_QP_RNR_RETRY
+        | ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC;
+    IbvQpAttr::new(attr, mask.0)
+}
+
+fn error_attr() -> IbvQpAttr {
+    let attr = ibv_qp_attr {
+        qp_state: ibv_qp_state::IBV_QPS_ERR,
+        ..Default::default()
+    };
+    IbvQpAttr::new(attr, ibv_qp_attr_mask::IBV_QP_STATE.0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, thread};
+
+    use ibverbs_sys::ibv_qp_init_attr;
+    use ipnetwork::Ipv4Network;
+
+    use crate::net::config::MacAddress;
+
+    use super::*;
+
+    /// Attributes of a QP set through `update_qp`
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+    struct FakeQp {
+        state: ibv_qp_state::Type,
+        dest_qpn: u32,
+        dest_ip: Option<Ipv4Addr>,
+        rq_psn: u32,
+        sq_psn: u32,
+    }
+
+    /// Device recording the QP transitions driven by the connection manager
+    struct FakeDevice {
+        ip: Ipv4Addr,
+        next_qpn: u32,
+        qps: HashMap<u32, FakeQp>,
+    }
+
+    impl FakeDevice {
+        fn new(ip: Ipv4Addr, first_qpn: u32) -> Self {
+            Self {
+                ip,
+                next_qpn: first_qpn,
+                qps: HashMap::new(),
+            }
+        }
+    }
+
+    impl CmDevice for FakeDevice {
+        fn create_qp(&mut self, _pd_handle: u32, _attr: IbvQpInitAttr) -> io::Result<u32> {
+            let qpn = self.next_qpn;
+            self.next_qpn += 1;
+            let _prev = self.qps.insert(qpn, FakeQp::default());
+            Ok(qpn)
+        }
+        fn update_qp(&mut self, qpn: u32, attr: IbvQpAttr) -> io::Result<()> {
+            let qp = self
+                .qps
+                .get_mut(&qpn)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+            qp.state = attr.qp_state().unwrap_or(qp.state);
+            qp.dest_qpn = attr.dest_qp_num().unwrap_or(qp.dest_qpn);
+            qp.dest_ip = attr.dest_qp_ip().or(qp.dest_ip);
+            qp.rq_psn = attr.rq_psn().unwrap_or(qp.rq_psn);
+            qp.sq_psn = attr.sq_psn().unwrap_or(qp.sq_psn);
+            Ok(())
+        }
+        fn destroy_qp(&mut self, qpn: u32) {
+            let _ignore = self.qps.remove(&qpn);
+        }
+        fn network_config(&self) -> NetworkConfig {
+            NetworkConfig {
+                ip: Ipv4Network::new(self.ip, 24).unwrap(),
+                gateway: Ipv4Addr::new(10, 0, 0, 1).into(),
+                mac: MacAddress([0; 6]),
+            }
+        }
+    }
+
+    fn qp_params() -> QpParams {
+        QpParams {
+            pd_handle: 0,
+            init_attr: IbvQpInitAttr::new(ibv_qp_init_attr::default()),
+            access_flags: 0,
+            path_mtu: ibverbs_sys::IBV_MTU_4096,
+        }
+    }
+
+    #[test]
+    fn connect_and_accept_exchange_qp_params() {
+        let (server_ip, client_ip) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3));
+        let listener = CmListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut dev = FakeDevice::new(server_ip, 0x100);
+            let req = listener.get_request().unwrap();
+            assert_eq!(req.private_data(), b"hello");
+            let conn = req.accept(&mut dev, qp_params(), b"world").unwrap();
+            let qpn = conn.qpn();
+            conn.wait_disconnect(&mut dev).unwrap();
+            (dev, qpn)
+        });
+
+        let mut dev = FakeDevice::new(client_ip, 0x200);
+        let conn = connect(&mut dev, addr, qp_params(), b"hello").unwrap();
+        assert_eq!(conn.remote_private_data(), b"world");
+        // the card addresses are advertised, not the address of the handshake connection
+        assert_eq!(conn.remote_ip(), server_ip);
+        let qpn = conn.qpn();
+        let client_qp = dev.qps[&qpn];
+        assert_eq!(client_qp.state, ibv_qp_state::IBV_QPS_RTS);
+        conn.disconnect(&mut dev).unwrap();
+
+        let (server_dev, server_qpn) = server.join().unwrap();
+        let server_qp = server_dev.qps[&server_qpn];
+        assert_eq!(client_qp.dest_qpn, server_qpn);
+        assert_eq!(client_qp.dest_ip, Some(server_ip));
+        assert_eq!(server_qp.dest_qpn, qpn);
+        assert_eq!(server_qp.dest_ip, Some(client_ip));
+        assert_eq!(client_qp.rq_psn, server_qp.sq_psn);
+        assert_eq!(server_qp.rq_psn, client_qp.sq_psn);
+        assert_eq!(server_qp.state, ibv_qp_state::IBV_QPS_ERR);
+        assert_eq!(dev.qps[&qpn].state, ibv_qp_state::IBV_QPS_ERR);
+    }
+
+    #[test]
+    fn rejected_connect_destroys_qp() {
+        let listener = CmListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            listener.get_request().unwrap().reject(b"busy").unwrap();
+        });
+
+        let mut dev = FakeDevice::new(Ipv4Addr::new(10, 0, 0, 3), 0x200);
+        let result = connect(&mut dev, addr, qp_params(), &[]);
+        assert!(matches!(result, Err(CmError::Rejected(data)) if data == b"busy"));
+        assert!(dev.qps.is_empty());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        let msg = CmMessage::new(
+            MsgKind::Reply,
+            0x1234,
+            Ipv4Addr::new(17, 34, 51, 10),
+            0xab_cdef,
+            b"rkey",
+        );
+        let mut buf = Vec::new();
+        msg.write_to(&mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_SIZE + 4);
+        assert_eq!(CmMessage::read_from(&mut buf.as_slice()).unwrap(), msg);
+
+        buf[0] = 0;
+        assert!(CmMessage::read_from(&mut buf.as_slice()).is_err());
+    }
+}
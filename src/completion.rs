@@ -1,12 +1,20 @@
-use std::{collections::VecDeque, iter, ops::ControlFlow, sync::Arc};
+use std::{collections::VecDeque, io, iter, mem, ops::ControlFlow, sync::Arc};
 
 use bitvec::vec::BitVec;
 use parking_lot::Mutex;
+use tracing::{error, warn};
 
 use crate::{
     ack_responder::AckResponse,
-    constants::MAX_CQ_CNT,
-    qp::QueuePairAttrTable,
+    async_event::AsyncEvent,
+    constants::{MAX_CQE, MAX_CQ_CNT},
+    device_protocol::WorkReqOpCode,
+    mem::staging::ReadScatter,
+    mw::MwTable,
+    protocol_impl::device::irq::EventFd,
+    qp::{QpState, QueuePairAttrTable},
+    send::SendWr,
+    srq::SrqTask,
     utils::Msn,
     utils::{Psn, QpTable},
 };
@@ -44,9 +52,49 @@ impl CompletionQueueRegistry {
 #[derive(Debug)]
 #[allow(variant_size_differences)]
 pub(crate) enum CompletionTask {
-    Register { qpn: u32, event: Event },
-    AckSend { qpn: u32, base_psn: Psn },
-    AckRecv { qpn: u32, base_psn: Psn },
+    Register {
+        qpn: u32,
+        event: Event,
+    },
+    AckSend {
+        qpn: u32,
+        base_psn: Psn,
+    },
+    AckRecv {
+        qpn: u32,
+        base_psn: Psn,
+    },
+    /// An error occurred on the QP, moves it to the error state and completes the oldest
+    /// outstanding send WR with `status`
+    Error {
+        qpn: u32,
+        status: CompletionStatus,
+    },
+    /// Flushes all outstanding WRs and resets the tracker, used on QP reset and teardown
+    Flush {
+        qpn: u32,
+    },
+    /// A send WR executed in software completed, e.g. an atomic operation, or a send WR
+    /// failed before it was issued
+    ///
+    /// The completion is reported after the WRs issued before it, an error completion moves
+    /// the QP to the error state.
+    Complete {
+        qpn: u32,
+        completion: Completion,
+    },
+    /// An RDMA Read was issued, a read landing in a staging buffer is scattered to its
+    /// local buffers before it completes
+    ReadIssued {
+        qpn: u32,
+        scatter: Option<ReadScatter>,
+    },
+    /// Receive WRs taken from an SRQ that the peer may have consumed before the QP
+    /// entered the error state, they complete with `WrFlushError`
+    FlushRecv {
+        qpn: u32,
+        wr_ids: Vec<u64>,
+    },
 }
 
 pub(crate) struct CompletionWorker {
@@ -55,6 +103,9 @@ pub(crate) struct CompletionWorker {
     cq_table: CompletionQueueTable,
     qp_table: QueuePairAttrTable,
     ack_resp_tx: flume::Sender<AckResponse>,
+    srq_tx: flume::Sender<SrqTask>,
+    /// Memory windows invalidated by incoming SEND with invalidate
+    mw_table: MwTable,
 }
 
 impl CompletionWorker {
@@ -63,6 +114,8 @@ impl CompletionWorker {
         cq_table: CompletionQueueTable,
         qp_table: QueuePairAttrTable,
         ack_resp_tx: flume::Sender<AckResponse>,
+        srq_tx: flume::Sender<SrqTask>,
+        mw_table: MwTable,
     ) -> Self {
         Self {
             completion_rx,
@@ -70,6 +123,8 @@ impl CompletionWorker {
             cq_table,
             qp_table,
             ack_resp_tx,
+            srq_tx,
+            mw_table,
         }
     }
 
@@ -85,7 +140,12 @@ impl CompletionWorker {
             let qpn = match x {
                 CompletionTask::Register { qpn, .. }
                 | CompletionTask::AckSend { qpn, .. }
-                | CompletionTask::AckRecv { qpn, .. } => qpn,
+                | CompletionTask::AckRecv { qpn, .. }
+                | CompletionTask::Error { qpn, .. }
+                | CompletionTask::Flush { qpn }
+                | CompletionTask::Complete { qpn, .. }
+                | CompletionTask::ReadIssued { qpn, .. }
+                | CompletionTask::FlushRecv { qpn, .. } => qpn,
             };
             let Some(tracker) = self.tracker_table.get_qp_mut(qpn) else {
                 continue;
@@ -93,22 +153,80 @@ impl CompletionWorker {
             let Some(qp_attr) = self.qp_table.get(qpn) else {
                 continue;
             };
+            let send_cq = qp_attr.send_cq.and_then(|h| self.cq_table.get_cq(h));
+            let recv_cq = qp_attr.recv_cq.and_then(|h| self.cq_table.get_cq(h));
+            let was_error = tracker.is_error;
             match x {
                 CompletionTask::Register { event, .. } => {
                     tracker.append(event);
+                    if tracker.is_error {
+                        tracker.flush(CompletionStatus::WrFlushError, send_cq, recv_cq);
+                    }
                 }
+                CompletionTask::Error { status, .. } => {
+                    tracker.set_error(status, send_cq, recv_cq);
+                    self.qp_table.set_state(qpn, QpState::Err);
+                }
+                CompletionTask::Flush { .. } => {
+                    tracker.flush(CompletionStatus::WrFlushError, send_cq, recv_cq);
+                    *tracker = QueuePairMessageTracker {
+                        srq_consumed: mem::take(&mut tracker.srq_consumed),
+                        srq_reclaim: tracker.srq_reclaim,
+                        ..Default::default()
+                    };
+                }
+                CompletionTask::Complete { completion, .. } => {
+                    tracker.complete_software(completion, send_cq, recv_cq);
+                }
+                CompletionTask::FlushRecv { wr_ids, .. } => {
+                    if let Some(cq) = recv_cq {
+                        for wr_id in wr_ids {
+                            cq.push_back(Completion::Error {
+                                wr_id,
+                                status: CompletionStatus::WrFlushError,
+                                opcode: ibverbs_sys::ibv_wc_opcode::IBV_WC_RECV,
+                            });
+                        }
+                    }
+                }
+                // no more successful completions after the QP entered the error state
+                CompletionTask::AckSend { .. }
+                | CompletionTask::AckRecv { .. }
+                | CompletionTask::ReadIssued { .. }
+                    if tracker.is_error => {}
                 CompletionTask::AckSend { base_psn, .. } => {
-                    if let Some(send_cq) = qp_attr.send_cq.and_then(|h| self.cq_table.get_cq(h)) {
-                        tracker.ack_send(Some(base_psn), send_cq);
+                    if let Some(send_cq) = send_cq {
+                        tracker.ack_send(Some(base_psn), send_cq, recv_cq);
                     }
                 }
+                CompletionTask::ReadIssued { scatter, .. } => {
+                    tracker.read_scatters.push_back(scatter);
+                }
                 CompletionTask::AckRecv { base_psn, .. } => {
-                    let send_cq = qp_attr.send_cq.and_then(|h| self.cq_table.get_cq(h));
-                    if let Some(recv_cq) = qp_attr.recv_cq.and_then(|h| self.cq_table.get_cq(h)) {
-                        tracker.ack_recv(base_psn, recv_cq, send_cq, qpn, &self.ack_resp_tx);
+                    if let Some(recv_cq) = recv_cq {
+                        if let Err(status) = tracker.ack_recv(
+                            base_psn,
+                            recv_cq,
+                            send_cq,
+                            qpn,
+                            &self.ack_resp_tx,
+                            &self.mw_table,
+                        ) {
+                            tracker.set_error(status, send_cq, Some(recv_cq));
+                            self.qp_table.set_state(qpn, QpState::Err);
+                        }
                     }
                 }
             }
+            if !was_error && tracker.is_error {
+                self.qp_table.set_state(qpn, QpState::Err);
+            }
+            for srq in tracker.srq_consumed.drain(..) {
+                let _ignore = self.srq_tx.send(SrqTask::Consumed { srq, qpn });
+            }
+            if mem::take(&mut tracker.srq_reclaim) {
+                let _ignore = self.srq_tx.send(SrqTask::Reclaim { qpn });
+            }
         }
     }
 }
@@ -129,7 +247,22 @@ struct QueuePairMessageTracker {
     send: MessageTracker<SendEvent>,
     recv: MessageTracker<RecvEvent>,
     read_resp_queue: VecDeque<RecvEvent>,
+    /// Staging buffers of the issued reads to scatter, one entry per read in issue order
+    read_scatters: VecDeque<Option<ReadScatter>>,
     post_recv_queue: VecDeque<PostRecvEvent>,
+    /// Whether the QP is in the error state
+    is_error: bool,
+    /// SRQs of the posted receive WRs consumed since the last task
+    srq_consumed: Vec<u32>,
+    /// Whether WRs taken from an SRQ were dropped unconsumed since the last task
+    srq_reclaim: bool,
+    /// Completions of send WRs executed in software, each with the number of send events
+    /// registered before it
+    software: VecDeque<(u64, Completion)>,
+    /// Number of send events registered
+    send_registered: u64,
+    /// Number of send events completed
+    send_completed: u64,
 }
 
 impl QueuePairMessageTracker {
@@ -143,31 +276,169 @@ impl QueuePairMessageTracker {
             send,
             recv,
             read_resp_queue,
+            read_scatters: VecDeque::new(),
             post_recv_queue,
+            is_error: false,
+            srq_consumed: Vec::new(),
+            srq_reclaim: false,
+            software: VecDeque::new(),
+            send_registered: 0,
+            send_completed: 0,
         }
     }
 
+    /// Moves the tracker to the error state and completes all outstanding WRs
+    fn set_error(
+        &mut self,
+        status: CompletionStatus,
+        send_cq: Option<&CompletionQueue>,
+        recv_cq: Option<&CompletionQueue>,
+    ) {
+        if self.is_error {
+            return;
+        }
+        self.is_error = true;
+        self.flush(status, send_cq, recv_cq);
+    }
+
+    /// Completes all outstanding WRs with error.
+    ///
+    /// The oldest send WR completes with `status`, all others complete with `WrFlushError`.
+    fn flush(
+        &mut self,
+        status: CompletionStatus,
+        send_cq: Option<&CompletionQueue>,
+        recv_cq: Option<&CompletionQueue>,
+    ) {
+        let mut status = status;
+        for event in self.send.drain() {
+            if let Some(cq) = send_cq {
+                cq.push_back(Completion::Error {
+                    wr_id: event.wr_id,
+                    status,
+                    opcode: event.op.wc_opcode(),
+                });
+            }
+            status = CompletionStatus::WrFlushError;
+        }
+        self.send_completed = self.send_registered;
+        for (_, completion) in self.software.drain(..) {
+            if let Some(cq) = send_cq {
+                cq.push_back(completion.into_flushed());
+            }
+        }
+        for event in self.post_recv_queue.drain(..) {
+            // WRs taken from an SRQ are not flushed here, the SRQ worker returns those the
+            // peer never consumed to the SRQ and flushes the others
+            if event.srq.is_some() {
+                self.srq_reclaim = true;
+                continue;
+            }
+            if let Some(cq) = recv_cq {
+                cq.push_back(Completion::Error {
+                    wr_id: event.wr_id,
+                    status: CompletionStatus::WrFlushError,
+                    opcode: ibverbs_sys::ibv_wc_opcode::IBV_WC_RECV,
+                });
+            }
+        }
+        let _drop = self.recv.drain();
+        self.read_resp_queue.clear();
+        self.read_scatters.clear();
+    }
+
     fn append(&mut self, event: Event) {
         match event {
-            Event::Send(x) => self.send.append(x),
-            Event::Recv(x) => self.recv.append(x),
+            Event::Send(x) => {
+                if self.send.append(x) {
+                    self.send_registered = self.send_registered.wrapping_add(1);
+                }
+            }
+            Event::Recv(x) => {
+                let _inserted = self.recv.append(x);
+            }
             Event::PostRecv(x) => {
                 self.post_recv_queue.push_back(x);
             }
         }
     }
 
-    fn ack_send(&mut self, psn: Option<Psn>, send_cq: &CompletionQueue) {
+    /// Queues the completion of a send WR executed in software behind the send WRs issued
+    /// before it
+    fn complete_software(
+        &mut self,
+        completion: Completion,
+        send_cq: Option<&CompletionQueue>,
+        recv_cq: Option<&CompletionQueue>,
+    ) {
+        if self.is_error {
+            if let Some(cq) = send_cq {
+                cq.push_back(completion.into_flushed());
+            }
+            return;
+        }
+        self.software.push_back((self.send_registered, completion));
+        if let Some(cq) = send_cq {
+            self.ack_send(None, cq, recv_cq);
+        }
+    }
+
+    /// Reports the software completion at the head of the send queue, returns `false` if
+    /// the head is a WR issued to the card
+    fn complete_software_head(
+        &mut self,
+        send_cq: &CompletionQueue,
+        recv_cq: Option<&CompletionQueue>,
+    ) -> bool {
+        let Some(&(registered, completion)) = self.software.front() else {
+            return false;
+        };
+        if self.send_completed != registered {
+            return false;
+        }
+        let _completion = self.software.pop_front();
+        send_cq.push_back(completion);
+        if completion.status() != CompletionStatus::Success {
+            // the WRs after the failed one are flushed
+            self.set_error(CompletionStatus::WrFlushError, Some(send_cq), recv_cq);
+        }
+        true
+    }
+
+    fn ack_send(
+        &mut self,
+        psn: Option<Psn>,
+        send_cq: &CompletionQueue,
+        recv_cq: Option<&CompletionQueue>,
+    ) {
         if let Some(psn) = psn {
             self.send.ack(psn);
         }
-        while let Some(event) = self.send.peek() {
+        loop {
+            if self.complete_software_head(send_cq, recv_cq) {
+                continue;
+            }
+            let Some(event) = self.send.peek() else {
+                break;
+            };
             match event.op {
-                SendEventOp::WriteSignaled | SendEventOp::SendSignaled => {
+                SendEventOp::WriteSignaled
+                | SendEventOp::SendSignaled
+                | SendEventOp::CompareSwapSignaled
+                | SendEventOp::FetchAddSignaled
+                | SendEventOp::BindMwSignaled
+                | SendEventOp::LocalInvSignaled => {
                     let x = self.send.pop().unwrap_or_else(|| unreachable!());
+                    self.send_completed = self.send_completed.wrapping_add(1);
                     let completion = match x.op {
                         SendEventOp::WriteSignaled => Completion::RdmaWrite { wr_id: x.wr_id },
                         SendEventOp::SendSignaled => Completion::Send { wr_id: x.wr_id },
+                        SendEventOp::CompareSwapSignaled => {
+                            Completion::CompareSwap { wr_id: x.wr_id }
+                        }
+                        SendEventOp::FetchAddSignaled => Completion::FetchAdd { wr_id: x.wr_id },
+                        SendEventOp::BindMwSignaled => Completion::BindMw { wr_id: x.wr_id },
+                        SendEventOp::LocalInvSignaled => Completion::LocalInv { wr_id: x.wr_id },
                         SendEventOp::ReadSignaled => unreachable!(),
                     };
                     send_cq.push_back(completion);
@@ -175,6 +446,7 @@ impl QueuePairMessageTracker {
                 SendEventOp::ReadSignaled => {
                     if let Some(recv_event) = self.read_resp_queue.pop_front() {
                         let x = self.send.pop().unwrap_or_else(|| unreachable!());
+                        self.send_completed = self.send_completed.wrapping_add(1);
                         let completion = Completion::RdmaRead { wr_id: x.wr_id };
                         send_cq.push_back(completion);
                     } else {
@@ -185,6 +457,20 @@ impl QueuePairMessageTracker {
         }
     }
 
+    /// Takes the receive WR consumed by an incoming SEND, WRs are consumed in the
+    /// order they were posted
+    ///
+    /// A SEND may find no WR, e.g. one delivered twice, or one landing on a WR that was
+    /// flushed or returned to its SRQ. It is dropped without a completion.
+    fn pop_post_recv(&mut self, qpn: u32) -> Option<PostRecvEvent> {
+        let Some(x) = self.post_recv_queue.pop_front() else {
+            warn!("dropping send to qp {qpn} without a posted recv wr");
+            return None;
+        };
+        self.srq_consumed.extend(x.srq);
+        Some(x)
+    }
+
     fn ack_recv(
         &mut self,
         psn: Psn,
@@ -192,41 +478,72 @@ impl QueuePairMessageTracker {
         send_cq: Option<&CompletionQueue>,
         qpn: u32,
         ack_resp_tx: &flume::Sender<AckResponse>,
-    ) {
+        mw_table: &MwTable,
+    ) -> Result<(), CompletionStatus> {
         self.recv.ack(psn);
         while let Some(event) = self.recv.pop() {
             match event.op {
                 RecvEventOp::WriteWithImm { imm } => {
-                    let completion = Completion::RecvRdmaWithImm { imm };
+                    let completion = Completion::RecvRdmaWithImm {
+                        imm,
+                        solicited: event.solicited,
+                    };
                     recv_cq.push_back(completion);
                 }
-                RecvEventOp::Recv => {
-                    let x = self
-                        .post_recv_queue
-                        .pop_back()
-                        .unwrap_or_else(|| unreachable!("no posted recv wr"));
+                RecvEventOp::Recv { len } => {
+                    let Some(x) = self.pop_post_recv(qpn) else {
+                        continue;
+                    };
+                    x.check_len(len, recv_cq)?;
                     let completion = Completion::Recv {
                         wr_id: x.wr_id,
                         imm: None,
+                        solicited: event.solicited,
                     };
                     recv_cq.push_back(completion);
                 }
-                RecvEventOp::RecvWithImm { imm } => {
-                    let x = self
-                        .post_recv_queue
-                        .pop_back()
-                        .unwrap_or_else(|| unreachable!("no posted recv wr"));
+                RecvEventOp::RecvWithImm { imm, len } => {
+                    let Some(x) = self.pop_post_recv(qpn) else {
+                        continue;
+                    };
+                    x.check_len(len, recv_cq)?;
                     let completion = Completion::Recv {
                         wr_id: x.wr_id,
                         imm: Some(imm),
+                        solicited: event.solicited,
+                    };
+                    recv_cq.push_back(completion);
+                }
+                RecvEventOp::RecvWithInv { rkey, len } => {
+                    let Some(x) = self.pop_post_recv(qpn) else {
+                        continue;
+                    };
+                    x.check_len(len, recv_cq)?;
+                    if let Err(err) = mw_table.invalidate(rkey, Some(qpn)) {
+                        error!("failed to invalidate rkey {rkey:#x}: {err}");
+                        recv_cq.push_back(Completion::Error {
+                            wr_id: x.wr_id,
+                            status: CompletionStatus::RemoteInvalidRequest,
+                            opcode: ibverbs_sys::ibv_wc_opcode::IBV_WC_RECV,
+                        });
+                        return Err(CompletionStatus::WrFlushError);
+                    }
+                    let completion = Completion::RecvWithInv {
+                        wr_id: x.wr_id,
+                        rkey,
+                        solicited: event.solicited,
                     };
                     recv_cq.push_back(completion);
                 }
                 RecvEventOp::ReadResp => {
+                    // the data reaches the local buffers before the read completes
+                    if let Some(Some(scatter)) = self.read_scatters.pop_front() {
+                        scatter.scatter();
+                    }
                     self.read_resp_queue.push_back(event);
                     // check if the read  completion could be updated
                     if let Some(cq) = send_cq {
-                        self.ack_send(None, cq);
+                        self.ack_send(None, cq, Some(recv_cq));
                     }
                 }
                 RecvEventOp::WriteAckReq => {
@@ -238,6 +555,8 @@ impl QueuePairMessageTracker {
                 }
             }
         }
+
+        Ok(())
     }
 }
 
@@ -257,7 +576,8 @@ impl<E> Default for MessageTracker<E> {
 }
 
 impl<E: EventMeta> MessageTracker<E> {
-    fn append(&mut self, event: E) {
+    /// Inserts the event in MSN order, returns `false` if an event with the same MSN exists
+    fn append(&mut self, event: E) -> bool {
         let pos = self
             .inner
             .iter()
@@ -271,7 +591,9 @@ impl<E: EventMeta> MessageTracker<E> {
             .is_none_or(|e| e.meta().msn != event.meta().msn)
         {
             self.inner.insert(index, event);
+            return true;
         }
+        false
     }
 
     fn ack(&mut self, base_psn: Psn) {
@@ -291,6 +613,11 @@ impl<E: EventMeta> MessageTracker<E> {
             None
         }
     }
+
+    /// Removes all events regardless of whether they are acknowledged
+    fn drain(&mut self) -> std::collections::vec_deque::Drain<'_, E> {
+        self.inner.drain(..)
+    }
 }
 
 trait EventMeta {
@@ -329,17 +656,63 @@ pub(crate) enum SendEventOp {
     WriteSignaled,
     SendSignaled,
     ReadSignaled,
+    CompareSwapSignaled,
+    FetchAddSignaled,
+    BindMwSignaled,
+    LocalInvSignaled,
+}
+
+impl SendEventOp {
+    pub(crate) fn from_opcode(opcode: WorkReqOpCode) -> Option<Self> {
+        #[allow(clippy::wildcard_enum_match_arm)]
+        let op = match opcode {
+            WorkReqOpCode::RdmaWrite | WorkReqOpCode::RdmaWriteWithImm => Self::WriteSignaled,
+            WorkReqOpCode::Send | WorkReqOpCode::SendWithImm | WorkReqOpCode::SendWithInv => {
+                Self::SendSignaled
+            }
+            WorkReqOpCode::RdmaRead => Self::ReadSignaled,
+            WorkReqOpCode::AtomicCmpAndSwp => Self::CompareSwapSignaled,
+            WorkReqOpCode::AtomicFetchAndAdd => Self::FetchAddSignaled,
+            WorkReqOpCode::BindMw => Self::BindMwSignaled,
+            WorkReqOpCode::LocalInv => Self::LocalInvSignaled,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    fn wc_opcode(self) -> u32 {
+        match self {
+            SendEventOp::WriteSignaled => ibverbs_sys::ibv_wc_opcode::IBV_WC_RDMA_WRITE,
+            SendEventOp::SendSignaled => ibverbs_sys::ibv_wc_opcode::IBV_WC_SEND,
+            SendEventOp::ReadSignaled => ibverbs_sys::ibv_wc_opcode::IBV_WC_RDMA_READ,
+            SendEventOp::CompareSwapSignaled => ibverbs_sys::ibv_wc_opcode::IBV_WC_COMP_SWAP,
+            SendEventOp::FetchAddSignaled => ibverbs_sys::ibv_wc_opcode::IBV_WC_FETCH_ADD,
+            SendEventOp::BindMwSignaled => ibverbs_sys::ibv_wc_opcode::IBV_WC_BIND_MW,
+            SendEventOp::LocalInvSignaled => ibverbs_sys::ibv_wc_opcode::IBV_WC_LOCAL_INV,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct RecvEvent {
     op: RecvEventOp,
     meta: MessageMeta,
+    /// The solicited event bit of the last packet is set
+    solicited: bool,
 }
 
 impl RecvEvent {
     pub(crate) fn new(op: RecvEventOp, meta: MessageMeta) -> Self {
-        Self { op, meta }
+        Self {
+            op,
+            meta,
+            solicited: false,
+        }
+    }
+
+    pub(crate) fn with_solicited(mut self, solicited: bool) -> Self {
+        self.solicited = solicited;
+        self
     }
 }
 
@@ -351,21 +724,62 @@ impl EventMeta for RecvEvent {
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum RecvEventOp {
-    WriteWithImm { imm: u32 },
+    WriteWithImm {
+        imm: u32,
+    },
     WriteAckReq,
-    Recv,
-    RecvWithImm { imm: u32 },
+    Recv {
+        len: u32,
+    },
+    RecvWithImm {
+        imm: u32,
+        len: u32,
+    },
+    /// A SEND with invalidate, `rkey` is the memory window to invalidate
+    RecvWithInv {
+        rkey: u32,
+        len: u32,
+    },
     ReadResp,
 }
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct PostRecvEvent {
     wr_id: u64,
+    /// Length of the posted receive buffer
+    len: u32,
+    /// The SRQ the WR was taken from
+    srq: Option<u32>,
 }
 
 impl PostRecvEvent {
-    pub(crate) fn new(wr_id: u64) -> Self {
-        Self { wr_id }
+    pub(crate) fn new(wr_id: u64, len: u32) -> Self {
+        Self {
+            wr_id,
+            len,
+            srq: None,
+        }
+    }
+
+    pub(crate) fn new_srq(wr_id: u64, len: u32, srq: u32) -> Self {
+        Self {
+            wr_id,
+            len,
+            srq: Some(srq),
+        }
+    }
+
+    /// Checks if the incoming message fits into the posted receive buffer
+    fn check_len(&self, msg_len: u32, recv_cq: &CompletionQueue) -> Result<(), CompletionStatus> {
+        if msg_len <= self.len {
+            return Ok(());
+        }
+        recv_cq.push_back(Completion::Error {
+            wr_id: self.wr_id,
+            status: CompletionStatus::LocalLengthError,
+            opcode: ibverbs_sys::ibv_wc_opcode::IBV_WC_RECV,
+        });
+        Err(CompletionStatus::WrFlushError)
     }
 }
 
@@ -386,9 +800,9 @@ pub(crate) struct CompletionQueueTable {
 }
 
 impl CompletionQueueTable {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(async_event_tx: &flume::Sender<AsyncEvent>) -> Self {
         Self {
-            inner: iter::repeat_with(CompletionQueue::default)
+            inner: iter::repeat_with(|| CompletionQueue::new(async_event_tx.clone()))
                 .take(MAX_CQ_CNT)
                 .collect(),
         }
@@ -407,42 +821,256 @@ impl CompletionQueueTable {
 
 #[derive(Default)]
 pub(crate) struct CompletionQueue {
-    inner: Mutex<VecDeque<Completion>>,
+    inner: Mutex<CqEntries>,
+    notifier: Mutex<CqNotifier>,
+    async_event_tx: Option<flume::Sender<AsyncEvent>>,
+}
+
+/// Completions of a CQ, bounded by the depth requested at creation
+struct CqEntries {
+    queue: VecDeque<Completion>,
+    capacity: usize,
+    /// The CQ overflowed, no more completions are accepted
+    is_error: bool,
+}
+
+impl Default for CqEntries {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            capacity: MAX_CQE,
+            is_error: false,
+        }
+    }
 }
 
 impl CompletionQueue {
+    fn new(async_event_tx: flume::Sender<AsyncEvent>) -> Self {
+        Self {
+            async_event_tx: Some(async_event_tx),
+            ..Default::default()
+        }
+    }
+
+    /// Prepares the CQ for a new owner with room for `cqe` completions
+    pub(crate) fn init(&self, cqe: usize) -> io::Result<()> {
+        if cqe == 0 || cqe > MAX_CQE {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        *self.inner.lock() = CqEntries {
+            queue: VecDeque::with_capacity(cqe),
+            capacity: cqe,
+            is_error: false,
+        };
+        *self.notifier.lock() = CqNotifier::default();
+        Ok(())
+    }
+
+    /// Changes the depth of the CQ, completions already in the CQ are preserved
+    pub(crate) fn resize(&self, cqe: usize) -> io::Result<()> {
+        let mut entries = self.inner.lock();
+        if cqe == 0 || cqe > MAX_CQE || cqe < entries.queue.len() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        entries.capacity = cqe;
+        entries.queue.shrink_to(cqe);
+        let additional = cqe.saturating_sub(entries.queue.len());
+        entries.queue.reserve_exact(additional);
+        Ok(())
+    }
+
     pub(crate) fn push_back(&self, event: Completion) {
-        let mut queue = self.inner.lock();
-        queue.push_back(event);
+        let mut entries = self.inner.lock();
+        if entries.is_error {
+            return;
+        }
+        if entries.queue.len() >= entries.capacity {
+            entries.is_error = true;
+            drop(entries);
+            error!("completion queue overflow");
+            let cq_context = self.notifier.lock().cq_context;
+            if let Some(tx) = self.async_event_tx.as_ref() {
+                let _ignore = tx.send(AsyncEvent::CqError { cq_context });
+            }
+            return;
+        }
+        entries.queue.push_back(event);
+        drop(entries);
+        self.notifier.lock().notify(&event);
+    }
+
+    /// Sets the value identifying the CQ in events, and binds the CQ to a completion
+    /// channel if any
+    ///
+    /// `cq_context` should be the address of the `ibv_cq`, which `ibv_get_cq_event` reads
+    /// back from the channel.
+    pub(crate) fn set_context(&self, cq_context: u64, channel: Option<Arc<CompChannel>>) {
+        let mut notifier = self.notifier.lock();
+        notifier.channel = channel;
+        notifier.cq_context = cq_context;
+    }
+
+    /// Arms the CQ, the next matching completion fires an event on the completion channel
+    pub(crate) fn req_notify(&self, mode: NotifyMode) -> io::Result<()> {
+        let mut notifier = self.notifier.lock();
+        if notifier.channel.is_none() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        // a pending next-completion request is not downgraded to solicited-only
+        if notifier.armed != Some(NotifyMode::NextCompletion) {
+            notifier.armed = Some(mode);
+        }
+        Ok(())
+    }
+
+    /// Clears all completions and the notification state
+    pub(crate) fn reset(&self) {
+        *self.inner.lock() = CqEntries::default();
+        *self.notifier.lock() = CqNotifier::default();
     }
 
     pub(crate) fn pop_front(&self) -> Option<Completion> {
-        let mut queue = self.inner.lock();
-        queue.pop_front()
+        let mut entries = self.inner.lock();
+        entries.queue.pop_front()
     }
 
     pub(crate) fn front(&self) -> Option<Completion> {
-        let queue = self.inner.lock();
-        queue.front().copied()
+        let entries = self.inner.lock();
+        entries.queue.front().copied()
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum Completion {
-    Send { wr_id: u64 },
-    RdmaWrite { wr_id: u64 },
-    RdmaRead { wr_id: u64 },
-    Recv { wr_id: u64, imm: Option<u32> },
-    RecvRdmaWithImm { imm: u32 },
+    Send {
+        wr_id: u64,
+    },
+    RdmaWrite {
+        wr_id: u64,
+    },
+    RdmaRead {
+        wr_id: u64,
+    },
+    CompareSwap {
+        wr_id: u64,
+    },
+    FetchAdd {
+        wr_id: u64,
+    },
+    BindMw {
+        wr_id: u64,
+    },
+    LocalInv {
+        wr_id: u64,
+    },
+    Recv {
+        wr_id: u64,
+        imm: Option<u32>,
+        solicited: bool,
+    },
+    RecvRdmaWithImm {
+        imm: u32,
+        solicited: bool,
+    },
+    /// A SEND with invalidate was received and `rkey` was invalidated
+    RecvWithInv {
+        wr_id: u64,
+        rkey: u32,
+        solicited: bool,
+    },
+    /// A WR completed with error
+    Error {
+        wr_id: u64,
+        status: CompletionStatus,
+        /// The `ibv_wc_opcode` of the WR
+        opcode: u32,
+    },
 }
 
 impl Completion {
+    pub(crate) fn status(&self) -> CompletionStatus {
+        match *self {
+            Completion::Error { status, .. } => status,
+            Completion::Send { .. }
+            | Completion::RdmaWrite { .. }
+            | Completion::RdmaRead { .. }
+            | Completion::CompareSwap { .. }
+            | Completion::FetchAdd { .. }
+            | Completion::BindMw { .. }
+            | Completion::LocalInv { .. }
+            | Completion::Recv { .. }
+            | Completion::RecvRdmaWithImm { .. }
+            | Completion::RecvWithInv { .. } => CompletionStatus::Success,
+        }
+    }
+
+    /// Returns the error completion of a send WR
+    pub(crate) fn send_error(wr: &SendWr, status: CompletionStatus) -> Option<Self> {
+        let op = SendEventOp::from_opcode(wr.opcode())?;
+        Some(Completion::Error {
+            wr_id: wr.wr_id(),
+            status,
+            opcode: op.wc_opcode(),
+        })
+    }
+
+    /// Returns the completion reported for the WR when its QP is flushed
+    fn into_flushed(self) -> Self {
+        let (wr_id, opcode) = match self {
+            Completion::Error { .. } | Completion::RecvRdmaWithImm { .. } => return self,
+            Completion::Send { wr_id } => (wr_id, ibverbs_sys::ibv_wc_opcode::IBV_WC_SEND),
+            Completion::RdmaWrite { wr_id } => {
+                (wr_id, ibverbs_sys::ibv_wc_opcode::IBV_WC_RDMA_WRITE)
+            }
+            Completion::RdmaRead { wr_id } => (wr_id, ibverbs_sys::ibv_wc_opcode::IBV_WC_RDMA_READ),
+            Completion::CompareSwap { wr_id } => {
+                (wr_id, ibverbs_sys::ibv_wc_opcode::IBV_WC_COMP_SWAP)
+            }
+            Completion::FetchAdd { wr_id } => (wr_id, ibverbs_sys::ibv_wc_opcode::IBV_WC_FETCH_ADD),
+            Completion::BindMw { wr_id } => (wr_id, ibverbs_sys::ibv_wc_opcode::IBV_WC_BIND_MW),
+            Completion::LocalInv { wr_id } => (wr_id, ibverbs_sys::ibv_wc_opcode::IBV_WC_LOCAL_INV),
+            Completion::Recv { wr_id, .. } | Completion::RecvWithInv { wr_id, .. } => {
+                (wr_id, ibverbs_sys::ibv_wc_opcode::IBV_WC_RECV)
+            }
+        };
+        Completion::Error {
+            wr_id,
+            status: CompletionStatus::WrFlushError,
+            opcode,
+        }
+    }
+
+    /// Returns `true` if the completion satisfies a solicited-only notification request
+    fn is_solicited(&self) -> bool {
+        match *self {
+            Completion::Recv { solicited, .. }
+            | Completion::RecvRdmaWithImm { solicited, .. }
+            | Completion::RecvWithInv { solicited, .. } => solicited,
+            // unsuccessful completions always generate an event
+            Completion::Error { .. } => true,
+            Completion::Send { .. }
+            | Completion::RdmaWrite { .. }
+            | Completion::RdmaRead { .. }
+            | Completion::CompareSwap { .. }
+            | Completion::FetchAdd { .. }
+            | Completion::BindMw { .. }
+            | Completion::LocalInv { .. } => false,
+        }
+    }
+
     pub(crate) fn opcode(&self) -> u32 {
         match *self {
+            Completion::Error { opcode, .. } => opcode,
             Completion::Send { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_SEND,
             Completion::RdmaWrite { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_RDMA_WRITE,
             Completion::RdmaRead { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_RDMA_READ,
-            Completion::Recv { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_RECV,
+            Completion::CompareSwap { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_COMP_SWAP,
+            Completion::FetchAdd { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_FETCH_ADD,
+            Completion::BindMw { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_BIND_MW,
+            Completion::LocalInv { .. } => ibverbs_sys::ibv_wc_opcode::IBV_WC_LOCAL_INV,
+            Completion::Recv { .. } | Completion::RecvWithInv { .. } => {
+                ibverbs_sys::ibv_wc_opcode::IBV_WC_RECV
+            }
             Completion::RecvRdmaWithImm { .. } => {
                 ibverbs_sys::ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM
             }
@@ -450,6 +1078,122 @@ impl Completion {
     }
 }
 
+/// Notification mode requested by `ibv_req_notify_cq`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NotifyMode {
+    /// Fire on the next completion
+    NextCompletion,
+    /// Fire on the next solicited or unsuccessful completion
+    SolicitedOnly,
+}
+
+/// Completion channel shared by the CQs created with the same `ibv_comp_channel`
+pub(crate) struct CompChannel {
+    /// Counts the queued events, waited on by `get_event`
+    event_fd: EventFd,
+    /// `cq_context` of the CQs that fired, in firing order
+    events: Mutex<VecDeque<u64>>,
+}
+
+impl CompChannel {
+    pub(crate) fn new() -> io::Result<Self> {
+        Ok(Self {
+            event_fd: EventFd::new()?,
+            events: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    fn push(&self, cq_context: u64) -> io::Result<()> {
+        self.events.lock().push_back(cq_context);
+        self.event_fd.signal()
+    }
+
+    /// Blocks until a CQ bound to the channel fires, returns its `cq_context`
+    pub(crate) fn get_event(&self) -> io::Result<u64> {
+        loop {
+            if let Some(cq_context) = self.events.lock().pop_front() {
+                return Ok(cq_context);
+            }
+            // signals of events already handed out only cause another check
+            let _count = self.event_fd.wait()?;
+        }
+    }
+}
+
+/// Completion event state of a CQ
+#[derive(Default)]
+struct CqNotifier {
+    /// Completion channel the CQ is bound to
+    channel: Option<Arc<CompChannel>>,
+    /// Value identifying the CQ in events
+    cq_context: u64,
+    /// The CQ is armed, cleared after an event fires
+    armed: Option<NotifyMode>,
+}
+
+impl CqNotifier {
+    fn notify(&mut self, completion: &Completion) {
+        let (Some(channel), Some(mode)) = (self.channel.as_ref(), self.armed) else {
+            return;
+        };
+        if mode == NotifyMode::SolicitedOnly && !completion.is_solicited() {
+            return;
+        }
+        self.armed = None;
+        if let Err(err) = channel.push(self.cq_context) {
+            error!("failed to signal completion channel: {err}");
+        }
+    }
+}
+
+/// Status of a work completion
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CompletionStatus {
+    #[default]
+    Success,
+    /// The incoming message exceeds the posted receive buffer
+    LocalLengthError,
+    /// The local buffer of the WR is not registered with the required access
+    LocalProtectionError,
+    /// The WR was flushed because the QP entered the error state
+    WrFlushError,
+    /// The remote QP rejected the request due to insufficient access rights
+    RemoteAccessError,
+    /// Transport retry counter exceeded while waiting for an ACK
+    RetryExceeded,
+    /// RNR retry counter exceeded while waiting for a posted receive WR
+    RnrRetryExceeded,
+    /// The remote QP rejected the request, e.g. a SEND larger than the posted receive buffer
+    RemoteInvalidRequest,
+    /// A memory window bind failed, e.g. the MR lacks the bind access or range
+    MwBindError,
+}
+
+impl CompletionStatus {
+    /// Converts to `ibv_wc_status`
+    pub(crate) fn to_ibv_wc_status(self) -> u32 {
+        match self {
+            CompletionStatus::Success => ibverbs_sys::ibv_wc_status::IBV_WC_SUCCESS,
+            CompletionStatus::LocalLengthError => ibverbs_sys::ibv_wc_status::IBV_WC_LOC_LEN_ERR,
+            CompletionStatus::LocalProtectionError => {
+                ibverbs_sys::ibv_wc_status::IBV_WC_LOC_PROT_ERR
+            }
+            CompletionStatus::WrFlushError => ibverbs_sys::ibv_wc_status::IBV_WC_WR_FLUSH_ERR,
+            CompletionStatus::RemoteAccessError => {
+                ibverbs_sys::ibv_wc_status::IBV_WC_REM_ACCESS_ERR
+            }
+            CompletionStatus::RetryExceeded => ibverbs_sys::ibv_wc_status::IBV_WC_RETRY_EXC_ERR,
+            CompletionStatus::RnrRetryExceeded => {
+                ibverbs_sys::ibv_wc_status::IBV_WC_RNR_RETRY_EXC_ERR
+            }
+            CompletionStatus::RemoteInvalidRequest => {
+                ibverbs_sys::ibv_wc_status::IBV_WC_REM_INV_REQ_ERR
+            }
+            CompletionStatus::MwBindError => ibverbs_sys::ibv_wc_status::IBV_WC_MW_BIND_ERR,
+        }
+    }
+}
+
 /// Manages CQs
 pub(crate) struct CqManager {
     /// Bitmap tracking allocated CQ handles
@@ -481,3 +1225,244 @@ impl CqManager {
         self.bitmap.set(handle as usize, false);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::device_protocol::mock::RecordCommand;
+
+    use super::*;
+
+    fn send_event(msn: u16, wr_id: u64) -> Event {
+        Event::Send(SendEvent::new(
+            SendEventOp::WriteSignaled,
+            MessageMeta::new(msn, Psn(u32::from(msn) + 1)),
+            wr_id,
+        ))
+    }
+
+    #[test]
+    fn software_completion_follows_issued_wrs() {
+        let send_cq = CompletionQueue::default();
+        let recv_cq = CompletionQueue::default();
+        let mut tracker = QueuePairMessageTracker::default();
+        tracker.append(send_event(0, 1));
+        let failed = Completion::Error {
+            wr_id: 2,
+            status: CompletionStatus::RnrRetryExceeded,
+            opcode: ibverbs_sys::ibv_wc_opcode::IBV_WC_SEND,
+        };
+        tracker.complete_software(failed, Some(&send_cq), Some(&recv_cq));
+        tracker.complete_software(
+            Completion::Send { wr_id: 3 },
+            Some(&send_cq),
+            Some(&recv_cq),
+        );
+        assert!(send_cq.pop_front().is_none());
+        assert!(!tracker.is_error);
+
+        tracker.ack_send(Some(Psn(1)), &send_cq, Some(&recv_cq));
+        assert!(matches!(
+            send_cq.pop_front(),
+            Some(Completion::RdmaWrite { wr_id: 1 })
+        ));
+        assert!(matches!(
+            send_cq.pop_front(),
+            Some(Completion::Error {
+                wr_id: 2,
+                status: CompletionStatus::RnrRetryExceeded,
+                ..
+            })
+        ));
+        assert!(matches!(
+            send_cq.pop_front(),
+            Some(Completion::Error {
+                wr_id: 3,
+                status: CompletionStatus::WrFlushError,
+                ..
+            })
+        ));
+        assert!(tracker.is_error);
+    }
+
+    #[test]
+    fn error_flushes_outstanding_wrs() {
+        let send_cq = CompletionQueue::default();
+        let recv_cq = CompletionQueue::default();
+        let mut tracker = QueuePairMessageTracker::default();
+        tracker.append(send_event(0, 1));
+        tracker.append(send_event(1, 2));
+        tracker.append(Event::PostRecv(PostRecvEvent::new(3, 64)));
+        tracker.set_error(
+            CompletionStatus::RetryExceeded,
+            Some(&send_cq),
+            Some(&recv_cq),
+        );
+
+        let statuses: Vec<_> = iter::from_fn(|| send_cq.pop_front())
+            .map(|c| c.status())
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                CompletionStatus::RetryExceeded,
+                CompletionStatus::WrFlushError
+            ]
+        );
+        let recv = recv_cq.pop_front().unwrap();
+        assert_eq!(recv.status(), CompletionStatus::WrFlushError);
+        assert_eq!(recv.opcode(), ibverbs_sys::ibv_wc_opcode::IBV_WC_RECV);
+
+        // WRs posted after the error are flushed immediately by the worker
+        tracker.append(send_event(2, 4));
+        tracker.flush(
+            CompletionStatus::WrFlushError,
+            Some(&send_cq),
+            Some(&recv_cq),
+        );
+        assert_eq!(
+            send_cq.pop_front().map(|c| c.status()),
+            Some(CompletionStatus::WrFlushError)
+        );
+    }
+
+    #[test]
+    fn recv_exceeds_posted_buffer() {
+        let recv_cq = CompletionQueue::default();
+        let (ack_tx, _ack_rx) = flume::unbounded();
+        let mut tracker = QueuePairMessageTracker::default();
+        tracker.append(Event::PostRecv(PostRecvEvent::new(1, 64)));
+        tracker.append(Event::Recv(RecvEvent::new(
+            RecvEventOp::Recv { len: 128 },
+            MessageMeta::new(0, Psn(1)),
+        )));
+        let mw_table = MwTable::new(Arc::new(RecordCommand::default()));
+        let result = tracker.ack_recv(Psn(1), &recv_cq, None, 0, &ack_tx, &mw_table);
+        assert_eq!(result, Err(CompletionStatus::WrFlushError));
+        assert_eq!(
+            recv_cq.pop_front().map(|c| c.status()),
+            Some(CompletionStatus::LocalLengthError)
+        );
+    }
+
+    #[test]
+    fn recvs_consume_posted_wrs_in_order() {
+        let recv_cq = CompletionQueue::default();
+        let (ack_tx, _ack_rx) = flume::unbounded();
+        let mut tracker = QueuePairMessageTracker::default();
+        tracker.append(Event::PostRecv(PostRecvEvent::new(1, 64)));
+        tracker.append(Event::PostRecv(PostRecvEvent::new(2, 64)));
+        for msn in 0..2 {
+            tracker.append(Event::Recv(RecvEvent::new(
+                RecvEventOp::Recv { len: 8 },
+                MessageMeta::new(msn, Psn(u32::from(msn) + 1)),
+            )));
+        }
+        let mw_table = MwTable::new(Arc::new(RecordCommand::default()));
+        let result = tracker.ack_recv(Psn(2), &recv_cq, None, 0, &ack_tx, &mw_table);
+        assert_eq!(result, Ok(()));
+        let wr_ids: Vec<_> = iter::from_fn(|| recv_cq.pop_front())
+            .map(|c| match c {
+                Completion::Recv { wr_id, .. } => wr_id,
+                _ => unreachable!("unexpected completion"),
+            })
+            .collect();
+        assert_eq!(wr_ids, [1, 2]);
+    }
+
+    #[test]
+    fn recv_without_posted_wr_is_dropped() {
+        let recv_cq = CompletionQueue::default();
+        let (ack_tx, _ack_rx) = flume::unbounded();
+        let mut tracker = QueuePairMessageTracker::default();
+        tracker.append(Event::Recv(RecvEvent::new(
+            RecvEventOp::Recv { len: 8 },
+            MessageMeta::new(0, Psn(1)),
+        )));
+        tracker.append(Event::PostRecv(PostRecvEvent::new(1, 64)));
+        tracker.append(Event::Recv(RecvEvent::new(
+            RecvEventOp::Recv { len: 8 },
+            MessageMeta::new(1, Psn(2)),
+        )));
+        let mw_table = MwTable::new(Arc::new(RecordCommand::default()));
+        let result = tracker.ack_recv(Psn(2), &recv_cq, None, 0, &ack_tx, &mw_table);
+        assert_eq!(result, Ok(()));
+        assert!(matches!(
+            recv_cq.pop_front(),
+            Some(Completion::Recv { wr_id: 1, .. })
+        ));
+        assert!(recv_cq.pop_front().is_none());
+    }
+
+    #[test]
+    fn armed_cq_signals_channel() {
+        let channel = Arc::new(CompChannel::new().unwrap());
+        let read_event = || channel.events.lock().pop_front();
+        let cq = CompletionQueue::default();
+        cq.set_context(0x1000, Some(Arc::clone(&channel)));
+
+        // not armed
+        cq.push_back(Completion::Send { wr_id: 1 });
+        assert_eq!(read_event(), None);
+
+        cq.req_notify(NotifyMode::SolicitedOnly).unwrap();
+        cq.push_back(Completion::Send { wr_id: 2 });
+        assert_eq!(read_event(), None);
+        cq.push_back(Completion::Recv {
+            wr_id: 3,
+            imm: None,
+            solicited: true,
+        });
+        assert_eq!(read_event(), Some(0x1000));
+
+        // disarmed after firing
+        cq.push_back(Completion::Send { wr_id: 4 });
+        assert_eq!(read_event(), None);
+
+        cq.req_notify(NotifyMode::NextCompletion).unwrap();
+        cq.push_back(Completion::Send { wr_id: 5 });
+        assert_eq!(read_event(), Some(0x1000));
+    }
+
+    #[test]
+    fn cqs_share_channel() {
+        let channel = Arc::new(CompChannel::new().unwrap());
+        let (cq0, cq1) = (CompletionQueue::default(), CompletionQueue::default());
+        cq0.set_context(0x1000, Some(Arc::clone(&channel)));
+        cq1.set_context(0x2000, Some(Arc::clone(&channel)));
+        for cq in [&cq1, &cq0] {
+            cq.req_notify(NotifyMode::NextCompletion).unwrap();
+            cq.push_back(Completion::Send { wr_id: 1 });
+        }
+        // each event names the CQ that fired, in firing order
+        assert_eq!(channel.get_event().unwrap(), 0x2000);
+        assert_eq!(channel.get_event().unwrap(), 0x1000);
+        assert!(channel.events.lock().is_empty());
+    }
+
+    #[test]
+    fn cq_overflow_raises_async_event() {
+        let (tx, rx) = flume::unbounded();
+        let cq = CompletionQueue::new(tx);
+        cq.init(2).unwrap();
+        cq.set_context(0x2000, None);
+        cq.push_back(Completion::Send { wr_id: 1 });
+        cq.push_back(Completion::Send { wr_id: 2 });
+        assert!(cq.resize(1).is_err());
+        cq.resize(3).unwrap();
+        cq.push_back(Completion::Send { wr_id: 3 });
+        assert!(rx.try_recv().is_err());
+
+        cq.push_back(Completion::Send { wr_id: 4 });
+        assert_eq!(
+            rx.try_recv(),
+            Ok(AsyncEvent::CqError { cq_context: 0x2000 })
+        );
+        // reported once, later completions are dropped
+        cq.push_back(Completion::Send { wr_id: 5 });
+        assert!(rx.try_recv().is_err());
+        for wr_id in 1..=3 {
+            assert!(matches!(cq.pop_front(), Some(Completion::Send { wr_id: x }) if x == wr_id));
+        }
+        assert!(cq.pop_front().is_none());
+    }
+}
@@ -1,6 +1,10 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{net::config::NetworkConfig, timeout_retransmit::AckTimeoutConfig};
+use crate::{
+    atomic::AtomicConfig, dcqcn::DcqcnConfig, net::config::NetworkConfig, poll::PollConfig,
+    protocol_impl::device::irq::IrqConfig, recv::PostRecvConfig,
+    timeout_retransmit::AckTimeoutConfig,
+};
 
 const DEFAULT_CONFIG_PATH: &str = "/etc/bluerdma/config.toml";
 
@@ -17,6 +21,16 @@ pub(crate) enum ConfigError {
 pub(crate) struct DeviceConfig {
     pub(crate) network: NetworkConfig,
     pub(crate) ack: AckTimeoutConfig,
+    #[serde(default)]
+    pub(crate) post_recv: PostRecvConfig,
+    #[serde(default)]
+    pub(crate) atomic: AtomicConfig,
+    #[serde(default)]
+    pub(crate) dcqcn: DcqcnConfig,
+    #[serde(default)]
+    pub(crate) poll: PollConfig,
+    #[serde(default)]
+    pub(crate) irq: IrqConfig,
 }
 
 impl DeviceConfig {
@@ -27,6 +41,26 @@ impl DeviceConfig {
     pub(crate) fn ack(&self) -> AckTimeoutConfig {
         self.ack
     }
+
+    pub(crate) fn post_recv(&self) -> PostRecvConfig {
+        self.post_recv
+    }
+
+    pub(crate) fn atomic(&self) -> AtomicConfig {
+        self.atomic
+    }
+
+    pub(crate) fn dcqcn(&self) -> DcqcnConfig {
+        self.dcqcn
+    }
+
+    pub(crate) fn poll(&self) -> PollConfig {
+        self.poll
+    }
+
+    pub(crate) fn irq(&self) -> &IrqConfig {
+        &self.irq
+    }
 }
 
 pub(crate) struct ConfigLoader;
@@ -43,4 +77,26 @@ impl ConfigLoader {
         let config: DeviceConfig = toml::from_str(&content)?;
         Ok(config)
     }
+
+    /// Loads only the interrupt configuration from the default path, for
+    /// devices whose remaining configuration is fixed.
+    ///
+    /// Returns the default configuration if the file does not exist.
+    pub(crate) fn load_irq_default() -> Result<IrqConfig, ConfigError> {
+        #[derive(Deserialize)]
+        struct IrqSection {
+            #[serde(default)]
+            irq: IrqConfig,
+        }
+
+        let content = match std::fs::read_to_string(DEFAULT_CONFIG_PATH) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(IrqConfig::default())
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let section: IrqSection = toml::from_str(&content)?;
+        Ok(section.irq)
+    }
 }
@@ -10,11 +10,32 @@ pub(crate) const MAX_MSN_SIZE_BITS: usize = 16;
 /// Maximum size of the PSN window. This represents the maximum number outstanding PSNs.
 pub(crate) const MAX_MSN_WINDOW: usize = 1 << (MAX_MSN_SIZE_BITS - 1);
 
+/// AETH syndrome of a NAK for a PSN sequence error
+///
+/// The ACK frames sent by the driver carry the syndrome in the MSN field, the card reports
+/// it in the MSN of the NAK descriptor.
+pub(crate) const NAK_PSN_SEQUENCE_ERROR: u16 = 0b0110_0000;
+/// AETH syndrome of a NAK for a remote access error
+pub(crate) const NAK_REMOTE_ACCESS_ERROR: u16 = 0b0110_0010;
+
 pub(crate) const MAX_QP_CNT: usize = 1024;
 pub(crate) const QPN_KEY_PART_WIDTH: u32 = 8;
 pub(crate) const QPN_IDX_PART_WIDTH: u32 = 32 - QPN_KEY_PART_WIDTH;
 
 pub(crate) const MAX_CQ_CNT: usize = 1024;
 
+pub(crate) const MAX_PD_CNT: usize = 256;
+
+/// Maximum number of entries in a single CQ
+pub(crate) const MAX_CQE: usize = 4096;
+
+pub(crate) const MAX_SRQ_CNT: usize = 1024;
+
+/// Maximum number of outstanding WRs in a single SRQ
+pub(crate) const MAX_SRQ_WR: u32 = 4096;
+
 /// Maximum number of outstanding send work requests (WRs) that can be posted to a Queue Pair (QP).
 pub(crate) const MAX_SEND_WR: usize = 0x8000;
+
+/// Maximum number of scatter/gather elements (SGEs) in a single work request.
+pub(crate) const MAX_SGE: usize = 8;
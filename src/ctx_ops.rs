@@ -43,10 +43,117 @@ pub unsafe trait RdmaCtxOps {
 
     fn destroy_cq(cq: *mut ffi::ibv_cq) -> ::std::os::raw::c_int;
 
+    fn resize_cq(cq: *mut ffi::ibv_cq, cqe: core::ffi::c_int) -> ::std::os::raw::c_int;
+
+    /// Blocks until a CQ bound to the channel fires, then returns the CQ and its context
+    fn get_cq_event(
+        channel: *mut ffi::ibv_comp_channel,
+        cq: *mut *mut ffi::ibv_cq,
+        cq_context: *mut *mut c_void,
+    ) -> ::std::os::raw::c_int;
+
+    fn req_notify_cq(
+        cq: *mut ffi::ibv_cq,
+        solicited_only: core::ffi::c_int,
+    ) -> ::std::os::raw::c_int;
+
+    fn get_async_event(
+        blue_context: *mut ffi::ibv_context,
+        event: *mut ffi::ibv_async_event,
+    ) -> ::std::os::raw::c_int;
+
+    fn create_srq(
+        pd: *mut ffi::ibv_pd,
+        srq_init_attr: *mut ffi::ibv_srq_init_attr,
+    ) -> *mut ffi::ibv_srq;
+
+    fn modify_srq(
+        srq: *mut ffi::ibv_srq,
+        srq_attr: *mut ffi::ibv_srq_attr,
+        srq_attr_mask: core::ffi::c_int,
+    ) -> ::std::os::raw::c_int;
+
+    fn query_srq(srq: *mut ffi::ibv_srq, srq_attr: *mut ffi::ibv_srq_attr)
+        -> ::std::os::raw::c_int;
+
+    fn destroy_srq(srq: *mut ffi::ibv_srq) -> ::std::os::raw::c_int;
+
+    fn post_srq_recv(
+        srq: *mut ffi::ibv_srq,
+        recv_wr: *mut ffi::ibv_recv_wr,
+        bad_recv_wr: *mut *mut ffi::ibv_recv_wr,
+    ) -> ::std::os::raw::c_int;
+
     fn create_qp(pd: *mut ffi::ibv_pd, init_attr: *mut ffi::ibv_qp_init_attr) -> *mut ffi::ibv_qp;
 
     fn destroy_qp(qp: *mut ffi::ibv_qp) -> ::std::os::raw::c_int;
 
+    /// Pins all packets of the QP to one send channel, a negative channel
+    /// restores the hashed channel
+    fn set_qp_channel(qp: *mut ffi::ibv_qp, channel: core::ffi::c_int) -> ::std::os::raw::c_int;
+
+    /// Sets the send scheduling weight of the QP, a QP gets `weight` times the
+    /// bandwidth share of a QP with weight 1
+    fn set_qp_weight(qp: *mut ffi::ibv_qp, weight: core::ffi::c_uint) -> ::std::os::raw::c_int;
+
+    /// Listens for connection requests on the TCP `port`
+    fn cm_listen(port: u16, listener: *mut *mut c_void) -> ::std::os::raw::c_int;
+
+    fn cm_destroy_listener(listener: *mut c_void);
+
+    /// Blocks until a connection request arrives, the private data of the requester is
+    /// copied to `private_data`, which must hold 56 bytes
+    fn cm_get_request(
+        listener: *mut c_void,
+        request: *mut *mut c_void,
+        private_data: *mut c_void,
+        private_data_len: *mut u8,
+    ) -> ::std::os::raw::c_int;
+
+    /// Accepts a request on a QP in the RESET state and moves the QP to RTS, the
+    /// request is consumed even on failure
+    fn cm_accept(
+        request: *mut c_void,
+        qp: *mut ffi::ibv_qp,
+        access_flags: core::ffi::c_uint,
+        private_data: *const c_void,
+        private_data_len: u8,
+        conn: *mut *mut c_void,
+    ) -> ::std::os::raw::c_int;
+
+    /// Rejects and consumes a request
+    fn cm_reject(
+        request: *mut c_void,
+        private_data: *const c_void,
+        private_data_len: u8,
+    ) -> ::std::os::raw::c_int;
+
+    /// Connects a QP in the RESET state to the listener at `addr` (`host:port`) and
+    /// moves the QP to RTS, returns `ECONNREFUSED` if the peer rejects the request
+    fn cm_connect(
+        qp: *mut ffi::ibv_qp,
+        addr: *const c_char,
+        access_flags: core::ffi::c_uint,
+        private_data: *const c_void,
+        private_data_len: u8,
+        conn: *mut *mut c_void,
+    ) -> ::std::os::raw::c_int;
+
+    /// Copies the private data sent by the peer of `conn` during the handshake to
+    /// `private_data`, which must hold 56 bytes
+    fn cm_conn_private_data(
+        conn: *mut c_void,
+        private_data: *mut c_void,
+        private_data_len: *mut u8,
+    ) -> ::std::os::raw::c_int;
+
+    /// Notifies the peer and moves the QP to the error state, `conn` is consumed
+    fn cm_disconnect(qp: *mut ffi::ibv_qp, conn: *mut c_void) -> ::std::os::raw::c_int;
+
+    /// Waits until the peer disconnects and moves the QP to the error state, `conn` is
+    /// consumed
+    fn cm_wait_disconnect(qp: *mut ffi::ibv_qp, conn: *mut c_void) -> ::std::os::raw::c_int;
+
     fn modify_qp(
         qp: *mut ffi::ibv_qp,
         attr: *mut ffi::ibv_qp_attr,
@@ -70,6 +177,25 @@ pub unsafe trait RdmaCtxOps {
 
     fn dereg_mr(mr: *mut ffi::ibv_mr) -> ::std::os::raw::c_int;
 
+    fn rereg_mr(
+        mr: *mut ffi::ibv_mr,
+        flags: core::ffi::c_int,
+        pd: *mut ffi::ibv_pd,
+        addr: *mut ::std::os::raw::c_void,
+        length: usize,
+        access: core::ffi::c_int,
+    ) -> ::std::os::raw::c_int;
+
+    fn alloc_mw(pd: *mut ffi::ibv_pd, mw_type: ffi::ibv_mw_type) -> *mut ffi::ibv_mw;
+
+    fn dealloc_mw(mw: *mut ffi::ibv_mw) -> ::std::os::raw::c_int;
+
+    fn bind_mw(
+        qp: *mut ffi::ibv_qp,
+        mw: *mut ffi::ibv_mw,
+        mw_bind: *mut ffi::ibv_mw_bind,
+    ) -> ::std::os::raw::c_int;
+
     fn post_send(
         qp: *mut ffi::ibv_qp,
         wr: *mut ffi::ibv_send_wr,
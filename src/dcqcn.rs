@@ -0,0 +1,469 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{device_protocol::WrChunk, protocol_impl::WrQueue, utils::QpTable};
+
+/// Fixed point scale of alpha, `ALPHA_SCALE` represents 1.0
+const ALPHA_SCALE: u64 = 1024;
+
+/// Maximum number of elapsed periods applied in a single update, longer gaps are truncated
+const MAX_CATCH_UP_PERIODS: u64 = 64;
+
+const DEFAULT_LINE_RATE_MBPS: u64 = 100_000;
+const DEFAULT_MIN_RATE_MBPS: u64 = 100;
+const DEFAULT_RATE_AI_MBPS: u64 = 40;
+const DEFAULT_RATE_HAI_MBPS: u64 = 200;
+const DEFAULT_ALPHA_G_SHIFT: u32 = 8;
+const DEFAULT_ALPHA_UPDATE_PERIOD_US: u64 = 55;
+const DEFAULT_RATE_INCREASE_PERIOD_US: u64 = 300;
+const DEFAULT_BYTE_COUNTER: u64 = 10 * 1024 * 1024;
+const DEFAULT_FAST_RECOVERY_THRESHOLD: u32 = 5;
+const DEFAULT_RATE_DECREASE_PERIOD_US: u64 = 4;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct DcqcnConfig {
+    /// Marks outgoing packets as ECN capable and reacts to CNPs, off unless configured
+    enabled: bool,
+    /// Line rate in Mbps, QPs at this rate are not paced
+    line_rate_mbps: u64,
+    /// Lower bound of the sending rate in Mbps
+    min_rate_mbps: u64,
+    /// Additive increase step in Mbps
+    rate_ai_mbps: u64,
+    /// Hyper increase step in Mbps
+    rate_hai_mbps: u64,
+    /// Alpha gain, g = 1 / 2^(ALPHA G SHIFT)
+    alpha_g_shift: u32,
+    /// Alpha decays once per period without CNP
+    alpha_update_period_us: u64,
+    /// Period of the rate increase timer
+    rate_increase_period_us: u64,
+    /// Bytes sent between two byte counter rate increase events
+    byte_counter: u64,
+    /// Number of increase events spent in fast recovery
+    fast_recovery_threshold: u32,
+    /// CNPs arriving within this period after a rate decrease are ignored
+    rate_decrease_period_us: u64,
+}
+
+impl Default for DcqcnConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            line_rate_mbps: DEFAULT_LINE_RATE_MBPS,
+            min_rate_mbps: DEFAULT_MIN_RATE_MBPS,
+            rate_ai_mbps: DEFAULT_RATE_AI_MBPS,
+            rate_hai_mbps: DEFAULT_RATE_HAI_MBPS,
+            alpha_g_shift: DEFAULT_ALPHA_G_SHIFT,
+            alpha_update_period_us: DEFAULT_ALPHA_UPDATE_PERIOD_US,
+            rate_increase_period_us: DEFAULT_RATE_INCREASE_PERIOD_US,
+            byte_counter: DEFAULT_BYTE_COUNTER,
+            fast_recovery_threshold: DEFAULT_FAST_RECOVERY_THRESHOLD,
+            rate_decrease_period_us: DEFAULT_RATE_DECREASE_PERIOD_US,
+        }
+    }
+}
+
+impl DcqcnConfig {
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn alpha_update_period(&self) -> Duration {
+        Duration::from_micros(self.alpha_update_period_us.max(1))
+    }
+
+    fn rate_increase_period(&self) -> Duration {
+        Duration::from_micros(self.rate_increase_period_us.max(1))
+    }
+
+    fn rate_decrease_period(&self) -> Duration {
+        Duration::from_micros(self.rate_decrease_period_us)
+    }
+}
+
+/// Reaction point state of a rate limited QP
+#[derive(Debug, Clone, Copy)]
+struct QpRate {
+    /// Current rate in Mbps
+    current: u64,
+    /// Target rate in Mbps
+    target: u64,
+    /// Congestion estimate scaled by `ALPHA_SCALE`
+    alpha: u64,
+    /// Rate increase events triggered by the timer since the last decrease
+    timer_stage: u32,
+    /// Rate increase events triggered by the byte counter since the last decrease
+    byte_stage: u32,
+    /// Bytes sent since the last byte counter event
+    bytes: u64,
+    last_decrease: Instant,
+    last_alpha_update: Instant,
+    last_increase: Instant,
+    /// Earliest time the next chunk may be released
+    next_send: Instant,
+    /// Number of chunks held by the pacer
+    delayed: usize,
+}
+
+impl QpRate {
+    fn new(config: &DcqcnConfig, now: Instant) -> Self {
+        Self {
+            current: config.line_rate_mbps,
+            target: config.line_rate_mbps,
+            alpha: ALPHA_SCALE,
+            timer_stage: 0,
+            byte_stage: 0,
+            bytes: 0,
+            last_decrease: now,
+            last_alpha_update: now,
+            last_increase: now,
+            next_send: now,
+            delayed: 0,
+        }
+    }
+
+    /// Cuts the rate on a CNP, returns `false` if the CNP is ignored
+    fn decrease(&mut self, config: &DcqcnConfig, now: Instant, first: bool) -> bool {
+        if !first && now.duration_since(self.last_decrease) < config.rate_decrease_period() {
+            return false;
+        }
+        self.target = self.current;
+        let cut = self.current * self.alpha / (2 * ALPHA_SCALE);
+        self.current = self
+            .current
+            .saturating_sub(cut)
+            .max(config.min_rate_mbps.max(1));
+        self.alpha = self.alpha - (self.alpha >> config.alpha_g_shift)
+            + (ALPHA_SCALE >> config.alpha_g_shift);
+        self.timer_stage = 0;
+        self.byte_stage = 0;
+        self.bytes = 0;
+        self.last_decrease = now;
+        self.last_alpha_update = now;
+        self.last_increase = now;
+        true
+    }
+
+    /// Applies the alpha decay and the timer driven increases elapsed until `now`
+    fn advance(&mut self, config: &DcqcnConfig, now: Instant) {
+        let alpha_period = config.alpha_update_period();
+        let periods = elapsed_periods(self.last_alpha_update, now, alpha_period);
+        for _ in 0..periods.min(MAX_CATCH_UP_PERIODS) {
+            self.alpha -= self.alpha >> config.alpha_g_shift;
+        }
+        self.last_alpha_update = catch_up(self.last_alpha_update, now, alpha_period, periods);
+
+        let increase_period = config.rate_increase_period();
+        let periods = elapsed_periods(self.last_increase, now, increase_period);
+        for _ in 0..periods.min(MAX_CATCH_UP_PERIODS) {
+            self.timer_stage = self.timer_stage.saturating_add(1);
+            self.increase(config);
+        }
+        self.last_increase = catch_up(self.last_increase, now, increase_period, periods);
+    }
+
+    /// Counts sent bytes, every `byte_counter` bytes trigger an increase event
+    fn account(&mut self, config: &DcqcnConfig, len: u64) {
+        let byte_counter = config.byte_counter.max(1);
+        self.bytes += len;
+        while self.bytes >= byte_counter {
+            self.bytes -= byte_counter;
+            self.byte_stage = self.byte_stage.saturating_add(1);
+            self.increase(config);
+        }
+    }
+
+    /// A single rate increase event
+    fn increase(&mut self, config: &DcqcnConfig) {
+        let threshold = config.fast_recovery_threshold;
+        let max_stage = self.timer_stage.max(self.byte_stage);
+        let min_stage = self.timer_stage.min(self.byte_stage);
+        if max_stage < threshold {
+            // fast recovery, only move towards the target
+        } else if min_stage > threshold {
+            let step = u64::from(min_stage - threshold);
+            self.target = self
+                .target
+                .saturating_add(config.rate_hai_mbps.saturating_mul(step));
+        } else {
+            self.target = self.target.saturating_add(config.rate_ai_mbps);
+        }
+        self.target = self.target.min(config.line_rate_mbps);
+        self.current = (self.current + self.target).div_ceil(2);
+    }
+
+    /// Returns `true` if the QP no longer needs to be paced
+    fn is_recovered(&self, config: &DcqcnConfig, now: Instant) -> bool {
+        self.current >= config.line_rate_mbps && self.delayed == 0 && self.next_send <= now
+    }
+
+    /// Time on the wire of `len` bytes at the current rate
+    fn tx_time(&self, len: u64) -> Duration {
+        // bytes * 8 / Mbps = us
+        Duration::from_nanos(len * 8 * 1000 / self.current.max(1))
+    }
+}
+
+fn elapsed_periods(since: Instant, now: Instant, period: Duration) -> u64 {
+    let elapsed = now.saturating_duration_since(since).as_nanos() / period.as_nanos();
+    u64::try_from(elapsed).unwrap_or(u64::MAX)
+}
+
+/// Moves the start of the current period forward by `periods`
+fn catch_up(since: Instant, now: Instant, period: Duration, periods: u64) -> Instant {
+    if periods > MAX_CATCH_UP_PERIODS {
+        // idle for a long time, restart the period from now
+        return now;
+    }
+    since + period * periods as u32
+}
+
+/// Per QP DCQCN rate limiter, shared between the meta handler and the send path
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    config: DcqcnConfig,
+    /// `None` for QPs sending at line rate
+    table: Arc<Mutex<QpTable<Option<QpRate>>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: DcqcnConfig) -> Self {
+        Self {
+            config,
+            table: Arc::new(Mutex::new(QpTable::new())),
+        }
+    }
+
+    pub(crate) fn clone_arc(&self) -> Self {
+        Self {
+            config: self.config,
+            table: Arc::clone(&self.table),
+        }
+    }
+
+    /// Reacts to a CNP received for `qpn`
+    pub(crate) fn on_cnp(&self, qpn: u32) {
+        let now = Instant::now();
+        let mut table = self.table.lock();
+        let Some(entry) = table.get_qp_mut(qpn) else {
+            return;
+        };
+        let first = entry.is_none();
+        let rate = entry.get_or_insert_with(|| QpRate::new(&self.config, now));
+        rate.advance(&self.config, now);
+        let _decreased = rate.decrease(&self.config, now, first);
+    }
+
+    /// Reserves the transmission of `len` bytes for `qpn`
+    ///
+    /// Returns the time the chunk should be released at, or `None` if it can be sent immediately.
+    fn reserve(&self, qpn: u32, len: u64, now: Instant) -> Option<Instant> {
+        let mut table = self.table.lock();
+        let entry = table.get_qp_mut(qpn)?;
+        let rate = entry.as_mut()?;
+        rate.advance(&self.config, now);
+        if rate.is_recovered(&self.config, now) {
+            *entry = None;
+            return None;
+        }
+        rate.account(&self.config, len);
+        let start = rate.next_send.max(now);
+        rate.next_send = start + rate.tx_time(len);
+        // chunks of a QP held by the pacer must not be overtaken
+        if start <= now && rate.delayed == 0 {
+            return None;
+        }
+        rate.delayed += 1;
+        Some(start)
+    }
+
+    /// Called by the pacer after a delayed chunk of `qpn` has been submitted
+    fn released(&self, qpn: u32) {
+        let mut table = self.table.lock();
+        if let Some(rate) = table.get_qp_mut(qpn).and_then(Option::as_mut) {
+            rate.delayed = rate.delayed.saturating_sub(1);
+        }
+    }
+
+    /// Drops the rate state of `qpn`
+    pub(crate) fn reset(&self, qpn: u32) {
+        let mut table = self.table.lock();
+        let _ignore = table.replace(qpn, None);
+    }
+}
+
+/// A chunk waiting in the pacer
+struct PacedChunk {
+    release_at: Instant,
+    seq: u64,
+    chunk: WrChunk,
+}
+
+impl PartialEq for PacedChunk {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for PacedChunk {}
+
+impl PartialOrd for PacedChunk {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PacedChunk {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.release_at
+            .cmp(&other.release_at)
+            .then(self.seq.cmp(&other.seq))
+    }
+}
+
+/// Paces `WrChunk` submission to the per QP DCQCN rate
+pub(crate) struct Pacer {
+    limiter: RateLimiter,
+    queue: Arc<WrQueue>,
+    delayed_tx: flume::Sender<(Instant, WrChunk)>,
+}
+
+impl Pacer {
+    /// Creates the pacer and spawns the thread releasing delayed chunks
+    pub(crate) fn spawn(limiter: RateLimiter, queue: Arc<WrQueue>) -> Self {
+        let (delayed_tx, delayed_rx) = flume::unbounded();
+        let worker = PacerWorker {
+            limiter: limiter.clone_arc(),
+            queue: Arc::clone(&queue),
+            delayed_rx,
+            heap: BinaryHeap::new(),
+            seq: 0,
+        };
+        let _handle = thread::Builder::new()
+            .name("dcqcn-pacer".into())
+            .spawn(move || worker.run())
+            .unwrap_or_else(|err| unreachable!("Failed to spawn thread: {err}"));
+        Self {
+            limiter,
+            queue,
+            delayed_tx,
+        }
+    }
+
+    pub(crate) fn clone_arc(&self) -> Self {
+        Self {
+            limiter: self.limiter.clone_arc(),
+            queue: Arc::clone(&self.queue),
+            delayed_tx: self.delayed_tx.clone(),
+        }
+    }
+
+    pub(crate) fn limiter(&self) -> RateLimiter {
+        self.limiter.clone_arc()
+    }
+
+    /// Submits the chunk now, or hands it to the pacer thread if the QP is over its rate
+    pub(crate) fn submit(&self, chunk: WrChunk) {
+        let now = Instant::now();
+        let Some(release_at) = self.limiter.reserve(chunk.sqpn, u64::from(chunk.len), now) else {
+            self.queue.push(chunk);
+            return;
+        };
+        if let Err(flume::SendError((_, chunk))) = self.delayed_tx.send((release_at, chunk)) {
+            // pacer thread exited, submit without pacing
+            self.limiter.released(chunk.sqpn);
+            self.queue.push(chunk);
+        }
+    }
+}
+
+struct PacerWorker {
+    limiter: RateLimiter,
+    queue: Arc<WrQueue>,
+    delayed_rx: flume::Receiver<(Instant, WrChunk)>,
+    heap: BinaryHeap<Reverse<PacedChunk>>,
+    seq: u64,
+}
+
+impl PacerWorker {
+    fn run(mut self) {
+        loop {
+            let received = match self.heap.peek() {
+                Some(next) => match self.delayed_rx.recv_deadline(next.0.release_at) {
+                    Ok(x) => Some(x),
+                    Err(flume::RecvTimeoutError::Timeout) => None,
+                    Err(flume::RecvTimeoutError::Disconnected) => break,
+                },
+                None => match self.delayed_rx.recv() {
+                    Ok(x) => Some(x),
+                    Err(flume::RecvError::Disconnected) => break,
+                },
+            };
+            if let Some((release_at, chunk)) = received {
+                self.heap.push(Reverse(PacedChunk {
+                    release_at,
+                    seq: self.seq,
+                    chunk,
+                }));
+                self.seq = self.seq.wrapping_add(1);
+            }
+            self.release_due();
+        }
+    }
+
+    fn release_due(&mut self) {
+        let now = Instant::now();
+        while self.heap.peek().is_some_and(|x| x.0.release_at <= now) {
+            let Some(Reverse(paced)) = self.heap.pop() else {
+                break;
+            };
+            let qpn = paced.chunk.sqpn;
+            self.queue.push(paced.chunk);
+            self.limiter.released(qpn);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_decreases_on_cnp_and_recovers() {
+        let config = DcqcnConfig::default();
+        let now = Instant::now();
+        let mut rate = QpRate::new(&config, now);
+        assert!(rate.decrease(&config, now, true));
+        // alpha starts at 1, the first CNP halves the rate
+        assert_eq!(rate.current, config.line_rate_mbps / 2);
+        assert_eq!(rate.target, config.line_rate_mbps);
+        assert!(!rate.decrease(&config, now, false));
+
+        // fast recovery converges towards the target
+        let before = rate.current;
+        rate.advance(&config, now + config.rate_increase_period());
+        assert_eq!(rate.timer_stage, 1);
+        assert!(rate.current > before);
+        assert!(rate.alpha < ALPHA_SCALE);
+
+        rate.account(&config, config.byte_counter * 3);
+        assert_eq!(rate.byte_stage, 3);
+        for i in 1..=MAX_CATCH_UP_PERIODS {
+            rate.advance(
+                &config,
+                now + config.rate_increase_period() * (i + 1) as u32,
+            );
+        }
+        assert!(rate.is_recovered(&config, now + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,62 @@
+//! Device command mock shared by the unit tests
+
+use parking_lot::Mutex;
+
+use crate::net::config::NetworkConfig;
+
+use super::{
+    CommandError, CommandKind, DeviceCommand, MttUpdate, PgtUpdate, RecvBufferMeta, UpdateQp,
+};
+
+/// Records the MTT updates and the sizes of PGT update batches, accepts all other commands
+#[derive(Default)]
+pub(crate) struct RecordCommand {
+    /// Fails the MTT update with this index
+    pub(crate) fail_mtt: Option<usize>,
+    /// (key, length, `base_pgt_offset`) of the MTT updates
+    pub(crate) mtt: Mutex<Vec<(u32, u32, u32)>>,
+    pub(crate) pgt_batches: Mutex<Vec<usize>>,
+}
+
+impl RecordCommand {
+    /// Returns the (key, length) of the MTT updates
+    pub(crate) fn mtt_lengths(&self) -> Vec<(u32, u32)> {
+        self.mtt
+            .lock()
+            .iter()
+            .map(|&(key, len, _)| (key, len))
+            .collect()
+    }
+}
+
+impl DeviceCommand for RecordCommand {
+    fn update_mtt(&self, update: MttUpdate) -> Result<(), CommandError> {
+        let mut mtt = self.mtt.lock();
+        mtt.push((update.mr_key, update.mr_length, update.base_pgt_offset));
+        if self.fail_mtt == Some(mtt.len() - 1) {
+            return Err(CommandError::Rejected(CommandKind::UpdateMtt));
+        }
+        Ok(())
+    }
+
+    fn update_pgt(&self, update: PgtUpdate) -> Result<(), CommandError> {
+        self.update_pgt_batch(&[update])
+    }
+
+    fn update_pgt_batch(&self, updates: &[PgtUpdate]) -> Result<(), CommandError> {
+        self.pgt_batches.lock().push(updates.len());
+        Ok(())
+    }
+
+    fn update_qp(&self, _entry: UpdateQp) -> Result<(), CommandError> {
+        Ok(())
+    }
+
+    fn set_network(&self, _param: NetworkConfig) -> Result<(), CommandError> {
+        Ok(())
+    }
+
+    fn set_raw_packet_recv_buffer(&self, _buffer: RecvBufferMeta) -> Result<(), CommandError> {
+        Ok(())
+    }
+}
@@ -1,23 +1,87 @@
+#[cfg(test)]
+pub(crate) mod mock;
 mod types;
 
 pub(crate) use types::*;
 
-use std::io;
+use std::{io, thread};
+
+use thiserror::Error;
 
 use crate::net::config::NetworkConfig;
 
 /// RDMA device configuration interface
 pub(crate) trait DeviceCommand {
     /// Updates Memory Translation Table entry
-    fn update_mtt(&self, update: MttUpdate) -> io::Result<()>;
+    fn update_mtt(&self, update: MttUpdate) -> Result<(), CommandError>;
     /// Updates Page Table entry
-    fn update_pgt(&self, update: PgtUpdate) -> io::Result<()>;
+    fn update_pgt(&self, update: PgtUpdate) -> Result<(), CommandError>;
+    /// Updates Page Table entries, returns after all updates complete
+    fn update_pgt_batch(&self, updates: &[PgtUpdate]) -> Result<(), CommandError> {
+        updates
+            .iter()
+            .try_for_each(|update| self.update_pgt(*update))
+    }
     /// Updates Queue Pair entry
-    fn update_qp(&self, entry: UpdateQp) -> io::Result<()>;
+    fn update_qp(&self, entry: UpdateQp) -> Result<(), CommandError>;
     /// Sets network parameters
-    fn set_network(&self, param: NetworkConfig) -> io::Result<()>;
+    fn set_network(&self, param: NetworkConfig) -> Result<(), CommandError>;
     /// Sets receive buffer for raw packets
-    fn set_raw_packet_recv_buffer(&self, buffer: RecvBufferMeta) -> io::Result<()>;
+    fn set_raw_packet_recv_buffer(&self, buffer: RecvBufferMeta) -> Result<(), CommandError>;
+}
+
+/// Kind of a device command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CommandKind {
+    /// Memory Translation Table update
+    UpdateMtt,
+    /// Page Table update
+    UpdatePgt,
+    /// Queue Pair update
+    UpdateQp,
+    /// Network parameters update
+    SetNetwork,
+    /// Raw packet receive buffer update
+    SetRawPacketRecvBuffer,
+}
+
+/// Error returned by device commands
+#[derive(Error, Debug)]
+pub(crate) enum CommandError {
+    /// The device responded with a failure status
+    #[error("device rejected command {0:?}")]
+    Rejected(CommandKind),
+    /// The device did not respond in time
+    #[error("command timed out")]
+    Timeout,
+    /// The command queue has no free slot
+    #[error("command queue full")]
+    QueueFull,
+    /// The response does not belong to the submitted command
+    #[error("unexpected response op code {op_code:#x} for command {expected:?}")]
+    UnexpectedResponse {
+        /// Kind of the submitted command
+        expected: CommandKind,
+        /// Op code carried by the response
+        op_code: u8,
+    },
+    /// Accessing the device failed
+    #[error("device access failed: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl From<CommandError> for io::Error {
+    #[inline]
+    fn from(err: CommandError) -> Self {
+        let kind = match err {
+            CommandError::Io(err) => return err,
+            CommandError::Rejected(_) => io::ErrorKind::InvalidInput,
+            CommandError::Timeout => io::ErrorKind::TimedOut,
+            CommandError::QueueFull => io::ErrorKind::WouldBlock,
+            CommandError::UnexpectedResponse { .. } => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
 }
 
 /// RDMA send operations interface
@@ -55,4 +119,9 @@ pub(crate) trait FrameTx {
 pub(crate) trait FrameRx {
     /// Try to receive a frame, returning immediately if none available
     fn recv_nonblocking(&mut self) -> io::Result<&[u8]>;
+
+    /// Called when no frame was available
+    fn idle(&mut self) {
+        thread::yield_now();
+    }
 }
@@ -36,6 +36,7 @@ impl MttUpdate {
     }
 }
 
+#[derive(Clone, Copy)]
 pub(crate) struct PgtUpdate {
     pub(crate) dma_addr: u64,
     pub(crate) pgt_offset: u32,
@@ -216,6 +217,8 @@ pub(crate) struct NakMetaRemoteDriver {
     pub(crate) qpn: u32,
     pub(crate) psn_now: Psn,
     pub(crate) psn_pre: Psn,
+    /// The remote rejected the request due to insufficient access rights
+    pub(crate) is_access_error: bool,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -328,6 +331,12 @@ impl WrChunkBuilder<WithIbvParams> {
     pub(crate) fn pmtu(&self) -> u16 {
         convert_ibv_mtu_to_u16(self.inner.pmtu).unwrap_or_else(|| unreachable!("invalid ibv_mtu"))
     }
+
+    /// Overrides the local key, used when the chunk belongs to a different SGE
+    pub(crate) fn set_lkey(mut self, lkey: u32) -> Self {
+        self.inner.lkey = lkey;
+        self
+    }
 }
 
 impl WrChunkBuilder<WithChunkInfo> {
@@ -375,6 +384,10 @@ impl WrChunk {
     pub(crate) fn set_is_retry(&mut self) {
         self.is_retry = true;
     }
+
+    pub(crate) fn set_enable_ecn(&mut self) {
+        self.enable_ecn = true;
+    }
 }
 
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
@@ -457,5 +470,6 @@ pub(crate) enum HeaderType {
     WriteWithImm,
     Send,
     SendWithImm,
+    SendWithInv,
     ReadResp,
 }
@@ -1,8 +1,8 @@
 use crate::{
     constants::PSN_MASK,
-    device_protocol::{QpParams, WithIbvParams, WorkReqOpCode, WrChunk, WrChunkBuilder},
-    qp::convert_ibv_mtu_to_u16,
-    send::SendWrRdma,
+    device_protocol::{ChunkPos, QpParams, WithIbvParams, WorkReqOpCode, WrChunk, WrChunkBuilder},
+    qp::{convert_ibv_mtu_to_u16, num_psn},
+    send::{SendWrRdma, SgeList},
     utils::Psn,
 };
 
@@ -92,13 +92,14 @@ impl IntoIterator for ChunkFragmenter {
     type IntoIter = IntoIter;
 
     fn into_iter(self) -> Self::IntoIter {
+        let sg_list = self.wr.sg_list();
         let builder = WrChunkBuilder::new_with_opcode(self.wr.opcode())
             .set_qp_params(self.qp_param)
             .set_ibv_params(
                 self.wr.send_flags() as u8,
                 self.wr.rkey(),
                 self.wr.length(),
-                self.wr.lkey(),
+                sg_list.as_slice().first().map_or(0, |sge| sge.lkey),
                 self.wr.imm(),
             );
 
@@ -106,51 +107,196 @@ impl IntoIterator for ChunkFragmenter {
             .unwrap_or_else(|| unreachable!("invalid ibv_mtu"))
             .into();
 
-        let f = Fragmenter::new(
-            self.chunk_size,
-            pmtu,
-            self.wr.raddr(),
-            self.wr.length().into(),
-        );
         IntoIter {
-            inner: f.into_iter(),
+            inner: None,
+            sg_list,
+            sge_index: 0,
             psn: self.base_psn,
-            wr: self.wr,
+            base_builder: builder,
             builder,
-            laddr: self.wr.laddr(),
+            laddr: 0,
+            raddr: self.wr.raddr(),
+            chunk_size: self.chunk_size,
             pmtu,
+            ibv_mtu: self.qp_param.pmtu,
+            is_first: true,
             is_retry: self.is_retry,
         }
     }
 }
 
+/// Iterator over the chunks of a work request.
+///
+/// A chunk never spans two SGEs, as each chunk only carries a single local buffer. The
+/// interior SGE boundaries must lie on pmtu boundaries of the remote address, so the
+/// packets are the same as those of a single buffer, the send path stages the packets
+/// straddling an unaligned boundary before fragmenting.
 pub(crate) struct IntoIter {
-    inner: super::IntoIter,
+    /// Fragments of the current SGE
+    inner: Option<super::IntoIter>,
+    sg_list: SgeList,
+    /// Index of the next SGE
+    sge_index: usize,
     psn: Psn,
-    wr: SendWrRdma,
+    base_builder: WrChunkBuilder<WithIbvParams>,
     builder: WrChunkBuilder<WithIbvParams>,
     laddr: u64,
+    /// Remote address of the next SGE
+    raddr: u64,
+    chunk_size: u64,
     pmtu: u64,
+    ibv_mtu: u8,
+    is_first: bool,
     is_retry: bool,
 }
 
+impl IntoIter {
+    /// Moves to the next SGE, returns `None` if all SGEs are consumed
+    fn next_sge(&mut self) -> Option<()> {
+        let sge = *self.sg_list.as_slice().get(self.sge_index)?;
+        let length = u64::from(sge.length);
+        debug_assert!(
+            self.is_first || length == 0 || self.raddr.is_multiple_of(self.pmtu),
+            "SGE boundary not aligned to pmtu"
+        );
+        self.inner =
+            Some(Fragmenter::new(self.chunk_size, self.pmtu, self.raddr, length).into_iter());
+        self.builder = self.base_builder.set_lkey(sge.lkey);
+        self.laddr = sge.addr;
+        self.raddr += length;
+        self.sge_index += 1;
+        Some(())
+    }
+
+    /// Converts the position of a fragment within its SGE to the position within the WR
+    fn chunk_pos(&mut self, pos_in_sge: ChunkPos) -> ChunkPos {
+        let is_first = std::mem::take(&mut self.is_first);
+        let is_last = matches!(pos_in_sge, ChunkPos::Last | ChunkPos::Only)
+            && self
+                .sg_list
+                .as_slice()
+                .get(self.sge_index..)
+                .is_none_or(|rest| rest.iter().all(|sge| sge.length == 0));
+        match (is_first, is_last) {
+            (true, true) => ChunkPos::Only,
+            (true, false) => ChunkPos::First,
+            (false, true) => ChunkPos::Last,
+            (false, false) => ChunkPos::Middle,
+        }
+    }
+}
+
 impl Iterator for IntoIter {
     type Item = WrChunk;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let f = self.inner.next()?;
-        let builder =
-            self.builder
-                .set_chunk_meta(self.psn, self.laddr, f.addr, f.len as u32, f.pos);
+        let f = loop {
+            if let Some(f) = self.inner.as_mut().and_then(Iterator::next) {
+                break f;
+            }
+            if self.next_sge().is_none() {
+                // a zero-length message is sent as a single empty packet
+                if !self.is_first {
+                    return None;
+                }
+                break super::Fragment {
+                    addr: self.raddr,
+                    len: 0,
+                    pos: ChunkPos::Only,
+                };
+            }
+        };
+        let pos = self.chunk_pos(f.pos);
+        let builder = self
+            .builder
+            .set_chunk_meta(self.psn, self.laddr, f.addr, f.len as u32, pos);
         let chunk = if self.is_retry {
             builder.set_is_retry().build()
         } else {
             builder.build()
         };
-        let num_packets = f.len.div_ceil(self.pmtu) as u32;
+        let num_packets = num_psn(self.ibv_mtu, f.addr, f.len as u32)
+            .unwrap_or_else(|| unreachable!("invalid ibv_mtu"))
+            .max(1);
         self.psn += num_packets;
         self.laddr += f.len;
 
         Some(chunk)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        qp::num_psn_wr,
+        send::{SendWrBase, Sge},
+    };
+
+    use super::*;
+
+    fn qp_params() -> QpParams {
+        QpParams::new(0, 0, 0, 0, 0, 0, ibverbs_sys::IBV_MTU_256 as u8)
+    }
+
+    #[test]
+    fn chunks_split_at_sge_boundaries() {
+        let sg_list = SgeList::new(&[
+            Sge::new(0x1000, 0x100, 1),
+            Sge::new(0x2000, 0, 2),
+            Sge::new(0x3000, 0x180, 3),
+        ])
+        .unwrap();
+        let base = SendWrBase::new_with_sg_list(0, 0, sg_list, 0, WorkReqOpCode::RdmaWrite);
+        let wr = SendWrRdma::new_from_base(base, 0x0, 0);
+        let chunks: Vec<_> = WrPacketFragmenter::new(wr, qp_params(), Psn(0))
+            .into_iter()
+            .collect();
+        let expect = [
+            (0x1000, 0x100, 1, 0x0, 0),
+            (0x3000, 0x100, 3, 0x100, 1),
+            (0x3100, 0x80, 3, 0x200, 2),
+        ];
+        assert_eq!(chunks.len(), expect.len());
+        for (chunk, (laddr, len, lkey, raddr, psn)) in chunks.iter().zip(expect) {
+            assert_eq!(chunk.laddr, laddr);
+            assert_eq!(chunk.len, len);
+            assert_eq!(chunk.lkey, lkey);
+            assert_eq!(chunk.raddr, raddr);
+            assert_eq!(chunk.psn, Psn(psn));
+        }
+        assert!(chunks[0].is_first && !chunks[0].is_last);
+        assert!(!chunks[1].is_first && !chunks[1].is_last);
+        assert!(!chunks[2].is_first && chunks[2].is_last);
+        assert_eq!(
+            num_psn_wr(qp_params().pmtu, wr.raddr(), sg_list.total_len()),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn zero_length_wr_is_one_packet() {
+        let base =
+            SendWrBase::new_with_sg_list(0, 0, SgeList::default(), 0, WorkReqOpCode::RdmaWrite);
+        let wr = SendWrRdma::new_from_base(base, 0x80, 0);
+        let chunks: Vec<_> = WrPacketFragmenter::new(wr, qp_params(), Psn(5))
+            .into_iter()
+            .collect();
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].len, 0);
+        assert_eq!(chunks[0].raddr, 0x80);
+        assert_eq!(chunks[0].psn, Psn(5));
+        assert!(chunks[0].is_first && chunks[0].is_last);
+        assert_eq!(num_psn_wr(qp_params().pmtu, 0x80, 0), Some(1));
+    }
+
+    #[test]
+    fn single_sge_chunk_is_only() {
+        let base = SendWrBase::new(0, 0, 0x1000, 0x100, 1, 0, WorkReqOpCode::RdmaWrite);
+        let wr = SendWrRdma::new_from_base(base, 0x0, 0);
+        let chunks: Vec<_> = WrChunkFragmenter::new(wr, qp_params(), Psn(0))
+            .into_iter()
+            .collect();
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].is_first && chunks[0].is_last);
+    }
+}
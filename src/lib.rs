@@ -171,26 +171,39 @@
 #![allow(clippy::arithmetic_side_effects)]
 
 mod ack_responder;
+mod async_event;
+mod atomic;
+/// Connection management
+mod cm;
 mod completion;
 mod config;
 /// Constants used throughout the driver
 mod constants;
+/// DCQCN congestion control
+mod dcqcn;
 mod device_protocol;
 mod fragmenter;
 /// Memory operation components
 #[allow(unsafe_code)]
 mod mem;
 mod meta_worker;
+mod mr;
 /// Memory translation table
 mod mtt;
+mod mw;
 mod packet_retransmit;
+mod pd;
+/// Adaptive polling of worker threads
+mod poll;
 mod protocol_impl;
 mod qp;
 mod rdma_write_worker;
 mod recv;
 mod ringbuf;
+mod rnr_retry;
 /// Send Queue implementations
 mod send;
+mod srq;
 mod sq_worker;
 mod timeout_retransmit;
 mod timer;
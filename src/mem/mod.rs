@@ -16,6 +16,9 @@ pub(crate) mod dmabuf;
 
 pub(crate) mod u_dma_buf;
 
+/// Staging memory for local buffers the card can not use directly
+pub(crate) mod staging;
+
 mod utils;
 
 use page::MmapMut;
@@ -0,0 +1,380 @@
+use std::{collections::HashMap, fmt, ptr, sync::Arc};
+
+use bitvec::vec::BitVec;
+use parking_lot::Mutex;
+
+use crate::{
+    constants::MAX_SGE,
+    send::{Sge, SgeList},
+};
+
+use super::{DmaBuf, PAGE_SIZE};
+
+/// Smallest segment registered for a pool
+const MIN_SEGMENT_SIZE: usize = 4 << 20;
+
+/// Largest segment registered for a pool sized from the QP capacities, a single
+/// message needing more staging memory gets a segment of its own size
+const MAX_SEGMENT_SIZE: usize = 64 << 20;
+
+/// Allocation granularity of the staging memory, the smallest pmtu
+const BLOCK_SIZE: usize = 256;
+
+/// Returns the size of the segment registered for a QP that needs `len` more bytes
+///
+/// Sized so that a send queue of `max_send_wr` WRs, each staging a packet at every
+/// interior SGE boundary, fits in one segment.
+pub(crate) fn segment_size(max_send_wr: u32, pmtu: u16, len: usize) -> usize {
+    let per_qp = usize::try_from(max_send_wr)
+        .unwrap_or(usize::MAX)
+        .saturating_mul(pmtu.into())
+        .saturating_mul(MAX_SGE - 1);
+    per_qp
+        .clamp(MIN_SEGMENT_SIZE, MAX_SEGMENT_SIZE)
+        .max(len)
+        .next_multiple_of(PAGE_SIZE)
+}
+
+/// Registered memory that messages are copied through when their local buffers can not
+/// be handed to the card as they are
+///
+/// The card reads a single local buffer for each packet it sends, a send descriptor
+/// carries one buffer address and one local key, so a packet can not gather from two
+/// SGEs. Likewise a read response is written to a single local buffer. The packets
+/// straddling an unaligned SGE boundary and the reads into several SGEs are therefore
+/// copied through this pool.
+///
+/// The pool starts with one segment and grows by another registered segment whenever
+/// a WR needs more staging memory than is free, segments are released with the PD.
+pub(crate) struct StagingPool {
+    /// Registered segments, in the order they were added
+    segments: Mutex<Vec<Segment>>,
+}
+
+/// A memory region of a `StagingPool`
+struct Segment {
+    /// Backing memory, registered as a memory region of the PD
+    buf: DmaBuf,
+    /// Local key of the memory region
+    lkey: u32,
+    /// Allocated blocks
+    blocks: BitVec,
+}
+
+impl Segment {
+    /// Returns the first block of `num_blocks` free contiguous blocks
+    fn first_fit(&self, num_blocks: usize) -> Option<usize> {
+        let mut start = 0;
+        loop {
+            start += self.blocks.get(start..)?.first_zero()?;
+            let end = start.checked_add(num_blocks)?;
+            match self.blocks.get(start..end)?.first_one() {
+                Some(used) => start += used + 1,
+                None => return Some(start),
+            }
+        }
+    }
+}
+
+impl StagingPool {
+    pub(crate) fn new(buf: DmaBuf, lkey: u32) -> Self {
+        let pool = Self {
+            segments: Mutex::new(Vec::new()),
+        };
+        pool.grow(buf, lkey);
+        pool
+    }
+
+    /// Adds a registered segment to the pool
+    pub(crate) fn grow(&self, buf: DmaBuf, lkey: u32) {
+        let mut blocks = BitVec::new();
+        blocks.resize(buf.len / BLOCK_SIZE, false);
+        self.segments.lock().push(Segment { buf, lkey, blocks });
+    }
+
+    /// Returns the start address, length and local key of every segment
+    #[allow(clippy::as_conversions)] // converting *mut c_void to u64
+    pub(crate) fn regions(&self) -> Vec<(u64, usize, u32)> {
+        self.segments
+            .lock()
+            .iter()
+            .map(|seg| (seg.buf.ptr as u64, seg.buf.len, seg.lkey))
+            .collect()
+    }
+
+    /// Returns `true` if `len` contiguous bytes are free
+    pub(crate) fn has_room(&self, len: usize) -> bool {
+        let num_blocks = len.div_ceil(BLOCK_SIZE).max(1);
+        self.segments
+            .lock()
+            .iter()
+            .any(|seg| seg.first_fit(num_blocks).is_some())
+    }
+
+    /// Allocates `len` contiguous bytes, returns `None` if the pool has no room
+    #[allow(clippy::as_conversions)] // converting *mut c_void to u64
+    pub(crate) fn alloc(self: &Arc<Self>, len: u32) -> Option<StagingBuf> {
+        let len = usize::try_from(len).ok()?;
+        let num_blocks = len.div_ceil(BLOCK_SIZE).max(1);
+        let mut segments = self.segments.lock();
+        let (segment, start) = segments
+            .iter()
+            .enumerate()
+            .find_map(|(index, seg)| Some((index, seg.first_fit(num_blocks)?)))?;
+        let seg = segments.get_mut(segment)?;
+        seg.blocks.get_mut(start..start + num_blocks)?.fill(true);
+        Some(StagingBuf {
+            pool: Arc::clone(self),
+            segment,
+            addr: seg.buf.ptr as u64 + (start * BLOCK_SIZE) as u64,
+            lkey: seg.lkey,
+            start,
+            len,
+            num_blocks,
+        })
+    }
+}
+
+/// Staging memory allocated from a `StagingPool`, freed on drop
+pub(crate) struct StagingBuf {
+    /// The pool the buffer is allocated from
+    pool: Arc<StagingPool>,
+    /// Index of the segment within the pool
+    segment: usize,
+    /// Start address
+    addr: u64,
+    /// Local key of the segment
+    lkey: u32,
+    /// First block within the segment
+    start: usize,
+    /// Length in bytes
+    len: usize,
+    /// Number of blocks allocated
+    num_blocks: usize,
+}
+
+impl StagingBuf {
+    /// Returns the SGE describing the buffer
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)] // allocated from a u32
+    pub(crate) fn sge(&self) -> Sge {
+        Sge::new(self.addr, self.len as u32, self.lkey)
+    }
+
+    /// Copies the bytes of `src` into the buffer, starting at `offset`
+    ///
+    /// # Safety
+    ///
+    /// The SGEs must describe readable memory, i.e. buffers of registered memory regions.
+    #[allow(clippy::as_conversions)] // converting u64 to pointer
+    pub(crate) unsafe fn gather(&self, offset: usize, src: impl IntoIterator<Item = Sge>) {
+        let mut offset = offset;
+        for sge in src {
+            let len = sge.length as usize;
+            debug_assert!(offset + len <= self.len, "gather out of bounds");
+            // SAFETY: the destination lies within the buffer, the caller guarantees the
+            // source is readable
+            unsafe {
+                ptr::copy_nonoverlapping(sge.addr as *const u8, self.as_ptr().add(offset), len);
+            }
+            offset += len;
+        }
+    }
+
+    /// Copies the buffer into the memory described by `dst`, in order
+    ///
+    /// # Safety
+    ///
+    /// The SGEs must describe writable memory, i.e. buffers of registered memory regions.
+    #[allow(clippy::as_conversions)] // converting u64 to pointer
+    pub(crate) unsafe fn scatter(&self, dst: impl IntoIterator<Item = Sge>) {
+        let mut offset = 0;
+        for sge in dst {
+            let len = sge.length as usize;
+            debug_assert!(offset + len <= self.len, "scatter out of bounds");
+            // SAFETY: the source lies within the buffer, the caller guarantees the
+            // destination is writable
+            unsafe {
+                ptr::copy_nonoverlapping(self.as_ptr().add(offset), sge.addr as *mut u8, len);
+            }
+            offset += len;
+        }
+    }
+
+    /// Returns a pointer to the start of the buffer
+    #[allow(clippy::as_conversions)] // converting u64 to pointer
+    fn as_ptr(&self) -> *mut u8 {
+        self.addr as *mut u8
+    }
+}
+
+impl Drop for StagingBuf {
+    fn drop(&mut self) {
+        let mut segments = self.pool.segments.lock();
+        if let Some(blocks) = segments
+            .get_mut(self.segment)
+            .and_then(|seg| seg.blocks.get_mut(self.start..self.start + self.num_blocks))
+        {
+            blocks.fill(false);
+        }
+    }
+}
+
+impl fmt::Debug for StagingBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StagingBuf")
+            .field("sge", &self.sge())
+            .finish_non_exhaustive()
+    }
+}
+
+/// An RDMA Read that lands in a staging buffer, the data is scattered to the local
+/// buffers of the WR once the read response completes
+#[derive(Debug)]
+pub(crate) struct ReadScatter {
+    /// Staging buffer the response is written to
+    buf: StagingBuf,
+    /// Local buffers of the WR
+    sg_list: SgeList,
+}
+
+impl ReadScatter {
+    pub(crate) fn new(buf: StagingBuf, sg_list: SgeList) -> Self {
+        Self { buf, sg_list }
+    }
+
+    /// Copies the read data to the local buffers
+    pub(crate) fn scatter(self) {
+        // SAFETY: the SGEs were validated against the local write access of their
+        // memory regions when the WR was posted
+        unsafe {
+            self.buf.scatter(self.sg_list.as_slice().iter().copied());
+        }
+    }
+}
+
+/// Staging pools of the PDs, created when a PD first posts a WR that needs staging
+pub(crate) struct StagingPoolTable {
+    /// Pools keyed by PD handle
+    inner: Arc<Mutex<HashMap<u32, Arc<StagingPool>>>>,
+}
+
+impl StagingPoolTable {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn clone_arc(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    /// Returns the pool of the PD
+    pub(crate) fn get(&self, pd_handle: u32) -> Option<Arc<StagingPool>> {
+        self.inner.lock().get(&pd_handle).map(Arc::clone)
+    }
+
+    pub(crate) fn insert(&self, pd_handle: u32, pool: StagingPool) {
+        let _prev = self.inner.lock().insert(pd_handle, Arc::new(pool));
+    }
+
+    /// Removes the pool of the PD, buffers still in use keep its memory alive
+    pub(crate) fn remove(&self, pd_handle: u32) -> Option<Arc<StagingPool>> {
+        self.inner.lock().remove(&pd_handle)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mem::page::MmapMut;
+
+    use super::*;
+
+    fn segment(len: usize) -> DmaBuf {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(ptr, libc::MAP_FAILED, "mmap failed");
+        DmaBuf::new(MmapMut::new(ptr, len), 0)
+    }
+
+    fn pool(len: usize) -> Arc<StagingPool> {
+        Arc::new(StagingPool::new(segment(len), 7))
+    }
+
+    fn base(pool: &StagingPool) -> u64 {
+        pool.regions()[0].0
+    }
+
+    #[test]
+    fn alloc_first_fit_and_free() {
+        let pool = pool(4 * BLOCK_SIZE);
+        let a = pool.alloc(1).unwrap();
+        let b = pool.alloc(2 * BLOCK_SIZE as u32).unwrap();
+        assert_eq!(a.sge().addr, base(&pool));
+        assert_eq!(b.sge().addr, base(&pool) + BLOCK_SIZE as u64);
+        assert_eq!(b.sge().lkey, 7);
+        assert!(pool.alloc(2 * BLOCK_SIZE as u32).is_none());
+        drop(a);
+        let c = pool.alloc(BLOCK_SIZE as u32).unwrap();
+        assert_eq!(c.sge().addr, base(&pool));
+        drop(b);
+        let d = pool.alloc(3 * BLOCK_SIZE as u32).unwrap();
+        assert_eq!(d.sge().addr, base(&pool) + BLOCK_SIZE as u64);
+    }
+
+    #[test]
+    fn grow_adds_a_segment() {
+        let pool = pool(2 * BLOCK_SIZE);
+        let a = pool.alloc(2 * BLOCK_SIZE as u32).unwrap();
+        assert!(!pool.has_room(1));
+        pool.grow(segment(4 * BLOCK_SIZE), 8);
+        assert!(pool.has_room(4 * BLOCK_SIZE));
+        assert!(!pool.has_room(5 * BLOCK_SIZE));
+        let b = pool.alloc(3 * BLOCK_SIZE as u32).unwrap();
+        let regions = pool.regions();
+        assert_eq!(b.sge().addr, regions[1].0);
+        assert_eq!(b.sge().lkey, 8);
+        drop(a);
+        let c = pool.alloc(BLOCK_SIZE as u32).unwrap();
+        assert_eq!(c.sge().addr, regions[0].0);
+        assert_eq!(c.sge().lkey, 7);
+    }
+
+    #[test]
+    fn segment_size_follows_send_queue() {
+        assert_eq!(segment_size(1, 256, 0), MIN_SEGMENT_SIZE);
+        assert_eq!(segment_size(2048, 1024, 0), 14 << 20);
+        assert_eq!(segment_size(u32::MAX, 4096, 0), MAX_SEGMENT_SIZE);
+        assert_eq!(
+            segment_size(1, 256, MAX_SEGMENT_SIZE + 1),
+            MAX_SEGMENT_SIZE + PAGE_SIZE
+        );
+    }
+
+    #[test]
+    fn gather_then_scatter() {
+        let pool = pool(4 * BLOCK_SIZE);
+        let src: Vec<u8> = (0..10).collect();
+        let mut dst = [0u8; 10];
+        let (src_addr, dst_addr) = (src.as_ptr() as u64, dst.as_mut_ptr() as u64);
+        let sge = |addr: u64, start: u64, end: u64| Sge::new(addr + start, (end - start) as u32, 0);
+        let buf = pool.alloc(10).unwrap();
+        unsafe {
+            buf.gather(0, [sge(src_addr, 0, 3)]);
+            buf.gather(3, [sge(src_addr, 3, 4), sge(src_addr, 4, 10)]);
+        }
+        let sg_list = SgeList::new(&[sge(dst_addr, 0, 6), sge(dst_addr, 6, 10)]).unwrap();
+        ReadScatter::new(buf, sg_list).scatter();
+        assert_eq!(dst.as_slice(), src.as_slice());
+    }
+}
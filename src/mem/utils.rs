@@ -1,4 +1,4 @@
-use std::io;
+use std::{collections::HashMap, io};
 
 use crate::mem::PAGE_SIZE;
 
@@ -31,6 +31,83 @@ pub(crate) fn unpin_pages(addr: u64, length: usize) -> io::Result<()> {
     Ok(())
 }
 
+/// Pin counts of the pages locked by memory regions
+///
+/// Locks do not nest, a single `munlock` unlocks a page however many regions locked it.
+/// A page shared by several regions stays locked until the last of them unpins it.
+#[derive(Debug, Default)]
+pub(crate) struct PinnedPages {
+    /// Number of pins of each page, keyed by the page address
+    counts: HashMap<u64, usize>,
+}
+
+impl PinnedPages {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pins the pages of a range
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pages could not be locked in memory
+    pub(crate) fn pin(&mut self, addr: u64, length: usize) -> io::Result<()> {
+        // locking a locked page is a no-op
+        pin_pages(addr, length)?;
+        for page in page_addrs(addr, length) {
+            let count = self.counts.entry(page).or_default();
+            *count = count.saturating_add(1);
+        }
+        Ok(())
+    }
+
+    /// Unpins the pages of a range, pages no longer pinned by any range are unlocked
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pages could not be unlocked
+    #[allow(clippy::as_conversions)]
+    pub(crate) fn unpin(&mut self, addr: u64, length: usize) -> io::Result<()> {
+        let mut result = Ok(());
+        let mut unlock = |start: u64, end: u64| {
+            if let Err(err) = unpin_pages(start, end.saturating_sub(start) as usize) {
+                result = Err(err);
+            }
+        };
+        let mut run: Option<(u64, u64)> = None;
+        for page in page_addrs(addr, length) {
+            let Some(count) = self.counts.get_mut(&page) else {
+                continue;
+            };
+            *count = count.saturating_sub(1);
+            if *count != 0 {
+                continue;
+            }
+            let _ignore = self.counts.remove(&page);
+            let page_end = page.saturating_add(PAGE_SIZE as u64);
+            run = match run {
+                Some((start, end)) if end == page => Some((start, page_end)),
+                Some((start, end)) => {
+                    unlock(start, end);
+                    Some((page, page_end))
+                }
+                None => Some((page, page_end)),
+            };
+        }
+        if let Some((start, end)) = run {
+            unlock(start, end);
+        }
+        result
+    }
+}
+
+/// Returns the addresses of the pages spanned by a range
+#[allow(clippy::as_conversions)]
+fn page_addrs(addr: u64, length: usize) -> impl Iterator<Item = u64> {
+    let start = addr & !(PAGE_SIZE as u64 - 1);
+    (start..addr.saturating_add(length as u64)).step_by(PAGE_SIZE)
+}
+
 /// Calculates the number of pages spanned by a memory region.
 #[allow(clippy::arithmetic_side_effects)]
 pub(crate) fn get_num_page(addr: u64, length: usize) -> usize {
@@ -42,3 +119,24 @@ pub(crate) fn get_num_page(addr: u64, length: usize) -> usize {
     let end_page = last / PAGE_SIZE as u64;
     (end_page.saturating_sub(start_page) + 1) as usize
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::as_conversions)]
+    fn shared_pages_stay_pinned() {
+        let buf = vec![0u8; 4 * PAGE_SIZE];
+        let page = PAGE_SIZE as u64;
+        let addr = (buf.as_ptr() as u64 + page - 1) & !(page - 1);
+        let mut pinned = PinnedPages::new();
+        pinned.pin(addr, 2 * PAGE_SIZE).unwrap();
+        pinned.pin(addr + page, PAGE_SIZE).unwrap();
+        pinned.unpin(addr, 2 * PAGE_SIZE).unwrap();
+        assert!(!pinned.counts.contains_key(&addr));
+        assert_eq!(pinned.counts.get(&(addr + page)), Some(&1));
+        pinned.unpin(addr + page, PAGE_SIZE).unwrap();
+        assert!(pinned.counts.is_empty());
+    }
+}
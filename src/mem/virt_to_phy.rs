@@ -9,6 +9,8 @@ const PFN_MASK_SIZE: usize = 8;
 const PFN_MASK: u64 = (1 << 55) - 1;
 /// Bit indicating if a page is present in memory
 const PAGE_PRESENT_BIT: u8 = 63;
+/// Number of pagemap entries read at once
+const PAGEMAP_READ_BATCH: usize = 4096;
 
 #[cfg(feature = "page_size_2m")]
 const PAGE_SIZE: u64 = 0x20_0000;
@@ -108,57 +110,68 @@ impl AddressResolver for PhysAddrResolverLinuxX86 {
         start_addr: u64,
         num_pages: usize,
     ) -> io::Result<Vec<Option<u64>>> {
-        let base_page_size = get_base_page_size();
-        let mut phy_addrs = vec![None; num_pages];
-        let mut file = File::open("/proc/self/pagemap")?;
-        let mut buf = [0u8; PFN_MASK_SIZE];
-
-        let mut maybe_gpu_ptr = true;
-
-        let mut addr = start_addr;
-        for i in 0..num_pages {
-            let virt_pfn = addr / base_page_size;
-            let offset = PFN_MASK_SIZE as u64 * virt_pfn;
-            let _pos = file.seek(io::SeekFrom::Start(offset))?;
-            file.read_exact(&mut buf)?;
-            let entry = u64::from_ne_bytes(buf);
-            if (entry >> PAGE_PRESENT_BIT) & 1 != 0 {
-                let phys_pfn = entry & PFN_MASK;
-                let phys_addr = phys_pfn * base_page_size + start_addr % base_page_size;
-                phy_addrs[i] = Some(phys_addr);
-
-                maybe_gpu_ptr = false;
-            }
-
-            addr += PAGE_SIZE;
+        let file = File::open("/proc/self/pagemap")?;
+        let phy_addrs = read_pagemap_range(file, start_addr, num_pages)?;
+        if phy_addrs.iter().any(Option::is_some) || File::open("/dev/gpu_ptr_translator").is_err() {
+            return Ok(phy_addrs);
         }
 
-        if maybe_gpu_ptr {
-            debug_assert!(phy_addrs.iter().all(|opt| opt.is_none()));
-
-            let Ok(mut gpu_ptr_translator) = File::open("/dev/gpu_ptr_translator") else {
-                return Ok(phy_addrs);
-            };
-
-            addr = start_addr;
-            for i in 0..num_pages {
-                let virt_pfn = addr / base_page_size;
-                let offset = PFN_MASK_SIZE as u64 * virt_pfn;
-                let _pos = file.seek(io::SeekFrom::Start(offset))?;
-                file.read_exact(&mut buf)?;
-                let entry = u64::from_ne_bytes(buf);
-                if (entry >> PAGE_PRESENT_BIT) & 1 != 0 {
-                    let phys_pfn = entry & PFN_MASK;
-                    let phys_addr = phys_pfn * base_page_size + start_addr % base_page_size;
-                    phy_addrs[i] = Some(phys_addr);
-                }
-
-                addr += PAGE_SIZE;
+        // the translator is read page by page
+        (0..num_pages as u64)
+            .map(|x| self.virt_to_phys(start_addr.saturating_add(x * PAGE_SIZE)))
+            .collect()
+    }
+}
+
+/// Reads the physical addresses of `num_pages` consecutive pages from `/proc/self/pagemap`
+///
+/// The entries of consecutive base pages are adjacent in the file, so they are read in
+/// batches instead of one seek and read per page.
+#[allow(
+    clippy::as_conversions,
+    clippy::arithmetic_side_effects,
+    clippy::host_endian_bytes
+)]
+fn read_pagemap_range(
+    mut file: File,
+    start_addr: u64,
+    num_pages: usize,
+) -> io::Result<Vec<Option<u64>>> {
+    if num_pages == 0 {
+        return Ok(vec![]);
+    }
+    let base_page_size = get_base_page_size();
+    // number of pagemap entries per page
+    let stride = (PAGE_SIZE / base_page_size).max(1) as usize;
+    let offset = PFN_MASK_SIZE as u64 * (start_addr / base_page_size);
+    let _pos = file.seek(io::SeekFrom::Start(offset))?;
+
+    let mut phy_addrs = Vec::with_capacity(num_pages);
+    let mut buf = vec![0u8; PAGEMAP_READ_BATCH * PFN_MASK_SIZE];
+    let mut remaining = (num_pages - 1) * stride + 1;
+    let mut entry_index = 0;
+    while remaining != 0 {
+        let num_entries = remaining.min(PAGEMAP_READ_BATCH);
+        let bytes = buf
+            .get_mut(..num_entries * PFN_MASK_SIZE)
+            .unwrap_or_else(|| unreachable!("bounded by the batch size"));
+        file.read_exact(bytes)?;
+        for (i, chunk) in bytes.chunks_exact(PFN_MASK_SIZE).enumerate() {
+            if (entry_index + i) % stride != 0 {
+                continue;
             }
+            let mut entry_bytes = [0u8; PFN_MASK_SIZE];
+            entry_bytes.copy_from_slice(chunk);
+            let entry = u64::from_ne_bytes(entry_bytes);
+            let phys_addr = ((entry >> PAGE_PRESENT_BIT) & 1 != 0)
+                .then(|| (entry & PFN_MASK) * base_page_size + start_addr % base_page_size);
+            phy_addrs.push(phys_addr);
         }
-
-        Ok(phy_addrs)
+        entry_index += num_entries;
+        remaining -= num_entries;
     }
+
+    Ok(phy_addrs)
 }
 
 pub(crate) struct PhysAddrResolverEmulated {
@@ -1,12 +1,16 @@
 use crate::{
     ack_responder::AckResponse,
-    completion::{CompletionTask, Event, MessageMeta, RecvEvent, RecvEventOp},
+    completion::{CompletionStatus, CompletionTask, Event, MessageMeta, RecvEvent, RecvEventOp},
     constants::PSN_MASK,
+    dcqcn::RateLimiter,
     device_protocol::{
-        AckMetaLocalHw, AckMetaRemoteDriver, HeaderReadMeta, HeaderType, HeaderWriteMeta,
+        AckMetaLocalHw, AckMetaRemoteDriver, CnpMeta, HeaderReadMeta, HeaderType, HeaderWriteMeta,
         NakMetaLocalHw, NakMetaRemoteDriver, NakMetaRemoteHw, PacketPos, WorkReqOpCode,
     },
+    mr::MrTable,
+    mw::MwTable,
     packet_retransmit::PacketRetransmitTask,
+    qp::QueuePairAttrTable,
     rdma_write_worker::RdmaWriteTask,
     send::{SendWrBase, SendWrRdma},
     timeout_retransmit::RetransmitTask,
@@ -17,6 +21,9 @@ use crate::{
 use super::ReportMeta;
 
 pub(crate) struct MetaHandler {
+    pub(super) qp_attr_table: QueuePairAttrTable,
+    pub(super) mr_table: MrTable,
+    pub(super) mw_table: MwTable,
     pub(super) send_table: QpTable<RemoteAckTracker>,
     pub(super) recv_table: QpTable<LocalAckTracker>,
     pub(super) ack_tx: flume::Sender<AckResponse>,
@@ -24,17 +31,26 @@ pub(crate) struct MetaHandler {
     pub(super) packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
     pub(super) completion_tx: flume::Sender<CompletionTask>,
     pub(super) rdma_write_tx: flume::Sender<RdmaWriteTask>,
+    pub(super) rate_limiter: Option<RateLimiter>,
 }
 
 impl MetaHandler {
+    #[allow(clippy::too_many_arguments, clippy::similar_names)]
     pub(crate) fn new(
+        qp_attr_table: QueuePairAttrTable,
+        mr_table: MrTable,
+        mw_table: MwTable,
         ack_tx: flume::Sender<AckResponse>,
         retransmit_tx: flume::Sender<RetransmitTask>,
         packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
         completion_tx: flume::Sender<CompletionTask>,
         rdma_write_tx: flume::Sender<RdmaWriteTask>,
+        rate_limiter: Option<RateLimiter>,
     ) -> Self {
         Self {
+            qp_attr_table,
+            mr_table,
+            mw_table,
             send_table: QpTable::new(),
             recv_table: QpTable::new(),
             ack_tx,
@@ -42,6 +58,7 @@ impl MetaHandler {
             packet_retransmit_tx,
             completion_tx,
             rdma_write_tx,
+            rate_limiter,
         }
     }
 
@@ -54,10 +71,16 @@ impl MetaHandler {
             ReportMeta::NakLocalHw(x) => self.handle_nak_local_hw(x),
             ReportMeta::NakRemoteHw(x) => self.handle_nak_remote_hw(x),
             ReportMeta::NakRemoteDriver(x) => self.handle_nak_remote_driver(x),
-            ReportMeta::Cnp { .. } => todo!(),
+            ReportMeta::Cnp(x) => self.handle_cnp(x),
         }
     }
 
+    fn handle_cnp(&self, meta: CnpMeta) -> Option<()> {
+        self.rate_limiter.as_ref()?.on_cnp(meta.qpn);
+
+        Some(())
+    }
+
     fn handle_ack_local_hw(&mut self, meta: AckMetaLocalHw) -> Option<()> {
         let tracker = self.recv_table.get_qp_mut(meta.qpn)?;
         if let Some(psn) = tracker.ack_bitmap(meta.psn_now, meta.now_bitmap) {
@@ -117,6 +140,14 @@ impl MetaHandler {
             self.sender_updates(meta.qpn, psn);
         }
 
+        if meta.is_access_error {
+            let _ignore = self.completion_tx.send(CompletionTask::Error {
+                qpn: meta.qpn,
+                status: CompletionStatus::RemoteAccessError,
+            });
+            return Some(());
+        }
+
         let _ignore = self
             .packet_retransmit_tx
             .send(PacketRetransmitTask::RetransmitRange {
@@ -132,6 +163,9 @@ impl MetaHandler {
         let _ignore = self
             .completion_tx
             .send(CompletionTask::AckSend { qpn, base_psn });
+        let _ignore = self
+            .retransmit_tx
+            .send(RetransmitTask::ReceiveACK { qpn, psn: base_psn });
         let _ignore = self
             .packet_retransmit_tx
             .send(PacketRetransmitTask::Ack { qpn, psn: base_psn });
@@ -149,7 +183,43 @@ impl MetaHandler {
             .send(PacketRetransmitTask::Ack { qpn, psn: base_psn });
     }
 
+    /// Checks if the local QP grants the given remote access
+    fn has_remote_access(&self, qpn: u32, access: ibverbs_sys::ibv_access_flags) -> bool {
+        self.qp_attr_table
+            .get(qpn)
+            .is_some_and(|qp| u32::from(qp.access_flags) & access.0 != 0)
+    }
+
+    /// Checks that `rkey` refers to an MR or a bound window in the PD of the local QP
+    ///
+    /// A window is checked through the MR it is bound to.
+    fn rkey_in_qp_pd(&self, qpn: u32, rkey: u32) -> bool {
+        let Some(qp) = self.qp_attr_table.get(qpn) else {
+            return false;
+        };
+        self.mr_table
+            .get(rkey)
+            .or_else(|| {
+                self.mw_table
+                    .bound_mr(rkey)
+                    .and_then(|mr_key| self.mr_table.get(mr_key))
+            })
+            .is_some_and(|mr| mr.pd_handle == qp.pd_handle)
+    }
+
     pub(super) fn handle_header_read(&mut self, meta: HeaderReadMeta) -> Option<()> {
+        if !self.has_remote_access(
+            meta.dqpn,
+            ibverbs_sys::ibv_access_flags::IBV_ACCESS_REMOTE_READ,
+        ) || !self.rkey_in_qp_pd(meta.dqpn, meta.rkey)
+        {
+            let _ignore = self.ack_tx.send(AckResponse::AccessNak {
+                qpn: meta.dqpn,
+                psn: meta.psn,
+            });
+            return Some(());
+        }
+
         if meta.ack_req {
             let end_psn = meta.psn + 1;
             let event = Event::Recv(RecvEvent::new(
@@ -206,48 +276,42 @@ impl MetaHandler {
             imm,
             header_type,
         } = meta;
+        if matches!(header_type, HeaderType::Write | HeaderType::WriteWithImm)
+            && (!self
+                .has_remote_access(dqpn, ibverbs_sys::ibv_access_flags::IBV_ACCESS_REMOTE_WRITE)
+                || !self.rkey_in_qp_pd(dqpn, rkey))
+        {
+            // NAKs the first packet, the rest of the message is dropped
+            if matches!(pos, PacketPos::First | PacketPos::Only) {
+                let _ignore = self.ack_tx.send(AckResponse::AccessNak { qpn: dqpn, psn });
+            }
+            return Some(());
+        }
         let tracker = self.recv_table.get_qp_mut(dqpn)?;
 
         if matches!(pos, PacketPos::Last | PacketPos::Only) {
             let end_psn = psn + 1;
-            match header_type {
-                HeaderType::Write => {}
-                HeaderType::WriteWithImm => {
-                    let event = Event::Recv(RecvEvent::new(
-                        RecvEventOp::WriteWithImm { imm },
-                        MessageMeta::new(msn, end_psn),
-                    ));
-                    let _ignore = self
-                        .completion_tx
-                        .send(CompletionTask::Register { qpn: dqpn, event });
-                }
-                HeaderType::Send => {
-                    let event = Event::Recv(RecvEvent::new(
-                        RecvEventOp::Recv,
-                        MessageMeta::new(msn, end_psn),
-                    ));
-                    let _ignore = self
-                        .completion_tx
-                        .send(CompletionTask::Register { qpn: dqpn, event });
-                }
-                HeaderType::SendWithImm => {
-                    let event = Event::Recv(RecvEvent::new(
-                        RecvEventOp::RecvWithImm { imm },
-                        MessageMeta::new(msn, end_psn),
-                    ));
-                    let _ignore = self
-                        .completion_tx
-                        .send(CompletionTask::Register { qpn: dqpn, event });
-                }
-                HeaderType::ReadResp => {
-                    let event = Event::Recv(RecvEvent::new(
-                        RecvEventOp::ReadResp,
-                        MessageMeta::new(msn, end_psn),
-                    ));
-                    let _ignore = self
-                        .completion_tx
-                        .send(CompletionTask::Register { qpn: dqpn, event });
-                }
+            let op = match header_type {
+                HeaderType::Write => None,
+                HeaderType::WriteWithImm => Some(RecvEventOp::WriteWithImm { imm }),
+                HeaderType::Send => Some(RecvEventOp::Recv { len: total_len }),
+                HeaderType::SendWithImm => Some(RecvEventOp::RecvWithImm {
+                    imm,
+                    len: total_len,
+                }),
+                HeaderType::SendWithInv => Some(RecvEventOp::RecvWithInv {
+                    rkey: imm,
+                    len: total_len,
+                }),
+                HeaderType::ReadResp => Some(RecvEventOp::ReadResp),
+            };
+            if let Some(op) = op {
+                let event = Event::Recv(
+                    RecvEvent::new(op, MessageMeta::new(msn, end_psn)).with_solicited(solicited),
+                );
+                let _ignore = self
+                    .completion_tx
+                    .send(CompletionTask::Register { qpn: dqpn, event });
             }
             if ack_req {
                 let event = Event::Recv(RecvEvent::new(
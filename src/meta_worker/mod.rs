@@ -19,6 +19,7 @@ use crate::{
     completion::CompletionTask,
     device_protocol::{MetaReport, ReportMeta},
     packet_retransmit::PacketRetransmitTask,
+    poll::AdaptivePoller,
     qp::QueuePairAttrTable,
     rdma_write_worker::RdmaWriteTask,
     timeout_retransmit::RetransmitTask,
@@ -29,11 +30,16 @@ pub(crate) struct MetaWorker<T> {
     /// Inner meta report queue
     inner: T,
     handler: MetaHandler,
+    poller: AdaptivePoller,
 }
 
 impl<T: MetaReport + Send + 'static> MetaWorker<T> {
-    pub(crate) fn new(inner: T, handler: MetaHandler) -> Self {
-        Self { inner, handler }
+    pub(crate) fn new(inner: T, handler: MetaHandler, poller: AdaptivePoller) -> Self {
+        Self {
+            inner,
+            handler,
+            poller,
+        }
     }
 
     pub(crate) fn spawn(self, is_shutdown: Arc<AtomicBool>) {
@@ -47,11 +53,16 @@ impl<T: MetaReport + Send + 'static> MetaWorker<T> {
     /// Run the handler loop
     fn run(mut self, is_shutdown: Arc<AtomicBool>) -> io::Result<()> {
         while !is_shutdown.load(Ordering::Relaxed) {
-            if let Some(meta) = self.inner.try_recv_meta()? {
-                if self.handler.handle_meta(meta).is_none() {
-                    error!("invalid meta: {meta:?}");
-                }
+            let Some(meta) = self.inner.try_recv_meta()? else {
+                // the meta report queue can only be checked by consuming it, the
+                // worker is woken by interrupts or by send workers submitting requests
+                self.poller.idle(|| false);
+                continue;
             };
+            self.poller.reset();
+            if self.handler.handle_meta(meta).is_none() {
+                error!("invalid meta: {meta:?}");
+            }
         }
 
         Ok(())
@@ -0,0 +1,78 @@
+use std::{collections::HashMap, sync::Arc};
+
+use ibverbs_sys::ibv_access_flags;
+use parking_lot::RwLock;
+
+use crate::mtt::PgtEntry;
+
+/// A registered memory region
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MemoryRegion {
+    pub(crate) addr: u64,
+    pub(crate) length: u64,
+    pub(crate) pd_handle: u32,
+    pub(crate) access: u8,
+}
+
+impl MemoryRegion {
+    pub(crate) fn new(addr: u64, length: u64, pd_handle: u32, access: u8) -> Self {
+        Self {
+            addr,
+            length,
+            pd_handle,
+            access,
+        }
+    }
+
+    /// Returns `true` if the range `[addr, addr + length)` lies within the region
+    pub(crate) fn contains(&self, addr: u64, length: u64) -> bool {
+        let Some(end) = addr.checked_add(length) else {
+            return false;
+        };
+        addr >= self.addr && end <= self.addr.saturating_add(self.length)
+    }
+
+    /// Returns `true` if the region was registered with the given access
+    pub(crate) fn allows(&self, access: ibv_access_flags) -> bool {
+        u32::from(self.access) & access.0 != 0
+    }
+}
+
+/// Registered MRs indexed by the MR key
+///
+/// The hardware keeps its own copy in the MTT, this table is used by the parts of the
+/// protocol executed in software.
+pub(crate) struct MrTable {
+    inner: Arc<RwLock<HashMap<u32, (MemoryRegion, PgtEntry)>>>,
+}
+
+impl MrTable {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn clone_arc(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    pub(crate) fn insert(&self, mr_key: u32, mr: MemoryRegion, pgt_entry: PgtEntry) {
+        let _prev = self.inner.write().insert(mr_key, (mr, pgt_entry));
+    }
+
+    pub(crate) fn remove(&self, mr_key: u32) -> Option<MemoryRegion> {
+        self.inner.write().remove(&mr_key).map(|(mr, _)| mr)
+    }
+
+    pub(crate) fn get(&self, mr_key: u32) -> Option<MemoryRegion> {
+        self.inner.read().get(&mr_key).map(|&(mr, _)| mr)
+    }
+
+    /// Returns the MR and the page table entries it is mapped by
+    pub(crate) fn get_with_pgt(&self, mr_key: u32) -> Option<(MemoryRegion, PgtEntry)> {
+        self.inner.read().get(&mr_key).copied()
+    }
+}
@@ -46,14 +46,31 @@ impl Alloc {
         self.pgt.dealloc(mr_index, length)
     }
 
-    fn alloc_mr_key(&mut self) -> Option<u32> {
+    /// Deallocates a key allocated without page table entries
+    pub(super) fn dealloc_key(&mut self, mr_key: u32) {
+        self.mr
+            .dealloc_mr_key(MrKeyIndex(mr_key >> LR_KEY_KEY_PART_WIDTH));
+    }
+
+    /// Returns the number of page table entries in use
+    pub(super) fn num_pgt_used(&self) -> usize {
+        self.pgt.free_list.count_ones()
+    }
+
+    /// Deallocates page table entries allocated by `alloc_pgt`
+    #[allow(clippy::as_conversions)]
+    pub(super) fn dealloc_pgt(&mut self, entry: PgtEntry) -> bool {
+        self.pgt.dealloc(entry.index as usize, entry.count as usize)
+    }
+
+    pub(super) fn alloc_mr_key(&mut self) -> Option<u32> {
         let mr_key_idx = self.mr.alloc_mr_key_idx()?;
         let key = rand::thread_rng().gen_range(0..1 << LR_KEY_KEY_PART_WIDTH);
         let mr_key = (mr_key_idx.0 << LR_KEY_KEY_PART_WIDTH) | key;
         Some(mr_key)
     }
 
-    fn alloc_pgt(&mut self, num_pages: usize) -> Option<PgtEntry> {
+    pub(super) fn alloc_pgt(&mut self, num_pages: usize) -> Option<PgtEntry> {
         let index = self.pgt.alloc(num_pages)? as u32;
         Some(PgtEntry {
             index,
@@ -62,6 +79,17 @@ impl Alloc {
     }
 }
 
+/// Returns the index part of a memory key, the key part changes on each memory window bind
+pub(crate) fn key_index(key: u32) -> u32 {
+    key >> LR_KEY_KEY_PART_WIDTH
+}
+
+/// Returns `key` with its key part incremented, mirrors `ibv_inc_rkey`
+pub(crate) fn inc_key(key: u32) -> u32 {
+    let mask = (1 << LR_KEY_KEY_PART_WIDTH) - 1;
+    (key & !mask) | (key.wrapping_add(1) & mask)
+}
+
 /// Memory region key
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub(crate) struct MrKeyIndex(u32);
@@ -3,11 +3,16 @@ mod alloc;
 
 use std::{collections::HashMap, io, mem::take};
 
-use alloc::Alloc;
+pub(crate) use alloc::{inc_key, key_index};
+use alloc::{Alloc, PGT_LEN};
+
+use ibverbs_sys::ibv_access_flags;
 
 use crate::{
-    device_protocol::MttUpdate,
-    mem::{get_num_page, page::ContiguousPages, virt_to_phy::AddressResolver, PAGE_SIZE},
+    device_protocol::{DeviceCommand, MttUpdate, PgtUpdate},
+    mem::{get_num_page, page::ContiguousPages, virt_to_phy::AddressResolver, DmaBuf, PAGE_SIZE},
+    mr::{MemoryRegion, MrTable},
+    send::Sge,
 };
 
 /// Memory Translation Table implementation
@@ -16,6 +21,8 @@ pub(crate) struct Mtt {
     alloc: Alloc,
     /// Table tracks `mr_key` to `PgtEntry` mapping
     mrkey_map: HashMap<u32, PgtEntry>,
+    /// Metadata of the registered memory regions, shared with the workers
+    mr_table: MrTable,
 }
 
 impl Mtt {
@@ -24,11 +31,28 @@ impl Mtt {
         Self {
             alloc: Alloc::new(),
             mrkey_map: HashMap::new(),
+            mr_table: MrTable::new(),
         }
     }
 
-    /// Register a memory region
-    pub(crate) fn register(&mut self, num_pages: usize) -> io::Result<(u32, PgtEntry)> {
+    /// Returns a handle to the table of registered memory regions
+    pub(crate) fn mr_table(&self) -> MrTable {
+        self.mr_table.clone_arc()
+    }
+
+    /// Returns the metadata of a registered memory region
+    pub(crate) fn get_mr(&self, mr_key: u32) -> Option<MemoryRegion> {
+        self.mr_table.get(mr_key)
+    }
+
+    /// Starts registering a memory region spanning `num_pages` pages
+    ///
+    /// The key is not visible to the rest of the driver until the registration commits.
+    pub(crate) fn begin_register(
+        &mut self,
+        num_pages: usize,
+        mr: MemoryRegion,
+    ) -> io::Result<Registration<'_>> {
         let (mr_key, pgt_entry) = self
             .alloc
             .alloc(num_pages)
@@ -38,7 +62,55 @@ impl Mtt {
             "mr_key exist"
         );
 
-        Ok((mr_key, pgt_entry))
+        Ok(Registration {
+            mtt: self,
+            mr_key,
+            pgt_entry,
+            mr,
+            source: PgtSource::NewKey,
+            invalidated: false,
+            committed: false,
+        })
+    }
+
+    /// Starts re-registering `mr_key` with a region spanning `num_pages` pages
+    ///
+    /// The page table entries of the key are reused if the new region fits in them,
+    /// the previous registration stays in place until the new one commits.
+    pub(crate) fn begin_reregister(
+        &mut self,
+        mr_key: u32,
+        num_pages: usize,
+        mr: MemoryRegion,
+    ) -> io::Result<Registration<'_>> {
+        let prev = self
+            .pgt_entry(mr_key)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        let count = u32::try_from(num_pages)
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let (pgt_entry, source) = if count <= prev.count {
+            let entry = PgtEntry {
+                index: prev.index,
+                count,
+            };
+            (entry, PgtSource::Reuse(prev))
+        } else {
+            let entry = self
+                .alloc
+                .alloc_pgt(num_pages)
+                .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))?;
+            (entry, PgtSource::Replace(prev))
+        };
+
+        Ok(Registration {
+            mtt: self,
+            mr_key,
+            pgt_entry,
+            mr,
+            source,
+            invalidated: false,
+            committed: false,
+        })
     }
 
     /// Deregister a memory region
@@ -47,6 +119,7 @@ impl Mtt {
             .mrkey_map
             .remove(&mr_key)
             .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        let _mr = self.mr_table.remove(mr_key);
         if !self
             .alloc
             .dealloc(mr_key, entry.index as usize, entry.count as usize)
@@ -56,6 +129,58 @@ impl Mtt {
         Ok(())
     }
 
+    /// Allocates a key without page table entries, used by memory windows
+    pub(crate) fn alloc_key(&mut self) -> io::Result<u32> {
+        self.alloc
+            .alloc_mr_key()
+            .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))
+    }
+
+    /// Frees a key allocated by `alloc_key`
+    pub(crate) fn free_key(&mut self, key: u32) {
+        self.alloc.dealloc_key(key);
+    }
+
+    /// Returns the number of page table entries in use and the size of the table
+    pub(crate) fn pgt_usage(&self) -> (usize, usize) {
+        (self.alloc.num_pgt_used(), PGT_LEN)
+    }
+
+    /// Returns the page table entries of a registered memory region
+    pub(crate) fn pgt_entry(&self, mr_key: u32) -> Option<PgtEntry> {
+        self.mrkey_map.get(&mr_key).copied()
+    }
+
+    /// Validates a local buffer against the registered memory regions
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` error if:
+    /// - The lkey does not refer to a registered memory region
+    /// - The memory region belongs to another PD
+    /// - The buffer is not within the bounds of the memory region
+    /// - The memory region was not registered with `access`
+    pub(crate) fn validate_sge(
+        &self,
+        pd_handle: u32,
+        sge: &Sge,
+        access: ibv_access_flags,
+    ) -> io::Result<()> {
+        // zero-length buffers are never accessed
+        if sge.length == 0 {
+            return Ok(());
+        }
+        let valid = self.mr_table.get(sge.lkey).is_some_and(|mr| {
+            mr.pd_handle == pd_handle
+                && mr.contains(sge.addr, u64::from(sge.length))
+                && (access.0 == 0 || mr.allows(access))
+        });
+        if !valid {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        Ok(())
+    }
+
     /// Validates memory region parameters
     ///
     /// # Errors
@@ -102,8 +227,397 @@ impl Mtt {
     }
 }
 
+/// A memory region registration in progress
+///
+/// Page table entries are written before the MR entry, so the card never translates the
+/// key through a partially written page table. A registration dropped before it commits
+/// releases its key and page table entries, a failed re-registration is rolled back with
+/// `restore`.
+pub(crate) struct Registration<'a> {
+    /// The table the registration is made in
+    mtt: &'a mut Mtt,
+    /// Key of the memory region
+    mr_key: u32,
+    /// Page table entries allocated for the memory region
+    pgt_entry: PgtEntry,
+    /// Metadata of the memory region
+    mr: MemoryRegion,
+    /// Where the page table entries come from
+    source: PgtSource,
+    /// Whether the previous registration of the key was invalidated to overwrite its entries
+    invalidated: bool,
+    /// Whether the MR entry has been committed
+    committed: bool,
+}
+
+/// Where the page table entries of a registration come from
+#[derive(Clone, Copy)]
+enum PgtSource {
+    /// Allocated together with a new key
+    NewKey,
+    /// Allocated to replace the given entries of an existing key
+    Replace(PgtEntry),
+    /// A prefix of the given entries of an existing key
+    Reuse(PgtEntry),
+}
+
+impl Registration<'_> {
+    /// Maximum number of Page Table entries (PGT entries) written by a single update command.
+    /// Each PGT entry is a u64 (8 bytes), so a command reads 512 bytes / 8 bytes per entry
+    /// = 64 entries.
+    const MAX_NUM_PGT_ENTRY_PER_UPDATE: usize = 64;
+
+    /// Writes the physical addresses of the pages into the page table
+    pub(crate) fn write_pgt(
+        &mut self,
+        cmd: &dyn DeviceCommand,
+        buf: &mut DmaBuf,
+        phys_addrs: &[u64],
+    ) -> io::Result<()> {
+        if let (PgtSource::Reuse(_), Some(prev)) = (self.source, self.mtt.mr_table.get(self.mr_key))
+        {
+            // the card must not translate the key through the entries being overwritten
+            self.invalidated = true;
+            cmd.update_mtt(MttUpdate::new(0, 0, self.mr_key, prev.pd_handle, 0, 0))?;
+        }
+        Self::write_entries(cmd, buf, self.pgt_entry, phys_addrs)
+    }
+
+    /// Writes the physical addresses of the pages into the page table entries of `pgt_entry`
+    ///
+    /// The card translates every page through its own entry, there is no larger entry
+    /// format, so physically contiguous pages still take one entry each. Memory windows
+    /// also locate their pages by offset into the entries of the MR. The entries are
+    /// staged in `buf`, each batch holds as many commands as fit in the buffer.
+    #[allow(clippy::arithmetic_side_effects, clippy::as_conversions)] // bounded by the PGT size
+    fn write_entries(
+        cmd: &dyn DeviceCommand,
+        buf: &mut DmaBuf,
+        pgt_entry: PgtEntry,
+        phys_addrs: &[u64],
+    ) -> io::Result<()> {
+        if phys_addrs.len() != pgt_entry.count as usize {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let chunk_size = Self::MAX_NUM_PGT_ENTRY_PER_UPDATE * size_of::<u64>();
+        let chunks_per_batch = buf.buf.len() / chunk_size;
+        if chunks_per_batch == 0 {
+            return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+        }
+        let mut index = pgt_entry.index;
+        for batch in phys_addrs.chunks(Self::MAX_NUM_PGT_ENTRY_PER_UPDATE * chunks_per_batch) {
+            let mut updates = Vec::with_capacity(chunks_per_batch);
+            let chunks = batch.chunks(Self::MAX_NUM_PGT_ENTRY_PER_UPDATE);
+            for (offset, entries) in (0..).step_by(chunk_size).zip(chunks) {
+                let count = entries.len();
+                let bytes: Vec<u8> = entries.iter().copied().flat_map(u64::to_ne_bytes).collect();
+                buf.buf
+                    .get_mut(offset..offset + bytes.len())
+                    .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))?
+                    .copy_from_slice(&bytes);
+                updates.push(PgtUpdate::new(
+                    buf.phys_addr + offset as u64,
+                    index,
+                    count as u32 - 1,
+                ));
+                index += count as u32;
+            }
+            cmd.update_pgt_batch(&updates)?;
+        }
+
+        Ok(())
+    }
+
+    /// Commits the MR entry, the key is valid on the card once this returns
+    pub(crate) fn commit(&mut self, cmd: &dyn DeviceCommand) -> io::Result<u32> {
+        let mr = self.mr;
+        let length = u32::try_from(mr.length)
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let update = MttUpdate::new(
+            mr.addr,
+            length,
+            self.mr_key,
+            mr.pd_handle,
+            mr.access,
+            self.pgt_entry.index,
+        );
+        if let Err(err) = cmd.update_mtt(update) {
+            // the entry may have been written before the failure
+            let _ignore = cmd.update_mtt(MttUpdate::new(0, 0, self.mr_key, mr.pd_handle, 0, 0));
+            return Err(err.into());
+        }
+        self.mtt.mr_table.insert(self.mr_key, mr, self.pgt_entry);
+        let _prev = self.mtt.mrkey_map.insert(self.mr_key, self.pgt_entry);
+        match self.source {
+            PgtSource::NewKey => {}
+            PgtSource::Replace(prev) => {
+                let _ignore = self.mtt.alloc.dealloc_pgt(prev);
+            }
+            PgtSource::Reuse(prev) => {
+                // releases the entries the new region no longer uses
+                let tail = PgtEntry {
+                    index: prev.index.saturating_add(self.pgt_entry.count),
+                    count: prev.count.saturating_sub(self.pgt_entry.count),
+                };
+                let _ignore = self.mtt.alloc.dealloc_pgt(tail);
+            }
+        }
+        self.committed = true;
+
+        Ok(self.mr_key)
+    }
+
+    /// Returns the registration the key had before a re-registration
+    pub(crate) fn prev_mr(&self) -> Option<MemoryRegion> {
+        match self.source {
+            PgtSource::NewKey => None,
+            PgtSource::Replace(_) | PgtSource::Reuse(_) => self.mtt.mr_table.get(self.mr_key),
+        }
+    }
+
+    /// Rolls back a failed re-registration, the previous registration of the key is valid
+    /// on the card again once this returns
+    ///
+    /// `prev_phys_addrs` are the pages of the previous region, they are written back if
+    /// its page table entries were overwritten.
+    pub(crate) fn restore(
+        &mut self,
+        cmd: &dyn DeviceCommand,
+        buf: &mut DmaBuf,
+        prev_phys_addrs: &[u64],
+    ) -> io::Result<()> {
+        let (PgtSource::Replace(prev_entry) | PgtSource::Reuse(prev_entry)) = self.source else {
+            return Ok(());
+        };
+        if self.committed {
+            return Ok(());
+        }
+        let Some(prev) = self.mtt.mr_table.get(self.mr_key) else {
+            return Ok(());
+        };
+        if self.invalidated {
+            Self::write_entries(cmd, buf, prev_entry, prev_phys_addrs)?;
+        }
+        let length = u32::try_from(prev.length)
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+        cmd.update_mtt(MttUpdate::new(
+            prev.addr,
+            length,
+            self.mr_key,
+            prev.pd_handle,
+            prev.access,
+            prev_entry.index,
+        ))?;
+        self.invalidated = false;
+
+        Ok(())
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+        match self.source {
+            PgtSource::NewKey => {
+                let _ignore = self.mtt.deregister(self.mr_key);
+            }
+            PgtSource::Replace(_) => {
+                let _ignore = self.mtt.alloc.dealloc_pgt(self.pgt_entry);
+            }
+            // the previous entries stay with the key, overwritten ones are written back by
+            // `restore`
+            PgtSource::Reuse(_) => {}
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub(crate) struct PgtEntry {
     pub(crate) index: u32,
     pub(crate) count: u32,
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{device_protocol::mock::RecordCommand, mem::page::MmapMut};
+
+    use super::*;
+
+    #[allow(unsafe_code)]
+    fn dma_buf(len: usize) -> DmaBuf {
+        // SAFETY: creates a new anonymous mapping, unmapped when the `MmapMut` drops
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(ptr, libc::MAP_FAILED);
+        DmaBuf::new(MmapMut::new(ptr, len), 0)
+    }
+
+    fn mr(access: ibv_access_flags) -> MemoryRegion {
+        #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+        MemoryRegion::new(0x1000, 0x2000, 1, access.0 as u8)
+    }
+
+    #[test]
+    fn registration_rollback() {
+        let mut mtt = Mtt::new();
+        // holds the entries of two commands
+        let buf_len = 2 * Registration::MAX_NUM_PGT_ENTRY_PER_UPDATE * size_of::<u64>();
+        let mut buf = dma_buf(buf_len);
+        let fail = RecordCommand {
+            fail_mtt: Some(0),
+            ..Default::default()
+        };
+        let mut reg = mtt.begin_register(2, mr(ibv_access_flags(0))).unwrap();
+        let key = reg.mr_key;
+        reg.write_pgt(&fail, &mut buf, &[0x1000, 0x2000]).unwrap();
+        assert!(reg.commit(&fail).is_err());
+        drop(reg);
+        // the entry is overwritten and the key released
+        assert_eq!(fail.mtt_lengths(), [(key, 0x2000), (key, 0)]);
+        assert!(mtt.get_mr(key).is_none());
+        assert!(mtt.pgt_entry(key).is_none());
+
+        let reg = mtt.begin_register(2, mr(ibv_access_flags(0))).unwrap();
+        let key = reg.mr_key;
+        drop(reg);
+        assert!(mtt.pgt_entry(key).is_none());
+
+        let cmd = RecordCommand::default();
+        let num_pages = 5 * Registration::MAX_NUM_PGT_ENTRY_PER_UPDATE;
+        let mut reg = mtt
+            .begin_register(num_pages, mr(ibv_access_flags(0)))
+            .unwrap();
+        reg.write_pgt(&cmd, &mut buf, &vec![0; num_pages]).unwrap();
+        let key = reg.commit(&cmd).unwrap();
+        drop(reg);
+        assert_eq!(*cmd.pgt_batches.lock(), [2, 2, 1]);
+        assert!(mtt.get_mr(key).is_some());
+    }
+
+    #[test]
+    fn reregister_keeps_key() {
+        let mut mtt = Mtt::new();
+        let mut buf = dma_buf(PAGE_SIZE);
+        let cmd = RecordCommand::default();
+        let key = mtt
+            .begin_register(4, mr(ibv_access_flags(0)))
+            .unwrap()
+            .commit(&cmd)
+            .unwrap();
+        let prev = mtt.pgt_entry(key).unwrap();
+
+        // fits in the previous entries, the key is invalidated before they are rewritten
+        let mut reg = mtt
+            .begin_reregister(key, 2, mr(ibv_access_flags(0)))
+            .unwrap();
+        reg.write_pgt(&cmd, &mut buf, &[0, 0]).unwrap();
+        assert_eq!(reg.commit(&cmd).unwrap(), key);
+        drop(reg);
+        assert_eq!(cmd.mtt_lengths()[1..], [(key, 0), (key, 0x2000)]);
+        let entry = mtt.pgt_entry(key).unwrap();
+        assert_eq!((entry.index, entry.count), (prev.index, 2));
+
+        // does not fit, new entries are written while the previous ones stay valid
+        let mut reg = mtt
+            .begin_reregister(key, 8, mr(ibv_access_flags(0)))
+            .unwrap();
+        reg.write_pgt(&cmd, &mut buf, &[0; 8]).unwrap();
+        reg.commit(&cmd).unwrap();
+        drop(reg);
+        assert_eq!(cmd.mtt.lock().len(), 4);
+        let entry = mtt.pgt_entry(key).unwrap();
+        assert_ne!(entry.index, prev.index);
+        assert_eq!(entry.count, 8);
+
+        // an uncommitted replacement keeps the current entries
+        let reg = mtt
+            .begin_reregister(key, 16, mr(ibv_access_flags(0)))
+            .unwrap();
+        drop(reg);
+        assert_eq!(mtt.pgt_entry(key).unwrap().index, entry.index);
+        mtt.deregister(key).unwrap();
+    }
+
+    #[test]
+    fn failed_reregister_restores_key() {
+        let mut mtt = Mtt::new();
+        let mut buf = dma_buf(PAGE_SIZE);
+        let cmd = RecordCommand {
+            fail_mtt: Some(2),
+            ..Default::default()
+        };
+        let key = mtt
+            .begin_register(4, MemoryRegion::new(0, 0x4000, 1, 0))
+            .unwrap()
+            .commit(&cmd)
+            .unwrap();
+        let prev = mtt.pgt_entry(key).unwrap();
+
+        // the entries are reused, the commit fails after the key was invalidated
+        let mut reg = mtt
+            .begin_reregister(key, 2, mr(ibv_access_flags(0)))
+            .unwrap();
+        reg.write_pgt(&cmd, &mut buf, &[0, 0]).unwrap();
+        assert!(reg.commit(&cmd).is_err());
+        reg.restore(&cmd, &mut buf, &[0; 4]).unwrap();
+        drop(reg);
+        // the previous entries are written back before the previous MR entry
+        assert_eq!(*cmd.pgt_batches.lock(), [1, 1]);
+        assert_eq!(
+            cmd.mtt_lengths(),
+            [
+                (key, 0x4000),
+                (key, 0),
+                (key, 0x2000),
+                (key, 0),
+                (key, 0x4000)
+            ]
+        );
+        let entry = mtt.pgt_entry(key).unwrap();
+        assert_eq!((entry.index, entry.count), (prev.index, prev.count));
+        assert_eq!(mtt.get_mr(key).unwrap().length, 0x4000);
+    }
+
+    #[test]
+    fn validate_sge_bounds_and_access() {
+        let mut mtt = Mtt::new();
+        let mr = mr(ibv_access_flags::IBV_ACCESS_REMOTE_READ);
+        let lkey = mtt
+            .begin_register(2, mr)
+            .unwrap()
+            .commit(&RecordCommand::default())
+            .unwrap();
+        let none = ibv_access_flags(0);
+        assert!(mtt
+            .validate_sge(1, &Sge::new(0x1000, 0x2000, lkey), none)
+            .is_ok());
+        assert!(mtt
+            .validate_sge(1, &Sge::new(0x2000, 0x1001, lkey), none)
+            .is_err());
+        assert!(mtt
+            .validate_sge(2, &Sge::new(0x1000, 8, lkey), none)
+            .is_err());
+        assert!(mtt
+            .validate_sge(
+                1,
+                &Sge::new(0x1000, 8, lkey),
+                ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
+            )
+            .is_err());
+        mtt.deregister(lkey).unwrap();
+        assert!(mtt.get_mr(lkey).is_none());
+        assert!(mtt
+            .validate_sge(1, &Sge::new(0x1000, 8, lkey), none)
+            .is_err());
+    }
+}
@@ -0,0 +1,394 @@
+use std::{collections::HashMap, io, sync::Arc};
+
+use ibverbs_sys::{ibv_access_flags, ibv_mw_bind_info};
+use parking_lot::Mutex;
+
+use crate::{
+    completion::{Completion, CompletionStatus},
+    device_protocol::{DeviceCommand, MttUpdate},
+    mem::PAGE_SIZE_BITS,
+    mr::{MemoryRegion, MrTable},
+    mtt::{key_index, PgtEntry},
+    send::{SendWr, SendWrBindMw},
+};
+
+/// Type of a memory window, mirrors `ibv_mw_type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MwType {
+    /// Bound with `ibv_bind_mw`, the new rkey is chosen by the driver
+    Type1,
+    /// Bound and invalidated through the send queue of a QP
+    Type2,
+}
+
+impl MwType {
+    pub(crate) fn from_ibv(mw_type: ibverbs_sys::ibv_mw_type) -> Option<Self> {
+        match mw_type {
+            ibverbs_sys::IBV_MW_TYPE_1 => Some(Self::Type1),
+            ibverbs_sys::IBV_MW_TYPE_2 => Some(Self::Type2),
+            _ => None,
+        }
+    }
+}
+
+/// Range and access granted by a memory window binding, mirrors `ibv_mw_bind_info`
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct MwBindInfo {
+    pub(crate) mr_key: u32,
+    pub(crate) addr: u64,
+    pub(crate) length: u64,
+    pub(crate) access: u32,
+}
+
+impl MwBindInfo {
+    #[allow(unsafe_code)]
+    /// Returns `None` if `info.mr` is null
+    pub(crate) fn from_ibv(info: ibv_mw_bind_info) -> Option<Self> {
+        if info.mr.is_null() {
+            return None;
+        }
+        // SAFETY: the MR is checked to be non-null above
+        let mr_key = unsafe { (*info.mr).handle };
+        Some(Self {
+            mr_key,
+            addr: info.addr,
+            length: info.length,
+            access: info.mw_access_flags,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MemoryWindow {
+    /// The rkey of the current binding
+    rkey: u32,
+    pd_handle: u32,
+    mw_type: MwType,
+    /// The window grants remote access
+    is_bound: bool,
+    /// QP a type 2 window is bound to, only this QP may invalidate it remotely
+    qpn: Option<u32>,
+    /// MR the bound range belongs to
+    mr_key: Option<u32>,
+}
+
+/// Allocated memory windows, indexed by the index part of the rkey
+///
+/// A bound window owns an MTT entry under its rkey that maps the bound range onto the
+/// page table entries of the underlying MR. Invalidation overwrites the entry with one
+/// granting no access.
+pub(crate) struct MwTable {
+    inner: Arc<Mutex<HashMap<u32, MemoryWindow>>>,
+    cmd: Arc<dyn DeviceCommand + Send + Sync>,
+}
+
+impl MwTable {
+    pub(crate) fn new(cmd: Arc<dyn DeviceCommand + Send + Sync>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(HashMap::new())),
+            cmd,
+        }
+    }
+
+    pub(crate) fn clone_arc(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            cmd: Arc::clone(&self.cmd),
+        }
+    }
+
+    /// Adds a newly allocated window, it grants no access until bound
+    pub(crate) fn insert(&self, rkey: u32, pd_handle: u32, mw_type: MwType) -> io::Result<()> {
+        // the key may have been used by a previous MR or window
+        self.revoke(rkey, pd_handle)?;
+        let mw = MemoryWindow {
+            rkey,
+            pd_handle,
+            mw_type,
+            is_bound: false,
+            qpn: None,
+            mr_key: None,
+        };
+        let _prev = self.inner.lock().insert(key_index(rkey), mw);
+        Ok(())
+    }
+
+    /// Removes the window and revokes its binding
+    pub(crate) fn remove(&self, rkey: u32) -> io::Result<()> {
+        let mw = self
+            .inner
+            .lock()
+            .remove(&key_index(rkey))
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        if mw.is_bound {
+            self.revoke(mw.rkey, mw.pd_handle)?;
+        }
+        Ok(())
+    }
+
+    /// Binds the window to a range of `mr` under the new rkey of `wr`
+    ///
+    /// A type 1 window can be rebound at any time, a type 2 window must be invalidated
+    /// before it is bound again. A zero length binding grants no access.
+    pub(crate) fn bind(
+        &self,
+        qpn: u32,
+        wr: &SendWrBindMw,
+        mr: &MemoryRegion,
+        mr_pgt: PgtEntry,
+    ) -> io::Result<()> {
+        let invalid = || io::Error::from(io::ErrorKind::InvalidInput);
+        let (info, new_rkey, mw_type) = (wr.info, wr.rkey, wr.mw_type);
+        let mut table = self.inner.lock();
+        let mw = table.get_mut(&key_index(wr.mw_rkey)).ok_or_else(invalid)?;
+        let state_valid = match mw_type {
+            MwType::Type1 => mw.rkey == wr.mw_rkey,
+            MwType::Type2 => !mw.is_bound && key_index(new_rkey) == key_index(wr.mw_rkey),
+        };
+        if mw.mw_type != mw_type || !state_valid {
+            return Err(invalid());
+        }
+        let remote_write = ibv_access_flags::IBV_ACCESS_REMOTE_WRITE.0
+            | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC.0;
+        let mr_allowed = mr.pd_handle == mw.pd_handle
+            && mr.allows(ibv_access_flags::IBV_ACCESS_MW_BIND)
+            && mr.contains(info.addr, info.length)
+            && (info.access & remote_write == 0
+                || mr.allows(ibv_access_flags::IBV_ACCESS_LOCAL_WRITE));
+        let access = u8::try_from(info.access).map_err(|_err| invalid())?;
+        let length = u32::try_from(info.length).map_err(|_err| invalid())?;
+        if !mr_allowed {
+            return Err(invalid());
+        }
+        let update = if length == 0 {
+            MttUpdate::new(0, 0, new_rkey, mw.pd_handle, 0, 0)
+        } else {
+            // the window reuses the page table entries of the MR, one entry per page
+            let base_pgt_offset = (info.addr >> PAGE_SIZE_BITS)
+                .checked_sub(mr.addr >> PAGE_SIZE_BITS)
+                .and_then(|offset| u32::try_from(offset).ok())
+                .and_then(|offset| mr_pgt.index.checked_add(offset))
+                .ok_or_else(invalid)?;
+            MttUpdate::new(
+                info.addr,
+                length,
+                new_rkey,
+                mw.pd_handle,
+                access,
+                base_pgt_offset,
+            )
+        };
+        self.cmd.update_mtt(update)?;
+        mw.rkey = new_rkey;
+        mw.is_bound = length != 0 || mw_type == MwType::Type2;
+        mw.qpn = (mw_type == MwType::Type2).then_some(qpn);
+        mw.mr_key = (length != 0).then_some(info.mr_key);
+
+        Ok(())
+    }
+
+    /// Invalidates a bound type 2 window
+    ///
+    /// With `qpn` set the invalidation comes from a SEND with invalidate received on
+    /// that QP, which must be the QP the window is bound to.
+    pub(crate) fn invalidate(&self, rkey: u32, qpn: Option<u32>) -> io::Result<()> {
+        let mut table = self.inner.lock();
+        let mw = table
+            .get_mut(&key_index(rkey))
+            .filter(|mw| {
+                mw.rkey == rkey
+                    && mw.mw_type == MwType::Type2
+                    && mw.is_bound
+                    && qpn.is_none_or(|qpn| mw.qpn == Some(qpn))
+            })
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.revoke(rkey, mw.pd_handle)?;
+        mw.is_bound = false;
+        mw.qpn = None;
+        mw.mr_key = None;
+
+        Ok(())
+    }
+
+    /// Executes a memory window bind or local invalidate posted to a QP of `pd_handle`
+    ///
+    /// Returns the completion of a signaled WR, or the status the WR fails with.
+    pub(crate) fn execute(
+        &self,
+        qpn: u32,
+        pd_handle: u32,
+        wr: &SendWr,
+        mr_table: &MrTable,
+    ) -> Result<Option<Completion>, CompletionStatus> {
+        let (result, completion, err_status) = match *wr {
+            SendWr::BindMw(ref bind) => {
+                let result = mr_table
+                    .get_with_pgt(bind.info.mr_key)
+                    .filter(|&(mr, _)| mr.pd_handle == pd_handle)
+                    .ok_or(io::Error::from(io::ErrorKind::InvalidInput))
+                    .and_then(|(mr, mr_pgt)| self.bind(qpn, bind, &mr, mr_pgt));
+                let completion = Completion::BindMw {
+                    wr_id: bind.wr_id(),
+                };
+                (result, completion, CompletionStatus::MwBindError)
+            }
+            SendWr::LocalInv(ref inv) => (
+                self.invalidate(inv.imm_data, None),
+                Completion::LocalInv { wr_id: inv.wr_id },
+                CompletionStatus::LocalProtectionError,
+            ),
+            SendWr::Rdma(_) | SendWr::Send(_) | SendWr::Atomic(_) => {
+                unreachable!("not a memory window WR")
+            }
+        };
+        if result.is_err() {
+            return Err(err_status);
+        }
+        let signaled = wr.send_flags() & ibverbs_sys::ibv_send_flags::IBV_SEND_SIGNALED.0 != 0;
+        Ok(signaled.then_some(completion))
+    }
+
+    /// Returns the MR the window currently bound under `rkey` grants access to
+    pub(crate) fn bound_mr(&self, rkey: u32) -> Option<u32> {
+        self.inner
+            .lock()
+            .get(&key_index(rkey))
+            .filter(|mw| mw.rkey == rkey && mw.is_bound)
+            .and_then(|mw| mw.mr_key)
+    }
+
+    /// Returns `true` if a window grants access to a range of the MR
+    pub(crate) fn has_binding_to(&self, mr_key: u32) -> bool {
+        self.inner
+            .lock()
+            .values()
+            .any(|mw| mw.mr_key == Some(mr_key))
+    }
+
+    /// Overwrites the MTT entry of `rkey` with one granting no access
+    fn revoke(&self, rkey: u32, pd_handle: u32) -> io::Result<()> {
+        self.cmd
+            .update_mtt(MttUpdate::new(0, 0, rkey, pd_handle, 0, 0))
+            .map_err(Into::into)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ibverbs_sys::{ibv_mr, ibv_mw, ibv_send_wr, ibv_wr_opcode::IBV_WR_BIND_MW};
+
+    use crate::{device_protocol::mock::RecordCommand, send::SendWr};
+
+    use super::*;
+
+    const MR_KEY: u32 = 1;
+    const MR_ADDR: u64 = 0x1000_0000;
+    const MR_PGT: PgtEntry = PgtEntry {
+        index: 10,
+        count: 4,
+    };
+
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)] // flags fit in u8
+    fn mr(access: ibv_access_flags) -> MemoryRegion {
+        let length = 4 << PAGE_SIZE_BITS;
+        MemoryRegion::new(MR_ADDR, length, 1, access.0 as u8)
+    }
+
+    fn info(addr: u64, length: u64) -> MwBindInfo {
+        MwBindInfo {
+            mr_key: MR_KEY,
+            addr,
+            length,
+            access: ibv_access_flags::IBV_ACCESS_REMOTE_READ.0,
+        }
+    }
+
+    #[allow(unsafe_code)]
+    fn bind_type2(mw_rkey: u32, rkey: u32, info: MwBindInfo) -> SendWrBindMw {
+        let mut ibv_mr: ibv_mr = unsafe { std::mem::zeroed() };
+        ibv_mr.handle = info.mr_key;
+        let mut mw: ibv_mw = unsafe { std::mem::zeroed() };
+        mw.rkey = mw_rkey;
+        mw.type_ = ibverbs_sys::IBV_MW_TYPE_2;
+        let mut wr: ibv_send_wr = unsafe { std::mem::zeroed() };
+        wr.opcode = IBV_WR_BIND_MW;
+        wr.__bindgen_anon_2.bind_mw.mw = &mut mw;
+        wr.__bindgen_anon_2.bind_mw.rkey = rkey;
+        wr.__bindgen_anon_2.bind_mw.bind_info = ibv_mw_bind_info {
+            mr: &mut ibv_mr,
+            addr: info.addr,
+            length: info.length,
+            mw_access_flags: info.access,
+        };
+        match SendWr::new(wr) {
+            Ok(SendWr::BindMw(wr)) => wr,
+            _ => panic!("invalid bind WR"),
+        }
+    }
+
+    #[test]
+    fn type1_bind_maps_mr_pages() {
+        let cmd = Arc::new(RecordCommand::default());
+        let table = MwTable::new(Arc::<RecordCommand>::clone(&cmd));
+        table.insert(0x100, 1, MwType::Type1).unwrap();
+        let mr = mr(ibv_access_flags::IBV_ACCESS_MW_BIND);
+        let wr = SendWrBindMw::new_type1(0, 0, 0x100, info(MR_ADDR + (2 << PAGE_SIZE_BITS), 8));
+        table.bind(0, &wr, &mr, MR_PGT).unwrap();
+        assert_eq!(cmd.mtt.lock().last(), Some(&(0x101, 8, 12)));
+
+        // the previous rkey no longer refers to the window
+        assert!(table.bind(0, &wr, &mr, MR_PGT).is_err());
+        // out of the MR range
+        let wr = SendWrBindMw::new_type1(0, 0, 0x101, info(MR_ADDR, 5 << PAGE_SIZE_BITS));
+        assert!(table.bind(0, &wr, &mr, MR_PGT).is_err());
+        // the MR does not allow binding
+        let mr = self::mr(ibv_access_flags::IBV_ACCESS_LOCAL_WRITE);
+        let wr = SendWrBindMw::new_type1(0, 0, 0x101, info(MR_ADDR, 8));
+        assert!(table.bind(0, &wr, &mr, MR_PGT).is_err());
+    }
+
+    #[test]
+    fn type2_invalidate() {
+        let cmd = Arc::new(RecordCommand::default());
+        let table = MwTable::new(Arc::<RecordCommand>::clone(&cmd));
+        table.insert(0x200, 1, MwType::Type2).unwrap();
+        let mr = mr(ibv_access_flags::IBV_ACCESS_MW_BIND);
+        table
+            .bind(3, &bind_type2(0x200, 0x201, info(MR_ADDR, 8)), &mr, MR_PGT)
+            .unwrap();
+        // must be invalidated before binding again
+        let rebind = bind_type2(0x201, 0x202, info(MR_ADDR, 8));
+        assert!(table.bind(3, &rebind, &mr, MR_PGT).is_err());
+
+        // only the QP the window is bound to may invalidate it remotely
+        assert!(table.invalidate(0x201, Some(4)).is_err());
+        assert!(table.invalidate(0x200, Some(3)).is_err());
+        assert!(table.has_binding_to(MR_KEY));
+        assert_eq!(table.bound_mr(0x201), Some(MR_KEY));
+        assert_eq!(table.bound_mr(0x200), None);
+        table.invalidate(0x201, Some(3)).unwrap();
+        assert!(!table.has_binding_to(MR_KEY));
+        assert_eq!(table.bound_mr(0x201), None);
+        assert_eq!(cmd.mtt.lock().last(), Some(&(0x201, 0, 0)));
+        assert!(table.invalidate(0x201, None).is_err());
+        table.bind(3, &rebind, &mr, MR_PGT).unwrap();
+        table.invalidate(0x202, None).unwrap();
+    }
+
+    #[test]
+    fn execute_checks_pd_of_mr() {
+        let table = MwTable::new(Arc::new(RecordCommand::default()));
+        table.insert(0x200, 1, MwType::Type2).unwrap();
+        let mr_table = MrTable::new();
+        mr_table.insert(MR_KEY, mr(ibv_access_flags::IBV_ACCESS_MW_BIND), MR_PGT);
+        let wr = SendWr::BindMw(bind_type2(0x200, 0x201, info(MR_ADDR, 8)));
+        // the QP belongs to another PD than the MR
+        assert!(matches!(
+            table.execute(3, 2, &wr, &mr_table),
+            Err(CompletionStatus::MwBindError)
+        ));
+        // unsignaled
+        assert!(matches!(table.execute(3, 1, &wr, &mr_table), Ok(None)));
+        assert_eq!(table.bound_mr(0x201), Some(MR_KEY));
+    }
+}
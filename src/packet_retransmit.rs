@@ -10,7 +10,7 @@ use crate::{
     utils::{Psn, QpTable},
 };
 
-#[allow(variant_size_differences)]
+#[allow(variant_size_differences, clippy::large_enum_variant)]
 pub(crate) enum PacketRetransmitTask {
     NewWr {
         qpn: u32,
@@ -0,0 +1,116 @@
+use std::{collections::HashMap, io};
+
+use bitvec::vec::BitVec;
+
+use crate::constants::MAX_PD_CNT;
+
+/// A resource created in a PD
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum PdResource {
+    /// Memory region, identified by its key
+    Mr(u32),
+    /// Memory window, identified by the index part of its rkey
+    Mw(u32),
+    /// Queue pair
+    Qp(u32),
+    /// Shared receive queue
+    Srq(u32),
+}
+
+/// Allocates PD handles and tracks the resources created in each PD
+pub(crate) struct PdTable {
+    /// Bitmap tracking allocated PD handles
+    bitmap: BitVec,
+    /// The PD each resource belongs to
+    owners: HashMap<PdResource, u32>,
+}
+
+#[allow(clippy::as_conversions, clippy::indexing_slicing)]
+impl PdTable {
+    pub(crate) fn new() -> Self {
+        let mut bitmap = BitVec::with_capacity(MAX_PD_CNT);
+        bitmap.resize(MAX_PD_CNT, false);
+        Self {
+            bitmap,
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Allocates a new PD handle
+    #[allow(clippy::cast_possible_truncation)] // no larger than u32
+    pub(crate) fn alloc(&mut self) -> Option<u32> {
+        let handle = self.bitmap.first_zero()? as u32;
+        self.bitmap.set(handle as usize, true);
+        Some(handle)
+    }
+
+    /// Frees the PD handle
+    ///
+    /// # Errors
+    ///
+    /// Returns `ResourceBusy` if resources are still attached to the PD.
+    pub(crate) fn dealloc(&mut self, handle: u32) -> io::Result<()> {
+        if !self.contains(handle) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if self.owners.values().any(|&pd| pd == handle) {
+            return Err(io::Error::from(io::ErrorKind::ResourceBusy));
+        }
+        self.bitmap.set(handle as usize, false);
+        Ok(())
+    }
+
+    /// Returns `true` if the PD handle is allocated
+    pub(crate) fn contains(&self, handle: u32) -> bool {
+        self.bitmap
+            .get(handle as usize)
+            .is_some_and(|allocated| *allocated)
+    }
+
+    /// Records that `resource` was created in the PD
+    pub(crate) fn attach(&mut self, handle: u32, resource: PdResource) -> io::Result<()> {
+        if !self.contains(handle) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let _prev = self.owners.insert(resource, handle);
+        Ok(())
+    }
+
+    /// Removes `resource` from its PD, returns the PD handle
+    pub(crate) fn detach(&mut self, resource: PdResource) -> Option<u32> {
+        self.owners.remove(&resource)
+    }
+
+    /// Returns the PD `resource` belongs to
+    pub(crate) fn owner(&self, resource: PdResource) -> Option<u32> {
+        self.owners.get(&resource).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dealloc_busy_pd() {
+        let mut table = PdTable::new();
+        let pd0 = table.alloc().unwrap();
+        let pd1 = table.alloc().unwrap();
+        assert_ne!(pd0, pd1);
+        table.attach(pd0, PdResource::Mr(0x100)).unwrap();
+        table.attach(pd0, PdResource::Qp(1)).unwrap();
+        assert_eq!(
+            table.dealloc(pd0).map_err(|err| err.kind()),
+            Err(io::ErrorKind::ResourceBusy)
+        );
+        assert_eq!(table.detach(PdResource::Mr(0x100)), Some(pd0));
+        assert_eq!(table.detach(PdResource::Qp(1)), Some(pd0));
+        table.dealloc(pd0).unwrap();
+        assert!(table.attach(pd0, PdResource::Qp(2)).is_err());
+        assert_eq!(
+            table.dealloc(pd0).map_err(|err| err.kind()),
+            Err(io::ErrorKind::NotFound)
+        );
+        table.dealloc(pd1).unwrap();
+    }
+}
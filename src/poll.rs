@@ -0,0 +1,198 @@
+use std::{
+    hint,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_BUSY_POLL_US: u64 = 1000;
+const DEFAULT_MAX_PARK_US: u64 = 200;
+
+/// Park timeout of the first back off step
+const MIN_PARK: Duration = Duration::from_micros(1);
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct PollConfig {
+    /// Busy polls this long after the last work before backing off
+    busy_poll_us: u64,
+    /// Upper bound of the park timeout, 0 disables parking
+    ///
+    /// Without interrupts the meta worker is only woken early when local sends
+    /// are submitted, packets of remote initiated traffic reaching a parked meta
+    /// worker are seen up to this late.
+    max_park_us: u64,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self {
+            busy_poll_us: DEFAULT_BUSY_POLL_US,
+            max_park_us: DEFAULT_MAX_PARK_US,
+        }
+    }
+}
+
+impl PollConfig {
+    pub(crate) fn new(busy_poll_us: u64, max_park_us: u64) -> Self {
+        Self {
+            busy_poll_us,
+            max_park_us,
+        }
+    }
+
+    /// Returns the config with a different park timeout bound
+    pub(crate) fn with_max_park(self, max_park_us: u64) -> Self {
+        Self {
+            max_park_us,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ParkState {
+    parked: AtomicBool,
+    /// Set by the `Unparker`, makes the woken thread restart busy polling
+    woken: AtomicBool,
+    thread: OnceLock<Thread>,
+}
+
+/// Wakes a thread parked by an `AdaptivePoller`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Unparker {
+    inner: Arc<ParkState>,
+}
+
+impl Unparker {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wakes the polling thread if it is parked, must be called after the work is visible
+    pub(crate) fn unpark(&self) {
+        if !self.inner.parked.load(Ordering::SeqCst) {
+            return;
+        }
+        self.inner.woken.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.inner.thread.get() {
+            thread.unpark();
+        }
+    }
+
+    /// Wakes the polling thread even if it has not parked yet, for notifications
+    /// of work the thread cannot check for before parking
+    pub(crate) fn notify(&self) {
+        self.inner.woken.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.inner.thread.get() {
+            thread.unpark();
+        }
+    }
+}
+
+/// Busy polls for a while after the last work, then parks with an exponentially
+/// growing timeout until woken by the `Unparker`, which restarts busy polling
+#[derive(Debug)]
+pub(crate) struct AdaptivePoller {
+    config: PollConfig,
+    unparker: Unparker,
+    /// Start of the current idle period
+    idle_since: Option<Instant>,
+    park_timeout: Duration,
+}
+
+impl AdaptivePoller {
+    pub(crate) fn new(config: PollConfig, unparker: Unparker) -> Self {
+        Self {
+            config,
+            unparker,
+            idle_since: None,
+            park_timeout: MIN_PARK,
+        }
+    }
+
+    /// Called after work was found
+    pub(crate) fn reset(&mut self) {
+        self.idle_since = None;
+        self.park_timeout = MIN_PARK;
+    }
+
+    /// Called when no work was found
+    ///
+    /// `has_work` is checked again after the thread announced parking, so work
+    /// published before the `Unparker` is called is never missed.
+    pub(crate) fn idle<F: FnOnce() -> bool>(&mut self, has_work: F) {
+        let now = Instant::now();
+        let idle_since = *self.idle_since.get_or_insert(now);
+        let busy_poll = Duration::from_micros(self.config.busy_poll_us);
+        if self.config.max_park_us == 0 || now.duration_since(idle_since) < busy_poll {
+            hint::spin_loop();
+            return;
+        }
+        let state = &self.unparker.inner;
+        let _thread = state.thread.get_or_init(thread::current);
+        state.parked.store(true, Ordering::SeqCst);
+        if !has_work() {
+            thread::park_timeout(self.park_timeout);
+        }
+        state.parked.store(false, Ordering::SeqCst);
+        if state.woken.swap(false, Ordering::SeqCst) {
+            self.reset();
+            return;
+        }
+        self.park_timeout =
+            (self.park_timeout * 2).min(Duration::from_micros(self.config.max_park_us));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parked_thread_is_woken() {
+        let unparker = Unparker::new();
+        let work = Arc::new(AtomicBool::new(false));
+        let mut poller = AdaptivePoller::new(PollConfig::new(0, 10_000_000), unparker.clone());
+        poller.park_timeout = Duration::from_secs(10);
+        let handle = {
+            let work = Arc::clone(&work);
+            thread::spawn(move || {
+                let start = Instant::now();
+                while !work.load(Ordering::SeqCst) {
+                    poller.idle(|| work.load(Ordering::SeqCst));
+                }
+                start.elapsed()
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        work.store(true, Ordering::SeqCst);
+        unparker.unpark();
+        assert!(handle.join().unwrap() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn wakeup_restarts_busy_polling() {
+        let unparker = Unparker::new();
+        let mut poller = AdaptivePoller::new(PollConfig::new(1000, 1000), unparker.clone());
+        poller.idle_since = Some(Instant::now() - Duration::from_secs(1));
+        poller.park_timeout = Duration::from_micros(1000);
+        unparker.notify();
+        poller.idle(|| false);
+        assert!(poller.idle_since.is_none());
+        assert_eq!(poller.park_timeout, MIN_PARK);
+
+        poller.idle_since = Some(Instant::now() - Duration::from_secs(1));
+        poller.idle(|| false);
+        assert!(poller.idle_since.is_some());
+        assert_eq!(
+            poller.park_timeout,
+            Duration::from_micros(1000).min(MIN_PARK * 2)
+        );
+    }
+}
@@ -1,15 +1,18 @@
 use std::{
-    io,
+    collections::HashMap,
+    io, iter,
     net::IpAddr,
     sync::atomic::{fence, Ordering},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use ipnetwork::IpNetwork;
 use parking_lot::Mutex;
 
 use crate::{
-    device_protocol::{DeviceCommand, MttUpdate, PgtUpdate, RecvBufferMeta, UpdateQp},
+    device_protocol::{
+        CommandError, CommandKind, DeviceCommand, MttUpdate, PgtUpdate, RecvBufferMeta, UpdateQp,
+    },
     mem::{page::ContiguousPages, DmaBuf, PageWithPhysAddr},
     mtt::Mtt,
     net::config::NetworkConfig,
@@ -21,7 +24,7 @@ use crate::{
 
 use super::{
     desc::{
-        cmd::{CmdQueueReqDescUpdateMrTable, CmdQueueReqDescUpdatePGT},
+        cmd::{CmdQueueDescOperators, CmdQueueReqDescUpdateMrTable, CmdQueueReqDescUpdatePGT},
         CmdQueueReqDescQpManagement, CmdQueueReqDescSetNetworkParam,
         CmdQueueReqDescSetRawPacketReceiveMeta,
     },
@@ -31,6 +34,9 @@ use super::{
     },
 };
 
+/// Time to wait for the response of a command
+const CMD_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Controller of the command queue
 pub(crate) struct CommandController<Dev> {
     /// Command queue pair
@@ -95,98 +101,322 @@ impl<Dev: DeviceAdaptor> CommandController<Dev> {
     pub(crate) fn flush_resp_queue(&self, resp_queue: &CmdRespQueue) -> io::Result<()> {
         self.resp_csr_proxy.write_tail(resp_queue.tail())
     }
-}
 
-impl<Dev: DeviceAdaptor> DeviceCommand for CommandController<Dev> {
-    fn update_mtt(&self, update: MttUpdate) -> io::Result<()> {
-        let update_mr_table = CmdQueueReqDescUpdateMrTable::new(
-            0,
-            update.mr_base_va,
-            update.mr_length,
-            update.mr_key,
-            update.pd_handler,
-            update.acc_flags,
-            update.base_pgt_offset,
-        );
-        let mut qp = self.cmd_qp.lock();
-        let mut qp_update = qp.update();
-        qp_update.push(CmdQueueDesc::UpdateMrTable(update_mr_table));
-        qp_update.flush(&self.req_csr_proxy);
-        qp_update.wait(&self.resp_csr_proxy);
+    /// Submits a command, the descriptor is built from the ID assigned to the command
+    ///
+    /// # Errors
+    ///
+    /// Returns `QueueFull` if the command queue stays full, or `Timeout` if no ID is released
+    /// before the command timeout.
+    pub(crate) fn submit<F>(&self, build: F) -> Result<CmdHandle<'_, Dev>, CommandError>
+    where
+        F: FnOnce(u8) -> CmdQueueDesc,
+    {
+        self.submit_all(iter::once(build))?
+            .pop()
+            .ok_or(CommandError::QueueFull)
+    }
 
-        Ok(())
+    /// Submits several commands and notifies the card once
+    ///
+    /// # Errors
+    ///
+    /// See `submit`, commands submitted before an error stay outstanding.
+    pub(crate) fn submit_all<I, F>(
+        &self,
+        builds: I,
+    ) -> Result<Vec<CmdHandle<'_, Dev>>, CommandError>
+    where
+        I: IntoIterator<Item = F>,
+        F: FnOnce(u8) -> CmdQueueDesc,
+    {
+        let deadline = Instant::now() + CMD_TIMEOUT;
+        let mut handles = Vec::new();
+        let mut result = Ok(());
+        for build in builds {
+            let (tx, rx) = flume::bounded(1);
+            let pushed = self
+                .reserve(build, tx, deadline)
+                .and_then(|(id, desc)| self.push_desc(id, desc, deadline).map(|()| id));
+            let id = match pushed {
+                Ok(id) => id,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+            handles.push(CmdHandle {
+                controller: self,
+                id,
+                rx,
+                deadline,
+            });
+        }
+        if !handles.is_empty() {
+            let mut qp = self.cmd_qp.lock();
+            self.req_csr_proxy.write_head(qp.req_queue.head())?;
+            if let Ok(tail_ptr) = self.req_csr_proxy.read_tail() {
+                qp.req_queue.set_tail(tail_ptr);
+            }
+        }
+
+        result.map(|()| handles)
     }
 
-    fn update_pgt(&self, update: PgtUpdate) -> io::Result<()> {
-        let desc = CmdQueueReqDescUpdatePGT::new(
-            0,
-            update.dma_addr,
-            update.pgt_offset,
-            update.zero_based_entry_count,
-        );
-        let mut qp = self.cmd_qp.lock();
-        let mut qp_update = qp.update();
-        qp_update.push(CmdQueueDesc::UpdatePGT(desc));
-        qp_update.flush(&self.req_csr_proxy);
-        qp_update.wait(&self.resp_csr_proxy);
+    /// Waits for the responses of all handles
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error reported by the handles, all handles are waited for.
+    pub(crate) fn wait_all(handles: Vec<CmdHandle<'_, Dev>>) -> Result<(), CommandError> {
+        let mut result = Ok(());
+        for handle in handles {
+            let res = handle.wait();
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        result
+    }
 
-        Ok(())
+    /// Allocates an ID and builds the descriptor, polling responses while all IDs are outstanding
+    fn reserve<F>(
+        &self,
+        build: F,
+        tx: ResultSender,
+        deadline: Instant,
+    ) -> Result<(u8, CmdQueueDesc), CommandError>
+    where
+        F: FnOnce(u8) -> CmdQueueDesc,
+    {
+        loop {
+            let mut qp = self.cmd_qp.lock();
+            if let Some(id) = qp.free_id() {
+                let desc = build(id);
+                let (kind, operator) = desc_kind(&desc);
+                let _prev = qp.pending.insert(id, PendingCmd { kind, operator, tx });
+                return Ok((id, desc));
+            }
+            drop(qp);
+            if Instant::now() >= deadline {
+                return Err(CommandError::Timeout);
+            }
+            self.poll_responses();
+        }
     }
 
-    fn update_qp(&self, entry: UpdateQp) -> io::Result<()> {
-        let desc = CmdQueueReqDescQpManagement::new(
-            0,
-            entry.ip_addr,
-            entry.qpn,
-            false,
-            true,
-            entry.peer_qpn,
-            entry.rq_access_flags,
-            entry.qp_type,
-            entry.pmtu,
-            entry.local_udp_port,
-            entry.peer_mac_addr,
-        );
+    /// Pushes a descriptor, notifying the card and retrying while the queue is full
+    ///
+    /// Responses are reaped while waiting, the card stops consuming requests once the
+    /// response queue is full.
+    fn push_desc(&self, id: u8, desc: CmdQueueDesc, deadline: Instant) -> Result<(), CommandError> {
+        loop {
+            let mut qp = self.cmd_qp.lock();
+            if qp.req_queue.push(desc) {
+                return Ok(());
+            }
+            let flushed = self.req_csr_proxy.write_head(qp.req_queue.head());
+            if let Ok(tail_ptr) = self.req_csr_proxy.read_tail() {
+                qp.req_queue.set_tail(tail_ptr);
+            }
+            if flushed.is_err() || Instant::now() >= deadline {
+                let _pending = qp.pending.remove(&id);
+                flushed?;
+                return Err(CommandError::QueueFull);
+            }
+            drop(qp);
+            self.poll_responses();
+        }
+    }
 
+    /// Releases the ID of a command that timed out
+    ///
+    /// A late response finds no outstanding command and is dropped. IDs are handed out
+    /// round robin, so the ID is only reused after all other IDs.
+    fn release(&self, id: u8) {
+        let _pending = self.cmd_qp.lock().pending.remove(&id);
+    }
+
+    /// Consumes the available responses and completes the commands they belong to
+    fn poll_responses(&self) {
         let mut qp = self.cmd_qp.lock();
-        let mut update = qp.update();
-        update.push(CmdQueueDesc::ManageQP(desc));
-        update.flush(&self.req_csr_proxy);
-        update.wait(&self.resp_csr_proxy);
+        let mut consumed = false;
+        while let Some(resp) = qp.resp_queue.try_pop() {
+            consumed = true;
+            let header = resp.headers().cmd_queue_common_header();
+            // the command may have timed out and dropped its handle
+            if let Some(pending) = qp.pending.remove(&header.user_data()) {
+                let result = if resp.op_code() != pending.operator.op_code() {
+                    Err(CommandError::UnexpectedResponse {
+                        expected: pending.kind,
+                        op_code: resp.op_code(),
+                    })
+                } else if header.is_success() {
+                    Ok(())
+                } else {
+                    Err(CommandError::Rejected(pending.kind))
+                };
+                let _ignore = pending.tx.send(result);
+            }
+        }
+        if consumed {
+            let _ignore = self.resp_csr_proxy.write_tail(qp.resp_queue.tail());
+            if let Ok(head_ptr) = self.resp_csr_proxy.read_head() {
+                qp.resp_queue.set_head(head_ptr);
+            }
+        }
+    }
 
-        Ok(())
+    /// Submits a single command and waits for its response
+    fn execute<F>(&self, build: F) -> Result<(), CommandError>
+    where
+        F: FnOnce(u8) -> CmdQueueDesc,
+    {
+        self.submit(build)?.wait()
     }
+}
 
-    fn set_network(&self, param: NetworkConfig) -> io::Result<()> {
+/// Returns the kind and the operator of a command descriptor
+fn desc_kind(desc: &CmdQueueDesc) -> (CommandKind, CmdQueueDescOperators) {
+    match *desc {
+        CmdQueueDesc::UpdateMrTable(_) => {
+            (CommandKind::UpdateMtt, CmdQueueDescOperators::UpdateMrTable)
+        }
+        CmdQueueDesc::UpdatePGT(_) => (CommandKind::UpdatePgt, CmdQueueDescOperators::UpdatePgt),
+        CmdQueueDesc::ManageQP(_) => (CommandKind::UpdateQp, CmdQueueDescOperators::ManageQp),
+        CmdQueueDesc::SetNetworkParam(_) => (
+            CommandKind::SetNetwork,
+            CmdQueueDescOperators::SetNetworkParam,
+        ),
+        CmdQueueDesc::SetRawPacketReceiveMeta(_) => (
+            CommandKind::SetRawPacketRecvBuffer,
+            CmdQueueDescOperators::SetRawPacketReceiveMeta,
+        ),
+    }
+}
+
+/// Sender completing a command with its result
+type ResultSender = flume::Sender<Result<(), CommandError>>;
+
+/// Handle to the response of a submitted command
+pub(crate) struct CmdHandle<'a, Dev> {
+    /// The controller polling the response queue
+    controller: &'a CommandController<Dev>,
+    /// ID of the command carried in `user_data`
+    id: u8,
+    /// Receives the result of the command
+    rx: flume::Receiver<Result<(), CommandError>>,
+    /// Time after which the command is considered lost
+    deadline: Instant,
+}
+
+impl<Dev: DeviceAdaptor> CmdHandle<'_, Dev> {
+    /// Returns the result of the command if its response has arrived
+    pub(crate) fn try_result(&self) -> Option<Result<(), CommandError>> {
+        self.controller.poll_responses();
+        match self.rx.try_recv() {
+            Ok(result) => Some(result),
+            Err(flume::TryRecvError::Empty) => None,
+            Err(flume::TryRecvError::Disconnected) => Some(Err(CommandError::Io(io::Error::from(
+                io::ErrorKind::BrokenPipe,
+            )))),
+        }
+    }
+
+    /// Waits for the response of the command
+    ///
+    /// # Errors
+    ///
+    /// Returns `Timeout` if the card does not respond before the command timeout.
+    pub(crate) fn wait(self) -> Result<(), CommandError> {
+        loop {
+            if let Some(result) = self.try_result() {
+                return result;
+            }
+            if Instant::now() >= self.deadline {
+                self.controller.release(self.id);
+                // the response may have been reaped right before the release
+                return self.rx.try_recv().unwrap_or(Err(CommandError::Timeout));
+            }
+            std::hint::spin_loop();
+        }
+    }
+}
+
+impl<Dev: DeviceAdaptor> DeviceCommand for CommandController<Dev> {
+    fn update_mtt(&self, update: MttUpdate) -> Result<(), CommandError> {
+        self.execute(|id| {
+            CmdQueueDesc::UpdateMrTable(CmdQueueReqDescUpdateMrTable::new(
+                id,
+                update.mr_base_va,
+                update.mr_length,
+                update.mr_key,
+                update.pd_handler,
+                update.acc_flags,
+                update.base_pgt_offset,
+            ))
+        })
+    }
+
+    fn update_pgt(&self, update: PgtUpdate) -> Result<(), CommandError> {
+        self.update_pgt_batch(&[update])
+    }
+
+    fn update_pgt_batch(&self, updates: &[PgtUpdate]) -> Result<(), CommandError> {
+        let handles = self.submit_all(updates.iter().map(|update| {
+            |id| {
+                CmdQueueDesc::UpdatePGT(CmdQueueReqDescUpdatePGT::new(
+                    id,
+                    update.dma_addr,
+                    update.pgt_offset,
+                    update.zero_based_entry_count,
+                ))
+            }
+        }))?;
+        Self::wait_all(handles)
+    }
+
+    fn update_qp(&self, entry: UpdateQp) -> Result<(), CommandError> {
+        self.execute(|id| {
+            CmdQueueDesc::ManageQP(CmdQueueReqDescQpManagement::new(
+                id,
+                entry.ip_addr,
+                entry.qpn,
+                false,
+                true,
+                entry.peer_qpn,
+                entry.rq_access_flags,
+                entry.qp_type,
+                entry.pmtu,
+                entry.local_udp_port,
+                entry.peer_mac_addr,
+            ))
+        })
+    }
+
+    fn set_network(&self, param: NetworkConfig) -> Result<(), CommandError> {
         let IpAddr::V4(gateway) = param.gateway else {
             unreachable!("IPv6 unsupported")
         };
         let network = param.ip;
-        let desc = CmdQueueReqDescSetNetworkParam::new(
-            0,
-            gateway.to_bits(),
-            network.mask().to_bits(),
-            network.ip().to_bits(),
-            param.mac.into(),
-        );
-        let mut qp = self.cmd_qp.lock();
-        let mut update = qp.update();
-        update.push(CmdQueueDesc::SetNetworkParam(desc));
-        update.flush(&self.req_csr_proxy);
-        update.wait(&self.resp_csr_proxy);
-
-        Ok(())
+        self.execute(|id| {
+            CmdQueueDesc::SetNetworkParam(CmdQueueReqDescSetNetworkParam::new(
+                id,
+                gateway.to_bits(),
+                network.mask().to_bits(),
+                network.ip().to_bits(),
+                param.mac.into(),
+            ))
+        })
     }
 
-    fn set_raw_packet_recv_buffer(&self, meta: RecvBufferMeta) -> io::Result<()> {
-        let desc = CmdQueueReqDescSetRawPacketReceiveMeta::new(0, meta.phys_addr);
-        let mut qp = self.cmd_qp.lock();
-        let mut update = qp.update();
-        update.push(CmdQueueDesc::SetRawPacketReceiveMeta(desc));
-        update.flush(&self.req_csr_proxy);
-        update.wait(&self.resp_csr_proxy);
-
-        Ok(())
+    fn set_raw_packet_recv_buffer(&self, meta: RecvBufferMeta) -> Result<(), CommandError> {
+        self.execute(|id| {
+            CmdQueueDesc::SetRawPacketReceiveMeta(CmdQueueReqDescSetRawPacketReceiveMeta::new(
+                id,
+                meta.phys_addr,
+            ))
+        })
     }
 }
 
@@ -196,6 +426,10 @@ struct CmdQp {
     req_queue: CmdQueue,
     /// The command response queue
     resp_queue: CmdRespQueue,
+    /// Outstanding commands indexed by the ID carried in `user_data`
+    pending: HashMap<u8, PendingCmd>,
+    /// The ID tried first by the next allocation
+    next_id: u8,
 }
 
 impl CmdQp {
@@ -204,55 +438,27 @@ impl CmdQp {
         Self {
             req_queue,
             resp_queue,
+            pending: HashMap::new(),
+            next_id: 0,
         }
     }
 
-    /// Creates a queue pair update handle to process commands
-    fn update(&mut self) -> QpUpdate<'_> {
-        QpUpdate {
-            num: 0,
-            req_queue: &mut self.req_queue,
-            resp_queue: &mut self.resp_queue,
-        }
+    /// Returns an ID not used by any outstanding command
+    fn free_id(&mut self) -> Option<u8> {
+        let id = iter::successors(Some(self.next_id), |id| Some(id.wrapping_add(1)))
+            .take(usize::from(u8::MAX) + 1)
+            .find(|id| !self.pending.contains_key(id))?;
+        self.next_id = id.wrapping_add(1);
+        Some(id)
     }
 }
 
-/// An updates handle
-struct QpUpdate<'a> {
-    /// Number of updates
-    num: usize,
-    /// The command request queue
-    req_queue: &'a mut CmdQueue,
-    /// The command response queue
-    resp_queue: &'a mut CmdRespQueue,
-}
-
-impl QpUpdate<'_> {
-    /// Pushes a new command queue descriptor to the queue.
-    fn push(&mut self, desc: CmdQueueDesc) {
-        self.num = self.num.wrapping_add(1);
-        //FIXME: handle failed condition
-        let _ignore = self.req_queue.push(desc);
-    }
-
-    /// Flushes the command queue by writing the head pointer to the CSR proxy.
-    fn flush<Dev: DeviceAdaptor>(&mut self, req_csr_proxy: &CmdQueueCsrProxy<Dev>) {
-        req_csr_proxy.write_head(self.req_queue.head());
-        if let Ok(tail_ptr) = req_csr_proxy.read_tail() {
-            self.req_queue.set_tail(tail_ptr);
-        }
-    }
-
-    /// Waits for responses to all pushed commands.
-    fn wait<Dev: DeviceAdaptor>(mut self, resp_csr_proxy: &CmdRespQueueCsrProxy<Dev>) {
-        while self.num != 0 {
-            if let Some(resp) = self.resp_queue.try_pop() {
-                self.num = self.num.wrapping_sub(1);
-                resp_csr_proxy.write_tail(self.resp_queue.tail());
-                if let Ok(head_ptr) = resp_csr_proxy.read_head() {
-                    self.resp_queue.set_head(head_ptr);
-                }
-            }
-        }
-    }
+/// A command waiting for its response
+struct PendingCmd {
+    /// Kind of the command
+    kind: CommandKind,
+    /// Operator expected in the response
+    operator: CmdQueueDescOperators,
+    /// Completes the command handle
+    tx: ResultSender,
 }
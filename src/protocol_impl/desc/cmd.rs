@@ -12,6 +12,14 @@ pub(crate) enum CmdQueueDescOperators {
     SetRawPacketReceiveMeta = 0x04,
 }
 
+#[allow(clippy::as_conversions)] // converting `repr(u8)` enum variants to u8
+impl CmdQueueDescOperators {
+    /// Returns the op code carried in the descriptor header
+    pub(crate) fn op_code(self) -> u8 {
+        self as u8
+    }
+}
+
 #[bitsize(16)]
 #[derive(Clone, Copy, DebugBits, FromBits)]
 pub(crate) struct RingbufDescCmdQueueCommonHead {
@@ -251,6 +259,11 @@ impl CmdQueueRespDescOnlyCommonHeader {
     pub(crate) fn headers(&self) -> CmdQueueReqDescHeaderChunk {
         self.header
     }
+
+    /// Returns the op code of the command this response belongs to
+    pub(crate) fn op_code(&self) -> u8 {
+        self.header.common_header().op_code()
+    }
 }
 
 #[bitsize(64)]
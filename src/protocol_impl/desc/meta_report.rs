@@ -85,6 +85,8 @@ impl RdmaOpCode {
                 | RdmaOpCode::RdmaReadResponseMiddle
                 | RdmaOpCode::RdmaReadResponseLast
                 | RdmaOpCode::RdmaReadResponseOnly
+                | RdmaOpCode::SendLastWithInvalidate
+                | RdmaOpCode::SendOnlyWithInvalidate
         )
     }
 
@@ -249,6 +251,10 @@ impl MetaReportQueuePacketBasicInfoDesc {
             RdmaOpCode::SendLastWithImmediate | RdmaOpCode::SendOnlyWithImmediate => {
                 HeaderType::SendWithImm
             }
+            // the invalidated rkey of the IETH is reported in the immediate field
+            RdmaOpCode::SendLastWithInvalidate | RdmaOpCode::SendOnlyWithInvalidate => {
+                HeaderType::SendWithInv
+            }
             RdmaOpCode::RdmaReadResponseFirst
             | RdmaOpCode::RdmaReadResponseMiddle
             | RdmaOpCode::RdmaReadResponseLast
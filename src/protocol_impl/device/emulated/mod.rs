@@ -1,10 +1,16 @@
 use std::{
     io,
     net::{SocketAddr, UdpSocket},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
 
 use crate::protocol_impl::device::constants::{
     CSR_ADDR_CMD_REQ_QUEUE_ADDR_HIGH, CSR_ADDR_CMD_REQ_QUEUE_ADDR_LOW,
@@ -16,9 +22,13 @@ use super::{
         CSR_ADDR_CMD_REQ_QUEUE_HEAD, CSR_ADDR_CMD_REQ_QUEUE_TAIL, CSR_ADDR_CMD_RESP_QUEUE_HEAD,
         CSR_ADDR_CMD_RESP_QUEUE_TAIL,
     },
+    irq::EventFd,
     CsrReaderAdaptor, CsrWriterAdaptor, DeviceAdaptor,
 };
 
+/// Receive timeout of the doorbell socket, bounds the delay of observing shutdown
+const DOORBELL_SHUTDOWN_POLL: Duration = Duration::from_millis(100);
+
 #[derive(Debug, Clone)]
 pub(super) struct RpcClient(Arc<UdpSocket>);
 
@@ -91,3 +101,109 @@ impl DeviceAdaptor for EmulatedDevice {
         self.0.write_csr(addr, data)
     }
 }
+
+#[derive(Serialize, Deserialize)]
+struct DoorbellRpcMessage {
+    vector: usize,
+}
+
+/// Interrupt emulation, the emulator sends a datagram per raised vector
+#[derive(Debug)]
+pub(crate) struct EmulatedDoorbell {
+    local_addr: SocketAddr,
+    event_fds: Vec<EventFd>,
+}
+
+impl EmulatedDoorbell {
+    /// Binds `addr` and spawns the thread signaling the eventfd of each rung vector
+    ///
+    /// The thread exits once `is_shutdown` is set.
+    pub(crate) fn bind(
+        addr: SocketAddr,
+        count: usize,
+        is_shutdown: Arc<AtomicBool>,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(DOORBELL_SHUTDOWN_POLL))?;
+        let local_addr = socket.local_addr()?;
+        let event_fds = std::iter::repeat_with(EventFd::new)
+            .take(count)
+            .collect::<io::Result<Vec<_>>>()?;
+        let signal_fds = event_fds
+            .iter()
+            .map(EventFd::try_clone)
+            .collect::<io::Result<Vec<_>>>()?;
+        let _handle = thread::Builder::new()
+            .name("emulated-doorbell".into())
+            .spawn(move || Self::run(&socket, &signal_fds, &is_shutdown))
+            .unwrap_or_else(|err| unreachable!("Failed to spawn thread: {err}"));
+
+        Ok(Self {
+            local_addr,
+            event_fds,
+        })
+    }
+
+    pub(crate) fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub(crate) fn into_event_fds(self) -> Vec<EventFd> {
+        self.event_fds
+    }
+
+    fn run(socket: &UdpSocket, event_fds: &[EventFd], is_shutdown: &AtomicBool) {
+        let mut buf = [0; 128];
+        while !is_shutdown.load(Ordering::Relaxed) {
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(err) => {
+                    error!("failed to receive doorbell: {err}");
+                    return;
+                }
+            };
+            let Some(Ok(msg)) = buf
+                .get(..len)
+                .map(serde_json::from_slice::<DoorbellRpcMessage>)
+            else {
+                warn!("invalid doorbell message");
+                continue;
+            };
+            let Some(event_fd) = event_fds.get(msg.vector) else {
+                warn!("doorbell rung for unknown vector: {}", msg.vector);
+                continue;
+            };
+            if let Err(err) = event_fd.signal() {
+                error!("failed to signal vector {}: {err}", msg.vector);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn doorbell_signals_vector() {
+        let is_shutdown = Arc::new(AtomicBool::new(false));
+        let doorbell =
+            EmulatedDoorbell::bind("127.0.0.1:0".parse().unwrap(), 2, Arc::clone(&is_shutdown))
+                .unwrap();
+        let addr = doorbell.local_addr();
+        let event_fds = doorbell.into_event_fds();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let msg = serde_json::to_vec(&DoorbellRpcMessage { vector: 1 }).unwrap();
+        let _len = socket.send_to(&msg, addr).unwrap();
+        assert_eq!(event_fds[1].wait().unwrap(), 1);
+        is_shutdown.store(true, Ordering::Relaxed);
+    }
+}
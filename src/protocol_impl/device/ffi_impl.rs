@@ -1,21 +1,36 @@
-use std::{io, net::Ipv4Addr, ptr};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    ptr, slice,
+    sync::{atomic::AtomicBool, Arc},
+};
 
 use ipnetwork::{IpNetwork, Ipv4Network};
 
 use crate::{
-    completion::Completion,
+    async_event::AsyncEvent,
+    atomic::AtomicConfig,
+    cm::{self, CmError, CmListener, ConnRequest, Connection, MAX_PRIVATE_DATA},
+    completion::{Completion, NotifyMode},
     config::{ConfigLoader, DeviceConfig},
+    constants::{MAX_CQE, MAX_PD_CNT, MAX_SGE, MAX_SRQ_CNT, MAX_SRQ_WR},
     ctx_ops::RdmaCtxOps,
+    dcqcn::DcqcnConfig,
+    device_protocol::CommandError,
     mem::{page::EmulatedPageAllocator, virt_to_phy::PhysAddrResolverEmulated},
+    mw::{MwBindInfo, MwType},
     net::config::{MacAddress, NetworkConfig},
-    recv::RecvWr,
-    send::SendWr,
+    poll::PollConfig,
+    recv::{PostRecvConfig, RecvWr},
+    send::{SendWr, SendWrAtomic, SendWrBindMw},
+    srq::SrqAttr,
     timeout_retransmit::AckTimeoutConfig,
 };
 
 use super::{
-    emulated::EmulatedDevice,
+    emulated::{EmulatedDevice, EmulatedDoorbell},
     hardware::PciHwDevice,
+    irq::EventFd,
     ops_impl::{
         qp_attr::{IbvQpAttr, IbvQpInitAttr},
         DeviceOps, HwDevice, HwDeviceCtx,
@@ -24,8 +39,12 @@ use super::{
 
 const CARD_MAC_ADDRESS: u64 = 0xAABB_CCDD_EE0A;
 const CARD_IP_ADDRESS: u32 = 0x1122_330A;
+/// Path MTU of QPs connected through the connection manager, the active MTU of the port
+const CM_PATH_MTU: ibverbs_sys::ibv_mtu = ibverbs_sys::IBV_MTU_4096;
 const POST_RECV_TCP_LOOP_BACK_SERVER_ADDRESS: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 1);
 const POST_RECV_TCP_LOOP_BACK_CLIENT_ADDRESS: Ipv4Addr = Ipv4Addr::new(127, 0, 0, 2);
+const POST_RECV_MUX_PORT: u16 = 60000;
+const ATOMIC_PORT: u16 = 62000;
 
 static HEAP_ALLOCATOR: bluesimalloc::BlueSimalloc = bluesimalloc::BlueSimalloc::new();
 
@@ -57,14 +76,17 @@ impl BlueRdmaCore {
 
     #[allow(clippy::unwrap_used, clippy::unwrap_in_result)]
     fn new_emulated(sysfs_name: &str) -> io::Result<HwDeviceCtx<EmulatedHwDevice>> {
+        // only interrupts are configurable, the rest is fixed by the emulator setup
+        let irq = ConfigLoader::load_irq_default()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
         let device = match sysfs_name {
             "uverbs0" => {
                 bluesimalloc::init_global_allocator(0, &HEAP_ALLOCATOR);
-                EmulatedHwDevice::new("127.0.0.1:7701".into())
+                EmulatedHwDevice::new("127.0.0.1:7701".into(), irq.emulated_doorbell(0))
             }
             "uverbs1" => {
                 bluesimalloc::init_global_allocator(1, &HEAP_ALLOCATOR);
-                EmulatedHwDevice::new("127.0.0.1:7702".into())
+                EmulatedHwDevice::new("127.0.0.1:7702".into(), irq.emulated_doorbell(1))
             }
             _ => unreachable!("unexpected sysfs_name"),
         };
@@ -86,7 +108,26 @@ impl BlueRdmaCore {
             mac: MacAddress([0x0A, 0xEE, 0xDD, 0xCC, 0xBB, 0xAA]),
         };
         let ack = AckTimeoutConfig::new(16, 18, 100);
-        let config = DeviceConfig { network, ack };
+        // both emulated devices share the card IP, so each listens on its own port
+        let post_recv = match sysfs_name {
+            "uverbs0" => PostRecvConfig::new(POST_RECV_MUX_PORT, POST_RECV_MUX_PORT + 1),
+            "uverbs1" => PostRecvConfig::new(POST_RECV_MUX_PORT + 1, POST_RECV_MUX_PORT),
+            _ => unreachable!("unexpected sysfs_name"),
+        };
+        let atomic = match sysfs_name {
+            "uverbs0" => AtomicConfig::new(ATOMIC_PORT, ATOMIC_PORT + 1),
+            "uverbs1" => AtomicConfig::new(ATOMIC_PORT + 1, ATOMIC_PORT),
+            _ => unreachable!("unexpected sysfs_name"),
+        };
+        let config = DeviceConfig {
+            network,
+            ack,
+            post_recv,
+            atomic,
+            dcqcn: DcqcnConfig::default(),
+            poll: PollConfig::default(),
+            irq,
+        };
         // (check_duration, local_ack_timeout) : (256ms, 1s) because emulator is slow
         HwDeviceCtx::initialize(device, config)
     }
@@ -94,11 +135,13 @@ impl BlueRdmaCore {
 
 struct EmulatedHwDevice {
     addr: String,
+    /// Address the emulator rings interrupt doorbells on
+    doorbell: Option<SocketAddr>,
 }
 
 impl EmulatedHwDevice {
-    fn new(addr: String) -> Self {
-        Self { addr }
+    fn new(addr: String, doorbell: Option<SocketAddr>) -> Self {
+        Self { addr, doorbell }
     }
 }
 
@@ -122,6 +165,21 @@ impl HwDevice for EmulatedHwDevice {
     fn new_phys_addr_resolver(&self) -> Self::PhysAddrResolver {
         PhysAddrResolverEmulated::new(bluesimalloc::shm_start_addr() as u64)
     }
+
+    fn enable_irq_vectors(
+        &self,
+        count: usize,
+        is_shutdown: &Arc<AtomicBool>,
+    ) -> io::Result<Vec<EventFd>> {
+        let Some(doorbell) = self.doorbell else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "no doorbell address configured for the emulated device",
+            ));
+        };
+        EmulatedDoorbell::bind(doorbell, count, Arc::clone(is_shutdown))
+            .map(EmulatedDoorbell::into_event_fds)
+    }
 }
 
 #[allow(unsafe_code)]
@@ -160,18 +218,32 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
 
     #[inline]
     fn alloc_pd(blue_context: *mut ibverbs_sys::ibv_context) -> *mut ibverbs_sys::ibv_pd {
+        let bluerdma = unsafe { get_device(blue_context) };
+        let Ok(handle) = bluerdma.alloc_pd() else {
+            return ptr::null_mut();
+        };
         Box::into_raw(Box::new(ibverbs_sys::ibv_pd {
             context: blue_context,
-            handle: 0,
+            handle,
         }))
     }
 
     #[inline]
     fn dealloc_pd(pd: *mut ibverbs_sys::ibv_pd) -> ::std::os::raw::c_int {
+        let Some(pd_ref) = (unsafe { pd.as_ref() }) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(pd_ref.context) };
+        if let Err(err) = bluerdma.dealloc_pd(pd_ref.handle) {
+            return to_errno(&err);
+        }
+        drop(unsafe { Box::from_raw(pd) });
+
         0
     }
 
     #[inline]
+    #[allow(clippy::cast_possible_wrap, clippy::cast_possible_truncation)] // fits in i32
     fn query_device_ex(
         _blue_context: *mut ibverbs_sys::ibv_context,
         _input: *const ibverbs_sys::ibv_query_device_ex_input,
@@ -182,11 +254,19 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
             (*device_attr) = ibverbs_sys::ibv_device_attr {
                 max_qp: 256,
                 max_qp_wr: 64,
-                max_sge: 8,
+                max_sge: MAX_SGE as i32,
                 max_cq: 256,
-                max_cqe: 4096,
+                max_cqe: MAX_CQE as i32,
                 max_mr: 256,
-                max_pd: 256,
+                max_pd: MAX_PD_CNT as i32,
+                max_mw: 256,
+                // atomics are executed one at a time per QP
+                max_qp_rd_atom: 1,
+                max_qp_init_rd_atom: 1,
+                atomic_cap: 1, // IBV_ATOMIC_HCA
+                max_srq: MAX_SRQ_CNT as i32,
+                max_srq_wr: MAX_SRQ_WR as i32,
+                max_srq_sge: MAX_SGE as i32,
                 phys_port_cnt: 1,
                 ..Default::default()
             };
@@ -223,7 +303,10 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         comp_vector: core::ffi::c_int,
     ) -> *mut ibverbs_sys::ibv_cq {
         let bluerdma = unsafe { get_device(blue_context) };
-        let Some(handle) = bluerdma.create_cq() else {
+        let Ok(cqe_num) = usize::try_from(cqe) else {
+            return ptr::null_mut();
+        };
+        let Ok(handle) = bluerdma.create_cq(cqe_num) else {
             return ptr::null_mut();
         };
         let cq = ibverbs_sys::ibv_cq {
@@ -237,8 +320,18 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
             comp_events_completed: 0,
             async_events_completed: 0,
         };
+        let cq_ptr = Box::into_raw(Box::new(cq));
+        let channel = (!channel.is_null()).then_some(channel as u64);
+        if bluerdma
+            .set_cq_context(handle, cq_ptr as u64, channel)
+            .is_err()
+        {
+            bluerdma.destroy_cq(handle);
+            drop(unsafe { Box::from_raw(cq_ptr) });
+            return ptr::null_mut();
+        }
 
-        Box::into_raw(Box::new(cq))
+        cq_ptr
     }
 
     #[inline]
@@ -250,6 +343,205 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         0
     }
 
+    #[inline]
+    fn resize_cq(cq: *mut ibverbs_sys::ibv_cq, cqe: core::ffi::c_int) -> ::std::os::raw::c_int {
+        let Some(cq) = (unsafe { cq.as_mut() }) else {
+            return libc::EINVAL;
+        };
+        let Ok(cqe_num) = usize::try_from(cqe) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(cq.context) };
+        if let Err(err) = bluerdma.resize_cq(cq.handle, cqe_num) {
+            return to_errno(&err);
+        }
+        cq.cqe = cqe;
+
+        0
+    }
+
+    #[inline]
+    fn get_async_event(
+        blue_context: *mut ibverbs_sys::ibv_context,
+        event: *mut ibverbs_sys::ibv_async_event,
+    ) -> ::std::os::raw::c_int {
+        let Some(event) = (unsafe { event.as_mut() }) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(blue_context) };
+        let async_event = match bluerdma.get_async_event() {
+            Ok(x) => x,
+            Err(err) => return to_errno(&err),
+        };
+        match async_event {
+            AsyncEvent::CqError { cq_context } => {
+                event.element.cq = cq_context as *mut ibverbs_sys::ibv_cq;
+            }
+            AsyncEvent::SrqLimitReached { srq_context } => {
+                event.element.srq = srq_context as *mut ibverbs_sys::ibv_srq;
+            }
+        }
+        event.event_type = async_event.event_type();
+
+        0
+    }
+
+    #[inline]
+    fn get_cq_event(
+        channel: *mut ibverbs_sys::ibv_comp_channel,
+        cq: *mut *mut ibverbs_sys::ibv_cq,
+        cq_context: *mut *mut core::ffi::c_void,
+    ) -> ::std::os::raw::c_int {
+        let (Some(comp_channel), Some(cq), Some(cq_context)) = (
+            unsafe { channel.as_ref() },
+            unsafe { cq.as_mut() },
+            unsafe { cq_context.as_mut() },
+        ) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(comp_channel.context) };
+        let cq_ptr = match bluerdma.get_cq_event(channel as u64) {
+            Ok(x) => x as *mut ibverbs_sys::ibv_cq,
+            Err(err) => return to_errno(&err),
+        };
+        *cq = cq_ptr;
+        *cq_context = unsafe { (*cq_ptr).cq_context };
+
+        0
+    }
+
+    #[inline]
+    fn req_notify_cq(
+        cq: *mut ibverbs_sys::ibv_cq,
+        solicited_only: core::ffi::c_int,
+    ) -> ::std::os::raw::c_int {
+        let Some(cq) = (unsafe { cq.as_ref() }) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(cq.context) };
+        let mode = if solicited_only != 0 {
+            NotifyMode::SolicitedOnly
+        } else {
+            NotifyMode::NextCompletion
+        };
+        if let Err(err) = bluerdma.req_notify_cq(cq.handle, mode) {
+            return to_errno(&err);
+        }
+
+        0
+    }
+
+    #[inline]
+    fn create_srq(
+        pd: *mut ibverbs_sys::ibv_pd,
+        srq_init_attr: *mut ibverbs_sys::ibv_srq_init_attr,
+    ) -> *mut ibverbs_sys::ibv_srq {
+        let context = unsafe { *pd }.context;
+        let bluerdma = unsafe { get_device(context) };
+        let Some(init_attr) = (unsafe { srq_init_attr.as_mut() }) else {
+            return ptr::null_mut();
+        };
+        let srq_ptr = Box::into_raw(Box::new(ibverbs_sys::ibv_srq {
+            context,
+            srq_context: init_attr.srq_context,
+            pd,
+            handle: 0,
+            mutex: ibverbs_sys::pthread_mutex_t::default(),
+            cond: ibverbs_sys::pthread_cond_t::default(),
+            events_completed: 0,
+        }));
+        let attr = SrqAttr::from_ibv(init_attr.attr);
+        let pd_handle = unsafe { *pd }.handle;
+        let Ok(handle) = bluerdma.create_srq(pd_handle, srq_ptr as u64, attr) else {
+            drop(unsafe { Box::from_raw(srq_ptr) });
+            return ptr::null_mut();
+        };
+        if let Some(srq) = unsafe { srq_ptr.as_mut() } {
+            srq.handle = handle;
+        }
+
+        srq_ptr
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[inline]
+    fn modify_srq(
+        srq: *mut ibverbs_sys::ibv_srq,
+        srq_attr: *mut ibverbs_sys::ibv_srq_attr,
+        srq_attr_mask: core::ffi::c_int,
+    ) -> ::std::os::raw::c_int {
+        let (Some(srq), Some(attr)) = (unsafe { srq.as_ref() }, unsafe { srq_attr.as_ref() })
+        else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(srq.context) };
+        let mask = srq_attr_mask as u32;
+        let max_wr =
+            (mask & ibverbs_sys::ibv_srq_attr_mask::IBV_SRQ_MAX_WR.0 != 0).then_some(attr.max_wr);
+        let srq_limit =
+            (mask & ibverbs_sys::ibv_srq_attr_mask::IBV_SRQ_LIMIT.0 != 0).then_some(attr.srq_limit);
+        if let Err(err) = bluerdma.modify_srq(srq.handle, max_wr, srq_limit) {
+            return to_errno(&err);
+        }
+
+        0
+    }
+
+    #[inline]
+    fn query_srq(
+        srq: *mut ibverbs_sys::ibv_srq,
+        srq_attr: *mut ibverbs_sys::ibv_srq_attr,
+    ) -> ::std::os::raw::c_int {
+        let (Some(srq), Some(srq_attr)) = (unsafe { srq.as_ref() }, unsafe { srq_attr.as_mut() })
+        else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(srq.context) };
+        match bluerdma.query_srq(srq.handle) {
+            Ok(attr) => *srq_attr = attr.to_ibv(),
+            Err(err) => return to_errno(&err),
+        }
+
+        0
+    }
+
+    #[inline]
+    fn destroy_srq(srq: *mut ibverbs_sys::ibv_srq) -> ::std::os::raw::c_int {
+        let Some(srq_ref) = (unsafe { srq.as_ref() }) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(srq_ref.context) };
+        if let Err(err) = bluerdma.destroy_srq(srq_ref.handle) {
+            return to_errno(&err);
+        }
+        drop(unsafe { Box::from_raw(srq) });
+
+        0
+    }
+
+    #[inline]
+    fn post_srq_recv(
+        srq: *mut ibverbs_sys::ibv_srq,
+        recv_wr: *mut ibverbs_sys::ibv_recv_wr,
+        bad_recv_wr: *mut *mut ibverbs_sys::ibv_recv_wr,
+    ) -> ::std::os::raw::c_int {
+        let (Some(srq), Some(wr)) = (unsafe { srq.as_ref() }, unsafe { recv_wr.as_ref() }) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(srq.context) };
+        let result = RecvWr::new(*wr)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))
+            .and_then(|wr| bluerdma.post_srq_recv(srq.handle, wr));
+        if let Err(err) = result {
+            if let Some(bad_recv_wr) = unsafe { bad_recv_wr.as_mut() } {
+                *bad_recv_wr = recv_wr;
+            }
+            return to_errno(&err);
+        }
+
+        0
+    }
+
     #[inline]
     fn create_qp(
         pd: *mut ibverbs_sys::ibv_pd,
@@ -258,19 +550,20 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         let context = unsafe { *pd }.context;
         let bluerdma = unsafe { get_device(context) };
         let init_attr = unsafe { *init_attr };
-        let Ok(qpn) = bluerdma.create_qp(IbvQpInitAttr::new(init_attr)) else {
+        let pd_handle = unsafe { *pd }.handle;
+        let Ok(qpn) = bluerdma.create_qp(pd_handle, IbvQpInitAttr::new(init_attr)) else {
             return ptr::null_mut();
         };
         Box::into_raw(Box::new(ibverbs_sys::ibv_qp {
             context,
-            qp_context: ptr::null_mut(),
+            qp_context: init_attr.qp_context,
             pd,
-            send_cq: ptr::null_mut(),
-            recv_cq: ptr::null_mut(),
-            srq: ptr::null_mut(),
+            send_cq: init_attr.send_cq,
+            recv_cq: init_attr.recv_cq,
+            srq: init_attr.srq,
             handle: 0,
             qp_num: qpn,
-            state: ibverbs_sys::ibv_qp_state::IBV_QPS_INIT,
+            state: ibverbs_sys::ibv_qp_state::IBV_QPS_RESET,
             qp_type: init_attr.qp_type,
             mutex: ibverbs_sys::pthread_mutex_t::default(),
             cond: ibverbs_sys::pthread_cond_t::default(),
@@ -289,6 +582,224 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         0
     }
 
+    #[inline]
+    fn set_qp_channel(
+        qp: *mut ibverbs_sys::ibv_qp,
+        channel: core::ffi::c_int,
+    ) -> ::std::os::raw::c_int {
+        let Some(qp) = (unsafe { qp.as_ref() }) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(qp.context) };
+        match bluerdma.set_qp_channel(qp.qp_num, usize::try_from(channel).ok()) {
+            Ok(()) => 0,
+            Err(err) => to_errno(&err),
+        }
+    }
+
+    #[inline]
+    fn set_qp_weight(
+        qp: *mut ibverbs_sys::ibv_qp,
+        weight: core::ffi::c_uint,
+    ) -> ::std::os::raw::c_int {
+        let Some(qp) = (unsafe { qp.as_ref() }) else {
+            return libc::EINVAL;
+        };
+        let Ok(weight) = u8::try_from(weight) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(qp.context) };
+        match bluerdma.set_qp_weight(qp.qp_num, weight) {
+            Ok(()) => 0,
+            Err(err) => to_errno(&err),
+        }
+    }
+
+    #[inline]
+    fn cm_listen(port: u16, listener: *mut *mut core::ffi::c_void) -> ::std::os::raw::c_int {
+        let Some(listener) = (unsafe { listener.as_mut() }) else {
+            return libc::EINVAL;
+        };
+        match CmListener::bind((Ipv4Addr::UNSPECIFIED, port)) {
+            Ok(x) => {
+                *listener = Box::into_raw(Box::new(x)).cast();
+                0
+            }
+            Err(err) => to_errno(&err),
+        }
+    }
+
+    #[inline]
+    fn cm_destroy_listener(listener: *mut core::ffi::c_void) {
+        if !listener.is_null() {
+            drop(unsafe { Box::from_raw(listener.cast::<CmListener>()) });
+        }
+    }
+
+    #[inline]
+    fn cm_get_request(
+        listener: *mut core::ffi::c_void,
+        request: *mut *mut core::ffi::c_void,
+        private_data: *mut core::ffi::c_void,
+        private_data_len: *mut u8,
+    ) -> ::std::os::raw::c_int {
+        let (Some(listener), Some(request)) =
+            (unsafe { listener.cast::<CmListener>().as_ref() }, unsafe {
+                request.as_mut()
+            })
+        else {
+            return libc::EINVAL;
+        };
+        let req = match listener.get_request() {
+            Ok(x) => x,
+            Err(err) => return cm_errno(&err),
+        };
+        if let Err(errno) =
+            unsafe { copy_private_data(req.private_data(), private_data, private_data_len) }
+        {
+            return errno;
+        }
+        *request = Box::into_raw(Box::new(req)).cast();
+
+        0
+    }
+
+    #[inline]
+    fn cm_accept(
+        request: *mut core::ffi::c_void,
+        qp: *mut ibverbs_sys::ibv_qp,
+        access_flags: core::ffi::c_uint,
+        private_data: *const core::ffi::c_void,
+        private_data_len: u8,
+        conn: *mut *mut core::ffi::c_void,
+    ) -> ::std::os::raw::c_int {
+        if request.is_null() {
+            return libc::EINVAL;
+        }
+        let request = unsafe { Box::from_raw(request.cast::<ConnRequest>()) };
+        let (Some(qp), Some(conn), Some(private_data)) =
+            (unsafe { qp.as_ref() }, unsafe { conn.as_mut() }, unsafe {
+                private_data_slice(private_data, private_data_len)
+            })
+        else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(qp.context) };
+        match request.accept_qp(bluerdma, qp.qp_num, access_flags, CM_PATH_MTU, private_data) {
+            Ok(x) => {
+                *conn = Box::into_raw(Box::new(x)).cast();
+                0
+            }
+            Err(err) => cm_errno(&err),
+        }
+    }
+
+    #[inline]
+    fn cm_reject(
+        request: *mut core::ffi::c_void,
+        private_data: *const core::ffi::c_void,
+        private_data_len: u8,
+    ) -> ::std::os::raw::c_int {
+        if request.is_null() {
+            return libc::EINVAL;
+        }
+        let request = unsafe { Box::from_raw(request.cast::<ConnRequest>()) };
+        let Some(private_data) = (unsafe { private_data_slice(private_data, private_data_len) })
+        else {
+            return libc::EINVAL;
+        };
+        match request.reject(private_data) {
+            Ok(()) => 0,
+            Err(err) => cm_errno(&err),
+        }
+    }
+
+    #[inline]
+    fn cm_connect(
+        qp: *mut ibverbs_sys::ibv_qp,
+        addr: *const core::ffi::c_char,
+        access_flags: core::ffi::c_uint,
+        private_data: *const core::ffi::c_void,
+        private_data_len: u8,
+        conn: *mut *mut core::ffi::c_void,
+    ) -> ::std::os::raw::c_int {
+        if addr.is_null() {
+            return libc::EINVAL;
+        }
+        let (Some(qp), Some(conn), Some(private_data), Ok(addr)) = (
+            unsafe { qp.as_ref() },
+            unsafe { conn.as_mut() },
+            unsafe { private_data_slice(private_data, private_data_len) },
+            unsafe { core::ffi::CStr::from_ptr(addr) }.to_str(),
+        ) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(qp.context) };
+        match cm::connect_qp(
+            bluerdma,
+            addr,
+            qp.qp_num,
+            access_flags,
+            CM_PATH_MTU,
+            private_data,
+        ) {
+            Ok(x) => {
+                *conn = Box::into_raw(Box::new(x)).cast();
+                0
+            }
+            Err(err) => cm_errno(&err),
+        }
+    }
+
+    #[inline]
+    fn cm_conn_private_data(
+        conn: *mut core::ffi::c_void,
+        private_data: *mut core::ffi::c_void,
+        private_data_len: *mut u8,
+    ) -> ::std::os::raw::c_int {
+        let Some(conn) = (unsafe { conn.cast::<Connection>().as_ref() }) else {
+            return libc::EINVAL;
+        };
+        match unsafe {
+            copy_private_data(conn.remote_private_data(), private_data, private_data_len)
+        } {
+            Ok(()) => 0,
+            Err(errno) => errno,
+        }
+    }
+
+    #[inline]
+    fn cm_disconnect(
+        qp: *mut ibverbs_sys::ibv_qp,
+        conn: *mut core::ffi::c_void,
+    ) -> ::std::os::raw::c_int {
+        let (Some(qp), false) = (unsafe { qp.as_ref() }, conn.is_null()) else {
+            return libc::EINVAL;
+        };
+        let conn = unsafe { Box::from_raw(conn.cast::<Connection>()) };
+        let bluerdma = unsafe { get_device(qp.context) };
+        match conn.disconnect(bluerdma) {
+            Ok(()) => 0,
+            Err(err) => cm_errno(&err),
+        }
+    }
+
+    #[inline]
+    fn cm_wait_disconnect(
+        qp: *mut ibverbs_sys::ibv_qp,
+        conn: *mut core::ffi::c_void,
+    ) -> ::std::os::raw::c_int {
+        let (Some(qp), false) = (unsafe { qp.as_ref() }, conn.is_null()) else {
+            return libc::EINVAL;
+        };
+        let conn = unsafe { Box::from_raw(conn.cast::<Connection>()) };
+        let bluerdma = unsafe { get_device(qp.context) };
+        match conn.wait_disconnect(bluerdma) {
+            Ok(()) => 0,
+            Err(err) => cm_errno(&err),
+        }
+    }
+
     #[allow(clippy::cast_sign_loss)]
     #[inline]
     fn modify_qp(
@@ -296,15 +807,24 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         attr: *mut ibverbs_sys::ibv_qp_attr,
         attr_mask: core::ffi::c_int,
     ) -> ::std::os::raw::c_int {
-        let qp = unsafe { *qp };
+        let Some(qp) = (unsafe { qp.as_mut() }) else {
+            return libc::EINVAL;
+        };
         let attr = unsafe { *attr };
         let context = qp.context;
         let bluerdma = unsafe { get_device(context) };
-        let mask = attr_mask as u32;
-        bluerdma.update_qp(qp.qp_num, IbvQpAttr::new(attr, attr_mask as u32));
+        let attr = IbvQpAttr::new(attr, attr_mask as u32);
+        let next_state = attr.qp_state();
+        if let Err(err) = bluerdma.update_qp(qp.qp_num, attr) {
+            return to_errno(&err);
+        }
+        if let Some(state) = next_state {
+            qp.state = state;
+        }
         0
     }
 
+    #[allow(clippy::cast_sign_loss)]
     #[inline]
     fn query_qp(
         qp: *mut ibverbs_sys::ibv_qp,
@@ -312,9 +832,28 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         attr_mask: core::ffi::c_int,
         init_attr: *mut ibverbs_sys::ibv_qp_init_attr,
     ) -> ::std::os::raw::c_int {
-        let qp = unsafe { *qp };
-        let context = qp.context;
-        let bluerdma = unsafe { get_device(context) };
+        let (Some(qp), Some(attr), Some(init_attr)) =
+            (unsafe { qp.as_ref() }, unsafe { attr.as_mut() }, unsafe {
+                init_attr.as_mut()
+            })
+        else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(qp.context) };
+        let qp_attr = match bluerdma.query_qp(qp.qp_num) {
+            Ok(x) => x,
+            Err(err) => return to_errno(&err),
+        };
+        *attr = IbvQpAttr::from_qp_attr(&qp_attr, attr_mask as u32).into_inner();
+        *init_attr = ibverbs_sys::ibv_qp_init_attr {
+            qp_context: qp.qp_context,
+            send_cq: qp.send_cq,
+            recv_cq: qp.recv_cq,
+            srq: qp.srq,
+            cap: qp_attr.cap.to_ibv(),
+            qp_type: qp.qp_type,
+            sq_sig_all: qp_attr.sq_sig_all.into(),
+        };
 
         0
     }
@@ -331,7 +870,10 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         let context = unsafe { (*pd) }.context;
         let bluerdma = unsafe { get_device(context) };
         let pd_handle = unsafe { *pd }.handle;
-        let Ok(mr_key) = bluerdma.reg_mr(addr as u64, length, pd_handle, access as u8) else {
+        let Ok(access) = u8::try_from(access) else {
+            return ptr::null_mut();
+        };
+        let Ok(mr_key) = bluerdma.reg_mr(addr as u64, length, pd_handle, access) else {
             return ptr::null_mut();
         };
         let ibv_mr = Box::new(ibverbs_sys::ibv_mr {
@@ -351,15 +893,130 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         if mr.is_null() {
             return libc::EINVAL;
         }
-        let mr = unsafe { *mr };
-        if mr.pd.is_null() {
+        let mr_ref = unsafe { *mr };
+        if mr_ref.pd.is_null() {
             return libc::EINVAL;
         }
-        let pd = unsafe { *mr.pd };
+        let bluerdma = unsafe { get_device(mr_ref.context) };
+        if let Err(err) = bluerdma.dereg_mr(mr_ref.handle) {
+            return to_errno(&err);
+        }
+        drop(unsafe { Box::from_raw(mr) });
+
+        0
+    }
+
+    #[allow(clippy::cast_sign_loss)]
+    #[inline]
+    fn rereg_mr(
+        mr: *mut ibverbs_sys::ibv_mr,
+        flags: core::ffi::c_int,
+        pd: *mut ibverbs_sys::ibv_pd,
+        addr: *mut ::std::os::raw::c_void,
+        length: usize,
+        access: core::ffi::c_int,
+    ) -> ::std::os::raw::c_int {
+        let Some(mr) = (unsafe { mr.as_mut() }) else {
+            return libc::EINVAL;
+        };
+        let flags = flags as u32;
+        if flags & !ibverbs_sys::IBV_REREG_MR_FLAGS_SUPPORTED != 0
+            || flags & ibverbs_sys::IBV_REREG_MR_KEEP_VALID != 0
+        {
+            return libc::EOPNOTSUPP;
+        }
+        let change_translation = flags & ibverbs_sys::IBV_REREG_MR_CHANGE_TRANSLATION != 0;
+        let change_pd = flags & ibverbs_sys::IBV_REREG_MR_CHANGE_PD != 0;
+        let change_access = flags & ibverbs_sys::IBV_REREG_MR_CHANGE_ACCESS != 0;
+        if change_pd && pd.is_null() {
+            return libc::EINVAL;
+        }
+        let access = match u8::try_from(access) {
+            Ok(access) => change_access.then_some(access),
+            Err(_err) if change_access => return libc::EINVAL,
+            Err(_err) => None,
+        };
         let bluerdma = unsafe { get_device(mr.context) };
-        if bluerdma.dereg_mr(mr.handle).is_err() {
+        let result = bluerdma.rereg_mr(
+            mr.handle,
+            change_translation.then_some((addr as u64, length)),
+            change_pd.then(|| unsafe { *pd }.handle),
+            access,
+        );
+        if let Err(err) = result {
+            return to_errno(&err);
+        }
+        if change_translation {
+            mr.addr = addr;
+            mr.length = length;
+        }
+        if change_pd {
+            mr.pd = pd;
+        }
+
+        0
+    }
+
+    #[inline]
+    fn alloc_mw(
+        pd: *mut ibverbs_sys::ibv_pd,
+        mw_type: ibverbs_sys::ibv_mw_type,
+    ) -> *mut ibverbs_sys::ibv_mw {
+        let (Some(pd_ref), Some(ty)) = (unsafe { pd.as_ref() }, MwType::from_ibv(mw_type)) else {
+            return ptr::null_mut();
+        };
+        let bluerdma = unsafe { get_device(pd_ref.context) };
+        let Ok(rkey) = bluerdma.alloc_mw(pd_ref.handle, ty) else {
+            return ptr::null_mut();
+        };
+        Box::into_raw(Box::new(ibverbs_sys::ibv_mw {
+            context: pd_ref.context,
+            pd,
+            rkey,
+            handle: rkey,
+            type_: mw_type,
+        }))
+    }
+
+    #[inline]
+    fn dealloc_mw(mw: *mut ibverbs_sys::ibv_mw) -> ::std::os::raw::c_int {
+        let Some(mw_ref) = (unsafe { mw.as_ref() }) else {
+            return libc::EINVAL;
+        };
+        let bluerdma = unsafe { get_device(mw_ref.context) };
+        if let Err(err) = bluerdma.dealloc_mw(mw_ref.rkey) {
+            return to_errno(&err);
+        }
+        drop(unsafe { Box::from_raw(mw) });
+
+        0
+    }
+
+    #[inline]
+    fn bind_mw(
+        qp: *mut ibverbs_sys::ibv_qp,
+        mw: *mut ibverbs_sys::ibv_mw,
+        mw_bind: *mut ibverbs_sys::ibv_mw_bind,
+    ) -> ::std::os::raw::c_int {
+        let (Some(qp), Some(mw), Some(mw_bind)) =
+            (unsafe { qp.as_ref() }, unsafe { mw.as_mut() }, unsafe {
+                mw_bind.as_ref()
+            })
+        else {
+            return libc::EINVAL;
+        };
+        if MwType::from_ibv(mw.type_) != Some(MwType::Type1) {
+            return libc::EINVAL;
+        }
+        let Some(info) = MwBindInfo::from_ibv(mw_bind.bind_info) else {
             return libc::EINVAL;
         };
+        let bluerdma = unsafe { get_device(qp.context) };
+        let wr = SendWrBindMw::new_type1(mw_bind.wr_id, mw_bind.send_flags, mw.rkey, info);
+        if let Err(err) = bluerdma.post_send(qp.qp_num, SendWr::BindMw(wr)) {
+            return to_errno(&err);
+        }
+        mw.rkey = wr.rkey;
 
         0
     }
@@ -371,12 +1028,19 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         bad_wr: *mut *mut ibverbs_sys::ibv_send_wr,
     ) -> ::std::os::raw::c_int {
         let qp = unsafe { *qp };
-        let wr = unsafe { *wr };
+        let send_wr = unsafe { *wr };
         let context = qp.context;
         let bluerdma = unsafe { get_device(context) };
         let qp_num = qp.qp_num;
-        let wr = SendWr::new(wr).unwrap_or_else(|_| todo!("handle invalid input"));
-        bluerdma.post_send(qp_num, wr);
+        let result = SendWr::new(send_wr)
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))
+            .and_then(|send_wr| bluerdma.post_send(qp_num, send_wr));
+        if let Err(err) = result {
+            if let Some(bad_wr) = unsafe { bad_wr.as_mut() } {
+                *bad_wr = wr;
+            }
+            return to_errno(&err);
+        }
 
         0
     }
@@ -388,12 +1052,19 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         bad_wr: *mut *mut ibverbs_sys::ibv_recv_wr,
     ) -> ::std::os::raw::c_int {
         let qp = unsafe { *qp };
-        let wr = unsafe { *wr };
+        let recv_wr = unsafe { *wr };
         let context = qp.context;
         let bluerdma = unsafe { get_device(context) };
         let qp_num = qp.qp_num;
-        let wr = RecvWr::new(wr).unwrap_or_else(|| todo!("handle invalid input"));
-        bluerdma.post_recv(qp_num, wr);
+        let result = RecvWr::new(recv_wr)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))
+            .and_then(|recv_wr| bluerdma.post_recv(qp_num, recv_wr));
+        if let Err(err) = result {
+            if let Some(bad_wr) = unsafe { bad_wr.as_mut() } {
+                *bad_wr = wr;
+            }
+            return to_errno(&err);
+        }
 
         0
     }
@@ -419,21 +1090,33 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
                 match c {
                     Completion::Send { wr_id }
                     | Completion::RdmaWrite { wr_id }
-                    | Completion::RdmaRead { wr_id } => {
+                    | Completion::RdmaRead { wr_id }
+                    | Completion::BindMw { wr_id }
+                    | Completion::LocalInv { wr_id }
+                    | Completion::Error { wr_id, .. } => {
+                        wc.wr_id = wr_id;
+                    }
+                    Completion::CompareSwap { wr_id } | Completion::FetchAdd { wr_id } => {
                         wc.wr_id = wr_id;
+                        wc.byte_len = SendWrAtomic::SIZE;
                     }
-                    Completion::Recv { wr_id, imm } => {
+                    Completion::Recv { wr_id, imm, .. } => {
                         wc.wr_id = wr_id;
                         if let Some(imm) = imm {
                             wc.__bindgen_anon_1.imm_data = imm;
                         }
                     }
-                    Completion::RecvRdmaWithImm { imm } => {
+                    Completion::RecvRdmaWithImm { imm, .. } => {
                         wc.__bindgen_anon_1.imm_data = imm;
                     }
+                    Completion::RecvWithInv { wr_id, rkey, .. } => {
+                        wc.wr_id = wr_id;
+                        wc.wc_flags = ibverbs_sys::ibv_wc_flags::IBV_WC_WITH_INV.0;
+                        wc.__bindgen_anon_1.invalidated_rkey = rkey;
+                    }
                 }
                 wc.opcode = c.opcode();
-                wc.status = ibverbs_sys::ibv_wc_status::IBV_WC_SUCCESS;
+                wc.status = c.status().to_ibv_wc_status();
             }
         }
 
@@ -441,6 +1124,82 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
     }
 }
 
+/// Converts an error returned by the device into an errno value
+#[allow(clippy::wildcard_enum_match_arm)]
+fn to_errno(err: &io::Error) -> core::ffi::c_int {
+    if let Some(errno) = err.raw_os_error() {
+        return errno;
+    }
+    if let Some(err) = err
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<CommandError>())
+    {
+        return match *err {
+            CommandError::Rejected(_) => libc::EINVAL,
+            CommandError::Timeout => libc::ETIMEDOUT,
+            CommandError::QueueFull => libc::EAGAIN,
+            CommandError::UnexpectedResponse { .. } => libc::EIO,
+            CommandError::Io(ref err) => to_errno(err),
+        };
+    }
+    match err.kind() {
+        io::ErrorKind::InvalidInput => libc::EINVAL,
+        io::ErrorKind::NotFound => libc::ENOENT,
+        io::ErrorKind::WouldBlock | io::ErrorKind::OutOfMemory => libc::ENOMEM,
+        io::ErrorKind::Unsupported => libc::EOPNOTSUPP,
+        io::ErrorKind::ResourceBusy => libc::EBUSY,
+        io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+        _ => libc::EIO,
+    }
+}
+
+/// Converts a connection manager error into an errno value
+fn cm_errno(err: &CmError) -> core::ffi::c_int {
+    match *err {
+        CmError::Rejected(_) => libc::ECONNREFUSED,
+        CmError::Protocol(_) => libc::EPROTO,
+        CmError::PrivateDataTooLong(_) => libc::EINVAL,
+        CmError::Io(ref err) => to_errno(err),
+    }
+}
+
+/// Returns the private data passed by the caller, `None` if it is too long or null
+/// with a non-zero length
+#[allow(unsafe_code)]
+unsafe fn private_data_slice<'a>(data: *const core::ffi::c_void, len: u8) -> Option<&'a [u8]> {
+    let len = usize::from(len);
+    if len > MAX_PRIVATE_DATA {
+        return None;
+    }
+    if len == 0 {
+        return Some(&[]);
+    }
+    (!data.is_null()).then(|| unsafe { slice::from_raw_parts(data.cast::<u8>(), len) })
+}
+
+/// Copies private data received from a peer to a caller buffer of `MAX_PRIVATE_DATA` bytes
+#[allow(unsafe_code)]
+unsafe fn copy_private_data(
+    src: &[u8],
+    dst: *mut core::ffi::c_void,
+    len: *mut u8,
+) -> Result<(), core::ffi::c_int> {
+    let Some(len) = (unsafe { len.as_mut() }) else {
+        return Err(libc::EINVAL);
+    };
+    if !src.is_empty() {
+        if dst.is_null() {
+            return Err(libc::EINVAL);
+        }
+        unsafe {
+            ptr::copy_nonoverlapping(src.as_ptr(), dst.cast::<u8>(), src.len());
+        }
+    }
+    *len = u8::try_from(src.len()).map_err(|_err| libc::EINVAL)?;
+
+    Ok(())
+}
+
 #[repr(C)]
 struct BlueRdmaDevice {
     pad: [u8; 712],
@@ -9,8 +9,9 @@ use pci_info::PciInfo;
 use std::{
     fs::{self, File, OpenOptions},
     io,
+    os::fd::AsRawFd,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{atomic::AtomicBool, Arc, OnceLock},
 };
 
 use crate::mem::{
@@ -18,7 +19,7 @@ use crate::mem::{
     virt_to_phy::PhysAddrResolverLinuxX86,
 };
 
-use super::{ops_impl::HwDevice, DeviceAdaptor};
+use super::{irq::EventFd, ops_impl::HwDevice, DeviceAdaptor};
 
 const BAR_INDEX: usize = 0;
 const BAR_INDEX_DMA_ENGINE: usize = 1;
@@ -29,6 +30,7 @@ const PCI_SYSFS_BUS_PATH: &str = "/sys/bus/pci/devices";
 
 #[derive(Clone, Debug)]
 pub(crate) struct VfioPciCsrAdaptor {
+    device: Arc<VfioPciDevice>,
     bar: Arc<MappedOwningPciRegion>,
 }
 
@@ -46,9 +48,35 @@ impl VfioPciCsrAdaptor {
         })?;
         let mapped_bar = bar.map(..BAR_MAP_RANGE_END, Permissions::ReadWrite)?;
         Ok(Self {
+            device: Arc::new(device),
             bar: Arc::new(mapped_bar),
         })
     }
+
+    /// Enables the first `count` MSI-X vectors, each signaling its own eventfd
+    fn enable_msix(&self, count: usize) -> io::Result<Vec<EventFd>> {
+        let interrupts = self.device.interrupts();
+        let msi_x = interrupts.msi_x();
+        if count > msi_x.max() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "device supports {} MSI-X vectors, {count} required",
+                    msi_x.max()
+                ),
+            ));
+        }
+        let event_fds = std::iter::repeat_with(EventFd::new)
+            .take(count)
+            .collect::<io::Result<Vec<_>>>()?;
+        let raw_fds: Vec<_> = event_fds.iter().map(AsRawFd::as_raw_fd).collect();
+        msi_x.enable(&raw_fds)?;
+        Ok(event_fds)
+    }
+
+    fn disable_msix(&self) -> io::Result<()> {
+        self.device.interrupts().msi_x().disable()
+    }
 }
 
 // TODO: use u64 instead of usize
@@ -117,12 +145,15 @@ impl DeviceAdaptor for SysfsPciCsrAdaptor {
 
 pub(crate) struct PciHwDevice {
     sysfs_path: PathBuf,
+    /// VFIO handle kept open while interrupts are enabled
+    vfio: OnceLock<VfioPciCsrAdaptor>,
 }
 
 impl PciHwDevice {
     pub(crate) fn new(sysfs_path: impl AsRef<Path>) -> Self {
         Self {
             sysfs_path: sysfs_path.as_ref().into(),
+            vfio: OnceLock::new(),
         }
     }
 
@@ -137,7 +168,10 @@ impl PciHwDevice {
         let location = device.location().map_err(|_err| build_err())?;
         let sysfs_path = PathBuf::from(PCI_SYSFS_BUS_PATH).join(location.to_string());
 
-        Ok(Self { sysfs_path })
+        Ok(Self {
+            sysfs_path,
+            vfio: OnceLock::new(),
+        })
     }
 
     pub(crate) fn reset(&self) -> io::Result<()> {
@@ -179,6 +213,26 @@ impl HwDevice for PciHwDevice {
     fn new_phys_addr_resolver(&self) -> Self::PhysAddrResolver {
         PhysAddrResolverLinuxX86
     }
+
+    fn enable_irq_vectors(
+        &self,
+        count: usize,
+        _is_shutdown: &Arc<AtomicBool>,
+    ) -> io::Result<Vec<EventFd>> {
+        if self.vfio.get().is_none() {
+            let _ignore = self.vfio.set(VfioPciCsrAdaptor::new(&self.sysfs_path)?);
+        }
+        self.vfio
+            .get()
+            .unwrap_or_else(|| unreachable!("VFIO device is opened"))
+            .enable_msix(count)
+    }
+
+    fn disable_irq_vectors(&self) -> io::Result<()> {
+        self.vfio
+            .get()
+            .map_or(Ok(()), VfioPciCsrAdaptor::disable_msix)
+    }
 }
 
 pub(crate) struct DmaEngineConfigurator {
@@ -0,0 +1,205 @@
+use std::{
+    io,
+    net::SocketAddr,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::poll::{PollConfig, Unparker};
+
+use super::mode::Mode;
+
+const DEFAULT_MODERATION_US: u64 = 20;
+const DEFAULT_FALLBACK_PARK_US: u64 = 100_000;
+
+/// Number of MSI-X vectors used by the driver
+///
+/// The vector raised by each queue is fixed by the hardware and has no CSR:
+/// vectors 0-3 signal the meta report queue of the channel with the same
+/// index, vector 4 signals the simple NIC RX queue. Other queues never raise
+/// interrupts.
+pub(crate) const IRQ_VECTOR_COUNT: usize = 5;
+/// Vector signaling the simple NIC RX queue
+const IRQ_VECTOR_SIMPLE_NIC_RX: usize = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct IrqConfig {
+    /// Waits for MSI-X interrupts instead of polling the queues
+    enabled: bool,
+    /// Minimum interval between two wakeups by the same vector, interrupts
+    /// arriving in between are coalesced
+    moderation_us: u64,
+    /// Park timeout of interrupt driven workers, bounds the latency of a lost interrupt
+    fallback_park_us: u64,
+    /// Addresses the emulator rings the doorbells of each emulated device on,
+    /// indexed by device
+    emulated_doorbells: Vec<SocketAddr>,
+}
+
+impl Default for IrqConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            moderation_us: DEFAULT_MODERATION_US,
+            fallback_park_us: DEFAULT_FALLBACK_PARK_US,
+            emulated_doorbells: Vec::new(),
+        }
+    }
+}
+
+impl IrqConfig {
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns the polling policy of workers woken by interrupts
+    pub(crate) fn notified_poll(&self, poll: PollConfig) -> PollConfig {
+        poll.with_max_park(self.fallback_park_us)
+    }
+
+    /// Returns the doorbell address of the emulated device at `index`
+    pub(crate) fn emulated_doorbell(&self, index: usize) -> Option<SocketAddr> {
+        self.emulated_doorbells.get(index).copied()
+    }
+
+    fn moderation(&self) -> Duration {
+        Duration::from_micros(self.moderation_us)
+    }
+}
+
+/// An eventfd signaled by an interrupt vector
+#[derive(Debug)]
+pub(crate) struct EventFd(OwnedFd);
+
+#[allow(unsafe_code, clippy::host_endian_bytes)] // eventfd takes a native endian u64
+impl EventFd {
+    pub(crate) fn new() -> io::Result<Self> {
+        // SAFETY: FFI call without pointer arguments
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is a newly created file descriptor owned by nobody else
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        self.0.try_clone().map(Self)
+    }
+
+    /// Adds one to the counter, waking the waiter
+    pub(crate) fn signal(&self) -> io::Result<()> {
+        let buf = 1u64.to_ne_bytes();
+        // SAFETY: `buf` is valid for reads of `buf.len()` bytes
+        let ret = unsafe { libc::write(self.0.as_raw_fd(), buf.as_ptr().cast(), buf.len()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Blocks until signaled, returns the number of signals since the last wait
+    pub(crate) fn wait(&self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        // SAFETY: `buf` is valid for writes of `buf.len()` bytes
+        let ret = unsafe { libc::read(self.0.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(u64::from_ne_bytes(buf))
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// Spawns a thread per used vector, waking the worker consuming the signaled queue
+///
+/// All meta report queues are consumed by a single worker. The threads exit
+/// once `is_shutdown` is set and the returned handle is shut down.
+pub(crate) fn spawn_irq_dispatchers(
+    vectors: Vec<EventFd>,
+    mode: Mode,
+    meta_report: &Unparker,
+    simple_nic_rx: &Unparker,
+    config: &IrqConfig,
+    is_shutdown: &Arc<AtomicBool>,
+) -> io::Result<IrqDispatchers> {
+    let mut wakers = Vec::new();
+    for (vector, event_fd) in vectors.into_iter().enumerate() {
+        let (name, unparker) = match vector {
+            IRQ_VECTOR_SIMPLE_NIC_RX => ("irq-simple-nic-rx".into(), simple_nic_rx),
+            x if x < mode.num_channel() => (format!("irq-meta-report-{x}"), meta_report),
+            _ => continue,
+        };
+        wakers.push(event_fd.try_clone()?);
+        IrqDispatcher {
+            event_fd,
+            unparker: unparker.clone(),
+            moderation: config.moderation(),
+            is_shutdown: Arc::clone(is_shutdown),
+        }
+        .spawn(name);
+    }
+    Ok(IrqDispatchers { wakers })
+}
+
+/// Handle of the spawned dispatcher threads
+#[derive(Debug)]
+pub(crate) struct IrqDispatchers {
+    wakers: Vec<EventFd>,
+}
+
+impl IrqDispatchers {
+    /// Wakes all dispatchers so they observe the shutdown flag and exit
+    pub(crate) fn shutdown(&self) {
+        for waker in &self.wakers {
+            if let Err(err) = waker.signal() {
+                error!("failed to wake interrupt dispatcher: {err}");
+            }
+        }
+    }
+}
+
+struct IrqDispatcher {
+    event_fd: EventFd,
+    unparker: Unparker,
+    moderation: Duration,
+    is_shutdown: Arc<AtomicBool>,
+}
+
+impl IrqDispatcher {
+    fn spawn(self, name: String) {
+        let _handle = thread::Builder::new()
+            .name(name)
+            .spawn(move || self.run())
+            .unwrap_or_else(|err| unreachable!("Failed to spawn thread: {err}"));
+    }
+
+    fn run(self) {
+        loop {
+            if let Err(err) = self.event_fd.wait() {
+                error!("failed to wait for interrupt: {err}");
+                return;
+            }
+            if self.is_shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+            self.unparker.notify();
+            // interrupts raised while sleeping are read as one signal
+            thread::sleep(self.moderation);
+        }
+    }
+}
@@ -14,6 +14,9 @@ pub(crate) mod proxy;
 /// Adaptors
 pub(crate) mod adaptor;
 
+/// MSI-X interrupt delivery
+pub(crate) mod irq;
+
 /// Device mode reader
 pub(crate) mod mode;
 
@@ -1,45 +1,65 @@
 use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
     io, iter,
     net::Ipv4Addr,
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
 };
 
 use crossbeam_deque::Worker;
 use parking_lot::Mutex;
 use qp_attr::{IbvQpAttr, IbvQpInitAttr};
+use tracing::{debug, error};
 
 use crate::{
     ack_responder::AckResponder,
+    async_event::AsyncEvent,
+    atomic::{AtomicResponder, AtomicResponderHandle, AtomicWorker},
     completion::{
-        Completion, CompletionQueueTable, CompletionTask, CompletionWorker, CqManager, Event,
-        PostRecvEvent,
+        CompChannel, Completion, CompletionQueueTable, CompletionStatus, CompletionTask,
+        CompletionWorker, CqManager, Event, NotifyMode, PostRecvEvent,
     },
     config::DeviceConfig,
     device_protocol::{
         DeviceCommand, MttUpdate, PgtUpdate, RecvBufferMeta, SimpleNicTunnel, UpdateQp,
+        WorkReqOpCode,
     },
     mem::{
-        get_num_page, page::PageAllocator, pin_pages, virt_to_phy::AddressResolver, DmaBuf,
-        DmaBufAllocator, PageWithPhysAddr,
+        get_num_page,
+        page::PageAllocator,
+        staging::{segment_size, StagingPool, StagingPoolTable},
+        virt_to_phy::AddressResolver,
+        DmaBuf, DmaBufAllocator, PageWithPhysAddr, PinnedPages,
     },
-    mtt::{Mtt, PgtEntry},
+    mr::MemoryRegion,
+    mtt::key_index,
+    mtt::Mtt,
+    mw::{MwTable, MwType},
     net::config::NetworkConfig,
     packet_retransmit::PacketRetransmitWorker,
+    pd::{PdResource, PdTable},
+    poll::{AdaptivePoller, Unparker},
     protocol_impl::{
         queue::{alloc::DescRingBufAllocator, meta_report_queue::init_and_spawn_meta_worker},
         spawn_send_workers, CommandController, SendQueueScheduler, SimpleNicController,
     },
-    qp::{QpManager, QueuePairAttrTable},
-    rdma_write_worker::{RdmaWriteTask, RdmaWriteWorker},
-    recv::{
-        post_recv_channel, PostRecvTx, PostRecvTxTable, RecvWorker, RecvWr, RecvWrQueueTable,
-        TcpChannel,
-    },
-    send::{SendWr, SendWrBase, SendWrRdma},
+    qp::{convert_ibv_mtu_to_u16, QpManager, QpState, QueuePairAttr, QueuePairAttrTable},
+    rdma_write_worker::{staged_len, RdmaWriteTask, RdmaWriteWorker},
+    recv::{MuxChannel, PostRecvChannel, PostRecvTx, PostRecvTxTable, RecvWr, RecvWrQueueTable},
+    rnr_retry::{RnrRetryHandle, RnrRetryWorker},
+    send::{SendWr, SendWrBase, SendWrRdma, Sge},
+    srq::{SrqAttr, SrqManager, SrqTable, SrqTask, SrqWorker},
     timeout_retransmit::TimeoutRetransmitWorker,
 };
 
-use super::{mode::Mode, DeviceAdaptor};
+use super::{
+    irq::{spawn_irq_dispatchers, EventFd, IrqDispatchers, IRQ_VECTOR_COUNT},
+    mode::Mode,
+    DeviceAdaptor,
+};
 
 pub(crate) trait HwDevice {
     type Adaptor;
@@ -49,53 +69,138 @@ pub(crate) trait HwDevice {
     fn new_adaptor(&self) -> io::Result<Self::Adaptor>;
     fn new_dma_buf_allocator(&self) -> io::Result<Self::DmaBufAllocator>;
     fn new_phys_addr_resolver(&self) -> Self::PhysAddrResolver;
+
+    /// Enables `count` interrupt vectors, each signaling the returned eventfd of the same index
+    ///
+    /// Threads spawned to deliver the interrupts exit once `is_shutdown` is set.
+    fn enable_irq_vectors(
+        &self,
+        count: usize,
+        is_shutdown: &Arc<AtomicBool>,
+    ) -> io::Result<Vec<EventFd>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Disables the interrupt vectors enabled by `enable_irq_vectors`
+    fn disable_irq_vectors(&self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 pub(crate) trait DeviceOps {
+    fn alloc_pd(&mut self) -> io::Result<u32>;
+    fn dealloc_pd(&mut self, handle: u32) -> io::Result<()>;
     fn reg_mr(&mut self, addr: u64, length: usize, pd_handle: u32, access: u8) -> io::Result<u32>;
     fn dereg_mr(&mut self, mr_key: u32) -> io::Result<()>;
-    fn create_qp(&mut self, attr: IbvQpInitAttr) -> io::Result<u32>;
+    fn rereg_mr(
+        &mut self,
+        mr_key: u32,
+        translation: Option<(u64, usize)>,
+        pd_handle: Option<u32>,
+        access: Option<u8>,
+    ) -> io::Result<()>;
+    fn alloc_mw(&mut self, pd_handle: u32, mw_type: MwType) -> io::Result<u32>;
+    fn dealloc_mw(&mut self, rkey: u32) -> io::Result<()>;
+    fn create_qp(&mut self, pd_handle: u32, attr: IbvQpInitAttr) -> io::Result<u32>;
     fn update_qp(&mut self, qpn: u32, attr: IbvQpAttr) -> io::Result<()>;
+    fn query_qp(&self, qpn: u32) -> io::Result<QueuePairAttr>;
     fn destroy_qp(&mut self, qpn: u32);
-    fn create_cq(&mut self) -> Option<u32>;
+    /// Pins all packets of a QP to one send channel, `None` restores the hashed channel
+    fn set_qp_channel(&self, qpn: u32, channel: Option<usize>) -> io::Result<()>;
+    /// Sets the send scheduling weight of a QP, a QP gets `weight` times the
+    /// bandwidth share of a QP with weight 1
+    fn set_qp_weight(&self, qpn: u32, weight: u8) -> io::Result<()>;
+    /// Returns the network configuration of the card, its address is advertised to peers
+    fn network_config(&self) -> NetworkConfig;
+    fn create_cq(&mut self, cqe: usize) -> io::Result<u32>;
     fn destroy_cq(&mut self, handle: u32);
+    fn resize_cq(&mut self, handle: u32, cqe: usize) -> io::Result<()>;
+    fn set_cq_context(
+        &mut self,
+        handle: u32,
+        cq_context: u64,
+        channel: Option<u64>,
+    ) -> io::Result<()>;
+    /// Blocks until a CQ bound to `channel` fires, returns the `cq_context` of the CQ
+    fn get_cq_event(&self, channel: u64) -> io::Result<u64>;
+    fn req_notify_cq(&mut self, handle: u32, mode: NotifyMode) -> io::Result<()>;
     fn poll_cq(&mut self, handle: u32, max_num_entries: usize) -> Vec<Completion>;
     fn post_send(&mut self, qpn: u32, wr: SendWr) -> io::Result<()>;
     fn post_recv(&mut self, qpn: u32, wr: RecvWr) -> io::Result<()>;
+    fn get_async_event(&self) -> io::Result<AsyncEvent>;
+    fn create_srq(&mut self, pd_handle: u32, srq_context: u64, attr: SrqAttr) -> io::Result<u32>;
+    fn modify_srq(
+        &mut self,
+        handle: u32,
+        max_wr: Option<u32>,
+        srq_limit: Option<u32>,
+    ) -> io::Result<()>;
+    fn query_srq(&self, handle: u32) -> io::Result<SrqAttr>;
+    fn destroy_srq(&mut self, handle: u32) -> io::Result<()>;
+    fn post_srq_recv(&mut self, handle: u32, wr: RecvWr) -> io::Result<()>;
 }
 
 pub(crate) struct HwDeviceCtx<H: HwDevice> {
     device: H,
     mtt: Mtt,
     mtt_buffer: DmaBuf,
+    /// Pages pinned by the registered memory regions
+    pinned_pages: PinnedPages,
+    pd_table: PdTable,
+    mw_table: MwTable,
+    qp_attr_table: QueuePairAttrTable,
     qp_manager: QpManager,
     cq_manager: CqManager,
     cq_table: CompletionQueueTable,
-    cmd_controller: CommandController<H::Adaptor>,
+    /// Completion channels with bound CQs, keyed by the address of the `ibv_comp_channel`
+    comp_channels: HashMap<u64, Arc<CompChannel>>,
+    async_event_rx: flume::Receiver<AsyncEvent>,
+    srq_manager: SrqManager,
+    srq_table: SrqTable,
+    srq_tx: flume::Sender<SrqTask>,
+    /// QPs attached to an SRQ whose post recv channel is owned by the SRQ worker
+    srq_qps: HashSet<u32>,
+    cmd_controller: Arc<CommandController<H::Adaptor>>,
+    /// Bound on the first QP connection
+    post_recv_channel: Option<MuxChannel>,
     post_recv_tx_table: PostRecvTxTable,
+    /// Responder executing the atomics of the connected QPs, bound on the first QP
+    /// connection
+    atomic_responder: Option<AtomicResponderHandle>,
     recv_wr_queue_table: RecvWrQueueTable,
     rdma_write_tx: flume::Sender<RdmaWriteTask>,
+    rnr: RnrRetryHandle,
     completion_tx: flume::Sender<CompletionTask>,
+    send_scheduler: SendQueueScheduler,
+    /// Staging memory of the PDs, registered and grown by the WRs that need staging
+    staging_pools: StagingPoolTable,
     config: DeviceConfig,
     allocator: H::DmaBufAllocator,
+    /// Stops the workers and interrupt threads on drop
+    is_shutdown: Arc<AtomicBool>,
+    irq_dispatchers: Option<IrqDispatchers>,
 }
 
 #[allow(private_bounds)]
 impl<H> HwDeviceCtx<H>
 where
     H: HwDevice,
-    H::Adaptor: DeviceAdaptor + Send + 'static,
+    H::Adaptor: DeviceAdaptor + Send + Sync + 'static,
     H::DmaBufAllocator: DmaBufAllocator,
     H::PhysAddrResolver: AddressResolver,
 {
+    #[allow(clippy::too_many_lines, clippy::similar_names)] // sets up and wires all the workers
     pub(crate) fn initialize(device: H, config: DeviceConfig) -> io::Result<Self> {
         let mode = Mode::default();
         let adaptor = device.new_adaptor()?;
         let mut allocator = device.new_dma_buf_allocator()?;
         let mut rb_allocator = DescRingBufAllocator::new(&mut allocator);
-        let cmd_controller =
-            CommandController::init_v2(&adaptor, rb_allocator.alloc()?, rb_allocator.alloc()?)?;
-        let send_scheduler = SendQueueScheduler::new();
+        let cmd_controller = Arc::new(CommandController::init_v2(
+            &adaptor,
+            rb_allocator.alloc()?,
+            rb_allocator.alloc()?,
+        )?);
+        let send_scheduler = SendQueueScheduler::new(mode.num_channel(), config.dcqcn());
         let send_bufs = iter::repeat_with(|| rb_allocator.alloc())
             .take(mode.num_channel())
             .collect::<Result<_, _>>()?;
@@ -109,30 +214,77 @@ where
         let (retransmit_tx, retransmit_rx) = flume::unbounded();
         let (packet_retransmit_tx, packet_retransmit_rx) = flume::unbounded();
         let (rdma_write_tx, rdma_write_rx) = flume::unbounded();
+        let (atomic_tx, atomic_rx) = flume::unbounded();
         let rx_buffer = rb_allocator.alloc()?;
         let rx_buffer_pa = rx_buffer.phys_addr;
         let qp_attr_table = QueuePairAttrTable::new();
+        // weak, the table is shared with the worker receiving the task
+        let stopped_tx = rdma_write_tx.downgrade();
+        qp_attr_table.set_stopped_hook(move |qpn| {
+            if let Some(tx) = stopped_tx.upgrade() {
+                let _ignore = tx.send(RdmaWriteTask::new_qp_stopped(qpn));
+            }
+        });
         let qp_manager = QpManager::new(qp_attr_table.clone_arc());
+        let mtt = Mtt::new();
+        let mw_table = MwTable::new(Arc::<CommandController<_>>::clone(&cmd_controller));
         let cq_manager = CqManager::new();
-        let cq_table = CompletionQueueTable::new();
+        let (async_event_tx, async_event_rx) = flume::unbounded();
+        let cq_table = CompletionQueueTable::new(&async_event_tx);
+        let srq_table = SrqTable::new();
+        let (srq_worker, srq_tx) = SrqWorker::new(
+            srq_table.clone_arc(),
+            qp_attr_table.clone_arc(),
+            completion_tx.clone(),
+            async_event_tx,
+        );
+        srq_worker.spawn();
 
-        let simple_nic_controller = SimpleNicController::init_v2(
+        let mut simple_nic_controller = SimpleNicController::init_v2(
             &adaptor,
             rb_allocator.alloc()?,
             rb_allocator.alloc()?,
             rb_allocator.alloc()?,
             rx_buffer,
         )?;
-        spawn_send_workers(&adaptor, send_bufs, mode, &send_scheduler.injector())?;
+        let meta_unparker = Unparker::new();
+        let mut meta_poll = config.poll();
+        let mut irq_dispatchers = None;
+        if config.irq().enabled() {
+            let vectors = device.enable_irq_vectors(IRQ_VECTOR_COUNT, &is_shutdown)?;
+            meta_poll = config.irq().notified_poll(config.poll());
+            let simple_nic_rx_unparker = simple_nic_controller.enable_rx_notification(meta_poll);
+            irq_dispatchers = Some(spawn_irq_dispatchers(
+                vectors,
+                mode,
+                &meta_unparker,
+                &simple_nic_rx_unparker,
+                config.irq(),
+                &is_shutdown,
+            )?);
+        }
+        spawn_send_workers(
+            &adaptor,
+            send_bufs,
+            mode,
+            &send_scheduler.queue(),
+            config.poll(),
+            &meta_unparker,
+        )?;
         init_and_spawn_meta_worker(
             &adaptor,
             meta_bufs,
             mode,
+            qp_attr_table.clone_arc(),
+            mtt.mr_table(),
+            mw_table.clone_arc(),
             ack_tx.clone(),
             retransmit_tx.clone(),
             packet_retransmit_tx.clone(),
             completion_tx.clone(),
             rdma_write_tx.clone(),
+            send_scheduler.rate_limiter(),
+            AdaptivePoller::new(meta_poll, meta_unparker),
             Arc::clone(&is_shutdown),
         )?;
         CompletionWorker::new(
@@ -140,6 +292,8 @@ where
             cq_table.clone_arc(),
             qp_attr_table.clone_arc(),
             ack_tx,
+            srq_tx.clone(),
+            mw_table.clone_arc(),
         )
         .spawn();
         cmd_controller.set_network(config.network())?;
@@ -149,16 +303,43 @@ where
         #[allow(clippy::mem_forget)]
         std::mem::forget(simple_nic_rx); // prevent libc::munmap being called
         AckResponder::new(qp_attr_table.clone_arc(), ack_rx, Box::new(simple_nic_tx)).spawn();
-        TimeoutRetransmitWorker::new(retransmit_rx, send_scheduler.clone_arc(), config.ack())
-            .spawn();
+        TimeoutRetransmitWorker::new(
+            retransmit_rx,
+            send_scheduler.clone_arc(),
+            completion_tx.clone(),
+            config.ack(),
+        )
+        .spawn();
         PacketRetransmitWorker::new(packet_retransmit_rx, send_scheduler.clone_arc()).spawn();
+        let recv_wr_queue_table = RecvWrQueueTable::new();
+        let (rnr_worker, rnr) = RnrRetryWorker::new(
+            qp_attr_table.clone_arc(),
+            recv_wr_queue_table.clone_arc(),
+            mw_table.clone_arc(),
+            mtt.mr_table(),
+            rdma_write_tx.clone(),
+            completion_tx.clone(),
+        );
+        rnr_worker.spawn();
+        AtomicWorker::new(
+            atomic_rx,
+            config.atomic(),
+            qp_attr_table.clone_arc(),
+            mtt.mr_table(),
+            completion_tx.clone(),
+            rnr.clone_arc(),
+        )
+        .spawn();
+        let staging_pools = StagingPoolTable::new();
         RdmaWriteWorker::new(
             rdma_write_rx,
-            qp_attr_table,
-            send_scheduler,
+            qp_attr_table.clone_arc(),
+            send_scheduler.clone_arc(),
             retransmit_tx,
             packet_retransmit_tx,
             completion_tx.clone(),
+            atomic_tx,
+            staging_pools.clone_arc(),
         )
         .spawn();
 
@@ -168,29 +349,174 @@ where
             qp_manager,
             cq_manager,
             cq_table,
+            comp_channels: HashMap::new(),
+            async_event_rx,
+            srq_manager: SrqManager::new(),
+            srq_table,
+            srq_tx,
+            srq_qps: HashSet::new(),
             mtt_buffer: rb_allocator.alloc()?,
-            mtt: Mtt::new(),
+            mtt,
+            pinned_pages: PinnedPages::new(),
+            pd_table: PdTable::new(),
+            mw_table,
+            qp_attr_table,
+            post_recv_channel: None,
             post_recv_tx_table: PostRecvTxTable::new(),
-            recv_wr_queue_table: RecvWrQueueTable::new(),
+            atomic_responder: None,
+            recv_wr_queue_table,
             rdma_write_tx,
+            rnr,
             completion_tx,
+            send_scheduler,
+            staging_pools,
             config,
             allocator,
+            is_shutdown,
+            irq_dispatchers,
         })
     }
+
+    /// Makes room in the staging pool of the PD for the copies the WR needs
+    ///
+    /// A write stages the packets straddling its unaligned SGE boundaries, a read into
+    /// several SGEs stages the whole message. The remote buffer of a SEND is only known
+    /// once a receive WR is taken, so the whole message is reserved. If the pool has no
+    /// room, a segment sized from the send queue of the QP is registered, so a valid WR
+    /// never fails for lack of staging memory.
+    fn reserve_staging(&mut self, qp: &QueuePairAttr, wr: &SendWr) -> io::Result<()> {
+        let sg_list = wr.sg_list();
+        if sg_list.len() <= 1 {
+            return Ok(());
+        }
+        let pmtu =
+            convert_ibv_mtu_to_u16(qp.pmtu).ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        #[allow(clippy::wildcard_enum_match_arm)]
+        let len = match *wr {
+            SendWr::Rdma(ref wr) if wr.opcode() != WorkReqOpCode::RdmaRead => {
+                staged_len(pmtu.into(), wr.raddr(), &sg_list)
+            }
+            _ => u64::from(sg_list.total_len()),
+        };
+        let len =
+            usize::try_from(len).map_err(|err| io::Error::new(io::ErrorKind::OutOfMemory, err))?;
+        let pool = self.staging_pools.get(qp.pd_handle);
+        if len == 0 || pool.as_ref().is_some_and(|pool| pool.has_room(len)) {
+            return Ok(());
+        }
+        let (buf, lkey) = self
+            .register_staging_segment(qp.pd_handle, segment_size(qp.cap.max_send_wr, pmtu, len))?;
+        match pool {
+            Some(pool) => pool.grow(buf, lkey),
+            None => self
+                .staging_pools
+                .insert(qp.pd_handle, StagingPool::new(buf, lkey)),
+        }
+        Ok(())
+    }
+
+    /// Registers `len` bytes of staging memory as a memory region of the PD
+    ///
+    /// The region has local write access, it is not attached to the PD so it does not
+    /// keep the PD busy.
+    #[allow(clippy::as_conversions)] // pointer and usize to u64
+    fn register_staging_segment(
+        &mut self,
+        pd_handle: u32,
+        len: usize,
+    ) -> io::Result<(DmaBuf, u32)> {
+        let buf = self.allocator.alloc(len)?;
+        let (addr, length) = (buf.ptr as u64, buf.len);
+        self.pinned_pages.pin(addr, length)?;
+        let access = ibverbs_sys::ibv_access_flags::IBV_ACCESS_LOCAL_WRITE.0 as u8;
+        let mr = MemoryRegion::new(addr, length as u64, pd_handle, access);
+        match self.write_mr(None, mr) {
+            Ok(lkey) => Ok((buf, lkey)),
+            Err(err) => {
+                let _ignore = self.pinned_pages.unpin(addr, length);
+                Err(err)
+            }
+        }
+    }
+
+    /// Deregisters the staging pool of the PD, if any
+    fn release_staging_pool(&mut self, pd_handle: u32) {
+        let Some(pool) = self.staging_pools.remove(pd_handle) else {
+            return;
+        };
+        for (addr, len, lkey) in pool.regions() {
+            let result = self
+                .cmd_controller
+                .update_mtt(MttUpdate::new(0, 0, lkey, pd_handle, 0, 0))
+                .map_err(io::Error::from)
+                .and_then(|()| self.mtt.deregister(lkey));
+            if let Err(err) = result {
+                error!(pd_handle, "failed to deregister staging pool: {err}");
+            }
+            if let Err(err) = self.pinned_pages.unpin(addr, len) {
+                error!(pd_handle, "failed to unpin staging pool: {err}");
+            }
+        }
+    }
+}
+
+impl<H: HwDevice> Drop for HwDeviceCtx<H> {
+    fn drop(&mut self) {
+        self.is_shutdown.store(true, Ordering::Relaxed);
+        if let Some(responder) = self.atomic_responder.take() {
+            responder.close();
+        }
+        if let Some(dispatchers) = self.irq_dispatchers.take() {
+            if let Err(err) = self.device.disable_irq_vectors() {
+                error!("failed to disable interrupt vectors: {err}");
+            }
+            dispatchers.shutdown();
+        }
+    }
 }
 
 impl<H: HwDevice> HwDeviceCtx<H> {
-    fn send(&self, qpn: u32, mut wr: SendWrBase) -> io::Result<()> {
-        match self.recv_wr_queue_table.pop(qpn) {
-            Some(x) => {
-                if wr.length != x.length {
-                    return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    /// Issues a SEND into the next receive WR of the peer
+    ///
+    /// A WR too short for the SEND stays with the peer, the SEND completes with
+    /// `RemoteInvalidRequest` and moves the QP to the error state.
+    fn send(&mut self, qpn: u32, mut wr: SendWrBase) -> io::Result<()> {
+        let x = match self.recv_wr_queue_table.pop_fitting(qpn, wr.length) {
+            Some(Ok(x)) => x,
+            Some(Err(_short)) => {
+                self.qp_attr_table.set_state(qpn, QpState::Err);
+                let completion = Completion::send_error(
+                    &SendWr::Send(wr),
+                    CompletionStatus::RemoteInvalidRequest,
+                );
+                if let Some(completion) = completion {
+                    let _ignore = self
+                        .completion_tx
+                        .send(CompletionTask::Complete { qpn, completion });
                 }
-                let wr = SendWrRdma::new_from_base(wr, x.addr, x.lkey);
-                self.rdma_write(qpn, wr)
+                return Ok(());
+            }
+            None => {
+                // receiver not ready, retried after the RNR delay
+                self.rnr.enqueue(qpn, SendWr::Send(wr));
+                self.demand_recv_wr(qpn);
+                return Ok(());
+            }
+        };
+        let wr = SendWrRdma::new_from_base(wr, x.addr, x.lkey);
+        self.rdma_write(qpn, wr)
+    }
+
+    /// Asks the peer for a receive WR, served if the peer draws its WRs from an SRQ
+    fn demand_recv_wr(&mut self, qpn: u32) {
+        if self.srq_qps.contains(&qpn) {
+            let _ignore = self.srq_tx.send(SrqTask::Demand { qpn });
+            return;
+        }
+        if let Some(tx) = self.post_recv_tx_table.get_qp_mut(qpn) {
+            if let Err(err) = tx.demand() {
+                error!("failed to demand recv wr for qp {qpn}: {err}");
             }
-            None => todo!("return rnr error"),
         }
     }
 
@@ -210,88 +536,324 @@ impl<H: HwDevice> HwDeviceCtx<H> {
             .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?
     }
 
-    fn network_config(&self) -> NetworkConfig {
-        self.config.network()
+    /// Checks that the local buffers lie within MRs of the PD registered with `access`
+    fn check_sges(
+        &self,
+        pd_handle: u32,
+        sges: &[Sge],
+        access: ibverbs_sys::ibv_access_flags,
+    ) -> io::Result<()> {
+        sges.iter()
+            .try_for_each(|sge| self.mtt.validate_sge(pd_handle, sge, access))
+    }
+
+    /// Executes a memory window bind or local invalidate, all earlier WRs of the QP have
+    /// been issued
+    ///
+    /// A failure moves the QP to the error state, the error completion and the flushed
+    /// WRs posted afterwards complete behind the earlier WRs.
+    fn post_mw_wr(&self, qp: &QueuePairAttr, wr: &SendWr) {
+        let qpn = qp.qpn;
+        let completion = match self
+            .mw_table
+            .execute(qpn, qp.pd_handle, wr, &self.mtt.mr_table())
+        {
+            Ok(completion) => completion,
+            Err(status) => {
+                self.qp_attr_table.set_state(qpn, QpState::Err);
+                Completion::send_error(wr, status)
+            }
+        };
+        if let Some(completion) = completion {
+            let _ignore = self
+                .completion_tx
+                .send(CompletionTask::Complete { qpn, completion });
+        }
+    }
+
+    /// Sets up the channels passing receive WRs and atomic requests between the QP and
+    /// its peer
+    ///
+    /// For a QP attached to an SRQ, the sending side is handed over to the SRQ worker.
+    fn connect_post_recv_channel(&mut self, qp: &QueuePairAttr) -> io::Result<()> {
+        let qpn = qp.qpn;
+        if self.post_recv_tx_table.get_qp_mut(qpn).is_some() || self.srq_qps.contains(&qpn) {
+            return Ok(());
+        }
+        let dqp_ip = Ipv4Addr::from_bits(qp.dqp_ip);
+        let wr_queue = self
+            .recv_wr_queue_table
+            .clone_recv_wr_queue(qpn)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        if self.post_recv_channel.is_none() {
+            let channel = MuxChannel::bind(self.config.network().ip.ip(), self.config.post_recv())?;
+            self.post_recv_channel = Some(channel);
+        }
+        let channel = self
+            .post_recv_channel
+            .as_ref()
+            .unwrap_or_else(|| unreachable!());
+        let tx = channel.open(qpn, dqp_ip, qp.dqpn, wr_queue)?;
+        if let Some(srq) = qp.srq {
+            channel.forward_demands(qpn, self.srq_tx.clone());
+            let _ignore = self.srq_tx.send(SrqTask::Attach { srq, qpn, tx });
+            let _ignore = self.srq_qps.insert(qpn);
+        } else {
+            self.post_recv_tx_table.insert(qpn, tx);
+        }
+        if self.atomic_responder.is_none() {
+            let responder = AtomicResponder::bind(
+                self.config.network().ip.ip(),
+                self.config.atomic(),
+                self.qp_attr_table.clone_arc(),
+                self.mtt.mr_table(),
+            )?
+            .spawn()?;
+            self.atomic_responder = Some(responder);
+        }
+
+        Ok(())
     }
 }
 
-impl<H> DeviceOps for HwDeviceCtx<H>
+impl<H> HwDeviceCtx<H>
 where
     H: HwDevice,
-    H::Adaptor: DeviceAdaptor + Send + 'static,
+    H::Adaptor: DeviceAdaptor + Send + Sync + 'static,
     H::PhysAddrResolver: AddressResolver,
 {
-    fn reg_mr(&mut self, addr: u64, length: usize, pd_handle: u32, access: u8) -> io::Result<u32> {
-        fn chunks(entry: PgtEntry) -> Vec<PgtEntry> {
-            /// Maximum number of Page Table entries (PGT entries) that can be allocated in a single `PCIe` transaction.
-            /// A `PCIe` transaction size is 128 bytes, and each PGT entry is a u64 (8 bytes).
-            /// Therefore, 512 bytes / 8 bytes per entry = 16 entries per allocation.
-            const MAX_NUM_PGT_ENTRY_PER_ALLOC: usize = 64;
-
-            let base_index = entry.index;
-            let end_index = base_index + entry.count;
-            (base_index..end_index)
-                .step_by(MAX_NUM_PGT_ENTRY_PER_ALLOC)
-                .map(|index| PgtEntry {
-                    index,
-                    count: (MAX_NUM_PGT_ENTRY_PER_ALLOC as u32).min(end_index - index),
-                })
-                .collect()
-        }
-
-        pin_pages(addr, length)?;
-
-        let addr_resolver = self.device.new_phys_addr_resolver();
-        let num_pages = get_num_page(addr, length);
-        let (mr_key, pgt_entry) = self.mtt.register(num_pages)?;
-        let length_u32 =
-            u32::try_from(length).map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
-        let mut phys_addrs = addr_resolver
-            .virt_to_phys_range(addr, num_pages)?
+    /// Writes the page table and the MR entry of a pinned memory region to the card
+    ///
+    /// Re-registers `mr_key` if given, otherwise registers a new key.
+    /// A failed re-registration restores the previous registration of the key.
+    fn write_mr(&mut self, mr_key: Option<u32>, mr: MemoryRegion) -> io::Result<u32> {
+        let start = Instant::now();
+        let phys_addrs = Self::resolve_pages(&self.device, &mr)?;
+        let num_pages = phys_addrs.len();
+        let mut registration = match mr_key {
+            Some(mr_key) => self.mtt.begin_reregister(mr_key, num_pages, mr)?,
+            None => self.mtt.begin_register(num_pages, mr)?,
+        };
+        let cmd = &*self.cmd_controller;
+        let result = registration
+            .write_pgt(cmd, &mut self.mtt_buffer, &phys_addrs)
+            .and_then(|()| registration.commit(cmd));
+        let mr_key = match result {
+            Ok(mr_key) => mr_key,
+            Err(err) => {
+                if let Some(prev) = registration.prev_mr() {
+                    // the previous pages stay pinned until the re-registration succeeds
+                    let restored =
+                        Self::resolve_pages(&self.device, &prev).and_then(|prev_pages| {
+                            registration.restore(cmd, &mut self.mtt_buffer, &prev_pages)
+                        });
+                    if let Err(restore_err) = restored {
+                        error!(mr_key, "failed to restore memory region: {restore_err}");
+                    }
+                }
+                return Err(err);
+            }
+        };
+        drop(registration);
+        let (pgt_used, pgt_len) = self.mtt.pgt_usage();
+        debug!(
+            mr_key,
+            num_pages,
+            pgt_used,
+            pgt_len,
+            elapsed = ?start.elapsed(),
+            "memory region written"
+        );
+
+        Ok(mr_key)
+    }
+
+    /// Resolves the physical addresses of the pages of a pinned memory region
+    ///
+    /// The pagemap is read in batches, see `virt_to_phys_range`.
+    fn resolve_pages(device: &H, mr: &MemoryRegion) -> io::Result<Vec<u64>> {
+        let length = usize::try_from(mr.length)
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let num_pages = get_num_page(mr.addr, length);
+        device
+            .new_phys_addr_resolver()
+            .virt_to_phys_range(mr.addr, num_pages)?
             .into_iter()
             .collect::<Option<Vec<_>>>()
             .ok_or(io::Error::new(
                 io::ErrorKind::NotFound,
                 "physical address not found",
-            ))?
-            .into_iter();
-        let buf = &mut self.mtt_buffer.buf;
-        let base_index = pgt_entry.index;
-        let mtt_update = MttUpdate::new(addr, length_u32, mr_key, pd_handle, access, base_index);
-        // TODO: makes updates atomic
-        self.cmd_controller.update_mtt(mtt_update)?;
-        for PgtEntry { index, count } in chunks(pgt_entry) {
-            let bytes: Vec<u8> = phys_addrs
-                .by_ref()
-                .take(count as usize)
-                .flat_map(u64::to_ne_bytes)
-                .collect();
-            buf.get_mut(..bytes.len())
-                .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))?
-                .copy_from_slice(&bytes);
-            let pgt_update = PgtUpdate::new(self.mtt_buffer.phys_addr, index, count - 1);
-            self.cmd_controller.update_pgt(pgt_update)?;
+            ))
+    }
+}
+
+impl<H> DeviceOps for HwDeviceCtx<H>
+where
+    H: HwDevice,
+    H::Adaptor: DeviceAdaptor + Send + Sync + 'static,
+    H::DmaBufAllocator: DmaBufAllocator,
+    H::PhysAddrResolver: AddressResolver,
+{
+    fn alloc_pd(&mut self) -> io::Result<u32> {
+        self.pd_table
+            .alloc()
+            .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))
+    }
+
+    fn dealloc_pd(&mut self, handle: u32) -> io::Result<()> {
+        self.pd_table.dealloc(handle)?;
+        self.release_staging_pool(handle);
+        Ok(())
+    }
+
+    fn reg_mr(&mut self, addr: u64, length: usize, pd_handle: u32, access: u8) -> io::Result<u32> {
+        if !self.pd_table.contains(pd_handle) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
         }
+        self.pinned_pages.pin(addr, length)?;
+        let mr = MemoryRegion::new(addr, length as u64, pd_handle, access);
+        let mr_key = match self.write_mr(None, mr) {
+            Ok(mr_key) => mr_key,
+            Err(err) => {
+                let _ignore = self.pinned_pages.unpin(addr, length);
+                return Err(err);
+            }
+        };
+        self.pd_table.attach(pd_handle, PdResource::Mr(mr_key))?;
 
         Ok(mr_key)
     }
 
     fn dereg_mr(&mut self, mr_key: u32) -> io::Result<()> {
-        self.mtt.deregister(mr_key)
+        let mr = self
+            .mtt
+            .get_mr(mr_key)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        if self.mw_table.has_binding_to(mr_key) {
+            return Err(io::Error::from(io::ErrorKind::ResourceBusy));
+        }
+        let length = usize::try_from(mr.length)
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // returns after the card has stopped translating the key
+        self.cmd_controller
+            .update_mtt(MttUpdate::new(0, 0, mr_key, mr.pd_handle, 0, 0))?;
+        self.mtt.deregister(mr_key)?;
+        let _pd = self.pd_table.detach(PdResource::Mr(mr_key));
+        // the MR is gone, failing here would leak the caller's handle
+        if let Err(err) = self.pinned_pages.unpin(mr.addr, length) {
+            error!(mr_key, "failed to unpin memory region: {err}");
+        }
+
+        Ok(())
     }
 
-    fn create_qp(&mut self, attr: IbvQpInitAttr) -> io::Result<u32> {
+    fn rereg_mr(
+        &mut self,
+        mr_key: u32,
+        translation: Option<(u64, usize)>,
+        pd_handle: Option<u32>,
+        access: Option<u8>,
+    ) -> io::Result<()> {
+        let prev = self
+            .mtt
+            .get_mr(mr_key)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        if pd_handle.is_some_and(|pd_handle| !self.pd_table.contains(pd_handle)) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        // bound windows translate through the page table entries of the MR
+        if self.mw_table.has_binding_to(mr_key) {
+            return Err(io::Error::from(io::ErrorKind::ResourceBusy));
+        }
+        let prev_length = usize::try_from(prev.length)
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let (addr, length) = translation.unwrap_or((prev.addr, prev_length));
+        let mr = MemoryRegion::new(
+            addr,
+            length as u64,
+            pd_handle.unwrap_or(prev.pd_handle),
+            access.unwrap_or(prev.access),
+        );
+        if translation.is_some() {
+            // pages shared with the previous range stay pinned by it
+            self.pinned_pages.pin(addr, length)?;
+            if let Err(err) = self.write_mr(Some(mr_key), mr) {
+                let _ignore = self.pinned_pages.unpin(addr, length);
+                return Err(err);
+            }
+            if let Err(err) = self.pinned_pages.unpin(prev.addr, prev_length) {
+                error!(mr_key, "failed to unpin memory region: {err}");
+            }
+        } else {
+            // the page table is unchanged, only the MR entry is rewritten
+            let num_pages = get_num_page(addr, length);
+            let cmd = &*self.cmd_controller;
+            let mut registration = self.mtt.begin_reregister(mr_key, num_pages, mr)?;
+            if let Err(err) = registration.commit(cmd) {
+                if let Err(restore_err) = registration.restore(cmd, &mut self.mtt_buffer, &[]) {
+                    error!(mr_key, "failed to restore memory region: {restore_err}");
+                }
+                return Err(err);
+            }
+        }
+        if mr.pd_handle != prev.pd_handle {
+            let _prev = self.pd_table.detach(PdResource::Mr(mr_key));
+            self.pd_table.attach(mr.pd_handle, PdResource::Mr(mr_key))?;
+        }
+
+        Ok(())
+    }
+
+    fn alloc_mw(&mut self, pd_handle: u32, mw_type: MwType) -> io::Result<u32> {
+        if !self.pd_table.contains(pd_handle) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let rkey = self.mtt.alloc_key()?;
+        if let Err(err) = self.mw_table.insert(rkey, pd_handle, mw_type) {
+            self.mtt.free_key(rkey);
+            return Err(err);
+        }
+        self.pd_table
+            .attach(pd_handle, PdResource::Mw(key_index(rkey)))?;
+        Ok(rkey)
+    }
+
+    fn dealloc_mw(&mut self, rkey: u32) -> io::Result<()> {
+        self.mw_table.remove(rkey)?;
+        let _pd = self.pd_table.detach(PdResource::Mw(key_index(rkey)));
+        self.mtt.free_key(rkey);
+        Ok(())
+    }
+
+    fn create_qp(&mut self, pd_handle: u32, attr: IbvQpInitAttr) -> io::Result<u32> {
+        if !self.pd_table.contains(pd_handle) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
         let qpn = self
             .qp_manager
             .create_qp()
             .ok_or(io::Error::from(io::ErrorKind::WouldBlock))?;
+        if let Some(srq) = attr.srq() {
+            if let Err(err) = self.srq_table.attach_qp(srq) {
+                self.qp_manager.destroy_qp(qpn);
+                return Err(err);
+            }
+        }
         let _ignore = self.qp_manager.update_qp(qpn, |current| {
-            current.qpn = qpn;
-            current.qp_type = attr.qp_type();
-            current.send_cq = attr.send_cq();
-            current.recv_cq = attr.recv_cq();
-            current.mac_addr = self.network_config().mac.into();
-            current.pmtu = ibverbs_sys::IBV_MTU_4096 as u8;
+            *current = QueuePairAttr {
+                qpn,
+                pd_handle,
+                qp_type: attr.qp_type(),
+                send_cq: attr.send_cq(),
+                recv_cq: attr.recv_cq(),
+                srq: attr.srq(),
+                mac_addr: self.network_config().mac.into(),
+                pmtu: ibverbs_sys::IBV_MTU_4096 as u8,
+                state: QpState::Reset,
+                sq_sig_all: attr.sq_sig_all(),
+                cap: attr.cap(),
+                ..Default::default()
+            };
         });
         let entry = UpdateQp {
             ip_addr: self.network_config().ip.ip().to_bits(),
@@ -302,75 +864,266 @@ where
             ..Default::default()
         };
         self.cmd_controller.update_qp(entry)?;
+        self.pd_table.attach(pd_handle, PdResource::Qp(qpn))?;
 
         Ok(qpn)
     }
 
     fn update_qp(&mut self, qpn: u32, attr: IbvQpAttr) -> io::Result<()> {
-        let entry = self
+        let next_state = attr
+            .qp_state()
+            .map(|x| QpState::from_ibv(x).ok_or(io::Error::from(io::ErrorKind::InvalidInput)))
+            .transpose()?;
+        let cur_state_hint = attr
+            .cur_qp_state()
+            .map(|x| QpState::from_ibv(x).ok_or(io::Error::from(io::ErrorKind::InvalidInput)))
+            .transpose()?;
+        let current = self
             .qp_manager
-            .update_qp(qpn, |current| {
-                let entry = UpdateQp {
-                    qpn,
-                    ip_addr: self.network_config().ip.ip().to_bits(),
-                    local_udp_port: 0x100,
-                    peer_mac_addr: self.network_config().mac.into(),
-                    qp_type: current.qp_type,
-                    peer_qpn: attr.dest_qp_num().unwrap_or(current.dqpn),
-                    rq_access_flags: attr
-                        .qp_access_flags()
-                        .map_or(current.access_flags, |x| x as u8),
-                    pmtu: attr.path_mtu().map_or(current.pmtu, |x| x as u8),
-                };
-                current.dqpn = entry.peer_qpn;
-                current.access_flags = entry.rq_access_flags;
-                current.pmtu = entry.pmtu;
-                current.dqp_ip = attr.dest_qp_ip().map_or(0, Ipv4Addr::to_bits);
-                entry
-            })
+            .get_qp(qpn)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        if cur_state_hint.is_some_and(|x| x != current.state) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let state = current
+            .state
+            .transition(next_state, attr.attr_mask())
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        let entry = UpdateQp {
+            qpn,
+            ip_addr: self.network_config().ip.ip().to_bits(),
+            local_udp_port: 0x100,
+            peer_mac_addr: self.network_config().mac.into(),
+            qp_type: current.qp_type,
+            peer_qpn: attr.dest_qp_num().unwrap_or(current.dqpn),
+            rq_access_flags: attr
+                .qp_access_flags()
+                .map_or(current.access_flags, |x| x as u8),
+            pmtu: attr.path_mtu().map_or(current.pmtu, |x| x as u8),
+        };
+        let mut next = current;
+        next.dqpn = entry.peer_qpn;
+        next.access_flags = entry.rq_access_flags;
+        next.pmtu = entry.pmtu;
+        next.dqp_ip = attr.dest_qp_ip().map_or(next.dqp_ip, Ipv4Addr::to_bits);
+        next.rq_psn = attr.rq_psn().unwrap_or(next.rq_psn);
+        next.sq_psn = attr.sq_psn().unwrap_or(next.sq_psn);
+        next.pkey_index = attr.pkey_index().unwrap_or(next.pkey_index);
+        next.port_num = attr.port_num().unwrap_or(next.port_num);
+        next.sl = attr.ah_attr().map_or(next.sl, |x| x.sl);
+        next.timeout = attr.timeout().unwrap_or(next.timeout);
+        next.retry_cnt = attr.retry_cnt().unwrap_or(next.retry_cnt);
+        next.rnr_retry = attr.rnr_retry().unwrap_or(next.rnr_retry);
+        next.min_rnr_timer = attr.min_rnr_timer().unwrap_or(next.min_rnr_timer);
+        next.max_rd_atomic = attr.max_rd_atomic().unwrap_or(next.max_rd_atomic);
+        next.max_dest_rd_atomic = attr.max_dest_rd_atomic().unwrap_or(next.max_dest_rd_atomic);
+        let cur_state = current.state;
+
+        // the software state only moves forward once the card accepted the update
+        self.cmd_controller.update_qp(entry)?;
+        let _ignore = self.qp_manager.update_qp(qpn, |attr| {
+            // an error raised by the workers in the meantime is kept
+            let state = if attr.state == cur_state {
+                state
+            } else {
+                attr.state
+            };
+            *attr = QueuePairAttr { state, ..next };
+        });
 
-        self.cmd_controller.update_qp(entry);
+        match state {
+            QpState::Err if cur_state != QpState::Err => {
+                let _ignore = self.completion_tx.send(CompletionTask::Error {
+                    qpn,
+                    status: CompletionStatus::WrFlushError,
+                });
+            }
+            QpState::Reset if cur_state != QpState::Reset => {
+                self.rnr.clear(qpn);
+                let _ignore = self.completion_tx.send(CompletionTask::Flush { qpn });
+            }
+            QpState::Rtr if cur_state != QpState::Rtr => {
+                // the SRQ hands out WRs only to QPs ready to receive
+                if let Some(srq) = self.qp_manager.get_qp(qpn).and_then(|qp| qp.srq) {
+                    let _ignore = self.srq_tx.send(SrqTask::Refill { srq });
+                }
+            }
+            QpState::Reset
+            | QpState::Init
+            | QpState::Rtr
+            | QpState::Rts
+            | QpState::Sqd
+            | QpState::Sqe
+            | QpState::Err => {}
+        }
 
         let qp = self
             .qp_manager
             .get_qp(qpn)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-        if qp.dqpn != 0 && qp.dqp_ip != 0 && self.post_recv_tx_table.get_qp_mut(qpn).is_none() {
-            let dqp_ip = Ipv4Addr::from_bits(qp.dqp_ip);
-            let (tx, rx) = post_recv_channel::<TcpChannel>(
-                self.network_config().ip.ip(),
-                dqp_ip,
-                qpn,
-                qp.dqpn,
-            )?;
-            self.post_recv_tx_table.insert(qpn, tx);
-            let wr_queue = self
-                .recv_wr_queue_table
-                .clone_recv_wr_queue(qpn)
-                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
-            RecvWorker::new(rx, wr_queue).spawn();
+        if qp.dqpn != 0 && qp.dqp_ip != 0 {
+            self.connect_post_recv_channel(&qp)?;
         }
 
         Ok(())
     }
 
+    fn query_qp(&self, qpn: u32) -> io::Result<QueuePairAttr> {
+        self.qp_manager
+            .get_qp(qpn)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))
+    }
+
     fn destroy_qp(&mut self, qpn: u32) {
+        self.rnr.clear(qpn);
+        if let Some(srq) = self.qp_manager.get_qp(qpn).and_then(|qp| qp.srq) {
+            let _ignore = self.srq_tx.send(SrqTask::Detach { qpn });
+            let _ignore = self.srq_qps.remove(&qpn);
+            self.srq_table.detach_qp(srq);
+        }
+        self.post_recv_tx_table.remove(qpn);
+        if let Some(responder) = self.atomic_responder.as_ref() {
+            responder.forget(qpn);
+        }
+        self.send_scheduler.remove_qp(qpn);
+        if let Some(channel) = self.post_recv_channel.as_ref() {
+            channel.close(qpn);
+        }
+        let _ignore = self.completion_tx.send(CompletionTask::Flush { qpn });
+        let _pd = self.pd_table.detach(PdResource::Qp(qpn));
         self.qp_manager.destroy_qp(qpn);
     }
 
-    fn create_cq(&mut self) -> Option<u32> {
-        self.cq_manager.create_cq()
+    fn set_qp_channel(&self, qpn: u32, channel: Option<usize>) -> io::Result<()> {
+        if self.qp_manager.get_qp(qpn).is_none() {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        self.send_scheduler.set_channel(qpn, channel)
+    }
+
+    fn set_qp_weight(&self, qpn: u32, weight: u8) -> io::Result<()> {
+        if self.qp_manager.get_qp(qpn).is_none() {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        self.send_scheduler.set_weight(qpn, weight)
+    }
+
+    fn network_config(&self) -> NetworkConfig {
+        self.config.network()
+    }
+
+    fn create_cq(&mut self, cqe: usize) -> io::Result<u32> {
+        let handle = self
+            .cq_manager
+            .create_cq()
+            .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))?;
+        let result = self
+            .cq_table
+            .get_cq(handle)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))
+            .and_then(|cq| cq.init(cqe));
+        if let Err(err) = result {
+            self.cq_manager.destroy_cq(handle);
+            return Err(err);
+        }
+        Ok(handle)
     }
 
     fn destroy_cq(&mut self, handle: u32) {
+        if let Some(cq) = self.cq_table.get_cq(handle) {
+            cq.reset();
+        }
         self.cq_manager.destroy_cq(handle);
+        self.comp_channels
+            .retain(|_, channel| Arc::strong_count(channel) > 1);
+    }
+
+    fn resize_cq(&mut self, handle: u32, cqe: usize) -> io::Result<()> {
+        self.cq_table
+            .get_cq(handle)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+            .resize(cqe)
+    }
+
+    fn set_cq_context(
+        &mut self,
+        handle: u32,
+        cq_context: u64,
+        channel: Option<u64>,
+    ) -> io::Result<()> {
+        let cq = self
+            .cq_table
+            .get_cq(handle)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let channel = match channel {
+            Some(key) => match self.comp_channels.entry(key) {
+                Entry::Occupied(entry) => Some(Arc::clone(entry.get())),
+                Entry::Vacant(entry) => {
+                    Some(Arc::clone(entry.insert(Arc::new(CompChannel::new()?))))
+                }
+            },
+            None => None,
+        };
+        cq.set_context(cq_context, channel);
+        Ok(())
+    }
+
+    fn get_cq_event(&self, channel: u64) -> io::Result<u64> {
+        self.comp_channels
+            .get(&channel)
+            .map(Arc::clone)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+            .get_event()
+    }
+
+    fn req_notify_cq(&mut self, handle: u32, mode: NotifyMode) -> io::Result<()> {
+        self.cq_table
+            .get_cq(handle)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+            .req_notify(mode)
     }
 
     fn post_send(&mut self, qpn: u32, wr: SendWr) -> io::Result<()> {
+        let qp = self
+            .qp_manager
+            .get_qp(qpn)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        if !qp.state.can_post_send() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.check_sges(qp.pd_handle, wr.sg_list().as_slice(), wr.local_access())?;
+        self.reserve_staging(&qp, &wr)?;
+        if qp.state == QpState::Err {
+            // flushed after the WRs posted before it complete
+            let completion = Completion::send_error(&wr, CompletionStatus::WrFlushError)
+                .ok_or(io::Error::from(io::ErrorKind::Unsupported))?;
+            let _ignore = self
+                .completion_tx
+                .send(CompletionTask::Complete { qpn, completion });
+            return Ok(());
+        }
         match wr {
+            _ if self.rnr.is_pending(qpn) => {
+                // keep the send queue ordered behind WRs waiting for an RNR retry or an
+                // atomic
+                let is_send = matches!(wr, SendWr::Send(_));
+                self.rnr.enqueue(qpn, wr);
+                if is_send {
+                    self.demand_recv_wr(qpn);
+                }
+                Ok(())
+            }
+            SendWr::BindMw(_) | SendWr::LocalInv(_) => {
+                self.post_mw_wr(&qp, &wr);
+                Ok(())
+            }
             SendWr::Rdma(wr) => self.rdma_write(qpn, wr),
             SendWr::Send(wr) => self.send(qpn, wr),
+            SendWr::Atomic(_) => {
+                // later WRs are held until the atomic completes
+                self.rnr.enqueue(qpn, wr);
+                Ok(())
+            }
         }
     }
 
@@ -390,9 +1143,21 @@ where
             .qp_manager
             .get_qp(qpn)
             .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
-        let event = Event::PostRecv(PostRecvEvent::new(wr.wr_id));
+        if !qp.state.can_post_recv() || qp.srq.is_some() {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        self.check_sges(
+            qp.pd_handle,
+            &[wr.sge()],
+            ibverbs_sys::ibv_access_flags::IBV_ACCESS_LOCAL_WRITE,
+        )?;
+        let event = Event::PostRecv(PostRecvEvent::new(wr.wr_id, wr.length));
         self.completion_tx
             .send(CompletionTask::Register { qpn, event });
+        if qp.state == QpState::Err {
+            // flushed by the completion worker, never handed to the remote
+            return Ok(());
+        }
         let tx = self
             .post_recv_tx_table
             .get_qp_mut(qpn)
@@ -401,6 +1166,63 @@ where
 
         Ok(())
     }
+
+    fn get_async_event(&self) -> io::Result<AsyncEvent> {
+        self.async_event_rx
+            .recv()
+            .map_err(|_err| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn create_srq(&mut self, pd_handle: u32, srq_context: u64, attr: SrqAttr) -> io::Result<u32> {
+        if !self.pd_table.contains(pd_handle) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let handle = self
+            .srq_manager
+            .create_srq()
+            .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))?;
+        if let Err(err) = self.srq_table.init(handle, srq_context, attr) {
+            self.srq_manager.destroy_srq(handle);
+            return Err(err);
+        }
+        self.pd_table.attach(pd_handle, PdResource::Srq(handle))?;
+        Ok(handle)
+    }
+
+    fn modify_srq(
+        &mut self,
+        handle: u32,
+        max_wr: Option<u32>,
+        srq_limit: Option<u32>,
+    ) -> io::Result<()> {
+        self.srq_table.modify(handle, max_wr, srq_limit)
+    }
+
+    fn query_srq(&self, handle: u32) -> io::Result<SrqAttr> {
+        self.srq_table.query(handle)
+    }
+
+    fn destroy_srq(&mut self, handle: u32) -> io::Result<()> {
+        self.srq_table.release(handle)?;
+        let _pd = self.pd_table.detach(PdResource::Srq(handle));
+        self.srq_manager.destroy_srq(handle);
+        Ok(())
+    }
+
+    fn post_srq_recv(&mut self, handle: u32, wr: RecvWr) -> io::Result<()> {
+        let pd_handle = self
+            .pd_table
+            .owner(PdResource::Srq(handle))
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.check_sges(
+            pd_handle,
+            &[wr.sge()],
+            ibverbs_sys::ibv_access_flags::IBV_ACCESS_LOCAL_WRITE,
+        )?;
+        self.srq_table.post(handle, wr)?;
+        let _ignore = self.srq_tx.send(SrqTask::Refill { srq: handle });
+        Ok(())
+    }
 }
 
 #[allow(unsafe_code, clippy::wildcard_imports)]
@@ -409,6 +1231,8 @@ pub(crate) mod qp_attr {
 
     use ibverbs_sys::*;
 
+    use crate::qp::{QpCap, QueuePairAttr};
+
     pub(crate) struct IbvQpInitAttr {
         inner: ibv_qp_init_attr,
     }
@@ -429,6 +1253,26 @@ pub(crate) mod qp_attr {
         pub(crate) fn recv_cq(&self) -> Option<u32> {
             unsafe { self.inner.recv_cq.as_ref() }.map(|cq| cq.handle)
         }
+
+        pub(crate) fn srq(&self) -> Option<u32> {
+            unsafe { self.inner.srq.as_ref() }.map(|srq| srq.handle)
+        }
+
+        pub(crate) fn sq_sig_all(&self) -> bool {
+            self.inner.sq_sig_all != 0
+        }
+
+        pub(crate) fn cap(&self) -> QpCap {
+            let cap = self.inner.cap;
+            QpCap {
+                max_send_wr: cap.max_send_wr,
+                max_recv_wr: cap.max_recv_wr,
+                max_send_sge: cap.max_send_sge,
+                max_recv_sge: cap.max_recv_sge,
+                // inline data is not supported
+                max_inline_data: 0,
+            }
+        }
     }
 
     pub(crate) struct IbvQpAttr {
@@ -449,6 +1293,79 @@ pub(crate) mod qp_attr {
             Self { inner, attr_mask }
         }
 
+        /// Creates the attributes reported by `ibv_query_qp`, only the fields
+        /// selected by `attr_mask` are filled
+        pub(crate) fn from_qp_attr(qp: &QueuePairAttr, attr_mask: u32) -> Self {
+            let mut inner = ibv_qp_attr::default();
+            let has = |mask: ibv_qp_attr_mask| attr_mask & mask.0 != 0;
+            if has(ibv_qp_attr_mask::IBV_QP_STATE) {
+                inner.qp_state = qp.state.to_ibv();
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_CUR_STATE) {
+                inner.cur_qp_state = qp.state.to_ibv();
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_PATH_MTU) {
+                inner.path_mtu = qp.pmtu.into();
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_RQ_PSN) {
+                inner.rq_psn = qp.rq_psn;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_SQ_PSN) {
+                inner.sq_psn = qp.sq_psn;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_DEST_QPN) {
+                inner.dest_qp_num = qp.dqpn;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS) {
+                inner.qp_access_flags = qp.access_flags.into();
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_CAP) {
+                inner.cap = qp.cap.to_ibv();
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_AV) {
+                inner.ah_attr.sl = qp.sl;
+                if qp.dqp_ip != 0 {
+                    let gid = Ipv4Addr::from_bits(qp.dqp_ip).to_ipv6_mapped().octets();
+                    inner.ah_attr.grh.dgid.raw = gid;
+                    inner.ah_attr.is_global = 1;
+                    inner.ah_attr.port_num = qp.port_num;
+                }
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_PKEY_INDEX) {
+                inner.pkey_index = qp.pkey_index;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC) {
+                inner.max_rd_atomic = qp.max_rd_atomic;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC) {
+                inner.max_dest_rd_atomic = qp.max_dest_rd_atomic;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER) {
+                inner.min_rnr_timer = qp.min_rnr_timer;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_PORT) {
+                inner.port_num = qp.port_num;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_TIMEOUT) {
+                inner.timeout = qp.timeout;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_RETRY_CNT) {
+                inner.retry_cnt = qp.retry_cnt;
+            }
+            if has(ibv_qp_attr_mask::IBV_QP_RNR_RETRY) {
+                inner.rnr_retry = qp.rnr_retry;
+            }
+            Self { inner, attr_mask }
+        }
+
+        pub(crate) fn into_inner(self) -> ibv_qp_attr {
+            self.inner
+        }
+
+        pub(crate) fn attr_mask(&self) -> u32 {
+            self.attr_mask
+        }
+
         pub(crate) fn dest_qp_ip(&self) -> Option<Ipv4Addr> {
             if self.attr_mask & ibv_qp_attr_mask::IBV_QP_AV.0 == 0 {
                 return None;
@@ -3,7 +3,7 @@ use std::io;
 use tracing::error;
 
 use crate::{
-    constants::PSN_MASK,
+    constants::{NAK_REMOTE_ACCESS_ERROR, PSN_MASK},
     device_protocol::{
         AckMetaLocalHw, AckMetaRemoteDriver, CnpMeta, HeaderReadMeta, HeaderWriteMeta, MetaReport,
         NakMetaLocalHw, NakMetaRemoteDriver, NakMetaRemoteHw, ReportMeta,
@@ -114,6 +114,7 @@ impl<Dev: DeviceAdaptor> MetaReport for MetaReportQueueHandler<Dev> {
                             qpn: f.qpn(),
                             psn_now: f.psn_now().into(),
                             psn_pre: f.psn_before_slide().into(),
+                            is_access_error: f.msn() == NAK_REMOTE_ACCESS_ERROR,
                         }),
                         (false, true) => ReportMeta::NakLocalHw(NakMetaLocalHw {
                             qpn: f.qpn(),
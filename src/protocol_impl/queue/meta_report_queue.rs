@@ -6,12 +6,16 @@ use std::{
 use crate::{
     ack_responder::AckResponse,
     completion::CompletionTask,
+    dcqcn::RateLimiter,
     mem::{
         virt_to_phy::{AddressResolver, PhysAddrResolverLinuxX86},
         DmaBuf, PageWithPhysAddr,
     },
     meta_worker::{MetaHandler, MetaWorker},
+    mr::MrTable,
+    mw::MwTable,
     packet_retransmit::PacketRetransmitTask,
+    poll::AdaptivePoller,
     protocol_impl::{
         desc::{
             MetaReportQueueAckDesc, MetaReportQueueAckExtraDesc, MetaReportQueueDescFirst,
@@ -107,16 +111,21 @@ impl MetaReportQueue {
     }
 }
 
-#[allow(clippy::too_many_arguments)]
+#[allow(clippy::too_many_arguments, clippy::similar_names)]
 pub(crate) fn init_and_spawn_meta_worker<Dev>(
     dev: &Dev,
     pages: Vec<DmaBuf>,
     mode: Mode,
+    qp_attr_table: QueuePairAttrTable,
+    mr_table: MrTable,
+    mw_table: MwTable,
     ack_tx: flume::Sender<AckResponse>,
     retransmit_tx: flume::Sender<RetransmitTask>,
     packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
     completion_tx: flume::Sender<CompletionTask>,
     rdma_write_tx: flume::Sender<RdmaWriteTask>,
+    rate_limiter: Option<RateLimiter>,
+    poller: AdaptivePoller,
     is_shutdown: Arc<AtomicBool>,
 ) -> io::Result<()>
 where
@@ -134,13 +143,17 @@ where
         .collect();
 
     let handler = MetaHandler::new(
+        qp_attr_table,
+        mr_table,
+        mw_table,
         ack_tx,
         retransmit_tx,
         packet_retransmit_tx,
         completion_tx,
         rdma_write_tx,
+        rate_limiter,
     );
-    MetaWorker::new(MetaReportQueueHandler::new(ctxs), handler).spawn(is_shutdown);
+    MetaWorker::new(MetaReportQueueHandler::new(ctxs), handler, poller).spawn(is_shutdown);
 
     Ok(())
 }
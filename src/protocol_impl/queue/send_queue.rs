@@ -53,4 +53,9 @@ impl SendQueue {
     pub(crate) fn remaining(&self) -> usize {
         self.inner.remaining()
     }
+
+    /// Returns `true` if the hardware has consumed every descriptor
+    pub(crate) fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
 }
@@ -1,12 +1,23 @@
-use std::{io, iter, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    io, iter,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
-use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use parking_lot::Mutex;
 use tracing::error;
 
 use crate::{
+    dcqcn::{DcqcnConfig, Pacer, RateLimiter},
     device_protocol::{WorkReqSend, WrChunk},
     mem::{DmaBuf, PageWithPhysAddr},
+    poll::{AdaptivePoller, PollConfig, Unparker},
     protocol_impl::device::CsrWriterAdaptor,
+    utils::{qpn_index, QpTable},
 };
 
 use super::{
@@ -22,42 +33,390 @@ use super::{
     },
 };
 
-/// Injector
-type WrInjector = Injector<WrChunk>;
-/// Stealer
-type WrStealer = Stealer<WrChunk>;
-/// Worker
-type WrWorker = Worker<WrChunk>;
+/// Scheduling quantum of a QP with weight 1, in bytes
+const DRR_QUANTUM: u64 = 4096;
+
+/// Largest scheduling weight accepted by `SendQueueScheduler::set_weight`
+pub(crate) const MAX_QP_WEIGHT: u8 = 16;
+
+/// Minimum interval between two steal attempts of an idle worker
+const STEAL_INTERVAL: Duration = Duration::from_micros(50);
+
+/// Submission queue of a single QP
+#[derive(Default)]
+struct QpSubmissionQueue {
+    chunks: VecDeque<WrChunk>,
+    /// Bytes the QP may send in the current round
+    deficit: u64,
+    /// Scheduling weight set through `set_weight`, a QP gets `weight` quanta per
+    /// round, 0 counts as the default weight 1
+    weight: u8,
+    /// Whether the QP is in the active list of its channel
+    active: bool,
+    /// Channel serving the QP, assigned on the first submission
+    channel: Option<usize>,
+    /// Channel set through `set_channel`, pinned QPs are never stolen
+    pinned: Option<usize>,
+    /// A popped chunk has not been pushed to the send queue yet
+    in_flight: bool,
+    /// Submission sequence of the last chunk pushed to the channel
+    last_submit: u64,
+}
+
+impl QpSubmissionQueue {
+    fn quantum(&self) -> u64 {
+        DRR_QUANTUM * u64::from(self.weight.max(1))
+    }
+
+    /// Returns `true` if the QP can move to another channel without
+    /// reordering, i.e. the hardware has consumed all of its descriptors
+    fn is_movable(&self, channels: &[Channel]) -> bool {
+        !self.in_flight
+            && self.channel.is_none_or(|x| {
+                channels
+                    .get(x)
+                    .is_some_and(|c| c.drained.load(Ordering::Acquire) >= self.last_submit)
+            })
+    }
+
+    /// Returns the channel the next chunk of the QP is queued on, an idle QP
+    /// returns to its home channel once movable
+    fn target_channel(&self, qpn: u32, channels: &[Channel]) -> usize {
+        let home = self
+            .pinned
+            .unwrap_or_else(|| qpn_index(qpn) % channels.len());
+        match self.channel {
+            Some(x) if self.active || !self.is_movable(channels) => x,
+            _ => home,
+        }
+    }
+}
+
+/// Scheduling state of a channel
+///
+/// The active list is locked before the queues of its QPs. A thread locking
+/// two channels locks the one with the lower index first.
+#[derive(Default)]
+struct Channel {
+    /// QPs with pending chunks, in round robin order
+    active: Mutex<VecDeque<u32>>,
+    /// Number of queued chunks, checked by the worker before taking the lock
+    queued: AtomicUsize,
+    /// Number of chunks pushed to the send queue of the channel
+    submitted: AtomicU64,
+    /// Value of `submitted` when the send queue was last seen empty
+    drained: AtomicU64,
+}
+
+impl Channel {
+    fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+}
+
+/// Per QP submission queues, each QP is served by a single channel in deficit
+/// round robin order so its chunks reach the hardware in PSN order
+pub(crate) struct WrQueue {
+    queues: QpTable<Mutex<QpSubmissionQueue>>,
+    channels: Box<[Channel]>,
+    /// Wakes the parked worker of each channel
+    unparkers: Box<[Unparker]>,
+}
+
+impl WrQueue {
+    fn new(num_channel: usize) -> Self {
+        let num_channel = num_channel.max(1);
+        Self {
+            queues: QpTable::new(),
+            channels: iter::repeat_with(Channel::default)
+                .take(num_channel)
+                .collect(),
+            unparkers: iter::repeat_with(Unparker::new).take(num_channel).collect(),
+        }
+    }
+
+    pub(crate) fn push(&self, chunk: WrChunk) {
+        let qpn = chunk.sqpn;
+        let Some(queue) = self.queues.get_qp(qpn) else {
+            error!("invalid qpn: {qpn}");
+            return;
+        };
+        let target = loop {
+            let target = queue.lock().target_channel(qpn, &self.channels);
+            let Some(channel) = self.channels.get(target) else {
+                unreachable!("target channel is in range");
+            };
+            let mut active = channel.active.lock();
+            let mut queue = queue.lock();
+            // moved by another thread before the channel was locked
+            if queue.target_channel(qpn, &self.channels) != target {
+                continue;
+            }
+            if !queue.active {
+                if queue.channel != Some(target) {
+                    queue.channel = Some(target);
+                    queue.last_submit = 0;
+                }
+                queue.active = true;
+                active.push_back(qpn);
+            }
+            queue.chunks.push_back(chunk);
+            let _prev = channel.queued.fetch_add(1, Ordering::Release);
+            break target;
+        };
+        if let Some(unparker) = self.unparkers.get(target) {
+            unparker.unpark();
+        }
+    }
+
+    /// Returns `true` if `channel` has QPs with pending chunks
+    pub(crate) fn has_work(&self, channel: usize) -> bool {
+        self.queued(channel) != 0
+    }
+
+    fn queued(&self, channel: usize) -> usize {
+        self.channels.get(channel).map_or(0, Channel::queued)
+    }
+
+    /// Moves a whole QP of a backlogged channel to the idle `thief` channel,
+    /// returns `false` if nothing was moved
+    ///
+    /// Only channels with more than one queued chunk are locked.
+    pub(crate) fn steal(&self, thief: usize) -> bool {
+        (0..self.channels.len())
+            .filter(|&x| x != thief && self.queued(x) > 1)
+            .any(|victim| self.steal_from(victim, thief))
+    }
+
+    fn steal_from(&self, victim: usize, thief: usize) -> bool {
+        let (Some(from), Some(to)) = (self.channels.get(victim), self.channels.get(thief)) else {
+            return false;
+        };
+        let (mut victim_active, mut thief_active) = if victim < thief {
+            let victim_active = from.active.lock();
+            (victim_active, to.active.lock())
+        } else {
+            let thief_active = to.active.lock();
+            (from.active.lock(), thief_active)
+        };
+        // leave the victim at least one QP
+        if victim_active.len() < 2 {
+            return false;
+        }
+        let Some(pos) = victim_active.iter().rposition(|&qpn| {
+            self.queues.get_qp(qpn).is_some_and(|q| {
+                let q = q.lock();
+                q.pinned.is_none() && q.is_movable(&self.channels)
+            })
+        }) else {
+            return false;
+        };
+        let Some(qpn) = victim_active.remove(pos) else {
+            return false;
+        };
+        let Some(mut queue) = self.queues.get_qp(qpn).map(|q| q.lock()) else {
+            return false;
+        };
+        queue.channel = Some(thief);
+        queue.deficit = 0;
+        queue.last_submit = 0;
+        thief_active.push_back(qpn);
+        let num = queue.chunks.len();
+        let _prev = from.queued.fetch_sub(num, Ordering::Release);
+        let _prev = to.queued.fetch_add(num, Ordering::Release);
+
+        true
+    }
+
+    fn unparker(&self, channel: usize) -> Unparker {
+        self.unparkers.get(channel).cloned().unwrap_or_default()
+    }
+
+    /// Pops the next chunk of `channel`, QPs are served in proportion to their weights
+    ///
+    /// The QP of the returned chunk stays on the channel until `complete` is called.
+    pub(crate) fn pop(&self, channel: usize) -> Option<WrChunk> {
+        let channel = self.channels.get(channel)?;
+        if channel.queued() == 0 {
+            return None;
+        }
+        let mut active = channel.active.lock();
+        loop {
+            let qpn = *active.front()?;
+            let Some(mut queue) = self.queues.get_qp(qpn).map(|q| q.lock()) else {
+                let _ignore = active.pop_front();
+                continue;
+            };
+            let Some(len) = queue.chunks.front().map(|x| u64::from(x.len)) else {
+                queue.active = false;
+                queue.deficit = 0;
+                let _ignore = active.pop_front();
+                continue;
+            };
+            if queue.deficit < len {
+                // not enough credit in this round, move on to the next QP
+                queue.deficit += queue.quantum();
+                active.rotate_left(1);
+                continue;
+            }
+            queue.deficit -= len;
+            queue.in_flight = true;
+            let chunk = queue.chunks.pop_front();
+            if queue.chunks.is_empty() {
+                queue.active = false;
+                queue.deficit = 0;
+                let _ignore = active.pop_front();
+            }
+            let _prev = channel.queued.fetch_sub(1, Ordering::Release);
+            return chunk;
+        }
+    }
+
+    /// Marks the popped chunk of `qpn` as pushed to the send queue of `channel`
+    ///
+    /// Only called by the worker of `channel`.
+    pub(crate) fn complete(&self, channel: usize, qpn: u32) {
+        let (Some(queue), Some(channel)) = (self.queues.get_qp(qpn), self.channels.get(channel))
+        else {
+            return;
+        };
+        let submitted = channel.submitted.fetch_add(1, Ordering::AcqRel) + 1;
+        let mut queue = queue.lock();
+        queue.last_submit = submitted;
+        queue.in_flight = false;
+    }
+
+    /// Records that the hardware has consumed every descriptor pushed to `channel`
+    ///
+    /// Only called by the worker of `channel`.
+    pub(crate) fn drained(&self, channel: usize) {
+        if let Some(channel) = self.channels.get(channel) {
+            let submitted = channel.submitted.load(Ordering::Acquire);
+            channel.drained.store(submitted, Ordering::Release);
+        }
+    }
+
+    fn set_weight(&self, qpn: u32, weight: u8) -> io::Result<()> {
+        let queue = self
+            .queues
+            .get_qp(qpn)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        queue.lock().weight = weight;
+
+        Ok(())
+    }
+
+    /// Pins a QP to `channel`, the QP moves once it has no pending chunks
+    fn set_channel(&self, qpn: u32, channel: Option<usize>) -> io::Result<()> {
+        if channel.is_some_and(|x| x >= self.channels.len()) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let queue = self
+            .queues
+            .get_qp(qpn)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        queue.lock().pinned = channel;
+
+        Ok(())
+    }
+
+    /// Drops the pending chunks of a QP and resets its scheduling state
+    fn remove_qp(&self, qpn: u32) {
+        let Some(queue) = self.queues.get_qp(qpn) else {
+            return;
+        };
+        loop {
+            let current = queue.lock().channel;
+            let channel = current.and_then(|x| self.channels.get(x));
+            let mut active = channel.map(|c| c.active.lock());
+            let mut queue = queue.lock();
+            // moved by another thread before the channel was locked
+            if queue.channel != current {
+                continue;
+            }
+            if let (Some(channel), Some(active)) = (channel, active.as_mut()) {
+                if queue.active {
+                    active.retain(|&x| x != qpn);
+                }
+                let _prev = channel
+                    .queued
+                    .fetch_sub(queue.chunks.len(), Ordering::Release);
+            }
+            *queue = QpSubmissionQueue::default();
+            return;
+        }
+    }
+}
 
 /// Schedules send work requests across worker threads
 pub(crate) struct SendQueueScheduler {
-    /// Work request injector for distributing work to worker threads
-    injector: Arc<WrInjector>,
+    /// Per QP queues shared with the worker threads
+    queue: Arc<WrQueue>,
+    /// Paces chunks to the DCQCN rate of each QP, `None` if DCQCN is disabled
+    pacer: Option<Pacer>,
 }
 
 impl SendQueueScheduler {
-    pub(crate) fn new() -> Self {
-        Self {
-            injector: WrInjector::new().into(),
-        }
+    pub(crate) fn new(num_channel: usize, dcqcn: DcqcnConfig) -> Self {
+        let queue = Arc::new(WrQueue::new(num_channel));
+        let pacer = dcqcn
+            .enabled()
+            .then(|| Pacer::spawn(RateLimiter::new(dcqcn), Arc::clone(&queue)));
+        Self { queue, pacer }
     }
 
     pub(crate) fn clone_arc(&self) -> Self {
         Self {
-            injector: Arc::clone(&self.injector),
+            queue: Arc::clone(&self.queue),
+            pacer: self.pacer.as_ref().map(Pacer::clone_arc),
+        }
+    }
+
+    /// Returns the DCQCN rate limiter, `None` if DCQCN is disabled
+    pub(crate) fn rate_limiter(&self) -> Option<RateLimiter> {
+        self.pacer.as_ref().map(Pacer::limiter)
+    }
+
+    pub(crate) fn queue(&self) -> Arc<WrQueue> {
+        Arc::clone(&self.queue)
+    }
+
+    /// Sets the scheduling weight of a QP, a QP gets `weight` times the
+    /// bandwidth share of a QP with weight 1
+    pub(crate) fn set_weight(&self, qpn: u32, weight: u8) -> io::Result<()> {
+        if !(1..=MAX_QP_WEIGHT).contains(&weight) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("QP weight must be in 1..={MAX_QP_WEIGHT}"),
+            ));
         }
+        self.queue.set_weight(qpn, weight)
     }
 
-    pub(crate) fn injector(&self) -> Arc<WrInjector> {
-        Arc::clone(&self.injector)
+    /// Overrides the channel of a QP, `None` restores the hashed channel
+    pub(crate) fn set_channel(&self, qpn: u32, channel: Option<usize>) -> io::Result<()> {
+        self.queue.set_channel(qpn, channel)
+    }
+
+    /// Drops the pending chunks of a destroyed QP and resets its scheduling state
+    pub(crate) fn remove_qp(&self, qpn: u32) {
+        self.queue.remove_qp(qpn);
+        if let Some(pacer) = self.pacer.as_ref() {
+            pacer.limiter().reset(qpn);
+        }
     }
 
     /// Submits a work request chunk to be processed by worker threads
     ///
     /// # Arguments
     /// * `wr` - The work request chunk to be scheduled
-    fn send_wr_task(&self, wr: WrChunk) {
-        self.injector.push(wr);
+    fn send_wr_task(&self, mut wr: WrChunk) {
+        let Some(pacer) = self.pacer.as_ref() else {
+            self.queue.push(wr);
+            return;
+        };
+        wr.set_enable_ecn();
+        pacer.submit(wr);
     }
 }
 
@@ -70,14 +429,19 @@ impl WorkReqSend for SendQueueScheduler {
 
 /// Worker thread for processing send work requests
 pub(crate) struct SendWorker<Dev> {
-    /// id of the worker
+    /// id of the worker, also the channel it serves
     id: usize,
-    /// Local work request queue for this worker
-    local: WrWorker,
-    /// Global work request injector shared across workers
-    global: Arc<WrInjector>,
-    /// Work stealers for taking work from other workers
-    remotes: Box<[WrStealer]>,
+    /// Chunk that did not fit into the send queue, retried first
+    pending: Option<WrChunk>,
+    /// Per QP queues shared across workers
+    queue: Arc<WrQueue>,
+    /// Chunks were pushed since the send queue was last seen empty
+    undrained: bool,
+    /// Earliest time of the next steal attempt
+    next_steal: Instant,
+    poller: AdaptivePoller,
+    /// Wakes the meta worker, which receives the responses of submitted requests
+    meta_unparker: Unparker,
     /// Queue for submitting send requests to the NIC
     send_queue: SendQueue,
     /// Csr proxy
@@ -95,9 +459,19 @@ impl<Dev: DeviceAdaptor + Send + 'static> SendWorker<Dev> {
     /// Run the worker
     pub(crate) fn run(mut self) {
         loop {
-            let Some(wr) = Self::find_task(&self.local, &self.global, &self.remotes) else {
+            let Some(wr) = self
+                .pending
+                .take()
+                .or_else(|| self.queue.pop(self.id))
+                .or_else(|| self.steal())
+            else {
+                self.report_drained();
+                // keeps polling the tail until the hardware consumed every descriptor
+                let (queue, id, undrained) = (&self.queue, self.id, self.undrained);
+                self.poller.idle(|| undrained || queue.has_work(id));
                 continue;
             };
+            self.poller.reset();
             let desc0 = SendQueueReqDescSeg0::new(
                 wr.opcode,
                 wr.msn,
@@ -126,39 +500,52 @@ impl<Dev: DeviceAdaptor + Send + 'static> SendWorker<Dev> {
             );
 
             if !self.send_queue.push(SendQueueDesc::Seg0(desc0)) {
-                self.local.push(wr);
+                self.pending = Some(wr);
                 continue;
             }
             if !self.send_queue.push(SendQueueDesc::Seg1(desc1)) {
-                self.local.push(wr);
+                self.pending = Some(wr);
                 continue;
             }
+            self.queue.complete(self.id, wr.sqpn);
+            self.undrained = true;
             if self.csr_adaptor.write_head(self.send_queue.head()).is_err() {
                 error!("failed to flush queue pointer");
             }
+            self.meta_unparker.unpark();
             if let Ok(tail_ptr) = self.csr_adaptor.read_tail() {
                 self.send_queue.set_tail(tail_ptr);
             }
         }
     }
 
-    /// Find a task
-    fn find_task<T>(local: &Worker<T>, global: &Injector<T>, stealers: &[Stealer<T>]) -> Option<T> {
-        // Pop a task from the local queue, if not empty.
-        local.pop().or_else(|| {
-            // Otherwise, we need to look for a task elsewhere.
-            iter::repeat_with(|| {
-                // Try stealing a batch of tasks from the global queue.
-                global
-                    .steal_batch_and_pop(local)
-                    // Or try stealing a task from one of the other threads.
-                    .or_else(|| stealers.iter().map(Stealer::steal).collect())
-            })
-            // Loop while no task was stolen and any steal operation needs to be retried.
-            .find(|s| !s.is_retry())
-            // Extract the stolen task, if there is one.
-            .and_then(Steal::success)
-        })
+    /// Takes over a QP of a backlogged channel, attempts are rate limited to
+    /// keep idle workers off the queue lock
+    fn steal(&mut self) -> Option<WrChunk> {
+        let now = Instant::now();
+        if now < self.next_steal {
+            return None;
+        }
+        self.next_steal = now + STEAL_INTERVAL;
+        if !self.queue.steal(self.id) {
+            return None;
+        }
+        self.queue.pop(self.id)
+    }
+
+    /// Tells the queue once the hardware has consumed every pushed descriptor,
+    /// after which QPs of this channel may be moved to other channels
+    fn report_drained(&mut self) {
+        if !self.undrained {
+            return;
+        }
+        if let Ok(tail_ptr) = self.csr_adaptor.read_tail() {
+            self.send_queue.set_tail(tail_ptr);
+        }
+        if self.send_queue.is_empty() {
+            self.undrained = false;
+            self.queue.drained(self.id);
+        }
     }
 }
 
@@ -166,7 +553,9 @@ pub(crate) fn spawn_send_workers<Dev>(
     dev: &Dev,
     bufs: Vec<DmaBuf>,
     mode: Mode,
-    global_injector: &Arc<WrInjector>,
+    queue: &Arc<WrQueue>,
+    poll: PollConfig,
+    meta_unparker: &Unparker,
 ) -> io::Result<()>
 where
     Dev: DeviceAdaptor + Clone + Send + 'static,
@@ -179,25 +568,18 @@ where
         .into_iter()
         .map(|p| SendQueue::new(DescRingBuffer::new(p.buf)))
         .collect();
-    let workers: Vec<_> = iter::repeat_with(WrWorker::new_fifo)
-        .take(send_queues.len())
-        .collect();
-    let stealers: Vec<_> = workers.iter().map(WrWorker::stealer).collect();
-    workers
+    send_queues
         .into_iter()
-        .zip(send_queues)
         .zip(sq_proxies)
         .enumerate()
-        .map(|(id, ((local, send_queue), csr_adaptor))| SendWorker {
+        .map(|(id, (send_queue, csr_adaptor))| SendWorker {
             id,
-            local,
-            global: Arc::clone(global_injector),
-            remotes: stealers
-                .clone()
-                .into_iter()
-                .enumerate()
-                .filter_map(|(i, x)| (i != id).then_some(x))
-                .collect(),
+            pending: None,
+            queue: Arc::clone(queue),
+            undrained: false,
+            next_steal: Instant::now(),
+            poller: AdaptivePoller::new(poll, queue.unparker(id)),
+            meta_unparker: meta_unparker.clone(),
             send_queue,
             csr_adaptor,
         })
@@ -205,3 +587,87 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use crate::constants::QPN_KEY_PART_WIDTH;
+
+    use super::*;
+
+    fn chunk(sqpn: u32, len: u32) -> WrChunk {
+        WrChunk {
+            sqpn,
+            len,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn wr_queue_shares_by_weight() {
+        let (bulk, latency) = (1 << QPN_KEY_PART_WIDTH, 2 << QPN_KEY_PART_WIDTH);
+        let queue = WrQueue::new(1);
+        queue.set_weight(latency, 2).unwrap();
+        for _ in 0..8 {
+            queue.push(chunk(bulk, 4096));
+            queue.push(chunk(latency, 4096));
+        }
+        // the bulk QP submitted first does not block the other QP
+        let order: Vec<_> = iter::from_fn(|| {
+            let x = queue.pop(0)?;
+            queue.complete(0, x.sqpn);
+            Some(x.sqpn)
+        })
+        .collect();
+        assert_eq!(
+            &order[..6],
+            &[bulk, latency, latency, bulk, latency, latency]
+        );
+        assert_eq!(order.len(), 16);
+        assert!(queue.pop(0).is_none());
+    }
+
+    #[test]
+    fn wr_queue_steals_whole_drained_qps() {
+        let (busy, idle) = (1 << QPN_KEY_PART_WIDTH, 3 << QPN_KEY_PART_WIDTH);
+        let queue = WrQueue::new(2);
+        for qpn in [busy, idle] {
+            queue.push(chunk(qpn, 4096));
+            queue.push(chunk(qpn, 4096));
+        }
+        // both QPs hash to channel 1
+        assert_eq!(queue.pop(1).unwrap().sqpn, busy);
+        queue.complete(1, busy);
+        // the QP not yet submitted to channel 1 moves as a whole
+        assert!(queue.pop(0).is_none());
+        assert!(queue.steal(0));
+        for _ in 0..2 {
+            let x = queue.pop(0).unwrap();
+            assert_eq!(x.sqpn, idle);
+            queue.complete(0, idle);
+        }
+        // a QP with descriptors not yet consumed by the hardware stays
+        assert!(!queue.steal(0));
+        assert!(queue.pop(0).is_none());
+        assert_eq!(queue.pop(1).unwrap().sqpn, busy);
+    }
+
+    #[test]
+    fn wr_queue_remove_qp_drops_pending_chunks() {
+        let (removed, other) = (1 << QPN_KEY_PART_WIDTH, 3 << QPN_KEY_PART_WIDTH);
+        let queue = WrQueue::new(2);
+        queue.set_weight(removed, 4).unwrap();
+        for qpn in [removed, other] {
+            queue.push(chunk(qpn, 4096));
+            queue.push(chunk(qpn, 4096));
+        }
+        queue.remove_qp(removed);
+        assert_eq!(queue.queued(1), 2);
+        for _ in 0..2 {
+            assert_eq!(queue.pop(1).unwrap().sqpn, other);
+            queue.complete(1, other);
+        }
+        assert!(queue.pop(1).is_none());
+        assert!(!queue.has_work(1));
+        assert_eq!(queue.queues.get_qp(removed).unwrap().lock().weight, 0);
+    }
+}
@@ -15,6 +15,7 @@ use crate::{
         page::{ContiguousPages, MmapMut},
         DmaBuf, PageWithPhysAddr,
     },
+    poll::{AdaptivePoller, PollConfig, Unparker},
     protocol_impl::device::{
         proxy::{SimpleNicRxQueueCsrProxy, SimpleNicTxQueueCsrProxy},
         CsrBaseAddrAdaptor, CsrWriterAdaptor, DeviceAdaptor,
@@ -55,6 +56,13 @@ impl<Dev: DeviceAdaptor> SimpleNicController<Dev> {
             rx: FrameRxQueue::new(rx_queue, rx_buffer.buf, resp_csr_proxy),
         })
     }
+
+    /// Parks the receiver while idle, returns the `Unparker` to call on RX notifications
+    pub(crate) fn enable_rx_notification(&mut self, poll: PollConfig) -> Unparker {
+        let unparker = Unparker::new();
+        self.rx.poller = Some(AdaptivePoller::new(poll, unparker.clone()));
+        unparker
+    }
 }
 
 impl<Dev: DeviceAdaptor + Send + 'static> SimpleNicTunnel for SimpleNicController<Dev> {
@@ -156,6 +164,8 @@ pub(crate) struct FrameRxQueue<Dev> {
     rx_buf: MmapMut,
     /// CSR Proxy
     csr_proxy: SimpleNicRxQueueCsrProxy<Dev>,
+    /// Poller parking the receiver between notifications, yields if not set
+    poller: Option<AdaptivePoller>,
 }
 
 impl<Dev> FrameRxQueue<Dev> {
@@ -169,6 +179,7 @@ impl<Dev> FrameRxQueue<Dev> {
             rx_queue,
             rx_buf,
             csr_proxy,
+            poller: None,
         }
     }
 }
@@ -180,6 +191,9 @@ impl<Dev: Send + 'static> FrameRx for FrameRxQueue<Dev> {
         let Some(desc) = self.rx_queue.pop() else {
             return Err(io::ErrorKind::WouldBlock.into());
         };
+        if let Some(poller) = self.poller.as_mut() {
+            poller.reset();
+        }
         let pos = (desc.slot_idx() as usize)
             .checked_mul(FRAME_SLOT_SIZE)
             .unwrap_or_else(|| unreachable!("invalid index"));
@@ -192,6 +206,14 @@ impl<Dev: Send + 'static> FrameRx for FrameRxQueue<Dev> {
 
         Ok(frame)
     }
+
+    fn idle(&mut self) {
+        match self.poller.as_mut() {
+            // the RX queue is only checked after waking up
+            Some(poller) => poller.idle(|| false),
+            None => thread::yield_now(),
+        }
+    }
 }
 
 /// Worker that handles transmitting frames from the network device to the NIC
@@ -275,7 +297,7 @@ impl<Rx: FrameRx + Send + 'static> RxWorker<Rx> {
                     let frame = match self.frame_rx.recv_nonblocking() {
                         Ok(frame) => frame,
                         Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock) => {
-                            thread::yield_now();
+                            self.frame_rx.idle();
                             continue;
                         }
                         Err(err) => {
@@ -2,12 +2,14 @@ use std::{
     iter,
     sync::{
         atomic::{AtomicU16, AtomicU32, AtomicU8, Ordering},
-        Arc,
+        Arc, OnceLock,
     },
 };
 
 use bitvec::vec::BitVec;
-use ibverbs_sys::{ibv_qp, ibv_qp_type::IBV_QPT_RC, ibv_send_wr};
+use ibverbs_sys::{
+    ibv_qp, ibv_qp_attr_mask, ibv_qp_cap, ibv_qp_state, ibv_qp_type::IBV_QPT_RC, ibv_send_wr,
+};
 use parking_lot::{Mutex, RwLock};
 use rand::Rng;
 
@@ -22,6 +24,7 @@ use crate::{
 pub(crate) struct QueuePairAttr {
     pub(crate) qp_type: u8,
     pub(crate) qpn: u32,
+    pub(crate) pd_handle: u32,
     pub(crate) dqpn: u32,
     pub(crate) dqp_ip: u32,
     pub(crate) mac_addr: u64,
@@ -29,10 +32,184 @@ pub(crate) struct QueuePairAttr {
     pub(crate) access_flags: u8,
     pub(crate) send_cq: Option<u32>,
     pub(crate) recv_cq: Option<u32>,
+    pub(crate) srq: Option<u32>,
+    pub(crate) state: QpState,
+    pub(crate) rq_psn: u32,
+    pub(crate) sq_psn: u32,
+    pub(crate) pkey_index: u16,
+    pub(crate) port_num: u8,
+    /// Service level from the address handle, reported back by `query_qp`
+    pub(crate) sl: u8,
+    pub(crate) timeout: u8,
+    pub(crate) retry_cnt: u8,
+    pub(crate) rnr_retry: u8,
+    pub(crate) min_rnr_timer: u8,
+    pub(crate) max_rd_atomic: u8,
+    pub(crate) max_dest_rd_atomic: u8,
+    pub(crate) sq_sig_all: bool,
+    pub(crate) cap: QpCap,
+}
+
+/// Work queue capacities requested at QP creation
+#[allow(clippy::struct_field_names)] // mirrors `ibv_qp_cap`
+#[derive(Default, Debug, Clone, Copy)]
+pub(crate) struct QpCap {
+    pub(crate) max_send_wr: u32,
+    pub(crate) max_recv_wr: u32,
+    pub(crate) max_send_sge: u32,
+    pub(crate) max_recv_sge: u32,
+    pub(crate) max_inline_data: u32,
+}
+
+impl QpCap {
+    pub(crate) fn to_ibv(self) -> ibv_qp_cap {
+        ibv_qp_cap {
+            max_send_wr: self.max_send_wr,
+            max_recv_wr: self.max_recv_wr,
+            max_send_sge: self.max_send_sge,
+            max_recv_sge: self.max_recv_sge,
+            max_inline_data: self.max_inline_data,
+        }
+    }
+}
+
+/// State of an RC queue pair
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum QpState {
+    #[default]
+    Reset,
+    Init,
+    Rtr,
+    Rts,
+    Sqd,
+    Sqe,
+    Err,
+}
+
+impl QpState {
+    pub(crate) fn from_ibv(state: ibv_qp_state::Type) -> Option<Self> {
+        let state = match state {
+            ibv_qp_state::IBV_QPS_RESET => Self::Reset,
+            ibv_qp_state::IBV_QPS_INIT => Self::Init,
+            ibv_qp_state::IBV_QPS_RTR => Self::Rtr,
+            ibv_qp_state::IBV_QPS_RTS => Self::Rts,
+            ibv_qp_state::IBV_QPS_SQD => Self::Sqd,
+            ibv_qp_state::IBV_QPS_SQE => Self::Sqe,
+            ibv_qp_state::IBV_QPS_ERR => Self::Err,
+            _ => return None,
+        };
+        Some(state)
+    }
+
+    pub(crate) fn to_ibv(self) -> ibv_qp_state::Type {
+        match self {
+            Self::Reset => ibv_qp_state::IBV_QPS_RESET,
+            Self::Init => ibv_qp_state::IBV_QPS_INIT,
+            Self::Rtr => ibv_qp_state::IBV_QPS_RTR,
+            Self::Rts => ibv_qp_state::IBV_QPS_RTS,
+            Self::Sqd => ibv_qp_state::IBV_QPS_SQD,
+            Self::Sqe => ibv_qp_state::IBV_QPS_SQE,
+            Self::Err => ibv_qp_state::IBV_QPS_ERR,
+        }
+    }
+
+    /// Returns `true` if send WRs can be posted in this state
+    ///
+    /// WRs posted in the error state are completed with a flush error.
+    pub(crate) fn can_post_send(self) -> bool {
+        matches!(self, Self::Rts | Self::Sqd | Self::Err)
+    }
+
+    /// Returns `true` if receive WRs can be posted in this state
+    ///
+    /// WRs posted in the error state are completed with a flush error.
+    pub(crate) fn can_post_recv(self) -> bool {
+        !matches!(self, Self::Reset)
+    }
+
+    /// Validates a `modify_qp` request against the RC state machine
+    ///
+    /// If `next` is `None` the QP stays in the current state and only the
+    /// attributes are modified. Returns the resulting state, or `None` if the
+    /// transition is illegal or `attr_mask` is missing a required attribute
+    /// or contains an attribute not allowed for this transition.
+    pub(crate) fn transition(self, next: Option<Self>, attr_mask: u32) -> Option<Self> {
+        let next = next.unwrap_or(self);
+        let (required, optional) = Self::transition_masks(self, next)?;
+        let mask = attr_mask & !ibv_qp_attr_mask::IBV_QP_STATE.0;
+        if mask & required != required || mask & !(required | optional) != 0 {
+            return None;
+        }
+        Some(next)
+    }
+
+    /// Returns the required and optional attribute masks of a transition, as
+    /// specified by libibverbs for RC QPs
+    fn transition_masks(cur: Self, next: Self) -> Option<(u32, u32)> {
+        const CUR_STATE: u32 = ibv_qp_attr_mask::IBV_QP_CUR_STATE.0;
+        const EN_SQD_ASYNC_NOTIFY: u32 = ibv_qp_attr_mask::IBV_QP_EN_SQD_ASYNC_NOTIFY.0;
+        const ACCESS_FLAGS: u32 = ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS.0;
+        const PKEY_INDEX: u32 = ibv_qp_attr_mask::IBV_QP_PKEY_INDEX.0;
+        const PORT: u32 = ibv_qp_attr_mask::IBV_QP_PORT.0;
+        const AV: u32 = ibv_qp_attr_mask::IBV_QP_AV.0;
+        const PATH_MTU: u32 = ibv_qp_attr_mask::IBV_QP_PATH_MTU.0;
+        const TIMEOUT: u32 = ibv_qp_attr_mask::IBV_QP_TIMEOUT.0;
+        const RETRY_CNT: u32 = ibv_qp_attr_mask::IBV_QP_RETRY_CNT.0;
+        const RNR_RETRY: u32 = ibv_qp_attr_mask::IBV_QP_RNR_RETRY.0;
+        const RQ_PSN: u32 = ibv_qp_attr_mask::IBV_QP_RQ_PSN.0;
+        const MAX_QP_RD_ATOMIC: u32 = ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC.0;
+        const ALT_PATH: u32 = ibv_qp_attr_mask::IBV_QP_ALT_PATH.0;
+        const MIN_RNR_TIMER: u32 = ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER.0;
+        const SQ_PSN: u32 = ibv_qp_attr_mask::IBV_QP_SQ_PSN.0;
+        const MAX_DEST_RD_ATOMIC: u32 = ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC.0;
+        const PATH_MIG_STATE: u32 = ibv_qp_attr_mask::IBV_QP_PATH_MIG_STATE.0;
+        const DEST_QPN: u32 = ibv_qp_attr_mask::IBV_QP_DEST_QPN.0;
+
+        let masks = match (cur, next) {
+            (_, Self::Reset | Self::Err) | (Self::Reset, Self::Reset) => (0, 0),
+            (Self::Reset, Self::Init) => (PKEY_INDEX | PORT | ACCESS_FLAGS, 0),
+            (Self::Init, Self::Init) => (0, PKEY_INDEX | PORT | ACCESS_FLAGS),
+            (Self::Init, Self::Rtr) => (
+                AV | PATH_MTU | DEST_QPN | RQ_PSN | MAX_DEST_RD_ATOMIC | MIN_RNR_TIMER,
+                ALT_PATH | ACCESS_FLAGS | PKEY_INDEX,
+            ),
+            (Self::Rtr, Self::Rts) => (
+                SQ_PSN | TIMEOUT | RETRY_CNT | RNR_RETRY | MAX_QP_RD_ATOMIC,
+                CUR_STATE | ALT_PATH | ACCESS_FLAGS | MIN_RNR_TIMER | PATH_MIG_STATE,
+            ),
+            (Self::Rts | Self::Sqd, Self::Rts) => (
+                0,
+                CUR_STATE | ACCESS_FLAGS | ALT_PATH | PATH_MIG_STATE | MIN_RNR_TIMER,
+            ),
+            (Self::Sqe, Self::Rts) => (0, CUR_STATE | ACCESS_FLAGS),
+            (Self::Rts, Self::Sqd) => (0, EN_SQD_ASYNC_NOTIFY),
+            (Self::Sqd, Self::Sqd) => (
+                0,
+                PKEY_INDEX
+                    | PORT
+                    | AV
+                    | ALT_PATH
+                    | ACCESS_FLAGS
+                    | MIN_RNR_TIMER
+                    | PATH_MIG_STATE
+                    | TIMEOUT
+                    | RETRY_CNT
+                    | RNR_RETRY
+                    | MAX_QP_RD_ATOMIC
+                    | MAX_DEST_RD_ATOMIC,
+            ),
+            _ => return None,
+        };
+        Some(masks)
+    }
 }
 
+/// Called with the QPN of a QP that leaves RTS or SQD
+type StoppedHook = Box<dyn Fn(u32) + Send + Sync>;
+
 pub(crate) struct QueuePairAttrTable {
     inner: Arc<[RwLock<QueuePairAttr>]>,
+    on_stopped: Arc<OnceLock<StoppedHook>>,
 }
 
 impl QueuePairAttrTable {
@@ -41,15 +218,22 @@ impl QueuePairAttrTable {
             inner: iter::repeat_with(RwLock::default)
                 .take(MAX_QP_CNT)
                 .collect(),
+            on_stopped: Arc::new(OnceLock::new()),
         }
     }
 
     pub(crate) fn clone_arc(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
+            on_stopped: Arc::clone(&self.on_stopped),
         }
     }
 
+    /// Sets the hook notified whenever a QP stops sending, only the first hook is kept
+    pub(crate) fn set_stopped_hook(&self, hook: impl Fn(u32) + Send + Sync + 'static) {
+        let _ignore = self.on_stopped.set(Box::new(hook));
+    }
+
     pub(crate) fn get(&self, qpn: u32) -> Option<QueuePairAttr> {
         let index = index(qpn);
         self.inner.get(index).map(|x| *x.read())
@@ -63,12 +247,30 @@ impl QueuePairAttrTable {
         self.inner.get(index).map(|x| f(&x.read()))
     }
 
+    pub(crate) fn set_state(&self, qpn: u32, state: QpState) {
+        let _ignore = self.map_qp_mut(qpn, |qp| qp.state = state);
+    }
+
     pub(crate) fn map_qp_mut<F, T>(&self, qpn: u32, mut f: F) -> Option<T>
     where
         F: FnMut(&mut QueuePairAttr) -> T,
     {
         let index = index(qpn);
-        self.inner.get(index).map(|x| f(&mut x.write()))
+        let (stopped, ret) = self.inner.get(index).map(|x| {
+            let mut qp = x.write();
+            let could_send = matches!(qp.state, QpState::Rts | QpState::Sqd);
+            let ret = f(&mut qp);
+            (
+                could_send && !matches!(qp.state, QpState::Rts | QpState::Sqd),
+                ret,
+            )
+        })?;
+        if stopped {
+            if let Some(hook) = self.on_stopped.get() {
+                hook(qpn);
+            }
+        }
+        Some(ret)
     }
 }
 
@@ -135,6 +337,8 @@ pub(crate) struct SqContext {
     psn: Psn,
     base_psn_acked: Psn,
     base_msn_acked: u16,
+    /// End PSN of the last WR that requested an ACK
+    ack_req_psn: Psn,
 }
 
 impl SqContext {
@@ -165,6 +369,19 @@ impl SqContext {
     pub(crate) fn update_msn_acked(&mut self, msn: u16) {
         self.base_msn_acked = msn;
     }
+
+    /// Records a WR ending at `end_psn` that requested an ACK
+    pub(crate) fn update_ack_req(&mut self, end_psn: Psn) {
+        self.ack_req_psn = end_psn;
+    }
+
+    /// Returns `true` if every WR that requested an ACK has been acknowledged
+    ///
+    /// WRs without an ACK request are not acknowledged on their own, so they are only
+    /// covered by a later ACK.
+    pub(crate) fn is_ack_req_acked(&self) -> bool {
+        self.base_psn_acked >= self.ack_req_psn
+    }
 }
 
 #[allow(clippy::as_conversions)] // u32 to usize
@@ -173,20 +390,24 @@ fn index(qpn: u32) -> usize {
 }
 
 /// Calculate the number of psn required for this WR
+///
+/// Packets are aligned to pmtu on the remote address, so an unaligned range may
+/// require one more packet than `length / pmtu`.
 pub(crate) fn num_psn(pmtu: u8, addr: u64, length: u32) -> Option<u32> {
-    let pmtu = convert_ibv_mtu_to_u16(pmtu)?;
-    let pmtu_mask = pmtu
-        .checked_sub(1)
-        .unwrap_or_else(|| unreachable!("pmtu should be greater than 1"));
-    let next_align_addr = addr.saturating_add(u64::from(pmtu_mask)) & !u64::from(pmtu_mask);
-    let gap = next_align_addr.saturating_sub(addr);
-    let length_u64 = u64::from(length);
-    length_u64
-        .checked_sub(gap)
-        .unwrap_or(length_u64)
-        .div_ceil(u64::from(pmtu))
-        .try_into()
-        .ok()
+    let pmtu = u64::from(convert_ibv_mtu_to_u16(pmtu)?);
+    if length == 0 {
+        return Some(0);
+    }
+    let end_addr = addr.checked_add(u64::from(length))?;
+    (end_addr.div_ceil(pmtu) - addr / pmtu).try_into().ok()
+}
+
+/// Calculate the number of psn required for a WR
+///
+/// Packets are only split at pmtu boundaries, whatever the gather list, and a
+/// zero-length message still takes one packet.
+pub(crate) fn num_psn_wr(pmtu: u8, raddr: u64, length: u32) -> Option<u32> {
+    num_psn(pmtu, raddr, length).map(|n| n.max(1))
 }
 
 pub(crate) fn convert_ibv_mtu_to_u16(ibv_mtu: u8) -> Option<u16> {
@@ -200,3 +421,69 @@ pub(crate) fn convert_ibv_mtu_to_u16(ibv_mtu: u8) -> Option<u16> {
     };
     Some(pmtu)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    type M = ibv_qp_attr_mask;
+
+    #[test]
+    fn rc_connect_sequence() {
+        let to_init = M::IBV_QP_STATE.0
+            | M::IBV_QP_PKEY_INDEX.0
+            | M::IBV_QP_PORT.0
+            | M::IBV_QP_ACCESS_FLAGS.0;
+        let to_rtr = M::IBV_QP_STATE.0
+            | M::IBV_QP_AV.0
+            | M::IBV_QP_PATH_MTU.0
+            | M::IBV_QP_DEST_QPN.0
+            | M::IBV_QP_RQ_PSN.0
+            | M::IBV_QP_MAX_DEST_RD_ATOMIC.0
+            | M::IBV_QP_MIN_RNR_TIMER.0;
+        let to_rts = M::IBV_QP_STATE.0
+            | M::IBV_QP_SQ_PSN.0
+            | M::IBV_QP_TIMEOUT.0
+            | M::IBV_QP_RETRY_CNT.0
+            | M::IBV_QP_RNR_RETRY.0
+            | M::IBV_QP_MAX_QP_RD_ATOMIC.0;
+        let state = QpState::Reset;
+        let state = state.transition(Some(QpState::Init), to_init).unwrap();
+        let state = state.transition(Some(QpState::Rtr), to_rtr).unwrap();
+        let state = state.transition(Some(QpState::Rts), to_rts).unwrap();
+        assert_eq!(state, QpState::Rts);
+        assert!(state.can_post_send());
+        assert_eq!(
+            state.transition(None, M::IBV_QP_MIN_RNR_TIMER.0),
+            Some(QpState::Rts)
+        );
+        assert_eq!(
+            state.transition(Some(QpState::Err), M::IBV_QP_STATE.0),
+            Some(QpState::Err)
+        );
+    }
+
+    #[test]
+    fn invalid_transitions_rejected() {
+        // skipping INIT
+        assert_eq!(
+            QpState::Reset.transition(Some(QpState::Rtr), M::IBV_QP_STATE.0),
+            None
+        );
+        // missing required attributes
+        assert_eq!(
+            QpState::Reset.transition(Some(QpState::Init), M::IBV_QP_STATE.0),
+            None
+        );
+        // attribute not allowed in this transition
+        let mask = M::IBV_QP_STATE.0
+            | M::IBV_QP_PKEY_INDEX.0
+            | M::IBV_QP_PORT.0
+            | M::IBV_QP_ACCESS_FLAGS.0
+            | M::IBV_QP_SQ_PSN.0;
+        assert_eq!(QpState::Reset.transition(Some(QpState::Init), mask), None);
+        assert!(!QpState::Rtr.can_post_send());
+        assert!(QpState::Sqd.can_post_send());
+        assert!(!QpState::Reset.can_post_recv());
+    }
+}
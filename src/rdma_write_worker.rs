@@ -1,16 +1,24 @@
-use std::io;
+use std::{
+    collections::{HashMap, VecDeque},
+    io, iter,
+    ops::Range,
+    sync::Arc,
+};
 
 use parking_lot::Mutex;
+use tracing::error;
 
 use crate::{
+    atomic::AtomicTask,
     completion::{Completion, CompletionTask, Event, MessageMeta, SendEvent, SendEventOp},
-    constants::PSN_MASK,
+    constants::{MAX_SGE, PSN_MASK},
     device_protocol::{ChunkPos, QpParams, WorkReqOpCode, WorkReqSend, WrChunkBuilder},
     fragmenter::{WrChunkFragmenter, WrPacketFragmenter},
+    mem::staging::{ReadScatter, StagingBuf, StagingPool, StagingPoolTable},
     packet_retransmit::{PacketRetransmitTask, SendQueueElem},
     protocol_impl::SendQueueScheduler,
-    qp::{num_psn, QueuePairAttrTable, SqContext},
-    send::SendWrRdma,
+    qp::{convert_ibv_mtu_to_u16, num_psn_wr, QpState, QueuePairAttrTable, SqContext},
+    send::{SendWrAtomic, SendWrRdma, Sge, SgeList},
     timeout_retransmit::RetransmitTask,
     utils::{Psn, QpTable},
 };
@@ -26,6 +34,15 @@ pub(crate) enum RdmaWriteTask {
         qpn: u32,
         base_psn: Psn,
     },
+    /// An atomic, issued once all earlier WRs of the QP are acknowledged
+    Atomic {
+        qpn: u32,
+        wr: SendWrAtomic,
+    },
+    /// The QP left RTS or SQD, its fenced atomic will never be acknowledged
+    QpStopped {
+        qpn: u32,
+    },
 }
 
 impl RdmaWriteTask {
@@ -37,6 +54,10 @@ impl RdmaWriteTask {
     pub(crate) fn new_ack(qpn: u32, base_psn: Psn) -> Self {
         Self::Ack { qpn, base_psn }
     }
+
+    pub(crate) fn new_qp_stopped(qpn: u32) -> Self {
+        Self::QpStopped { qpn }
+    }
 }
 
 pub(crate) struct RdmaWriteWorker {
@@ -47,9 +68,18 @@ pub(crate) struct RdmaWriteWorker {
     retransmit_tx: flume::Sender<RetransmitTask>,
     packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
     completion_tx: flume::Sender<CompletionTask>,
+    atomic_tx: flume::Sender<AtomicTask>,
+    /// Atomics waiting for the earlier WRs of the QP to be acknowledged
+    fenced_atomics: HashMap<u32, SendWrAtomic>,
+    /// Staging memory of the PDs, registered by the device context
+    staging_pools: StagingPoolTable,
+    /// Staging buffers of the issued WRs, each with the end PSN of its WR, kept for
+    /// retransmission until the WR is acknowledged
+    staged: HashMap<u32, VecDeque<(Psn, StagingBuf)>>,
 }
 
 impl RdmaWriteWorker {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         rdma_write_rx: flume::Receiver<RdmaWriteTask>,
         qp_attr_table: QueuePairAttrTable,
@@ -57,6 +87,8 @@ impl RdmaWriteWorker {
         retransmit_tx: flume::Sender<RetransmitTask>,
         packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
         completion_tx: flume::Sender<CompletionTask>,
+        atomic_tx: flume::Sender<AtomicTask>,
+        staging_pools: StagingPoolTable,
     ) -> Self {
         Self {
             rdma_write_rx,
@@ -66,6 +98,10 @@ impl RdmaWriteWorker {
             retransmit_tx,
             packet_retransmit_tx,
             completion_tx,
+            atomic_tx,
+            fenced_atomics: HashMap::new(),
+            staging_pools,
+            staged: HashMap::new(),
         }
     }
 
@@ -86,6 +122,7 @@ impl RdmaWriteWorker {
                         | WorkReqOpCode::RdmaWriteWithImm
                         | WorkReqOpCode::Send
                         | WorkReqOpCode::SendWithImm
+                        | WorkReqOpCode::SendWithInv
                         | WorkReqOpCode::RdmaReadResp => self.write(qpn, wr),
                         WorkReqOpCode::RdmaRead => self.rdma_read(qpn, wr),
                         _ => unreachable!("opcode unsupported"),
@@ -96,16 +133,86 @@ impl RdmaWriteWorker {
                     if let Some(ctx) = self.sq_ctx_table.get_qp_mut(qpn) {
                         ctx.update_psn_acked(base_psn);
                     }
+                    if let Some(staged) = self.staged.get_mut(&qpn) {
+                        while staged.front().is_some_and(|&(end, _)| end <= base_psn) {
+                            let _buf = staged.pop_front();
+                        }
+                    }
+                    self.release_fenced_atomics();
                 }
+                RdmaWriteTask::Atomic { qpn, wr } => {
+                    let _prev = self.fenced_atomics.insert(qpn, wr);
+                    self.release_fenced_atomics();
+                }
+                RdmaWriteTask::QpStopped { qpn } => {
+                    let _bufs = self.staged.remove(&qpn);
+                    if self.fenced_atomics.contains_key(&qpn) {
+                        self.release_fenced_atomics();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hands the fenced atomics over to the atomic worker once all earlier WRs that
+    /// requested an ACK are acknowledged
+    ///
+    /// Atomics bypass the hardware, so this keeps them ordered after the WRs issued
+    /// before them. Atomics of QPs that can no longer send are released as well, the
+    /// atomic worker completes them with an error.
+    fn release_fenced_atomics(&mut self) {
+        let ready: Vec<_> = self
+            .fenced_atomics
+            .keys()
+            .copied()
+            .filter(|&qpn| {
+                let can_send = self
+                    .qp_attr_table
+                    .get(qpn)
+                    .is_some_and(|qp| matches!(qp.state, QpState::Rts | QpState::Sqd));
+                let acked = self
+                    .sq_ctx_table
+                    .get_qp(qpn)
+                    .is_none_or(SqContext::is_ack_req_acked);
+                !can_send || acked
+            })
+            .collect();
+        for qpn in ready {
+            let Some(wr) = self.fenced_atomics.remove(&qpn) else {
+                continue;
+            };
+            if self.atomic_tx.send(AtomicTask { qpn, wr }).is_err() {
+                error!("atomic worker exited");
             }
         }
     }
 
-    fn rdma_read(&mut self, qpn: u32, wr: SendWrRdma) -> io::Result<()> {
+    /// Issues the RDMA Read as a single read request
+    ///
+    /// The response is written to a single local buffer, so a read into several SGEs
+    /// lands in a staging buffer and is scattered to the SGEs when the response
+    /// completes.
+    fn rdma_read(&mut self, qpn: u32, mut wr: SendWrRdma) -> io::Result<()> {
         let qp = self
             .qp_attr_table
             .get(qpn)
             .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        let sg_list = wr.sg_list();
+        let scatter = if sg_list.len() > 1 {
+            let buf = self
+                .staging_pool(qp.pd_handle)?
+                .alloc(sg_list.total_len())
+                .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))?;
+            wr.set_sg_list(
+                SgeList::new(&[buf.sge()])
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?,
+            );
+            Some(ReadScatter::new(buf, sg_list))
+        } else {
+            None
+        };
+        // a zero-length read has no local buffer
+        let sge = wr.sg_list().as_slice().first().copied().unwrap_or_default();
 
         let addr = wr.raddr();
         let length = wr.length();
@@ -132,10 +239,10 @@ impl RdmaWriteWorker {
                 wr.send_flags() as u8,
                 wr.rkey(),
                 wr.length(),
-                wr.lkey(),
+                sge.lkey,
                 wr.imm(),
             )
-            .set_chunk_meta(psn, wr.laddr(), wr.raddr(), wr.length(), ChunkPos::Only)
+            .set_chunk_meta(psn, sge.addr, wr.raddr(), wr.length(), ChunkPos::Only)
             .build();
         let flags = wr.send_flags();
         let mut ack_req = false;
@@ -152,6 +259,9 @@ impl RdmaWriteWorker {
             ));
             self.completion_tx
                 .send(CompletionTask::Register { qpn, event });
+            if let Some(ctx) = self.sq_ctx_table.get_qp_mut(qpn) {
+                ctx.update_ack_req(end_psn);
+            }
         }
 
         if ack_req {
@@ -166,26 +276,81 @@ impl RdmaWriteWorker {
             wr: SendQueueElem::new(wr, psn, qp_params),
         });
 
+        // queued before the response can arrive
+        let _ignore = self
+            .completion_tx
+            .send(CompletionTask::ReadIssued { qpn, scatter });
         self.send_scheduler.send(chunk)?;
 
         Ok(())
     }
 
-    fn write(&mut self, qpn: u32, wr: SendWrRdma) -> io::Result<()> {
+    /// Returns the staging pool of the PD
+    fn staging_pool(&self, pd_handle: u32) -> io::Result<Arc<StagingPool>> {
+        self.staging_pools
+            .get(pd_handle)
+            .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))
+    }
+
+    /// Copies the packets straddling an unaligned SGE boundary to a staging buffer and
+    /// redirects the gather list to it, so that every packet reads a single buffer
+    ///
+    /// Returns `None` if all interior SGE boundaries lie on pmtu boundaries.
+    /// `post_send` makes room for the `staged_len` bytes beforehand.
+    fn stage_unaligned(
+        &self,
+        pd_handle: u32,
+        pmtu: u64,
+        wr: &mut SendWrRdma,
+    ) -> io::Result<Option<StagingBuf>> {
+        let sg_list = wr.sg_list();
+        let mut straddling = straddling_packets(pmtu, wr.raddr(), &sg_list);
+        if straddling.is_empty() {
+            return Ok(None);
+        }
+        fit_staged(&sg_list, &mut straddling);
+        let len: u64 = straddling.iter().map(|range| range.end - range.start).sum();
+        let buf = u32::try_from(len)
+            .ok()
+            .and_then(|len| self.staging_pool(pd_handle).ok()?.alloc(len))
+            .ok_or(io::Error::from(io::ErrorKind::OutOfMemory))?;
+        let mut offset = 0;
+        for range in &straddling {
+            // SAFETY: the SGEs were validated against their memory regions when the WR
+            // was posted
+            #[allow(unsafe_code)]
+            unsafe {
+                buf.gather(offset, sg_list.slice(range.clone()));
+            }
+            offset += usize::try_from(range.end - range.start)
+                .unwrap_or_else(|_| unreachable!("staged length fits in u32"));
+        }
+        wr.set_sg_list(redirect_sg_list(&sg_list, &straddling, buf.sge())?);
+        Ok(Some(buf))
+    }
+
+    fn write(&mut self, qpn: u32, mut wr: SendWrRdma) -> io::Result<()> {
         let qp = self
             .qp_attr_table
             .get(qpn)
             .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
-        let addr = wr.raddr();
-        let length = wr.length();
-        let num_psn =
-            num_psn(qp.pmtu, addr, length).ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        let num_psn = num_psn_wr(qp.pmtu, wr.raddr(), wr.length())
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        let pmtu =
+            convert_ibv_mtu_to_u16(qp.pmtu).ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        let staging = self.stage_unaligned(qp.pd_handle, pmtu.into(), &mut wr)?;
         let (msn, psn) = self
             .sq_ctx_table
             .get_qp_mut(qpn)
             .and_then(|ctx| ctx.next_wr(num_psn))
             .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
         let end_psn = psn + num_psn;
+        if let Some(buf) = staging {
+            self.staged
+                .entry(qpn)
+                .or_default()
+                .push_back((end_psn, buf));
+        }
         let flags = wr.send_flags();
         let mut ack_req = false;
         if flags & ibverbs_sys::ibv_send_flags::IBV_SEND_SIGNALED.0 != 0 {
@@ -194,18 +359,14 @@ impl RdmaWriteWorker {
             let send_cq_handle = qp
                 .send_cq
                 .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
-            #[allow(clippy::wildcard_enum_match_arm)]
-            let op = match wr.opcode() {
-                WorkReqOpCode::RdmaWrite | WorkReqOpCode::RdmaWriteWithImm => {
-                    SendEventOp::WriteSignaled
-                }
-                WorkReqOpCode::Send | WorkReqOpCode::SendWithImm => SendEventOp::SendSignaled,
-                WorkReqOpCode::RdmaRead => SendEventOp::ReadSignaled,
-                _ => return Err(io::ErrorKind::Unsupported.into()),
-            };
+            let op = SendEventOp::from_opcode(wr.opcode())
+                .ok_or(io::Error::from(io::ErrorKind::Unsupported))?;
             let event = Event::Send(SendEvent::new(op, MessageMeta::new(msn, end_psn), wr_id));
             self.completion_tx
                 .send(CompletionTask::Register { qpn, event });
+            if let Some(ctx) = self.sq_ctx_table.get_qp_mut(qpn) {
+                ctx.update_ack_req(end_psn);
+            }
         }
         let qp_params = QpParams::new(
             msn,
@@ -241,3 +402,171 @@ impl RdmaWriteWorker {
         Ok(())
     }
 }
+
+/// Returns the number of bytes of a write staged by the send path, the byte ranges
+/// are those of `straddling_packets` widened by `fit_staged`
+pub(crate) fn staged_len(pmtu: u64, raddr: u64, sg_list: &SgeList) -> u64 {
+    let mut staged = straddling_packets(pmtu, raddr, sg_list);
+    if staged.is_empty() {
+        return 0;
+    }
+    fit_staged(sg_list, &mut staged);
+    staged.iter().map(|range| range.end - range.start).sum()
+}
+
+/// Returns the byte ranges of the message held by packets that straddle an unaligned
+/// SGE boundary, adjacent packets are merged into one range
+///
+/// Packets are split at the pmtu boundaries of the remote address.
+fn straddling_packets(pmtu: u64, raddr: u64, sg_list: &SgeList) -> Vec<Range<u64>> {
+    let total_len = u64::from(sg_list.total_len());
+    let mut ranges: Vec<Range<u64>> = Vec::new();
+    let mut offset = 0;
+    for sge in sg_list.as_slice() {
+        offset += u64::from(sge.length);
+        let addr = raddr + offset;
+        let packet_start = addr & !(pmtu - 1);
+        if offset == 0 || offset >= total_len || packet_start == addr {
+            continue;
+        }
+        let start = packet_start.max(raddr) - raddr;
+        let end = (packet_start + pmtu).min(raddr + total_len) - raddr;
+        match ranges.last_mut() {
+            Some(last) if last.end >= start => last.end = end,
+            _ => ranges.push(start..end),
+        }
+    }
+    ranges
+}
+
+/// Widens the staged ranges until the redirected gather list fits in `MAX_SGE`
+/// elements, each step stages the shortest range of bytes still read in place
+///
+/// A redirected list holds up to one element per staged range and per SGE part read in
+/// place, which may exceed the capacity of a gather list.
+fn fit_staged(sg_list: &SgeList, staged: &mut Vec<Range<u64>>) {
+    let total_len = u64::from(sg_list.total_len());
+    loop {
+        // the ranges read in place, between and around the staged ranges
+        let bounds: Vec<_> = iter::once(0)
+            .chain(staged.iter().flat_map(|range| [range.start, range.end]))
+            .chain(iter::once(total_len))
+            .collect();
+        let gaps: Vec<_> = bounds
+            .iter()
+            .step_by(2)
+            .zip(bounds.iter().skip(1).step_by(2))
+            .map(|(&start, &end)| start..end)
+            .collect();
+        let num_sge = staged.len()
+            + gaps
+                .iter()
+                .map(|gap| sg_list.slice(gap.clone()).count())
+                .sum::<usize>();
+        if num_sge <= MAX_SGE {
+            return;
+        }
+        let Some((index, _)) = gaps
+            .iter()
+            .enumerate()
+            .filter(|&(_, gap)| !gap.is_empty())
+            .min_by_key(|&(_, gap)| gap.end - gap.start)
+        else {
+            return;
+        };
+        if index == 0 {
+            if let Some(first) = staged.first_mut() {
+                first.start = 0;
+            }
+        } else if index == staged.len() {
+            if let Some(last) = staged.last_mut() {
+                last.end = total_len;
+            }
+        } else {
+            let next = staged.remove(index);
+            if let Some(prev) = staged.get_mut(index - 1) {
+                prev.end = next.end;
+            }
+        }
+    }
+}
+
+/// Rewrites the gather list to read the `staged` ranges of the message from `staging`,
+/// which holds them back to back
+fn redirect_sg_list(sg_list: &SgeList, staged: &[Range<u64>], staging: Sge) -> io::Result<SgeList> {
+    let mut sges = Vec::new();
+    let mut addr = staging.addr;
+    let mut pos = 0;
+    for range in staged {
+        sges.extend(sg_list.slice(pos..range.start));
+        let length = u32::try_from(range.end - range.start)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        sges.push(Sge::new(addr, length, staging.lkey));
+        addr += u64::from(length);
+        pos = range.end;
+    }
+    sges.extend(sg_list.slice(pos..u64::from(sg_list.total_len())));
+    SgeList::new(&sges).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn straddling_packets_are_merged() {
+        let sg_list = SgeList::new(&[
+            Sge::new(0x1000, 0x100, 1),
+            Sge::new(0x2000, 0x40, 2),
+            Sge::new(0x3000, 0x80, 3),
+            Sge::new(0x4000, 0x200, 4),
+        ])
+        .unwrap();
+        // boundaries at 0x100 (aligned), 0x140 and 0x1c0 (same packet)
+        assert_eq!(straddling_packets(0x100, 0, &sg_list), [0x100..0x200]);
+        // shifted by 0x80: 0x180 and 0x240 fall in adjacent packets
+        assert_eq!(straddling_packets(0x100, 0x80, &sg_list), [0x80..0x280]);
+        let single = SgeList::new(&[Sge::new(0x1000, 0x1000, 1)]).unwrap();
+        assert!(straddling_packets(0x100, 0x10, &single).is_empty());
+    }
+
+    #[test]
+    fn redirect_keeps_aligned_parts() {
+        let sg_list =
+            SgeList::new(&[Sge::new(0x1000, 0x180, 1), Sge::new(0x2000, 0x180, 2)]).unwrap();
+        let mut staged = straddling_packets(0x100, 0, &sg_list);
+        assert_eq!(staged, [0x100..0x200]);
+        fit_staged(&sg_list, &mut staged);
+        assert_eq!(staged, [0x100..0x200]);
+        let redirected = redirect_sg_list(&sg_list, &staged, Sge::new(0x9000, 0x100, 9)).unwrap();
+        assert_eq!(
+            redirected.as_slice(),
+            [
+                Sge::new(0x1000, 0x100, 1),
+                Sge::new(0x9000, 0x100, 9),
+                Sge::new(0x2080, 0x100, 2),
+            ]
+        );
+        assert_eq!(redirected.total_len(), sg_list.total_len());
+    }
+
+    #[test]
+    fn staged_ranges_widen_to_fit_sg_list() {
+        // every boundary is unaligned, redirecting would take 15 elements
+        let sges: Vec<_> = (1..=8).map(|i| Sge::new(0x10000 * i, 0x290, 1)).collect();
+        let sg_list = SgeList::new(&sges).unwrap();
+        let mut staged = straddling_packets(0x100, 0, &sg_list);
+        assert_eq!(staged.len(), 7);
+        fit_staged(&sg_list, &mut staged);
+        let staging = Sge::new(0x9000, 0, 9);
+        let redirected = redirect_sg_list(&sg_list, &staged, staging).unwrap();
+        assert!(redirected.len() <= MAX_SGE);
+        assert_eq!(redirected.total_len(), sg_list.total_len());
+        // interior boundaries stay on pmtu boundaries
+        let mut offset = 0;
+        for sge in &redirected.as_slice()[..redirected.len() - 1] {
+            offset += u64::from(sge.length);
+            assert_eq!(offset % 0x100, 0);
+        }
+    }
+}
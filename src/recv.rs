@@ -1,15 +1,18 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, VecDeque},
     io::{self, Read, Write},
-    net::{Ipv4Addr, TcpListener, TcpStream},
+    mem,
+    net::{Ipv4Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
     sync::Arc,
     thread,
+    time::{Duration, Instant},
 };
 
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
 
-use crate::{utils::qpn_index, utils::QpTable};
+use crate::{send::Sge, srq::SrqTask, utils::qpn_index, utils::QpTable};
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct RecvWr {
@@ -37,6 +40,21 @@ impl RecvWr {
         })
     }
 
+    /// Returns the receive buffer
+    pub(crate) fn sge(&self) -> Sge {
+        Sge::new(self.addr, self.length, self.lkey)
+    }
+
+    /// Returns the WR of a frame carrying no WR, `wr_id` holds the value of the frame
+    fn control(value: u64) -> Self {
+        Self {
+            wr_id: value,
+            addr: 0,
+            length: 0,
+            lkey: 0,
+        }
+    }
+
     fn to_bytes(self) -> [u8; size_of::<RecvWr>()] {
         let mut bytes = [0u8; 24];
         bytes[0..8].copy_from_slice(&self.wr_id.to_be_bytes());
@@ -57,15 +75,39 @@ impl RecvWr {
     }
 }
 
+/// A channel for the responder to pass `ibv_recv_wr` to the initiator
 pub(crate) trait PostRecvChannel {
     type Tx: PostRecvTx;
-    type Rx: PostRecvRx;
+
+    /// Opens the channel between `qpn` and its peer, WRs posted by the peer are appended
+    /// to `wr_queue`
+    fn open(
+        &self,
+        qpn: u32,
+        dest_addr: Ipv4Addr,
+        dest_qpn: u32,
+        wr_queue: SharedRecvWrQueue,
+    ) -> io::Result<Self::Tx>;
+
+    /// Stops accepting WRs for `qpn`
+    fn close(&self, qpn: u32);
 }
 
-/// A channel for the responder to pass `ibv_recv_wr` to the initiator
 pub(crate) trait PostRecvTx: Sized {
-    fn connect(addr: Ipv4Addr, dqpn: u32) -> io::Result<Self>;
     fn send(&mut self, wr: RecvWr) -> io::Result<()>;
+
+    /// Asks the peer for one more WR, a no-op unless the peer draws its WRs from an SRQ
+    fn demand(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Withdraws the WRs the peer has not consumed yet, returns their number
+    ///
+    /// The peer never uses the withdrawn WRs, they are the ones sent last. Fails if the
+    /// channel can not tell which WRs the peer consumed.
+    fn revoke(&mut self) -> io::Result<usize> {
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
 }
 
 pub(crate) trait PostRecvRx: Sized {
@@ -75,11 +117,36 @@ pub(crate) trait PostRecvRx: Sized {
 
 const BASE_PORT: u16 = 60000;
 
-pub(crate) struct TcpChannel;
+/// Channel using one TCP connection and one receive thread per QP
+pub(crate) struct TcpChannel {
+    local_addr: Ipv4Addr,
+}
+
+impl TcpChannel {
+    pub(crate) fn new(local_addr: Ipv4Addr) -> Self {
+        Self { local_addr }
+    }
+}
 
 impl PostRecvChannel for TcpChannel {
     type Tx = TcpChannelTx;
-    type Rx = TcpChannelRx;
+
+    fn open(
+        &self,
+        qpn: u32,
+        dest_addr: Ipv4Addr,
+        dest_qpn: u32,
+        wr_queue: SharedRecvWrQueue,
+    ) -> io::Result<Self::Tx> {
+        let tx = TcpChannelTx::connect(dest_addr, dest_qpn);
+        let rx = TcpChannelRx::listen(self.local_addr, qpn)?;
+        RecvWorker::new(rx, wr_queue).spawn();
+        Ok(tx)
+    }
+
+    fn close(&self, _qpn: u32) {
+        // the receive thread exits when the peer closes the connection
+    }
 }
 
 pub(crate) struct TcpChannelTx {
@@ -88,15 +155,18 @@ pub(crate) struct TcpChannelTx {
     inner: Option<TcpStream>,
 }
 
-impl PostRecvTx for TcpChannelTx {
-    fn connect(addr: Ipv4Addr, dqpn: u32) -> io::Result<Self> {
-        Ok(Self {
+impl TcpChannelTx {
+    /// Creates the sending side, the connection is established on the first send
+    fn connect(addr: Ipv4Addr, dqpn: u32) -> Self {
+        Self {
             inner: None,
             addr,
             dqpn,
-        })
+        }
     }
+}
 
+impl PostRecvTx for TcpChannelTx {
     fn send(&mut self, wr: RecvWr) -> io::Result<()> {
         if self.inner.is_none() {
             self.inner = Some(TcpStream::connect((self.addr, qpn_to_port(self.dqpn)))?);
@@ -135,23 +205,449 @@ impl PostRecvRx for TcpChannelRx {
     }
 }
 
-pub(crate) fn post_recv_channel<C: PostRecvChannel>(
-    local_addr: Ipv4Addr,
-    dest_addr: Ipv4Addr,
-    local_qpn: u32,
-    dest_qpn: u32,
-) -> io::Result<(C::Tx, C::Rx)> {
-    let tx = C::Tx::connect(dest_addr, dest_qpn)?;
-    let rx = C::Rx::listen(local_addr, local_qpn)?;
-    Ok((tx, rx))
-}
-
 fn qpn_to_port(qpn: u32) -> u16 {
     let index = qpn_index(qpn);
     BASE_PORT + index as u16
 }
 
-pub(crate) struct PostRecvTxTable<Tx = TcpChannelTx> {
+/// Default port of the multiplexed post recv connections
+const DEFAULT_MUX_PORT: u16 = 60000;
+
+/// Configuration of the multiplexed post recv connections
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct PostRecvConfig {
+    /// Port the device listens on
+    port: u16,
+    /// Port the peers listen on
+    peer_port: u16,
+}
+
+impl Default for PostRecvConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_MUX_PORT,
+            peer_port: DEFAULT_MUX_PORT,
+        }
+    }
+}
+
+impl PostRecvConfig {
+    pub(crate) fn new(port: u16, peer_port: u16) -> Self {
+        Self { port, peer_port }
+    }
+}
+
+/// Size of a multiplexed frame: destination QPN, source QPN, sequence number and the WR
+const MUX_FRAME_SIZE: usize = 16 + size_of::<RecvWr>();
+
+/// Sequence number of a frame carrying no WR, it asks the destination QP for one
+///
+/// Sent by a QP that has a SEND but no receive WR of the peer, so that a peer
+/// drawing its WRs from an SRQ hands one out.
+const DEMAND_SEQ: u64 = u64::MAX;
+
+/// Sequence number of a frame carrying no WR, it withdraws the WRs the destination QP
+/// has not consumed
+///
+/// The peer answers on the same connection with a frame of the same sequence number,
+/// the `wr_id` of its WR holds the number of WRs withdrawn.
+const REVOKE_SEQ: u64 = u64::MAX - 1;
+
+/// Sequence number of a frame carrying no WR, it acknowledges the WRs of the
+/// destination QP
+///
+/// Sent back by the peer for every WR frame, the `wr_id` of its WR holds the sequence
+/// number of the next WR expected.
+const ACK_SEQ: u64 = u64::MAX - 2;
+
+/// Time to wait for the peer to answer a revoke
+const REVOKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Receive WR queue of a QP and the peer allowed to fill it
+struct MuxRoute {
+    peer_addr: Ipv4Addr,
+    peer_qpn: u32,
+    wr_queue: SharedRecvWrQueue,
+    /// Sequence number of the next WR expected from the peer QP
+    next_seq: u64,
+    /// SRQ worker handing out WRs of the QP on demand of the peer
+    srq_tx: Option<flume::Sender<SrqTask>>,
+}
+
+/// Routes of the local QPs, indexed by QPN
+type MuxRoutes = Arc<Mutex<HashMap<u32, MuxRoute>>>;
+
+/// Connection to a peer, shared by all QPs connected to that peer
+struct PeerConn {
+    stream: Option<TcpStream>,
+    /// WR frames the peer has not acknowledged as `(src_qpn, seq, frame)`, written
+    /// again to a new connection
+    unacked: VecDeque<(u32, u64, [u8; MUX_FRAME_SIZE])>,
+    /// Acknowledgements read from the connection as `(qpn, next_seq)`
+    ack_tx: flume::Sender<(u32, u64)>,
+    ack_rx: flume::Receiver<(u32, u64)>,
+    /// Answers to revokes read from the connection as `(qpn, number of WRs withdrawn)`
+    revoke_tx: flume::Sender<(u32, u64)>,
+    revoke_rx: flume::Receiver<(u32, u64)>,
+}
+
+impl Default for PeerConn {
+    fn default() -> Self {
+        let (ack_tx, ack_rx) = flume::unbounded();
+        let (revoke_tx, revoke_rx) = flume::unbounded();
+        Self {
+            stream: None,
+            unacked: VecDeque::new(),
+            ack_tx,
+            ack_rx,
+            revoke_tx,
+            revoke_rx,
+        }
+    }
+}
+
+impl PeerConn {
+    /// Drops the WR frames the peer acknowledged
+    fn apply_acks(&mut self) {
+        for (qpn, next_seq) in self.ack_rx.try_iter() {
+            self.unacked
+                .retain(|&(src_qpn, seq, _)| src_qpn != qpn || seq >= next_seq);
+        }
+    }
+
+    /// Closes the connection, its reader exits
+    fn disconnect(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            let _ignore = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+type SharedPeerConn = Arc<Mutex<PeerConn>>;
+
+/// Channel multiplexing the WRs of all QPs over one TCP connection per peer
+///
+/// The device accepts connections on a single port and a single thread per peer
+/// dispatches the received WRs to the QPs.
+pub(crate) struct MuxChannel {
+    /// Port the device listens on
+    local_port: u16,
+    /// Port the peers listen on
+    peer_port: u16,
+    routes: MuxRoutes,
+    peers: Mutex<HashMap<Ipv4Addr, SharedPeerConn>>,
+}
+
+impl MuxChannel {
+    /// Listens for peer connections on `addr`
+    pub(crate) fn bind(addr: Ipv4Addr, config: PostRecvConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind((addr, config.port))?;
+        let local_port = listener.local_addr()?.port();
+        let routes = MuxRoutes::default();
+        let routes_c = Arc::clone(&routes);
+        let _handle = thread::Builder::new()
+            .name("post-recv-listener".into())
+            .spawn(move || Self::accept(&listener, &routes_c))?;
+
+        Ok(Self {
+            local_port,
+            peer_port: config.peer_port,
+            routes,
+            peers: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Returns the port the device listens on
+    pub(crate) fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// Passes the WR demands of the peer of `qpn` to the SRQ worker
+    pub(crate) fn forward_demands(&self, qpn: u32, srq_tx: flume::Sender<SrqTask>) {
+        if let Some(route) = self.routes.lock().get_mut(&qpn) {
+            route.srq_tx = Some(srq_tx);
+        }
+    }
+
+    /// Spawns a receive thread for every peer connection
+    fn accept(listener: &TcpListener, routes: &MuxRoutes) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("failed to accept post recv connection: {err}");
+                    continue;
+                }
+            };
+            let Ok(SocketAddr::V4(peer)) = stream.peer_addr() else {
+                continue;
+            };
+            let routes = Arc::clone(routes);
+            if let Err(err) = thread::Builder::new()
+                .name("post-recv-conn".into())
+                .spawn(move || Self::serve(stream, *peer.ip(), &routes))
+            {
+                error!("failed to spawn post recv thread: {err}");
+            }
+        }
+    }
+
+    /// Dispatches the WRs received from a peer until the connection is closed
+    ///
+    /// A reconnecting peer opens a new connection, the old one is dropped here.
+    fn serve(mut stream: TcpStream, peer_addr: Ipv4Addr, routes: &MuxRoutes) {
+        let mut frame = [0u8; MUX_FRAME_SIZE];
+        while stream.read_exact(&mut frame).is_ok() {
+            let (dest_qpn, src_qpn, seq, wr) = decode_frame(&frame);
+            let Some((reply_seq, value)) =
+                Self::dispatch(routes, peer_addr, dest_qpn, src_qpn, seq, wr)
+            else {
+                continue;
+            };
+            let reply = encode_frame(src_qpn, dest_qpn, reply_seq, RecvWr::control(value));
+            if let Err(err) = stream.write_all(&reply) {
+                error!("failed to answer {peer_addr} qp {src_qpn}: {err}");
+                return;
+            }
+        }
+    }
+
+    /// Handles a frame received from a peer, returns the sequence number and the value
+    /// of the answer if one is due
+    fn dispatch(
+        routes: &MuxRoutes,
+        peer_addr: Ipv4Addr,
+        dest_qpn: u32,
+        src_qpn: u32,
+        seq: u64,
+        wr: RecvWr,
+    ) -> Option<(u64, u64)> {
+        let mut routes = routes.lock();
+        let route = routes
+            .get_mut(&dest_qpn)
+            .filter(|route| route.peer_addr == peer_addr && route.peer_qpn == src_qpn);
+        if seq == REVOKE_SEQ {
+            // WRs taken by the QP may be the target of a SEND, only the others are
+            // withdrawn
+            let revoked = route.map_or(0, |route| mem::take(&mut *route.wr_queue.lock()).len());
+            return Some((REVOKE_SEQ, u64::try_from(revoked).unwrap_or(0)));
+        }
+        let Some(route) = route else {
+            warn!("dropping recv wr from {peer_addr} qp {src_qpn} to unknown qp {dest_qpn}");
+            // acknowledged all the same, so that the peer stops resending it
+            return (seq < ACK_SEQ).then(|| (ACK_SEQ, seq + 1));
+        };
+        if seq == DEMAND_SEQ {
+            if let Some(srq_tx) = route.srq_tx.as_ref() {
+                let _ignore = srq_tx.send(SrqTask::PeerDemand { qpn: dest_qpn });
+            }
+            return None;
+        }
+        // a WR resent after a reconnect may also have arrived on the old connection
+        if seq < route.next_seq {
+            return Some((ACK_SEQ, route.next_seq));
+        }
+        if seq > route.next_seq {
+            warn!(
+                "lost {} recv wrs from {peer_addr} qp {src_qpn}",
+                seq - route.next_seq
+            );
+        }
+        route.next_seq = seq + 1;
+        route.wr_queue.lock().push_back(wr);
+        Some((ACK_SEQ, route.next_seq))
+    }
+}
+
+impl PostRecvChannel for MuxChannel {
+    type Tx = MuxChannelTx;
+
+    fn open(
+        &self,
+        qpn: u32,
+        dest_addr: Ipv4Addr,
+        dest_qpn: u32,
+        wr_queue: SharedRecvWrQueue,
+    ) -> io::Result<Self::Tx> {
+        let route = MuxRoute {
+            peer_addr: dest_addr,
+            peer_qpn: dest_qpn,
+            wr_queue,
+            next_seq: 0,
+            srq_tx: None,
+        };
+        let _prev = self.routes.lock().insert(qpn, route);
+        let conn = Arc::clone(self.peers.lock().entry(dest_addr).or_default());
+
+        Ok(MuxChannelTx {
+            conn,
+            peer_addr: dest_addr,
+            peer_port: self.peer_port,
+            qpn,
+            dest_qpn,
+            next_seq: 0,
+        })
+    }
+
+    fn close(&self, qpn: u32) {
+        let _route = self.routes.lock().remove(&qpn);
+    }
+}
+
+/// Sending side of a QP on a `MuxChannel`
+///
+/// WR frames are kept until the peer acknowledges them. A frame written to a connection
+/// that broke may never have reached the peer, so a new connection first carries the
+/// unacknowledged frames again, the peer drops those it already has.
+pub(crate) struct MuxChannelTx {
+    conn: SharedPeerConn,
+    peer_addr: Ipv4Addr,
+    peer_port: u16,
+    qpn: u32,
+    dest_qpn: u32,
+    /// Sequence number of the next WR, lets the peer drop WRs delivered twice
+    next_seq: u64,
+}
+
+impl MuxChannelTx {
+    /// Writes a frame, connecting to the peer if not connected
+    ///
+    /// The peer may have restarted, a failed write is retried once on a new connection.
+    /// A frame kept in `unacked` goes out with the other unacknowledged frames of the new
+    /// connection. The connection is dropped on failure so that the next write reconnects.
+    fn write_frame(&self, conn: &mut PeerConn, frame: &[u8], unacked: bool) -> io::Result<()> {
+        if let Some(stream) = conn.stream.as_mut() {
+            if stream.write_all(frame).is_ok() {
+                return Ok(());
+            }
+            conn.disconnect();
+        }
+        self.connect(conn)?;
+        if unacked {
+            return Ok(());
+        }
+        let stream = conn.stream.as_mut().unwrap_or_else(|| unreachable!());
+        let result = stream.write_all(frame);
+        if result.is_err() {
+            conn.disconnect();
+        }
+        result
+    }
+
+    /// Connects to the peer and writes the unacknowledged frames
+    fn connect(&self, conn: &mut PeerConn) -> io::Result<()> {
+        let mut stream = TcpStream::connect((self.peer_addr, self.peer_port))?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let ack_tx = conn.ack_tx.clone();
+        let revoke_tx = conn.revoke_tx.clone();
+        let _handle = thread::Builder::new()
+            .name("post-recv-reply".into())
+            .spawn(move || Self::read_replies(reader, &ack_tx, &revoke_tx))?;
+        conn.apply_acks();
+        for frame in conn.unacked.iter().map(|entry| &entry.2) {
+            if let Err(err) = stream.write_all(frame) {
+                let _ignore = stream.shutdown(Shutdown::Both);
+                return Err(err);
+            }
+        }
+        conn.stream = Some(stream);
+        Ok(())
+    }
+
+    /// Passes the answers of the peer on until the connection is closed
+    fn read_replies(
+        mut stream: TcpStream,
+        ack_tx: &flume::Sender<(u32, u64)>,
+        revoke_tx: &flume::Sender<(u32, u64)>,
+    ) {
+        let mut frame = [0u8; MUX_FRAME_SIZE];
+        while stream.read_exact(&mut frame).is_ok() {
+            let (dest_qpn, _src_qpn, seq, wr) = decode_frame(&frame);
+            let tx = match seq {
+                ACK_SEQ => ack_tx,
+                REVOKE_SEQ => revoke_tx,
+                _ => continue,
+            };
+            let _ignore = tx.send((dest_qpn, wr.wr_id));
+        }
+    }
+
+    /// Encodes a frame carrying no WR
+    fn control_frame(&self, seq: u64) -> [u8; MUX_FRAME_SIZE] {
+        encode_frame(self.dest_qpn, self.qpn, seq, RecvWr::control(0))
+    }
+}
+
+impl PostRecvTx for MuxChannelTx {
+    /// Queues the WR for the peer
+    ///
+    /// Succeeds even if the peer can not be reached, the WR is written once the
+    /// connection is back, at the latest when the QP demands a WR of the peer.
+    fn send(&mut self, wr: RecvWr) -> io::Result<()> {
+        let frame = encode_frame(self.dest_qpn, self.qpn, self.next_seq, wr);
+        let mut conn = self.conn.lock();
+        conn.apply_acks();
+        conn.unacked.push_back((self.qpn, self.next_seq, frame));
+        self.next_seq += 1;
+        if let Err(err) = self.write_frame(&mut conn, &frame, true) {
+            warn!("post recv connection to {} is down: {err}", self.peer_addr);
+        }
+        Ok(())
+    }
+
+    fn demand(&mut self) -> io::Result<()> {
+        let frame = self.control_frame(DEMAND_SEQ);
+        let mut conn = self.conn.lock();
+        self.write_frame(&mut conn, &frame, false)
+    }
+
+    /// Asks the peer for the number of WRs it withdrew and waits for the answer
+    fn revoke(&mut self) -> io::Result<usize> {
+        let frame = self.control_frame(REVOKE_SEQ);
+        let revoke_rx = {
+            let mut conn = self.conn.lock();
+            // answers to earlier revokes that timed out
+            conn.revoke_rx.drain().for_each(drop);
+            self.write_frame(&mut conn, &frame, false)?;
+            conn.revoke_rx.clone()
+        };
+        let deadline = Instant::now() + REVOKE_TIMEOUT;
+        loop {
+            let (qpn, revoked) = revoke_rx
+                .recv_deadline(deadline)
+                .map_err(|_err| io::Error::from(io::ErrorKind::TimedOut))?;
+            if qpn == self.qpn {
+                return usize::try_from(revoked)
+                    .map_err(|_err| io::Error::from(io::ErrorKind::InvalidData));
+            }
+        }
+    }
+}
+
+/// Encodes a frame of the multiplexed channel
+fn encode_frame(dest_qpn: u32, src_qpn: u32, seq: u64, wr: RecvWr) -> [u8; MUX_FRAME_SIZE] {
+    let mut frame = [0u8; MUX_FRAME_SIZE];
+    frame[0..4].copy_from_slice(&dest_qpn.to_be_bytes());
+    frame[4..8].copy_from_slice(&src_qpn.to_be_bytes());
+    frame[8..16].copy_from_slice(&seq.to_be_bytes());
+    frame[16..].copy_from_slice(&wr.to_bytes());
+    frame
+}
+
+/// Decodes a frame of the multiplexed channel into the destination QPN, the source QPN,
+/// the sequence number and the WR
+#[allow(clippy::unwrap_used)]
+fn decode_frame(frame: &[u8; MUX_FRAME_SIZE]) -> (u32, u32, u64, RecvWr) {
+    (
+        u32::from_be_bytes(frame[0..4].try_into().unwrap()),
+        u32::from_be_bytes(frame[4..8].try_into().unwrap()),
+        u64::from_be_bytes(frame[8..16].try_into().unwrap()),
+        RecvWr::from_bytes(frame[16..].try_into().unwrap()),
+    )
+}
+
+pub(crate) struct PostRecvTxTable<Tx = MuxChannelTx> {
     inner: QpTable<Option<Tx>>,
 }
 
@@ -169,18 +665,28 @@ impl<Tx> PostRecvTxTable<Tx> {
     pub(crate) fn get_qp_mut(&mut self, qpn: u32) -> Option<&mut Tx> {
         self.inner.get_qp_mut(qpn).and_then(Option::as_mut)
     }
+
+    pub(crate) fn remove(&mut self, qpn: u32) {
+        let _ignore = self.inner.replace(qpn, None);
+    }
 }
 
 pub(crate) type SharedRecvWrQueue = Arc<Mutex<VecDeque<RecvWr>>>;
 
 pub(crate) struct RecvWrQueueTable {
-    inner: QpTable<SharedRecvWrQueue>,
+    inner: Arc<QpTable<SharedRecvWrQueue>>,
 }
 
 impl RecvWrQueueTable {
     pub(crate) fn new() -> Self {
         Self {
-            inner: QpTable::new(),
+            inner: Arc::new(QpTable::new()),
+        }
+    }
+
+    pub(crate) fn clone_arc(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
         }
     }
 
@@ -188,9 +694,17 @@ impl RecvWrQueueTable {
         self.inner.get_qp(qpn).cloned()
     }
 
-    pub(crate) fn pop(&self, qpn: u32) -> Option<RecvWr> {
+    /// Takes the next WR if it holds `length` bytes, a shorter WR stays in the queue and
+    /// is returned as `Err`
+    pub(crate) fn pop_fitting(&self, qpn: u32, length: u32) -> Option<Result<RecvWr, RecvWr>> {
         let queue = self.inner.get_qp(qpn)?;
-        queue.lock().pop_front()
+        let mut queue = queue.lock();
+        let wr = *queue.front()?;
+        if wr.length < length {
+            return Some(Err(wr));
+        }
+        let _wr = queue.pop_front();
+        Some(Ok(wr))
     }
 }
 
@@ -220,3 +734,187 @@ impl<Rx: PostRecvRx + Send + 'static> RecvWorker<Rx> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn recv_wr(wr_id: u64) -> RecvWr {
+        RecvWr {
+            wr_id,
+            addr: 0x1000,
+            length: 64,
+            lkey: 1,
+        }
+    }
+
+    fn wait_pop(queue: &SharedRecvWrQueue) -> Option<RecvWr> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if let Some(wr) = queue.lock().pop_front() {
+                return Some(wr);
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        None
+    }
+
+    #[test]
+    fn short_recv_wr_stays_queued() {
+        let table = RecvWrQueueTable::new();
+        let queue = table.clone_recv_wr_queue(1).unwrap();
+        queue.lock().push_back(recv_wr(1));
+        assert!(matches!(table.pop_fitting(1, 65), Some(Err(wr)) if wr.wr_id == 1));
+        assert!(matches!(table.pop_fitting(1, 64), Some(Ok(wr)) if wr.wr_id == 1));
+        assert!(table.pop_fitting(1, 0).is_none());
+    }
+
+    #[test]
+    fn mux_channel_dispatches_by_qpn() {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let responder = MuxChannel::bind(localhost, PostRecvConfig::new(0, 0)).unwrap();
+        let requester =
+            MuxChannel::bind(localhost, PostRecvConfig::new(0, responder.local_port())).unwrap();
+        let queue_a = SharedRecvWrQueue::default();
+        let queue_b = SharedRecvWrQueue::default();
+        let _tx = responder
+            .open(1, localhost, 11, Arc::clone(&queue_a))
+            .unwrap();
+        let _tx = responder
+            .open(2, localhost, 12, Arc::clone(&queue_b))
+            .unwrap();
+        let unused = SharedRecvWrQueue::default();
+        let mut tx_a = requester
+            .open(11, localhost, 1, Arc::clone(&unused))
+            .unwrap();
+        let mut tx_b = requester
+            .open(12, localhost, 2, Arc::clone(&unused))
+            .unwrap();
+        // a WR claiming to come from another QP is dropped
+        let mut tx_c = requester.open(13, localhost, 1, unused).unwrap();
+
+        tx_c.send(recv_wr(3)).unwrap();
+        tx_b.send(recv_wr(2)).unwrap();
+        tx_a.send(recv_wr(1)).unwrap();
+
+        assert_eq!(wait_pop(&queue_b).map(|wr| wr.wr_id), Some(2));
+        assert_eq!(wait_pop(&queue_a).map(|wr| wr.wr_id), Some(1));
+        assert!(queue_a.lock().is_empty());
+        assert_eq!(requester.peers.lock().len(), 1);
+    }
+
+    #[test]
+    fn mux_channel_drops_resent_wrs() {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let responder = MuxChannel::bind(localhost, PostRecvConfig::new(0, 0)).unwrap();
+        let queue = SharedRecvWrQueue::default();
+        let _tx = responder
+            .open(1, localhost, 11, Arc::clone(&queue))
+            .unwrap();
+        let connect = || TcpStream::connect((localhost, responder.local_port())).unwrap();
+        let mut conn = connect();
+        conn.write_all(&encode_frame(1, 11, 0, recv_wr(1))).unwrap();
+        assert_eq!(wait_pop(&queue).map(|wr| wr.wr_id), Some(1));
+
+        // the WR already delivered on the old connection is resent after a reconnect
+        let mut conn = connect();
+        conn.write_all(&encode_frame(1, 11, 0, recv_wr(1))).unwrap();
+        conn.write_all(&encode_frame(1, 11, 1, recv_wr(2))).unwrap();
+        assert_eq!(wait_pop(&queue).map(|wr| wr.wr_id), Some(2));
+        assert!(queue.lock().is_empty());
+    }
+
+    #[test]
+    fn mux_channel_forwards_demands() {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let responder = MuxChannel::bind(localhost, PostRecvConfig::new(0, 0)).unwrap();
+        let requester =
+            MuxChannel::bind(localhost, PostRecvConfig::new(0, responder.local_port())).unwrap();
+        let queue = SharedRecvWrQueue::default();
+        let _tx = responder
+            .open(1, localhost, 11, Arc::clone(&queue))
+            .unwrap();
+        let (srq_tx, srq_rx) = flume::unbounded();
+        responder.forward_demands(1, srq_tx);
+        let mut tx = requester.open(11, localhost, 1, queue).unwrap();
+        tx.demand().unwrap();
+        tx.send(recv_wr(1)).unwrap();
+
+        let task = srq_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(task, SrqTask::PeerDemand { qpn: 1 }));
+        // the demand does not take a sequence number
+        assert_eq!(tx.next_seq, 1);
+    }
+
+    #[test]
+    fn mux_channel_replays_unacked_wrs() {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let responder = MuxChannel::bind(localhost, PostRecvConfig::new(0, 0)).unwrap();
+        let requester =
+            MuxChannel::bind(localhost, PostRecvConfig::new(0, responder.local_port())).unwrap();
+        let queue = SharedRecvWrQueue::default();
+        let _tx = responder
+            .open(1, localhost, 11, Arc::clone(&queue))
+            .unwrap();
+        let mut tx = requester
+            .open(11, localhost, 1, SharedRecvWrQueue::default())
+            .unwrap();
+        tx.send(recv_wr(0)).unwrap();
+        assert_eq!(wait_pop(&queue).map(|wr| wr.wr_id), Some(0));
+
+        // the next WR is written into a connection that breaks before it is delivered
+        {
+            let mut conn = tx.conn.lock();
+            let frame = encode_frame(1, 11, 1, recv_wr(1));
+            conn.unacked.push_back((11, 1, frame));
+            conn.disconnect();
+        }
+        tx.next_seq = 2;
+        tx.send(recv_wr(2)).unwrap();
+        assert_eq!(wait_pop(&queue).map(|wr| wr.wr_id), Some(1));
+        assert_eq!(wait_pop(&queue).map(|wr| wr.wr_id), Some(2));
+        assert!(queue.lock().is_empty());
+
+        // acknowledged frames are dropped
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            let mut conn = tx.conn.lock();
+            conn.apply_acks();
+            if conn.unacked.is_empty() {
+                return;
+            }
+            drop(conn);
+            thread::sleep(Duration::from_millis(1));
+        }
+        panic!("frames not acknowledged");
+    }
+
+    #[test]
+    fn mux_channel_revokes_unconsumed_wrs() {
+        let localhost = Ipv4Addr::LOCALHOST;
+        let responder = MuxChannel::bind(localhost, PostRecvConfig::new(0, 0)).unwrap();
+        let requester =
+            MuxChannel::bind(localhost, PostRecvConfig::new(0, responder.local_port())).unwrap();
+        let queue = SharedRecvWrQueue::default();
+        let _tx = responder
+            .open(1, localhost, 11, Arc::clone(&queue))
+            .unwrap();
+        let mut tx = requester
+            .open(11, localhost, 1, SharedRecvWrQueue::default())
+            .unwrap();
+        for wr_id in 0..3 {
+            tx.send(recv_wr(wr_id)).unwrap();
+        }
+        // the peer takes the first WR before the revoke
+        assert_eq!(wait_pop(&queue).map(|wr| wr.wr_id), Some(0));
+        assert_eq!(tx.revoke().unwrap(), 2);
+        assert!(queue.lock().is_empty());
+        // a QP unknown to the peer withdraws nothing
+        let mut tx = requester
+            .open(12, localhost, 2, SharedRecvWrQueue::default())
+            .unwrap();
+        assert_eq!(tx.revoke().unwrap(), 0);
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    iter,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use flume::RecvTimeoutError;
+use parking_lot::Mutex;
+use tracing::error;
+
+use crate::{
+    completion::{Completion, CompletionStatus, CompletionTask},
+    constants::MAX_QP_CNT,
+    mr::MrTable,
+    mw::MwTable,
+    qp::{QpState, QueuePairAttr, QueuePairAttrTable},
+    rdma_write_worker::RdmaWriteTask,
+    recv::RecvWrQueueTable,
+    send::{SendWr, SendWrRdma},
+    utils::qpn_index,
+};
+
+/// `rnr_retry` value meaning the WR is retried indefinitely
+const RNR_RETRY_INFINITE: u8 = 7;
+
+/// Converts the encoded `min_rnr_timer` value to the RNR delay
+pub(crate) fn rnr_timer_duration(min_rnr_timer: u8) -> Duration {
+    /// RNR timer values in units of 10 us, indexed by the 5-bit encoding
+    #[allow(clippy::decimal_literal_representation)]
+    const RNR_TIMER_10US: [u64; 32] = [
+        65536, 1, 2, 3, 4, 6, 8, 12, 16, 24, 32, 48, 64, 96, 128, 192, 256, 384, 512, 768, 1024,
+        1536, 2048, 3072, 4096, 6144, 8192, 12288, 16384, 24576, 32768, 49152,
+    ];
+    let units = RNR_TIMER_10US
+        .get(usize::from(min_rnr_timer & 0x1f))
+        .copied()
+        .unwrap_or(0);
+    Duration::from_micros(units * 10)
+}
+
+/// Number of WRs held by the RNR retry worker per QP, including an atomic in flight
+///
+/// While a QP has WRs waiting for the peer to post a receive or for an atomic to
+/// complete, newly posted WRs are queued behind them to preserve the ordering of the
+/// send queue.
+pub(crate) struct RnrPendingTable {
+    inner: Arc<[Mutex<usize>]>,
+}
+
+impl RnrPendingTable {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: iter::repeat_with(Mutex::default).take(MAX_QP_CNT).collect(),
+        }
+    }
+
+    pub(crate) fn clone_arc(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    pub(crate) fn is_pending(&self, qpn: u32) -> bool {
+        self.inner
+            .get(qpn_index(qpn))
+            .is_some_and(|x| *x.lock() != 0)
+    }
+
+    fn add(&self, qpn: u32, num: usize) {
+        if let Some(x) = self.inner.get(qpn_index(qpn)) {
+            let mut pending = x.lock();
+            *pending = pending.saturating_add(num);
+        }
+    }
+
+    fn sub(&self, qpn: u32, num: usize) {
+        self.release(qpn, num, || {});
+    }
+
+    /// Runs `hand_over`, which passes WRs on to the next worker, then removes them from the
+    /// pending count
+    ///
+    /// Both happen under the lock of the QP, a WR posted concurrently is either queued
+    /// behind the released WRs or issued after them.
+    fn release(&self, qpn: u32, num: usize, hand_over: impl FnOnce()) {
+        if let Some(x) = self.inner.get(qpn_index(qpn)) {
+            let mut pending = x.lock();
+            hand_over();
+            *pending = pending.saturating_sub(num);
+        }
+    }
+}
+
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)] // WRs are passed by value like in the other tasks
+pub(crate) enum RnrTask {
+    /// A SEND that found no posted receive WR, an atomic, or a WR queued behind one
+    Enqueue { qpn: u32, wr: SendWr },
+    /// Drops all queued WRs, used on QP reset and teardown
+    Clear { qpn: u32 },
+    /// The atomic in flight completed, the WRs queued behind it can be issued
+    AtomicDone { qpn: u32 },
+}
+
+/// Handle used to hand WRs over to the RNR retry worker
+pub(crate) struct RnrRetryHandle {
+    pending: RnrPendingTable,
+    rnr_tx: flume::Sender<RnrTask>,
+}
+
+impl RnrRetryHandle {
+    /// Returns `true` if the QP has WRs waiting for an RNR retry
+    pub(crate) fn is_pending(&self, qpn: u32) -> bool {
+        self.pending.is_pending(qpn)
+    }
+
+    pub(crate) fn enqueue(&self, qpn: u32, wr: SendWr) {
+        self.pending.add(qpn, 1);
+        let _ignore = self.rnr_tx.send(RnrTask::Enqueue { qpn, wr });
+    }
+
+    pub(crate) fn clear(&self, qpn: u32) {
+        let _ignore = self.rnr_tx.send(RnrTask::Clear { qpn });
+    }
+
+    pub(crate) fn atomic_done(&self, qpn: u32) {
+        let _ignore = self.rnr_tx.send(RnrTask::AtomicDone { qpn });
+    }
+
+    pub(crate) fn clone_arc(&self) -> Self {
+        Self {
+            pending: self.pending.clone_arc(),
+            rnr_tx: self.rnr_tx.clone(),
+        }
+    }
+}
+
+/// WRs of a QP waiting for the peer to post a receive
+#[derive(Default)]
+struct PendingQueue {
+    wrs: VecDeque<SendWr>,
+    /// Remaining retries of the WR at the head of the queue
+    retry_left: u8,
+    /// Time of the next retry, `None` if the head WR has not hit RNR yet
+    deadline: Option<Instant>,
+    /// An atomic was issued and its result is not back yet
+    atomic_in_flight: bool,
+}
+
+/// Retries SENDs that found no receive WR posted by the peer
+///
+/// The delay between retries is given by `min_rnr_timer`. As the sender has no
+/// knowledge of the timer configured on the remote QP, the local value is used.
+/// After `rnr_retry` failed retries the WR completes with `IBV_WC_RNR_RETRY_EXC_ERR`
+/// and the QP enters the error state.
+///
+/// Atomics are issued from here as well, the WRs posted after an atomic are held until
+/// it completes. Memory window WRs queued behind other WRs are executed once they reach
+/// the head of the queue.
+pub(crate) struct RnrRetryWorker {
+    rnr_rx: flume::Receiver<RnrTask>,
+    queues: HashMap<u32, PendingQueue>,
+    pending: RnrPendingTable,
+    qp_attr_table: QueuePairAttrTable,
+    recv_wr_queue_table: RecvWrQueueTable,
+    mw_table: MwTable,
+    mr_table: MrTable,
+    rdma_write_tx: flume::Sender<RdmaWriteTask>,
+    completion_tx: flume::Sender<CompletionTask>,
+}
+
+impl RnrRetryWorker {
+    #[allow(clippy::similar_names)]
+    pub(crate) fn new(
+        qp_attr_table: QueuePairAttrTable,
+        recv_wr_queue_table: RecvWrQueueTable,
+        mw_table: MwTable,
+        mr_table: MrTable,
+        rdma_write_tx: flume::Sender<RdmaWriteTask>,
+        completion_tx: flume::Sender<CompletionTask>,
+    ) -> (Self, RnrRetryHandle) {
+        let (rnr_tx, rnr_rx) = flume::unbounded();
+        let pending = RnrPendingTable::new();
+        let handle = RnrRetryHandle {
+            pending: pending.clone_arc(),
+            rnr_tx,
+        };
+        let worker = Self {
+            rnr_rx,
+            queues: HashMap::new(),
+            pending,
+            qp_attr_table,
+            recv_wr_queue_table,
+            mw_table,
+            mr_table,
+            rdma_write_tx,
+            completion_tx,
+        };
+        (worker, handle)
+    }
+
+    pub(crate) fn spawn(self) {
+        let _handle = thread::Builder::new()
+            .name("rnr-retry-worker".into())
+            .spawn(move || self.run())
+            .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
+    }
+
+    fn run(mut self) {
+        loop {
+            let next_deadline = self.queues.values().filter_map(|q| q.deadline).min();
+            let task = match next_deadline {
+                Some(deadline) => self.rnr_rx.recv_deadline(deadline),
+                None => self
+                    .rnr_rx
+                    .recv()
+                    .map_err(|_err| RecvTimeoutError::Disconnected),
+            };
+            match task {
+                Ok(RnrTask::Enqueue { qpn, wr }) => {
+                    let queue = self.queues.entry(qpn).or_default();
+                    queue.wrs.push_back(wr);
+                    if queue.deadline.is_none() {
+                        self.process(qpn, false);
+                    }
+                }
+                Ok(RnrTask::Clear { qpn }) => {
+                    if let Some(queue) = self.queues.remove(&qpn) {
+                        let in_flight = usize::from(queue.atomic_in_flight);
+                        self.pending.sub(qpn, queue.wrs.len() + in_flight);
+                    }
+                }
+                Ok(RnrTask::AtomicDone { qpn }) => {
+                    if let Some(queue) = self.queues.get_mut(&qpn) {
+                        if queue.atomic_in_flight {
+                            queue.atomic_in_flight = false;
+                            self.pending.sub(qpn, 1);
+                            self.process(qpn, false);
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            let now = Instant::now();
+            let expired: Vec<_> = self
+                .queues
+                .iter()
+                .filter(|&(_, q)| q.deadline.is_some_and(|d| d <= now))
+                .map(|(&qpn, _)| qpn)
+                .collect();
+            for qpn in expired {
+                self.process(qpn, true);
+            }
+            self.queues
+                .retain(|_, q| !q.wrs.is_empty() || q.atomic_in_flight);
+        }
+    }
+
+    /// Issues queued WRs of the QP until one of them hits RNR
+    fn process(&mut self, qpn: u32, is_retry: bool) {
+        let Some(qp) = self.qp_attr_table.get(qpn) else {
+            return;
+        };
+        let Some(queue) = self.queues.get_mut(&qpn) else {
+            return;
+        };
+        let status = if qp.state == QpState::Err {
+            CompletionStatus::WrFlushError
+        } else {
+            if is_retry && queue.retry_left != RNR_RETRY_INFINITE {
+                queue.retry_left = queue.retry_left.saturating_sub(1);
+            }
+            match self.issue(&qp) {
+                Some(status) => status,
+                None => return,
+            }
+        };
+        if let Some(queue) = self.queues.get_mut(&qpn) {
+            let wrs: Vec<_> = queue.wrs.drain(..).collect();
+            self.fail(qpn, wrs, status);
+        }
+    }
+
+    /// Issues WRs from the head of the queue, returns the error status if the queue must be
+    /// failed
+    fn issue(&mut self, qp: &QueuePairAttr) -> Option<CompletionStatus> {
+        let qpn = qp.qpn;
+        let queue = self.queues.get_mut(&qpn)?;
+        while let Some(wr) = queue.wrs.front().copied() {
+            if queue.atomic_in_flight {
+                break;
+            }
+            let wr = match wr {
+                SendWr::Rdma(wr) => wr,
+                SendWr::Atomic(wr) => {
+                    let _wr = queue.wrs.pop_front();
+                    queue.deadline = None;
+                    queue.atomic_in_flight = true;
+                    if self
+                        .rdma_write_tx
+                        .send(RdmaWriteTask::Atomic { qpn, wr })
+                        .is_err()
+                    {
+                        error!("rdma write worker exited");
+                    }
+                    continue;
+                }
+                SendWr::BindMw(_) | SendWr::LocalInv(_) => {
+                    // a failed WR stays at the head and fails with the queue
+                    let completion =
+                        match self
+                            .mw_table
+                            .execute(qpn, qp.pd_handle, &wr, &self.mr_table)
+                        {
+                            Ok(completion) => completion,
+                            Err(status) => return Some(status),
+                        };
+                    let _wr = queue.wrs.pop_front();
+                    queue.deadline = None;
+                    self.pending.release(qpn, 1, || {
+                        if let Some(completion) = completion {
+                            let _ignore = self
+                                .completion_tx
+                                .send(CompletionTask::Complete { qpn, completion });
+                        }
+                    });
+                    continue;
+                }
+                SendWr::Send(wr) => {
+                    let Some(recv_wr) = self.recv_wr_queue_table.pop_fitting(qpn, wr.length) else {
+                        break;
+                    };
+                    // the peer keeps a WR too short for the SEND
+                    let Ok(recv_wr) = recv_wr else {
+                        return Some(CompletionStatus::RemoteInvalidRequest);
+                    };
+                    SendWrRdma::new_from_base(wr, recv_wr.addr, recv_wr.lkey)
+                }
+            };
+            let _wr = queue.wrs.pop_front();
+            queue.deadline = None;
+            let (task, _result_rx) = RdmaWriteTask::new_write(qpn, wr);
+            self.pending.release(qpn, 1, || {
+                if self.rdma_write_tx.send(task).is_err() {
+                    error!("rdma write worker exited");
+                }
+            });
+        }
+        if queue.wrs.is_empty() || queue.atomic_in_flight {
+            return None;
+        }
+        if queue.deadline.is_none() {
+            // the head WR just hit RNR
+            queue.retry_left = qp.rnr_retry;
+        }
+        if queue.retry_left == 0 {
+            return Some(CompletionStatus::RnrRetryExceeded);
+        }
+        queue.deadline = Some(Instant::now() + rnr_timer_duration(qp.min_rnr_timer));
+        None
+    }
+
+    /// Completes the first WR with `status` and flushes the rest
+    ///
+    /// The QP enters the error state before the WRs leave the queue, so WRs posted
+    /// afterwards are flushed instead of issued.
+    fn fail(&self, qpn: u32, wrs: Vec<SendWr>, status: CompletionStatus) {
+        self.qp_attr_table.set_state(qpn, QpState::Err);
+        let mut status = status;
+        let num = wrs.len();
+        self.pending.release(qpn, num, || {
+            for wr in wrs {
+                if let Some(completion) = Completion::send_error(&wr, status) {
+                    let _ignore = self
+                        .completion_tx
+                        .send(CompletionTask::Complete { qpn, completion });
+                }
+                status = CompletionStatus::WrFlushError;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rnr_timer_encoding() {
+        assert_eq!(rnr_timer_duration(0), Duration::from_micros(655_360));
+        assert_eq!(rnr_timer_duration(1), Duration::from_micros(10));
+        assert_eq!(rnr_timer_duration(14), Duration::from_micros(1280));
+        assert_eq!(rnr_timer_duration(31), Duration::from_micros(491_520));
+    }
+}
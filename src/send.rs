@@ -1,49 +1,64 @@
-use crate::device_protocol::WorkReqOpCode;
+use crate::{
+    atomic::AtomicOp,
+    constants::MAX_SGE,
+    device_protocol::WorkReqOpCode,
+    mtt::inc_key,
+    mw::{MwBindInfo, MwType},
+};
 
 use ibverbs_sys::{
-    ibv_send_wr,
+    ibv_access_flags, ibv_send_wr, ibv_sge,
     ibv_wr_opcode::{
+        IBV_WR_ATOMIC_CMP_AND_SWP, IBV_WR_ATOMIC_FETCH_AND_ADD, IBV_WR_BIND_MW, IBV_WR_LOCAL_INV,
         IBV_WR_RDMA_READ, IBV_WR_RDMA_WRITE, IBV_WR_RDMA_WRITE_WITH_IMM, IBV_WR_SEND,
-        IBV_WR_SEND_WITH_IMM,
+        IBV_WR_SEND_WITH_IMM, IBV_WR_SEND_WITH_INV,
     },
 };
+use std::ops::Range;
 use thiserror::Error;
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) enum SendWr {
     Rdma(SendWrRdma),
     Send(SendWrBase),
+    Atomic(SendWrAtomic),
+    BindMw(SendWrBindMw),
+    /// Invalidates the memory window whose rkey is stored in `imm_data`
+    LocalInv(SendWrBase),
 }
 
 impl SendWr {
     #[allow(unsafe_code)]
     /// Creates a new `SendWr`
     pub(crate) fn new(wr: ibv_send_wr) -> Result<Self, ValidationError> {
-        let num_sge = usize::try_from(wr.num_sge).map_err(ValidationError::invalid_input)?;
-        if num_sge != 1 {
-            return Err(ValidationError::unimplemented("only support single sge"));
-        }
-        // SAFETY: sg_list is valid when num_sge > 0, which we've verified above
-        let sge = unsafe { *wr.sg_list };
+        // memory window operations carry no data
+        let sg_list = match wr.opcode {
+            IBV_WR_BIND_MW | IBV_WR_LOCAL_INV => SgeList::default(),
+            _ => SgeList::from_ibv_sge(wr.sg_list, wr.num_sge)?,
+        };
         let opcode = match wr.opcode {
             IBV_WR_RDMA_WRITE => WorkReqOpCode::RdmaWrite,
             IBV_WR_RDMA_WRITE_WITH_IMM => WorkReqOpCode::RdmaWriteWithImm,
             IBV_WR_RDMA_READ => WorkReqOpCode::RdmaRead,
             IBV_WR_SEND => WorkReqOpCode::Send,
             IBV_WR_SEND_WITH_IMM => WorkReqOpCode::SendWithImm,
+            IBV_WR_ATOMIC_CMP_AND_SWP => WorkReqOpCode::AtomicCmpAndSwp,
+            IBV_WR_ATOMIC_FETCH_AND_ADD => WorkReqOpCode::AtomicFetchAndAdd,
+            IBV_WR_LOCAL_INV => WorkReqOpCode::LocalInv,
+            IBV_WR_BIND_MW => WorkReqOpCode::BindMw,
+            IBV_WR_SEND_WITH_INV => WorkReqOpCode::SendWithInv,
             _ => return Err(ValidationError::unimplemented("opcode not supported")),
         };
 
-        let base = SendWrBase {
-            wr_id: wr.wr_id,
-            send_flags: wr.send_flags,
-            laddr: sge.addr,
-            length: sge.length,
-            lkey: sge.lkey,
-            // SAFETY: imm_data is valid for operations with immediate data
-            imm_data: unsafe { wr.__bindgen_anon_1.imm_data },
+        let base = SendWrBase::new_with_sg_list(
+            wr.wr_id,
+            wr.send_flags,
+            sg_list,
+            // SAFETY: imm_data is valid for operations with immediate data, and shares
+            // its storage with the rkey of operations with invalidate
+            unsafe { wr.__bindgen_anon_1.imm_data },
             opcode,
-        };
+        );
 
         match wr.opcode {
             IBV_WR_RDMA_WRITE | IBV_WR_RDMA_WRITE_WITH_IMM | IBV_WR_RDMA_READ => {
@@ -55,7 +70,28 @@ impl SendWr {
                 };
                 Ok(Self::Rdma(wr))
             }
-            IBV_WR_SEND | IBV_WR_SEND_WITH_IMM => Ok(Self::Send(base)),
+            IBV_WR_SEND | IBV_WR_SEND_WITH_IMM | IBV_WR_SEND_WITH_INV => Ok(Self::Send(base)),
+            IBV_WR_LOCAL_INV => Ok(Self::LocalInv(base)),
+            IBV_WR_BIND_MW => {
+                // SAFETY: bind_mw field is valid for bind operations
+                let bind_mw = unsafe { wr.__bindgen_anon_2.bind_mw };
+                SendWrBindMw::new(base, bind_mw).map(Self::BindMw)
+            }
+            IBV_WR_ATOMIC_CMP_AND_SWP | IBV_WR_ATOMIC_FETCH_AND_ADD => {
+                // SAFETY: atomic field is valid for atomic operations
+                let atomic = unsafe { wr.wr.atomic };
+                let op = if wr.opcode == IBV_WR_ATOMIC_CMP_AND_SWP {
+                    AtomicOp::CompareSwap {
+                        compare: atomic.compare_add,
+                        swap: atomic.swap,
+                    }
+                } else {
+                    AtomicOp::FetchAdd {
+                        add: atomic.compare_add,
+                    }
+                };
+                SendWrAtomic::new(base, atomic.remote_addr, atomic.rkey, op).map(Self::Atomic)
+            }
             _ => Err(ValidationError::unimplemented("opcode not supported")),
         }
     }
@@ -63,41 +99,67 @@ impl SendWr {
     pub(crate) fn wr_id(&self) -> u64 {
         match *self {
             SendWr::Rdma(wr) => wr.base.wr_id,
-            SendWr::Send(wr) => wr.wr_id,
+            SendWr::Send(wr) | SendWr::LocalInv(wr) => wr.wr_id,
+            SendWr::Atomic(wr) => wr.base.wr_id,
+            SendWr::BindMw(wr) => wr.base.wr_id,
         }
     }
     pub(crate) fn send_flags(&self) -> u32 {
         match *self {
             SendWr::Rdma(wr) => wr.base.send_flags,
-            SendWr::Send(wr) => wr.send_flags,
+            SendWr::Send(wr) | SendWr::LocalInv(wr) => wr.send_flags,
+            SendWr::Atomic(wr) => wr.base.send_flags,
+            SendWr::BindMw(wr) => wr.base.send_flags,
         }
     }
 
-    pub(crate) fn laddr(&self) -> u64 {
+    pub(crate) fn sg_list(&self) -> SgeList {
         match *self {
-            SendWr::Rdma(wr) => wr.base.laddr,
-            SendWr::Send(wr) => wr.laddr,
+            SendWr::Rdma(wr) => wr.base.sg_list,
+            SendWr::Send(wr) | SendWr::LocalInv(wr) => wr.sg_list,
+            SendWr::Atomic(wr) => wr.base.sg_list,
+            SendWr::BindMw(wr) => wr.base.sg_list,
         }
     }
 
     pub(crate) fn length(&self) -> u32 {
         match *self {
             SendWr::Rdma(wr) => wr.base.length,
-            SendWr::Send(wr) => wr.length,
+            SendWr::Send(wr) | SendWr::LocalInv(wr) => wr.length,
+            SendWr::Atomic(wr) => wr.base.length,
+            SendWr::BindMw(wr) => wr.base.length,
         }
     }
 
-    pub(crate) fn lkey(&self) -> u32 {
+    pub(crate) fn imm_data(&self) -> u32 {
         match *self {
-            SendWr::Rdma(wr) => wr.base.lkey,
-            SendWr::Send(wr) => wr.lkey,
+            SendWr::Rdma(wr) => wr.base.imm_data,
+            SendWr::Send(wr) | SendWr::LocalInv(wr) => wr.imm_data,
+            SendWr::Atomic(wr) => wr.base.imm_data,
+            SendWr::BindMw(wr) => wr.base.imm_data,
         }
     }
 
-    pub(crate) fn imm_data(&self) -> u32 {
+    pub(crate) fn opcode(&self) -> WorkReqOpCode {
         match *self {
-            SendWr::Rdma(wr) => wr.base.imm_data,
-            SendWr::Send(wr) => wr.imm_data,
+            SendWr::Rdma(wr) => wr.base.opcode,
+            SendWr::Send(wr) | SendWr::LocalInv(wr) => wr.opcode,
+            SendWr::Atomic(wr) => wr.base.opcode,
+            SendWr::BindMw(wr) => wr.base.opcode,
+        }
+    }
+
+    /// Returns the access the local buffers must be registered with
+    pub(crate) fn local_access(&self) -> ibv_access_flags {
+        match *self {
+            // the responses are written into the local buffers
+            SendWr::Atomic(_) => ibv_access_flags::IBV_ACCESS_LOCAL_WRITE,
+            SendWr::Rdma(wr) if wr.opcode() == WorkReqOpCode::RdmaRead => {
+                ibv_access_flags::IBV_ACCESS_LOCAL_WRITE
+            }
+            SendWr::Rdma(_) | SendWr::Send(_) | SendWr::LocalInv(_) | SendWr::BindMw(_) => {
+                ibv_access_flags(0)
+            }
         }
     }
 }
@@ -120,14 +182,7 @@ impl SendWrRdma {
             _ => return Err(ValidationError::unimplemented("opcode not supported")),
         }
 
-        let num_sge = usize::try_from(wr.num_sge).map_err(ValidationError::invalid_input)?;
-
-        if num_sge != 1 {
-            return Err(ValidationError::unimplemented("only support single sge"));
-        }
-
-        // SAFETY: sg_list is valid when num_sge > 0, which we've verified above
-        let sge = unsafe { *wr.sg_list };
+        let sg_list = SgeList::from_ibv_sge(wr.sg_list, wr.num_sge)?;
 
         let opcode = match wr.opcode {
             IBV_WR_RDMA_WRITE => WorkReqOpCode::RdmaWrite,
@@ -139,16 +194,14 @@ impl SendWrRdma {
         };
 
         Ok(Self {
-            base: SendWrBase {
-                wr_id: wr.wr_id,
-                send_flags: wr.send_flags,
-                laddr: sge.addr,
-                length: sge.length,
-                lkey: sge.lkey,
+            base: SendWrBase::new_with_sg_list(
+                wr.wr_id,
+                wr.send_flags,
+                sg_list,
                 // SAFETY: imm_data is valid for operations with immediate data
-                imm_data: unsafe { wr.__bindgen_anon_1.imm_data },
+                unsafe { wr.__bindgen_anon_1.imm_data },
                 opcode,
-            },
+            ),
             // SAFETY: rdma field is valid for RDMA operations
             raddr: unsafe { wr.wr.rdma.remote_addr },
             rkey: unsafe { wr.wr.rdma.rkey },
@@ -159,22 +212,27 @@ impl SendWrRdma {
         Self { base, raddr, rkey }
     }
 
-    /// Returns the local address of the SGE buffer
-    #[inline]
-    pub(crate) fn laddr(&self) -> u64 {
-        self.base.laddr
+    /// Replaces the gather list with one describing the same message, e.g. a list
+    /// redirected to staging buffers
+    pub(crate) fn set_sg_list(&mut self, sg_list: SgeList) {
+        debug_assert_eq!(
+            sg_list.total_len(),
+            self.base.length,
+            "message length changed"
+        );
+        self.base.sg_list = sg_list;
     }
 
-    /// Returns the length of the SGE buffer in bytes
+    /// Returns the gather list of local buffers
     #[inline]
-    pub(crate) fn length(&self) -> u32 {
-        self.base.length
+    pub(crate) fn sg_list(&self) -> SgeList {
+        self.base.sg_list
     }
 
-    /// Returns the local key associated with the SGE buffer
+    /// Returns the total length of all SGE buffers in bytes
     #[inline]
-    pub(crate) fn lkey(&self) -> u32 {
-        self.base.lkey
+    pub(crate) fn length(&self) -> u32 {
+        self.base.length
     }
 
     /// Returns the remote memory address for RDMA operations
@@ -212,18 +270,158 @@ impl SendWrRdma {
     }
 }
 
+/// An 8-byte atomic operation on remote memory
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SendWrAtomic {
+    base: SendWrBase,
+    pub(crate) raddr: u64,
+    pub(crate) rkey: u32,
+    pub(crate) op: AtomicOp,
+}
+
+impl SendWrAtomic {
+    /// Size of the operand and of the returned original value in bytes
+    pub(crate) const SIZE: u32 = 8;
+
+    /// Creates a new atomic WR.
+    ///
+    /// The local buffer must be a single 8-byte SGE and the remote address must be
+    /// naturally aligned.
+    pub(crate) fn new(
+        base: SendWrBase,
+        raddr: u64,
+        rkey: u32,
+        op: AtomicOp,
+    ) -> Result<Self, ValidationError> {
+        if base.sg_list.len() != 1 || base.length != Self::SIZE {
+            return Err(ValidationError::invalid_input(
+                "atomic operations require a single 8-byte SGE",
+            ));
+        }
+        if !raddr.is_multiple_of(u64::from(Self::SIZE)) {
+            return Err(ValidationError::invalid_input(
+                "atomic remote address must be 8-byte aligned",
+            ));
+        }
+        Ok(Self {
+            base,
+            raddr,
+            rkey,
+            op,
+        })
+    }
+
+    /// Returns the local buffer receiving the original value
+    pub(crate) fn sge(&self) -> Sge {
+        self.base
+            .sg_list
+            .as_slice()
+            .first()
+            .copied()
+            .unwrap_or_else(|| unreachable!("validated on creation"))
+    }
+
+    /// Returns the send flags
+    #[inline]
+    pub(crate) fn send_flags(&self) -> u32 {
+        self.base.send_flags
+    }
+
+    /// Returns the ID associated with this WR
+    #[inline]
+    pub(crate) fn wr_id(&self) -> u64 {
+        self.base.wr_id
+    }
+
+    pub(crate) fn opcode(&self) -> WorkReqOpCode {
+        self.base.opcode
+    }
+}
+
+/// Binds a memory window
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SendWrBindMw {
+    base: SendWrBase,
+    pub(crate) mw_type: MwType,
+    /// The rkey of the window before the bind
+    pub(crate) mw_rkey: u32,
+    /// The rkey of the window after the bind
+    pub(crate) rkey: u32,
+    pub(crate) info: MwBindInfo,
+}
+
+impl SendWrBindMw {
+    /// Creates a bind WR for a type 1 window, as posted by `ibv_bind_mw`
+    pub(crate) fn new_type1(wr_id: u64, send_flags: u32, mw_rkey: u32, info: MwBindInfo) -> Self {
+        Self {
+            base: SendWrBase::new_with_sg_list(
+                wr_id,
+                send_flags,
+                SgeList::default(),
+                0,
+                WorkReqOpCode::BindMw,
+            ),
+            mw_type: MwType::Type1,
+            mw_rkey,
+            rkey: inc_key(mw_rkey),
+            info,
+        }
+    }
+
+    #[allow(unsafe_code)]
+    /// Creates a new bind WR.
+    ///
+    /// Only type 2 windows can be bound through the send queue.
+    fn new(
+        base: SendWrBase,
+        bind_mw: ibverbs_sys::ibv_send_wr__bindgen_ty_4__bindgen_ty_1,
+    ) -> Result<Self, ValidationError> {
+        // SAFETY: the window is checked to be non-null
+        let Some(mw) = (unsafe { bind_mw.mw.as_ref() }) else {
+            return Err(ValidationError::invalid_input("memory window is null"));
+        };
+        if MwType::from_ibv(mw.type_) != Some(MwType::Type2) {
+            return Err(ValidationError::invalid_input(
+                "only type 2 memory windows can be bound by a WR",
+            ));
+        }
+        let info = MwBindInfo::from_ibv(bind_mw.bind_info)
+            .ok_or(ValidationError::invalid_input("memory region is null"))?;
+        Ok(Self {
+            base,
+            mw_type: MwType::Type2,
+            mw_rkey: mw.rkey,
+            rkey: bind_mw.rkey,
+            info,
+        })
+    }
+
+    /// Returns the send flags
+    #[inline]
+    pub(crate) fn send_flags(&self) -> u32 {
+        self.base.send_flags
+    }
+
+    /// Returns the ID associated with this WR
+    #[inline]
+    pub(crate) fn wr_id(&self) -> u64 {
+        self.base.wr_id
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct SendWrBase {
     pub(crate) wr_id: u64,
     pub(crate) send_flags: u32,
-    pub(crate) laddr: u64,
+    pub(crate) sg_list: SgeList,
+    /// Total length of the gather list in bytes
     pub(crate) length: u32,
-    pub(crate) lkey: u32,
     pub(crate) imm_data: u32,
     pub(crate) opcode: WorkReqOpCode,
 }
 
 impl SendWrBase {
+    /// Creates a new `SendWrBase` with a single local buffer
     pub(crate) fn new(
         wr_id: u64,
         send_flags: u32,
@@ -232,19 +430,142 @@ impl SendWrBase {
         lkey: u32,
         imm_data: u32,
         opcode: WorkReqOpCode,
+    ) -> Self {
+        Self::new_with_sg_list(
+            wr_id,
+            send_flags,
+            SgeList::new_single(Sge::new(laddr, length, lkey)),
+            imm_data,
+            opcode,
+        )
+    }
+
+    /// Creates a new `SendWrBase` with a gather list
+    pub(crate) fn new_with_sg_list(
+        wr_id: u64,
+        send_flags: u32,
+        sg_list: SgeList,
+        imm_data: u32,
+        opcode: WorkReqOpCode,
     ) -> Self {
         Self {
             wr_id,
             send_flags,
-            laddr,
-            length,
-            lkey,
+            sg_list,
+            length: sg_list.total_len(),
             imm_data,
             opcode,
         }
     }
 }
 
+/// A scatter/gather element describing a local buffer
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Sge {
+    pub(crate) addr: u64,
+    pub(crate) length: u32,
+    pub(crate) lkey: u32,
+}
+
+impl Sge {
+    pub(crate) fn new(addr: u64, length: u32, lkey: u32) -> Self {
+        Self { addr, length, lkey }
+    }
+}
+
+/// A fixed capacity gather list, holds at most `MAX_SGE` elements
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SgeList {
+    sges: [Sge; MAX_SGE],
+    num_sge: usize,
+    total_len: u32,
+}
+
+impl SgeList {
+    /// Creates a gather list contains a single element
+    pub(crate) fn new_single(sge: Sge) -> Self {
+        Self::new(&[sge]).unwrap_or_else(|_| unreachable!("single element is always valid"))
+    }
+
+    /// Creates a gather list from the given elements.
+    ///
+    /// Returns an error if there are more than `MAX_SGE` elements or the total length overflows.
+    pub(crate) fn new(sges: &[Sge]) -> Result<Self, ValidationError> {
+        if sges.len() > MAX_SGE {
+            return Err(ValidationError::invalid_input(format!(
+                "num_sge should be in range 0..={MAX_SGE}"
+            )));
+        }
+        let total_len = sges
+            .iter()
+            .try_fold(0u32, |acc, sge| acc.checked_add(sge.length))
+            .ok_or(ValidationError::invalid_input("total length overflow"))?;
+        let mut this = Self {
+            num_sge: sges.len(),
+            total_len,
+            ..Default::default()
+        };
+        this.sges
+            .get_mut(..sges.len())
+            .unwrap_or_else(|| unreachable!("length checked"))
+            .copy_from_slice(sges);
+        Ok(this)
+    }
+
+    #[allow(unsafe_code)]
+    /// Creates a gather list from the `sg_list` of an `ibv_send_wr`
+    ///
+    /// A zero-length message has no elements, `sg_list` is not read then.
+    fn from_ibv_sge(sg_list: *const ibv_sge, num_sge: i32) -> Result<Self, ValidationError> {
+        let num_sge = usize::try_from(num_sge).map_err(ValidationError::invalid_input)?;
+        if num_sge == 0 {
+            return Ok(Self::default());
+        }
+        if num_sge > MAX_SGE || sg_list.is_null() {
+            return Err(ValidationError::invalid_input(format!(
+                "num_sge should be in range 0..={MAX_SGE}"
+            )));
+        }
+        // SAFETY: sg_list points to `num_sge` elements, which we've verified above
+        let ibv_sges = unsafe { std::slice::from_raw_parts(sg_list, num_sge) };
+        let mut sges = [Sge::default(); MAX_SGE];
+        for (sge, ibv_sge) in sges.iter_mut().zip(ibv_sges) {
+            *sge = Sge::new(ibv_sge.addr, ibv_sge.length, ibv_sge.lkey);
+        }
+        Self::new(sges.get(..num_sge).unwrap_or_else(|| unreachable!()))
+    }
+
+    /// Returns the elements of the list
+    pub(crate) fn as_slice(&self) -> &[Sge] {
+        self.sges
+            .get(..self.num_sge)
+            .unwrap_or_else(|| unreachable!("num_sge should not exceed MAX_SGE"))
+    }
+
+    /// Returns the number of elements
+    pub(crate) fn len(&self) -> usize {
+        self.num_sge
+    }
+
+    /// Returns the total length of all elements in bytes
+    pub(crate) fn total_len(&self) -> u32 {
+        self.total_len
+    }
+
+    /// Returns the parts of the elements holding the bytes `range` of the message
+    pub(crate) fn slice(&self, range: Range<u64>) -> impl Iterator<Item = Sge> + '_ {
+        let mut offset = 0u64;
+        self.as_slice().iter().filter_map(move |sge| {
+            let start = offset;
+            offset += u64::from(sge.length);
+            let lo = start.max(range.start);
+            let hi = offset.min(range.end);
+            let length = u32::try_from(hi.checked_sub(lo)?).ok()?;
+            (length != 0).then(|| Sge::new(sge.addr + (lo - start), length, sge.lkey))
+        })
+    }
+}
+
 /// Error type for invalid input validation
 #[derive(Error, Debug)]
 pub(crate) enum ValidationError {
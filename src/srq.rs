@@ -0,0 +1,526 @@
+use std::{collections::HashMap, collections::VecDeque, io, iter, mem, sync::Arc, thread};
+
+use bitvec::vec::BitVec;
+use ibverbs_sys::ibv_srq_attr;
+use parking_lot::Mutex;
+use tracing::error;
+
+use crate::{
+    async_event::AsyncEvent,
+    completion::{CompletionTask, Event, PostRecvEvent},
+    constants::{MAX_SGE, MAX_SRQ_CNT, MAX_SRQ_WR},
+    qp::{QpState, QueuePairAttrTable},
+    recv::{MuxChannelTx, PostRecvTx, RecvWr},
+};
+
+/// Attributes of a shared receive queue, mirrors `ibv_srq_attr`
+#[derive(Default, Debug, Clone, Copy)]
+pub(crate) struct SrqAttr {
+    pub(crate) max_wr: u32,
+    pub(crate) max_sge: u32,
+    pub(crate) srq_limit: u32,
+}
+
+impl SrqAttr {
+    pub(crate) fn from_ibv(attr: ibv_srq_attr) -> Self {
+        Self {
+            max_wr: attr.max_wr,
+            max_sge: attr.max_sge,
+            srq_limit: attr.srq_limit,
+        }
+    }
+
+    pub(crate) fn to_ibv(self) -> ibv_srq_attr {
+        ibv_srq_attr {
+            max_wr: self.max_wr,
+            max_sge: self.max_sge,
+            srq_limit: self.srq_limit,
+        }
+    }
+}
+
+#[derive(Default)]
+struct SharedRecvQueue {
+    attr: SrqAttr,
+    /// Address of the `ibv_srq`, reported in async events
+    srq_context: u64,
+    /// WRs not yet handed out to any QP
+    wrs: VecDeque<RecvWr>,
+    /// Number of WRs posted and not yet consumed, including the handed out ones
+    outstanding: usize,
+    /// Number of QPs attached to the SRQ
+    num_qps: usize,
+}
+
+pub(crate) struct SrqTable {
+    inner: Arc<[Mutex<SharedRecvQueue>]>,
+}
+
+impl SrqTable {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: iter::repeat_with(Mutex::default)
+                .take(MAX_SRQ_CNT)
+                .collect(),
+        }
+    }
+
+    pub(crate) fn clone_arc(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    fn get(&self, handle: u32) -> io::Result<&Mutex<SharedRecvQueue>> {
+        usize::try_from(handle)
+            .ok()
+            .and_then(|index| self.inner.get(index))
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    /// Prepares the SRQ for a new owner
+    pub(crate) fn init(&self, handle: u32, srq_context: u64, attr: SrqAttr) -> io::Result<()> {
+        if attr.max_wr == 0 || attr.max_wr > MAX_SRQ_WR || attr.srq_limit > attr.max_wr {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        if usize::try_from(attr.max_sge).map_or(true, |x| x > MAX_SGE) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        *self.get(handle)?.lock() = SharedRecvQueue {
+            attr,
+            srq_context,
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    /// Releases the SRQ, fails if QPs are still attached to it
+    pub(crate) fn release(&self, handle: u32) -> io::Result<()> {
+        let mut srq = self.get(handle)?.lock();
+        if srq.num_qps != 0 {
+            return Err(io::Error::from(io::ErrorKind::ResourceBusy));
+        }
+        *srq = SharedRecvQueue::default();
+        Ok(())
+    }
+
+    /// Resizes the SRQ and/or arms the limit event
+    pub(crate) fn modify(
+        &self,
+        handle: u32,
+        max_wr: Option<u32>,
+        srq_limit: Option<u32>,
+    ) -> io::Result<()> {
+        let mut srq = self.get(handle)?.lock();
+        let max_wr = max_wr.unwrap_or(srq.attr.max_wr);
+        let srq_limit = srq_limit.unwrap_or(srq.attr.srq_limit);
+        let fits_outstanding = usize::try_from(max_wr).is_ok_and(|x| x >= srq.outstanding);
+        if max_wr > MAX_SRQ_WR || !fits_outstanding || srq_limit > max_wr {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        srq.attr.max_wr = max_wr;
+        srq.attr.srq_limit = srq_limit;
+        Ok(())
+    }
+
+    pub(crate) fn query(&self, handle: u32) -> io::Result<SrqAttr> {
+        Ok(self.get(handle)?.lock().attr)
+    }
+
+    pub(crate) fn post(&self, handle: u32, wr: RecvWr) -> io::Result<()> {
+        let mut srq = self.get(handle)?.lock();
+        if usize::try_from(srq.attr.max_wr).map_or(true, |x| srq.outstanding >= x) {
+            return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+        }
+        srq.wrs.push_back(wr);
+        srq.outstanding += 1;
+        Ok(())
+    }
+
+    pub(crate) fn attach_qp(&self, handle: u32) -> io::Result<()> {
+        self.get(handle)?.lock().num_qps += 1;
+        Ok(())
+    }
+
+    pub(crate) fn detach_qp(&self, handle: u32) {
+        if let Ok(srq) = self.get(handle) {
+            let mut srq = srq.lock();
+            srq.num_qps = srq.num_qps.saturating_sub(1);
+        }
+    }
+
+    /// Takes the next WR that is not handed out yet
+    fn take(&self, handle: u32) -> Option<RecvWr> {
+        self.get(handle).ok()?.lock().wrs.pop_front()
+    }
+
+    /// Returns WRs handed out to a QP that will never consume them, ahead of the WRs
+    /// not handed out yet
+    fn give_back(&self, handle: u32, wrs: VecDeque<RecvWr>) {
+        if let Ok(srq) = self.get(handle) {
+            let mut srq = srq.lock();
+            for wr in wrs.into_iter().rev() {
+                srq.wrs.push_front(wr);
+            }
+        }
+    }
+
+    /// Records a consumed WR, returns the limit event if the number of outstanding WRs
+    /// dropped below the armed limit
+    fn consume(&self, handle: u32) -> Option<AsyncEvent> {
+        let mut srq = self.get(handle).ok()?.lock();
+        srq.outstanding = srq.outstanding.saturating_sub(1);
+        let limit = usize::try_from(srq.attr.srq_limit).ok()?;
+        if limit == 0 || srq.outstanding >= limit {
+            return None;
+        }
+        // the limit event is one-shot, it must be re-armed by `ibv_modify_srq`
+        srq.attr.srq_limit = 0;
+        Some(AsyncEvent::SrqLimitReached {
+            srq_context: srq.srq_context,
+        })
+    }
+}
+
+/// Manages SRQ handle allocation
+pub(crate) struct SrqManager {
+    /// Bitmap tracking allocated SRQ handles
+    bitmap: BitVec,
+}
+
+#[allow(clippy::as_conversions, clippy::indexing_slicing)]
+impl SrqManager {
+    pub(crate) fn new() -> Self {
+        let mut bitmap = BitVec::with_capacity(MAX_SRQ_CNT);
+        bitmap.resize(MAX_SRQ_CNT, false);
+        Self { bitmap }
+    }
+
+    /// Allocates a new SRQ handle
+    #[allow(clippy::cast_possible_truncation)] // no larger than u32
+    pub(crate) fn create_srq(&mut self) -> Option<u32> {
+        let handle = self.bitmap.first_zero()? as u32;
+        self.bitmap.set(handle as usize, true);
+        Some(handle)
+    }
+
+    pub(crate) fn destroy_srq(&mut self, handle: u32) {
+        if handle as usize >= MAX_SRQ_CNT {
+            return;
+        }
+        self.bitmap.set(handle as usize, false);
+    }
+}
+
+pub(crate) enum SrqTask<Tx = MuxChannelTx> {
+    /// WRs were posted to the SRQ or an attached QP became ready to receive
+    Refill { srq: u32 },
+    /// A QP attached to the SRQ got connected to its peer
+    Attach { srq: u32, qpn: u32, tx: Tx },
+    /// Stops handing out WRs to the QP, used on QP teardown
+    Detach { qpn: u32 },
+    /// A WR handed out to the QP was consumed by an incoming SEND
+    Consumed { srq: u32, qpn: u32 },
+    /// The QP entered the error state, the WRs handed out to it that its peer never
+    /// consumed return to the SRQ
+    Reclaim { qpn: u32 },
+    /// The peer of the QP has a SEND and no WR to put it in
+    PeerDemand { qpn: u32 },
+    /// The QP has a SEND and no WR of its peer, the demand is passed on to the peer
+    Demand { qpn: u32 },
+}
+
+/// A QP attached to an SRQ
+struct AttachedQp<Tx> {
+    srq: u32,
+    tx: Tx,
+    /// WRs handed out to the peer and not yet consumed, in the order they are consumed
+    handed_out: VecDeque<RecvWr>,
+    /// Number of WRs demanded by the peer and not yet handed out
+    demanded: usize,
+}
+
+/// Hands out WRs posted to SRQs to the peers of the attached QPs
+///
+/// WRs stay in the SRQ until a peer needs one. The initiator needs the receive buffer
+/// before it issues a SEND, so each ready QP without WRs gets one ahead of time, and
+/// a SEND finding none makes the peer demand another. Demands are served first, in
+/// the order of the QPs with the most unserved demands.
+///
+/// Each handed out WR is registered to the QP it was given to, so the receive completion
+/// is reported on that QP like a WR posted by `ibv_post_recv`. When a QP enters the error
+/// state, the WRs its peer withdraws go back to the SRQ. The peer may already have taken
+/// the others as the target of a SEND, they complete with a flush error on the QP.
+/// On teardown they are dropped without a completion.
+pub(crate) struct SrqWorker<Tx = MuxChannelTx> {
+    srq_rx: flume::Receiver<SrqTask<Tx>>,
+    srq_table: SrqTable,
+    qp_table: QueuePairAttrTable,
+    attached: HashMap<u32, AttachedQp<Tx>>,
+    completion_tx: flume::Sender<CompletionTask>,
+    async_event_tx: flume::Sender<AsyncEvent>,
+}
+
+impl<Tx: PostRecvTx + Send + 'static> SrqWorker<Tx> {
+    pub(crate) fn new(
+        srq_table: SrqTable,
+        qp_table: QueuePairAttrTable,
+        completion_tx: flume::Sender<CompletionTask>,
+        async_event_tx: flume::Sender<AsyncEvent>,
+    ) -> (Self, flume::Sender<SrqTask<Tx>>) {
+        let (srq_tx, srq_rx) = flume::unbounded();
+        let worker = Self {
+            srq_rx,
+            srq_table,
+            qp_table,
+            attached: HashMap::new(),
+            completion_tx,
+            async_event_tx,
+        };
+        (worker, srq_tx)
+    }
+
+    pub(crate) fn spawn(self) {
+        let _handle = thread::Builder::new()
+            .name("srq-worker".into())
+            .spawn(move || self.run())
+            .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
+    }
+
+    fn run(mut self) {
+        while let Ok(task) = self.srq_rx.recv() {
+            self.handle(task);
+        }
+    }
+
+    fn handle(&mut self, task: SrqTask<Tx>) {
+        match task {
+            SrqTask::Refill { srq } => self.distribute(srq),
+            SrqTask::Attach { srq, qpn, tx } => {
+                let qp = AttachedQp {
+                    srq,
+                    tx,
+                    handed_out: VecDeque::new(),
+                    demanded: 0,
+                };
+                let _ignore = self.attached.insert(qpn, qp);
+                self.distribute(srq);
+            }
+            SrqTask::Detach { qpn } => {
+                if let Some(mut qp) = self.attached.remove(&qpn) {
+                    let _taken = Self::withdraw(&self.srq_table, &self.async_event_tx, &mut qp);
+                    self.distribute(qp.srq);
+                }
+            }
+            SrqTask::Consumed { srq, qpn } => {
+                if let Some(qp) = self.attached.get_mut(&qpn) {
+                    let _wr = qp.handed_out.pop_front();
+                }
+                if let Some(event) = self.srq_table.consume(srq) {
+                    let _ignore = self.async_event_tx.send(event);
+                }
+                self.distribute(srq);
+            }
+            SrqTask::Reclaim { qpn } => {
+                if let Some(qp) = self.attached.get_mut(&qpn) {
+                    let srq = qp.srq;
+                    qp.demanded = 0;
+                    let taken = Self::withdraw(&self.srq_table, &self.async_event_tx, qp);
+                    if !taken.is_empty() {
+                        let wr_ids = taken.iter().map(|wr| wr.wr_id).collect();
+                        let _ignore = self
+                            .completion_tx
+                            .send(CompletionTask::FlushRecv { qpn, wr_ids });
+                    }
+                    self.distribute(srq);
+                }
+            }
+            SrqTask::PeerDemand { qpn } => {
+                if let Some(qp) = self.attached.get_mut(&qpn) {
+                    let srq = qp.srq;
+                    qp.demanded += 1;
+                    self.distribute(srq);
+                }
+            }
+            SrqTask::Demand { qpn } => {
+                if let Some(qp) = self.attached.get_mut(&qpn) {
+                    if let Err(err) = qp.tx.demand() {
+                        error!("failed to demand recv wr for qp {qpn}: {err}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Withdraws the WRs handed out to the peer of the QP, those the peer never consumed
+    /// return to the SRQ
+    ///
+    /// Returns the WRs the peer took, they are consumed. All of them count as taken if the
+    /// peer can not be asked.
+    fn withdraw(
+        srq_table: &SrqTable,
+        async_event_tx: &flume::Sender<AsyncEvent>,
+        qp: &mut AttachedQp<Tx>,
+    ) -> VecDeque<RecvWr> {
+        let mut taken = mem::take(&mut qp.handed_out);
+        if taken.is_empty() {
+            return taken;
+        }
+        let revoked = qp.tx.revoke().unwrap_or_else(|err| {
+            error!("failed to revoke srq wrs from the peer: {err}");
+            0
+        });
+        let revoked = taken.split_off(taken.len().saturating_sub(revoked));
+        srq_table.give_back(qp.srq, revoked);
+        for _wr in &taken {
+            if let Some(event) = srq_table.consume(qp.srq) {
+                let _ignore = async_event_tx.send(event);
+            }
+        }
+        taken
+    }
+
+    /// Hands out WRs of the SRQ to the attached QPs that demanded them, then one to
+    /// each QP without WRs
+    fn distribute(&mut self, srq: u32) {
+        loop {
+            let next = self
+                .attached
+                .iter_mut()
+                .filter(|&(&qpn, ref qp)| {
+                    qp.srq == srq
+                        && (qp.demanded > 0 || qp.handed_out.is_empty())
+                        && self.qp_table.get(qpn).is_some_and(|attr| {
+                            matches!(
+                                attr.state,
+                                QpState::Rtr | QpState::Rts | QpState::Sqd | QpState::Sqe
+                            )
+                        })
+                })
+                .max_by_key(|entry| entry.1.demanded);
+            let Some((&qpn, qp)) = next else {
+                break;
+            };
+            let Some(wr) = self.srq_table.take(srq) else {
+                break;
+            };
+            qp.demanded = qp.demanded.saturating_sub(1);
+            qp.handed_out.push_back(wr);
+            let event = Event::PostRecv(PostRecvEvent::new_srq(wr.wr_id, wr.length, srq));
+            let _ignore = self
+                .completion_tx
+                .send(CompletionTask::Register { qpn, event });
+            if let Err(err) = qp.tx.send(wr) {
+                error!("failed to hand out srq wr to qp {qpn}: {err}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn recv_wr(wr_id: u64) -> RecvWr {
+        RecvWr {
+            wr_id,
+            addr: 0x1000,
+            length: 64,
+            lkey: 1,
+        }
+    }
+
+    #[test]
+    fn srq_limit_event_is_one_shot() {
+        let table = SrqTable::new();
+        let attr = SrqAttr {
+            max_wr: 4,
+            max_sge: 1,
+            srq_limit: 0,
+        };
+        table.init(0, 0x3000, attr).unwrap();
+        for wr_id in 0..4 {
+            table.post(0, recv_wr(wr_id)).unwrap();
+        }
+        assert!(table.post(0, recv_wr(4)).is_err());
+        table.modify(0, None, Some(3)).unwrap();
+
+        assert!(table.consume(0).is_none());
+        assert_eq!(
+            table.consume(0),
+            Some(AsyncEvent::SrqLimitReached {
+                srq_context: 0x3000
+            })
+        );
+        assert_eq!(table.query(0).unwrap().srq_limit, 0);
+        assert!(table.consume(0).is_none());
+        // shrinking below the outstanding WRs is rejected
+        table.post(0, recv_wr(5)).unwrap();
+        assert!(table.modify(0, Some(1), None).is_err());
+    }
+
+    /// Records the WRs handed out to the peer, the peer consumes all but the last one
+    #[derive(Default)]
+    struct RecordTx(Arc<Mutex<Vec<u64>>>);
+
+    impl PostRecvTx for RecordTx {
+        fn send(&mut self, wr: RecvWr) -> io::Result<()> {
+            self.0.lock().push(wr.wr_id);
+            Ok(())
+        }
+
+        fn revoke(&mut self) -> io::Result<usize> {
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn srq_wrs_are_handed_out_on_demand() {
+        const QPN: u32 = 1 << 8;
+        let table = SrqTable::new();
+        let attr = SrqAttr {
+            max_wr: 8,
+            max_sge: 1,
+            srq_limit: 0,
+        };
+        table.init(0, 0, attr).unwrap();
+        for wr_id in 0..4 {
+            table.post(0, recv_wr(wr_id)).unwrap();
+        }
+        let qp_table = QueuePairAttrTable::new();
+        qp_table.set_state(QPN, QpState::Rts);
+        let (completion_tx, completion_rx) = flume::unbounded();
+        let (async_event_tx, _async_event_rx) = flume::unbounded();
+        let (mut worker, _srq_tx) = SrqWorker::new(
+            table.clone_arc(),
+            qp_table.clone_arc(),
+            completion_tx,
+            async_event_tx,
+        );
+        let tx = RecordTx::default();
+        let handed_out = Arc::clone(&tx.0);
+
+        // one WR ahead of time, the rest stays shared
+        worker.handle(SrqTask::Attach {
+            srq: 0,
+            qpn: QPN,
+            tx,
+        });
+        assert_eq!(*handed_out.lock(), [0]);
+        worker.handle(SrqTask::PeerDemand { qpn: QPN });
+        worker.handle(SrqTask::PeerDemand { qpn: QPN });
+        assert_eq!(*handed_out.lock(), [0, 1, 2]);
+        worker.handle(SrqTask::Consumed { srq: 0, qpn: QPN });
+
+        // the WR withdrawn from the peer returns to the SRQ, the one it took is flushed
+        qp_table.set_state(QPN, QpState::Err);
+        worker.handle(SrqTask::Reclaim { qpn: QPN });
+        assert_eq!(table.take(0).map(|wr| wr.wr_id), Some(2));
+        assert_eq!(table.take(0).map(|wr| wr.wr_id), Some(3));
+        let flushed = completion_rx.try_iter().find_map(|task| match task {
+            CompletionTask::FlushRecv { qpn, wr_ids } => Some((qpn, wr_ids)),
+            _ => None,
+        });
+        assert_eq!(flushed, Some((QPN, vec![1])));
+    }
+}
@@ -4,11 +4,13 @@ use serde::{Deserialize, Serialize};
 use tracing::error;
 
 use crate::{
+    completion::{CompletionStatus, CompletionTask},
     constants::MAX_QP_CNT,
     device_protocol::{WorkReqSend, WrChunk},
     protocol_impl::SendQueueScheduler,
+    qp::num_psn,
     timer::TransportTimer,
-    utils::qpn_index,
+    utils::{qpn_index, Psn},
 };
 
 const DEFAULT_INIT_RETRY_COUNT: usize = 5;
@@ -81,6 +83,23 @@ impl Entry {
     fn set_last_packet(&mut self, packet: WrChunk) {
         self.last_packet_chunk = Some(packet);
     }
+
+    /// Stops the timer if the last `AckReq` chunk is acknowledged, otherwise restarts it
+    ///
+    /// All PSNs before `base_psn` are acknowledged, the chunk is acknowledged once its
+    /// last packet is.
+    fn ack(&mut self, base_psn: Psn) {
+        let Some(packet) = self.last_packet_chunk else {
+            return;
+        };
+        let num_psn = num_psn(packet.pmtu, packet.raddr, packet.len).unwrap_or(1);
+        if packet.psn + num_psn.max(1) <= base_psn {
+            self.timer.stop();
+            self.last_packet_chunk = None;
+        } else {
+            self.timer.reset();
+        }
+    }
 }
 
 #[allow(variant_size_differences)]
@@ -93,13 +112,14 @@ pub(crate) enum RetransmitTask {
     },
     ReceiveACK {
         qpn: u32,
+        psn: Psn,
     },
 }
 
 impl RetransmitTask {
     fn qpn(&self) -> u32 {
         match *self {
-            RetransmitTask::NewAckReq { qpn, .. } | RetransmitTask::ReceiveACK { qpn } => qpn,
+            RetransmitTask::NewAckReq { qpn, .. } | RetransmitTask::ReceiveACK { qpn, .. } => qpn,
         }
     }
 }
@@ -108,6 +128,7 @@ pub(crate) struct TimeoutRetransmitWorker {
     receiver: flume::Receiver<RetransmitTask>,
     table: TransportTimerTable,
     wr_sender: SendQueueScheduler,
+    completion_tx: flume::Sender<CompletionTask>,
     config: AckTimeoutConfig,
 }
 
@@ -115,11 +136,13 @@ impl TimeoutRetransmitWorker {
     pub(crate) fn new(
         receiver: flume::Receiver<RetransmitTask>,
         wr_sender: SendQueueScheduler,
+        completion_tx: flume::Sender<CompletionTask>,
         config: AckTimeoutConfig,
     ) -> Self {
         Self {
             receiver,
             wr_sender,
+            completion_tx,
             table: TransportTimerTable::new(config.local_ack_timeout_exp, config.init_retry_count),
             config,
         }
@@ -142,12 +165,14 @@ impl TimeoutRetransmitWorker {
                 let Some(entry) = self.table.get_qp_mut(task.qpn()) else {
                     continue;
                 };
-                if let RetransmitTask::NewAckReq {
-                    last_packet_chunk, ..
-                } = task
-                {
-                    entry.timer.reset();
-                    entry.set_last_packet(last_packet_chunk);
+                match task {
+                    RetransmitTask::NewAckReq {
+                        last_packet_chunk, ..
+                    } => {
+                        entry.timer.reset();
+                        entry.set_last_packet(last_packet_chunk);
+                    }
+                    RetransmitTask::ReceiveACK { psn, .. } => entry.ack(psn),
                 }
             }
             for (index, entry) in self.table.inner.iter_mut().enumerate() {
@@ -161,9 +186,40 @@ impl TimeoutRetransmitWorker {
                         }
                     }
                     Ok(false) => {}
-                    Err(_) => todo!("handles retry failure"),
+                    Err(_) => {
+                        entry.timer.stop();
+                        if let Some(packet) = entry.last_packet_chunk.take() {
+                            let _ignore = self.completion_tx.send(CompletionTask::Error {
+                                qpn: packet.sqpn,
+                                status: CompletionStatus::RetryExceeded,
+                            });
+                        }
+                    }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ack_covers_last_packet_of_chunk() {
+        let mut entry = Entry::new(TransportTimer::new(DEFAULT_LOCAL_ACK_TIMEOUT, 1));
+        let chunk = WrChunk {
+            pmtu: u8::try_from(ibverbs_sys::IBV_MTU_1024).unwrap(),
+            psn: Psn(10),
+            len: 3 * 1024,
+            ..Default::default()
+        };
+        entry.set_last_packet(chunk);
+        entry.ack(Psn(11));
+        assert!(entry.last_packet_chunk.is_some());
+        entry.ack(Psn(12));
+        assert!(entry.last_packet_chunk.is_some());
+        entry.ack(Psn(13));
+        assert!(entry.last_packet_chunk.is_none());
+    }
+}